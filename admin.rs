@@ -0,0 +1,476 @@
+use std::fmt::Write as _;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use crate::auth;
+use crate::auth::Role;
+use crate::bans::Network;
+use crate::drain;
+use crate::logging;
+use crate::logging::Level;
+use crate::logging::format_time;
+use crate::logging::info;
+use crate::metrics::Metrics;
+use crate::registry;
+use crate::registry::SharedConnections;
+use crate::state::RateTarget;
+use crate::state::SharedState;
+use crate::state::State;
+
+const HELP: &str = "Commands:
+  list        List all connected clients
+  info <id>   Show everything known about one client
+  kick <id> [reason]
+              Disconnect a client, telling it the reason if control packets are enabled
+  ban <ip|cidr>
+              Disconnect and refuse all clients from an address or network
+  unban <ip|cidr>
+              Lift a ban
+  bans        List all bans
+  access      Show the allow and deny lists
+  filters     List the payload filter rules
+  reload      Re-read the ban file, the allow and deny lists and the filter rules,
+              kick clients that may not connect anymore
+  stats       Show server counters
+  announce <message>, say <message>
+              Send a message from the server to all clients
+  ratelimit [<id|ip> <n|clear>]
+              List rate limit overrides, or give one client or address its own limit
+  latency [<id> <ms|clear>]
+              List clients with added latency, or delay the packets relayed to one
+              (e.g. to play with a high ping on a local playtest)
+  get         Show the settings that can be changed at runtime
+  set <setting> <n>
+              Change max_players, max_per_ip, max_rate, max_packets, total_rate or slow_client_ms
+              (0 = unlimited/off)
+  slow        List the clients that take longest to receive packets and their backlog
+  pause       Stop accepting new connections, connected clients keep playing
+  resume      Accept new connections again
+  drain [seconds]
+              Refuse new connections, count down and stop once everyone left or
+              the deadline passed (default drain_timeout)
+  shutdown    Stop the server
+  loglevel [debug|info|warning|error]
+              Show or change the log level
+  token <name> [player|spectator|admin] [seconds]
+              Issue a client token, valid for the given time (default 1 hour, 0 = forever)
+  help        Show this help
+  quit        Close this admin session
+";
+
+const SLOW_LIST_SIZE: usize = 10;
+
+/// Seconds a token issued with `token` is valid for by default.
+const TOKEN_LIFETIME: u64 = 3600;
+
+/// Runs a single admin command and returns its textual output.
+pub fn execute(command: &str, state: &SharedState) -> String {
+    let connections = &state.connections;
+    let mut parts = command.split_whitespace();
+
+    match parts.next() {
+        Some("list") => list(connections),
+        Some("info") => match parts.next().and_then(|v| v.parse::<i32>().ok()) {
+            Some(id) => client_info(state, id),
+            None => "Usage: info <id>\n".to_string()
+        },
+        Some("kick") => match parts.next().and_then(|v| v.parse::<i32>().ok()) {
+            Some(id) if state.kick(id, &parts.collect::<Vec<_>>().join(" "), "admin console") => format!("Kicked {}.\n", id),
+            Some(id) => format!("No client with ID {}.\n", id),
+            None => "Usage: kick <id> [reason]\n".to_string()
+        },
+        Some("ban") => match parts.next().map(|v| v.parse::<Network>()) {
+            Some(Ok(network)) => match state.ban(network, "admin console") {
+                Ok(kicked) => format!("Banned {}, {} client(s) kicked.\n", network, kicked),
+                Err(e) => format!("Banned {} but {}!\n", network, e)
+            },
+            Some(Err(e)) => format!("Could not parse ban, {}.\n", e),
+            None => "Usage: ban <ip|cidr>\n".to_string()
+        },
+        Some("unban") => match parts.next().map(|v| v.parse::<Network>()) {
+            Some(Ok(network)) => match state.unban(&network, "admin console") {
+                Ok(true) => format!("Unbanned {}.\n", network),
+                Ok(false) => format!("{} is not banned.\n", network),
+                Err(e) => format!("Could not unban {}, {}!\n", network, e)
+            },
+            Some(Err(e)) => format!("Could not parse ban, {}.\n", e),
+            None => "Usage: unban <ip|cidr>\n".to_string()
+        },
+        Some("bans") => bans(state),
+        Some("access") => match state.access.lock() {
+            Ok(access) => format!(
+                "Allow: {}\nDeny:  {}\n",
+                if access.allow().is_empty() { "everyone".to_string() } else { access.allow().iter().map(|n| n.to_string()).collect::<Vec<_>>().join(", ") },
+                if access.deny().is_empty() { "-".to_string() } else { access.deny().iter().map(|n| n.to_string()).collect::<Vec<_>>().join(", ") }
+            ),
+            Err(_) => "Could not lock allow and deny lists!\n".to_string()
+        },
+        Some("filters") => match state.filters().rules() {
+            [] => "No filter rules.\n".to_string(),
+            rules => rules.iter().enumerate().map(|(i, r)| format!("{:>3}  {}\n", i + 1, r)).collect()
+        },
+        Some("reload") => match state.reload("admin console") {
+            Ok((bans, kicked)) => format!("Reloaded {} ban(s), the allow and deny lists and the filter rules, {} client(s) kicked.\n", bans, kicked),
+            Err(e) => format!("Could not reload, {}!\n", e)
+        },
+        Some("stats") => stats(&state.metrics),
+        Some("announce" | "say") => match parts.collect::<Vec<_>>().join(" ") {
+            message if message.is_empty() => "Usage: announce <message>\n".to_string(),
+            message => match state.announce(&message, "admin console") {
+                Ok(sent) => format!("Announcement sent to {} client(s).\n", sent),
+                Err(e) => format!("Could not send announcement, {}.\n", e)
+            }
+        },
+        Some("ratelimit") => match (parts.next().map(RateTarget::parse), parts.next()) {
+            (None, _) => rate_overrides(state),
+            (Some(Some(target)), Some("clear")) if state.set_rate_override(target, None, "admin console") => format!("Removed the rate limit override of {}.\n", target),
+            (Some(Some(target)), Some("clear")) => format!("{} has no rate limit override.\n", target),
+            (Some(Some(target)), Some(n)) if let Ok(n) = n.parse::<i32>() && n >= 0 => {
+                state.set_rate_override(target, Some(n), "admin console");
+                format!("Rate limit of {} set to {}.\n", target, n)
+            },
+            _ => "Usage: ratelimit [<id|ip> <n|clear>]\n".to_string()
+        },
+        Some("latency") => match (parts.next().map(|v| v.parse::<i32>()), parts.next()) {
+            (None, _) => latencies(connections),
+            (Some(Ok(id)), Some("clear")) if state.set_latency(id, 0, "admin console") => format!("Removed the added latency of {}.\n", id),
+            (Some(Ok(id)), Some(ms)) if let Ok(ms) = ms.parse::<u32>() && state.set_latency(id, ms, "admin console") => format!("Packets to {} are delayed by {}ms.\n", id, ms),
+            (Some(Ok(id)), Some(_)) if !connections.read().is_ok_and(|c| c.contains_key(&id)) => format!("No client with ID {}.\n", id),
+            _ => "Usage: latency [<id> <ms|clear>]\n".to_string()
+        },
+        Some("get") => state.settings().iter().map(|(key, value)| format!("{:<16} {}\n", key, value)).collect(),
+        Some("set") => match (parts.next(), parts.next().and_then(|v| v.parse::<i32>().ok())) {
+            (Some(key), Some(n)) => match state.set(key, n, "admin console") {
+                Ok(_) => format!("{} set to {}.\n", key, n),
+                Err(e) => format!("Could not change setting, {}.\n", e)
+            },
+            _ => "Usage: set <setting> <n>\n".to_string()
+        },
+        Some("pause" | "resume") if state.draining.load(Ordering::SeqCst) => "The server is draining.\n".to_string(),
+        Some("pause") if state.set_paused(true, "admin console") => "Paused, new connections are refused.\n".to_string(),
+        Some("pause") => "Already paused.\n".to_string(),
+        Some("resume") if state.set_paused(false, "admin console") => "Resumed accepting connections.\n".to_string(),
+        Some("resume") => "Not paused.\n".to_string(),
+        Some("drain") => match parts.next().map(|v| v.parse::<i32>()) {
+            Some(Err(_)) => "Usage: drain [seconds]\n".to_string(),
+            seconds => {
+                let seconds = seconds.and_then(Result::ok).unwrap_or(state.drain_timeout);
+                if drain::start(state, seconds, "admin console") { format!("Draining, stopping in at most {}s.\n", seconds) } else { "Already draining.\n".to_string() }
+            }
+        },
+        Some("shutdown") => {
+            info!("Shutdown requested over the admin console.");
+            state.stop();
+            "Shutting down.\n".to_string()
+        },
+        Some("slow") => slow(connections),
+        Some("loglevel") => match parts.next() {
+            None => format!("Log level is {}.\n", logging::level().name()),
+            Some(name) => match Level::parse(name) {
+                Some(level) => {
+                    logging::change_level(level, "admin console");
+                    format!("Log level set to {}.\n", level.name())
+                },
+                None => "Usage: loglevel [debug|info|warning|error]\n".to_string()
+            }
+        },
+        Some("token") => match (parts.next(), parts.next().map(Role::parse), parts.next().map(|s| s.parse::<u64>())) {
+            _ if state.token_secret.is_empty() => "Token authentication is disabled, set token_secret first.\n".to_string(),
+            (Some(name), role, seconds) if !matches!(role, Some(None)) && !matches!(seconds, Some(Err(_))) => {
+                let role = role.flatten().unwrap_or(Role::Player);
+                let seconds = seconds.and_then(|s| s.ok()).unwrap_or(TOKEN_LIFETIME);
+                info!("Token issued for {} ({}) over the admin console.", name, role.name());
+                format!("{}\n", auth::issue_token(&state.token_secret, name, role, seconds))
+            },
+            _ => "Usage: token <name> [player|spectator|admin] [seconds]\n".to_string()
+        },
+        Some("help") => HELP.to_string(),
+        Some(other) => format!("Unknown command '{}', type 'help' for a list of commands.\n", other),
+        None => String::new()
+    }
+}
+
+fn list(connections: &SharedConnections) -> String {
+    let Some(clients) = registry::snapshot(connections) else { return "Could not lock connections!\n".to_string(); };
+
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<6} {:<22} {:<21} {:>9} {:>10} {:>12} {:>10} {:>12} {:>8}", "ID", "ADDRESS", "CONNECTED", "IDLE", "PKTS IN", "BYTES IN", "PKTS OUT", "BYTES OUT", "DROPS");
+    for client in &clients {
+        let idle = SystemTime::now().duration_since(client.last_activity()).map(|d| d.as_secs()).unwrap_or(0);
+        let _ = writeln!(
+            out, "{:<6} {:<22} {:<21} {:>8}s {:>10} {:>12} {:>10} {:>12} {:>8}",
+            client.id, client.meta.addr.to_string(), format_time(client.meta.connected_at), idle,
+            client.traffic.packets_received.load(Ordering::Relaxed), client.traffic.bytes_received.load(Ordering::Relaxed),
+            client.traffic.packets_sent.load(Ordering::Relaxed), client.traffic.bytes_sent.load(Ordering::Relaxed),
+            client.traffic.rate_limit_drops.load(Ordering::Relaxed)
+        );
+    }
+    let _ = writeln!(out, "{} client(s) connected.", clients.len());
+
+    out
+}
+
+fn client_info(state: &State, id: i32) -> String {
+    let Some(clients) = registry::snapshot(&state.connections) else { return "Could not lock connections!\n".to_string(); };
+    let Some(client) = clients.iter().find(|c| c.id == id) else { return format!("No client with ID {}.\n", id); };
+
+    let mut out = String::new();
+    let _ = writeln!(out, "ID            {}", client.id);
+    let _ = writeln!(out, "Session       {}", client.meta.session);
+    let _ = writeln!(out, "Address       {}", client.meta.addr);
+    let _ = writeln!(out, "Transport     {}", client.meta.transport);
+    let _ = writeln!(out, "Features      {}", if client.meta.features.is_empty() { "-".to_string() } else { client.meta.features.join(", ") });
+    let _ = writeln!(out, "Identity      {}", client.meta.identity.as_ref().map(|i| i.to_string()).unwrap_or("-".to_string()));
+    let _ = writeln!(out, "Connected     {}", format_time(client.meta.connected_at));
+    let _ = writeln!(out, "Last activity {}", format_time(client.last_activity()));
+    let _ = writeln!(out, "Traffic       {}", client.traffic.summary());
+    let _ = writeln!(out, "Rate limit    {}", match state.client_rate_limit(id, &client.meta.addr.ip()) { 0 => "unlimited".to_string(), n => format!("{} bytes/s", n) });
+    let _ = writeln!(out, "Avg write     {}us (max {}us)", client.traffic.average_write_micros(), client.traffic.max_write_micros.load(Ordering::Relaxed));
+
+    out
+}
+
+fn bans(state: &State) -> String {
+    let Ok(bans) = state.bans.lock() else { return "Could not lock ban list!\n".to_string(); };
+    let Ok(offenders) = state.offenders.lock() else { return "Could not lock temporary bans!\n".to_string(); };
+
+    let mut out: String = bans.networks().iter().map(|n| format!("{}\n", n)).collect();
+    let now = Instant::now();
+    let mut temporary = 0;
+    for (ip, offender) in offenders.iter().filter(|(_, o)| o.banned_until > now) {
+        let _ = writeln!(out, "{} (temporary, {}s left, offense {})", ip, (offender.banned_until - now).as_secs(), offender.offenses);
+        temporary += 1;
+    }
+    let _ = writeln!(out, "{} ban(s), {} temporary.", bans.networks().len(), temporary);
+    out
+}
+
+fn rate_overrides(state: &State) -> String {
+    let Ok(overrides) = state.rate_overrides.lock() else { return "Could not lock rate limit overrides!\n".to_string(); };
+
+    let mut out = String::new();
+    for (target, limit) in overrides.iter() {
+        let _ = writeln!(out, "{:<24} {}", target.to_string(), limit);
+    }
+    let _ = writeln!(out, "{} override(s).", overrides.len());
+    out
+}
+
+fn latencies(connections: &SharedConnections) -> String {
+    let Ok(connections) = connections.read() else { return "Could not lock connections!\n".to_string(); };
+
+    let mut delayed: Vec<(i32, u32)> = connections.iter().map(|(id, c)| (*id, c.latency_ms.load(Ordering::Relaxed))).filter(|(_, ms)| *ms > 0).collect();
+    delayed.sort_unstable();
+    let mut out = String::new();
+    for (id, ms) in &delayed {
+        let _ = writeln!(out, "{:<6} +{}ms", id, ms);
+    }
+    let _ = writeln!(out, "{} client(s) with added latency.", delayed.len());
+    out
+}
+
+fn stats(metrics: &Metrics) -> String {
+    let mut out = String::new();
+    for (name, _, value) in metrics.gauges().into_iter().chain(metrics.counters()) {
+        let _ = writeln!(out, "{:<29} {}", name, value);
+    }
+    for (reason, value) in metrics.disconnects() {
+        let _ = writeln!(out, "{:<29} {}", format!("disconnects.{}", reason), value);
+    }
+
+    out
+}
+
+fn slow(connections: &SharedConnections) -> String {
+    let connections = match connections.read() {
+        Ok(c) => c,
+        Err(_) => return "Could not lock connections!\n".to_string()
+    };
+
+    let mut clients: Vec<_> = connections.iter().collect();
+    clients.sort_by_key(|(_, c)| std::cmp::Reverse(c.traffic.average_write_micros()));
+
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<6} {:<22} {:>12} {:>12} {:>10} {:>10} {:>5}", "ID", "ADDRESS", "AVG WRITE", "MAX WRITE", "UNSENT", "QUEUED", "SLOW");
+    for (id, client) in clients.into_iter().take(SLOW_LIST_SIZE) {
+        let _ = writeln!(
+            out, "{:<6} {:<22} {:>10}us {:>10}us {:>10} {:>10} {:>5}",
+            id, client.meta.addr.to_string(), client.traffic.average_write_micros(), client.traffic.max_write_micros.load(Ordering::Relaxed),
+            client.stream.unsent_bytes().map(|n| n.to_string()).unwrap_or("?".to_string()), client.outbox.queued_bytes(),
+            if client.traffic.is_slow() { "yes" } else { "no" }
+        );
+    }
+
+    out
+}
+
+fn handle_session(reader: impl Read, mut writer: impl Write, peer: String, state: SharedState) {
+    info!("Admin session opened from {}.", peer);
+
+    let reader = BufReader::new(reader);
+
+    for line in reader.lines() {
+        let Ok(line) = line else { break; };
+        let command = line.trim();
+        if command == "quit" || command == "exit" { break; }
+
+        if writer.write_all(execute(command, &state).as_bytes()).is_err() { break; }
+    }
+
+    info!("Admin session from {} closed.", peer);
+}
+
+/// Reads admin commands from stdin, for servers running in the foreground. `quit` stops the server here.
+pub fn serve_stdin(state: SharedState) {
+    thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            let Ok(line) = line else { break; };
+            let command = line.trim();
+
+            if command == "quit" || command == "exit" {
+                info!("Shutdown requested on the console.");
+                state.stop();
+                break;
+            }
+            print!("{}", execute(command, &state));
+        }
+    });
+}
+
+/// Starts the admin console on `address:port`, accepting one text command per line.
+pub fn serve(address: &str, port: i32, state: SharedState) -> std::io::Result<()> {
+    let listener = TcpListener::bind(format!("{}:{}", address, port))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let state = Arc::clone(&state);
+            let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+            thread::spawn(move || handle_session(&stream, &stream, peer, state));
+        }
+    });
+
+    Ok(())
+}
+
+/// Starts the admin console on a unix socket at `path`, access is controlled by the file `mode` (e.g. 0o660).
+#[cfg(unix)]
+pub fn serve_unix(path: &str, mode: u32, state: SharedState) -> std::io::Result<()> {
+    let listener = bind_unix(std::path::Path::new(path), mode)?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let state = Arc::clone(&state);
+            let peer = peer_uid(&stream).map(|uid| format!("unix socket (uid {})", uid)).unwrap_or("unix socket".to_string());
+            thread::spawn(move || handle_session(&stream, &stream, peer, state));
+        }
+    });
+
+    Ok(())
+}
+
+/// Binds a unix socket at `path` with the file `mode`. It is bound in a directory only we can enter, given its mode
+/// there and then moved to `path`, so nobody can connect to it while it still has the permissions of the umask.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path, mode: u32) -> std::io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::DirBuilderExt;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+
+    // a socket left behind by a previous run would be in the way, anything else is not ours to replace
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "the path exists and is not a socket")),
+        Err(_) => ()
+    }
+
+    let name = path.file_name().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "the path has no file name"))?;
+    let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join(name);
+    let result = UnixListener::bind(&bound).and_then(|listener| {
+        std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&bound);
+    let _ = std::fs::remove_dir(&private);
+    result
+}
+
+/// User ID of the process on the other end of a unix socket (Linux only).
+#[cfg(unix)]
+fn peer_uid(stream: &std::os::unix::net::UnixStream) -> Option<u32> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // SAFETY: SO_PEERCRED writes at most `len` bytes into `cred` for a valid unix socket descriptor.
+        let result = unsafe {
+            libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+        };
+        if result == 0 { Some(cred.uid) } else { None }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = stream;
+        None
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("echoserver-admin-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn binds_the_socket_with_its_mode() {
+        let directory = directory("mode");
+        let path = directory.join("control.sock");
+        let listener = bind_unix(&path, 0o600).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        // nothing is left of the directory it was bound in
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+
+        let _client = UnixStream::connect(&path).unwrap();
+        assert!(listener.accept().is_ok());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn replaces_a_stale_socket_only() {
+        let directory = directory("stale");
+        let path = directory.join("control.sock");
+        drop(bind_unix(&path, 0o660).unwrap());
+        let _listener = bind_unix(&path, 0o660).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+
+        let file = directory.join("file");
+        std::fs::write(&file, "keep").unwrap();
+        assert!(bind_unix(&file, 0o660).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 2);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,53 @@
+//! Pinning threads to CPU cores (`accept_cpu`, `worker_cpus`), so the server can stay off the cores of
+//! game servers on the same machine. Only supported on Linux.
+
+use std::io;
+
+/// Cores a `cpu_set_t` can hold.
+const MAX_CPUS: usize = 1024;
+
+/// Parses a list of cores such as `0,2,4-7`, an empty list means not pinned.
+pub fn parse(list: &str) -> Result<Vec<usize>, String> {
+    let mut cores = Vec::new();
+    for part in list.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        match (first.trim().parse::<usize>(), last.trim().parse::<usize>()) {
+            (Ok(first), Ok(last)) if first <= last && last < MAX_CPUS => cores.extend(first..=last),
+            _ => return Err(format!("CPU list entry {}", part))
+        }
+    }
+    Ok(cores)
+}
+
+/// The cores the calling thread may run on.
+#[cfg(target_os = "linux")]
+pub fn current() -> io::Result<Vec<usize>> {
+    // SAFETY: cpu_set_t is a plain bit set, all zeroes is an empty one the kernel fills in.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: `set` is a valid cpu_set_t of the given size, 0 is the calling thread.
+    if unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut set) } != 0 { return Err(io::Error::last_os_error()); }
+    // SAFETY: every core checked is below MAX_CPUS, the size of the set.
+    Ok((0..MAX_CPUS).filter(|core| unsafe { libc::CPU_ISSET(*core, &set) }).collect())
+}
+
+/// Restricts the calling thread (and the threads it starts from then on) to `cores`.
+#[cfg(target_os = "linux")]
+pub fn pin(cores: &[usize]) -> io::Result<()> {
+    // SAFETY: see `current`.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: `parse` only returns cores below MAX_CPUS.
+    for core in cores.iter().filter(|core| **core < MAX_CPUS) { unsafe { libc::CPU_SET(*core, &mut set); } }
+    // SAFETY: `set` is a valid cpu_set_t of the given size, 0 is the calling thread.
+    if unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) } != 0 { return Err(io::Error::last_os_error()); }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn current() -> io::Result<Vec<usize>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin(_cores: &[usize]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
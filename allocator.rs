@@ -0,0 +1,177 @@
+//! The global allocator and its statistics. The system allocator by default, or jemalloc / mimalloc with
+//! the `jemalloc` / `mimalloc` build features (linking the system libjemalloc / libmimalloc), which hold
+//! up better against the fragmentation of a long-running relay's many small frame allocations.
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the jemalloc and mimalloc features can't be enabled together");
+
+/// Memory as seen by the allocator, `None` where it doesn't tell.
+pub struct Stats {
+    /// Bytes handed out to the server.
+    pub allocated: Option<u64>,
+    /// Bytes the allocator holds from the system, allocated or not, the difference is fragmentation and caches.
+    pub resident: Option<u64>
+}
+
+#[cfg(feature = "jemalloc")]
+pub const NAME: &str = "jemalloc";
+#[cfg(feature = "mimalloc")]
+pub const NAME: &str = "mimalloc";
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub const NAME: &str = "system";
+
+#[cfg(feature = "jemalloc")]
+mod jemalloc {
+    use std::alloc::GlobalAlloc;
+    use std::alloc::Layout;
+    use std::ffi::c_char;
+    use std::ffi::c_int;
+    use std::ffi::c_void;
+    use std::ptr;
+
+    use super::Stats;
+
+    const MALLOCX_ZERO: c_int = 0x40;
+    /// Alignment `malloc` guarantees anyway, larger ones are passed as flags.
+    const MIN_ALIGN: usize = 16;
+
+    #[link(name = "jemalloc")]
+    unsafe extern "C" {
+        fn mallocx(size: usize, flags: c_int) -> *mut c_void;
+        fn rallocx(ptr: *mut c_void, size: usize, flags: c_int) -> *mut c_void;
+        fn sdallocx(ptr: *mut c_void, size: usize, flags: c_int);
+        fn mallctl(name: *const c_char, oldp: *mut c_void, oldlenp: *mut usize, newp: *mut c_void, newlen: usize) -> c_int;
+    }
+
+    /// `MALLOCX_ALIGN` for alignments over `MIN_ALIGN`.
+    fn flags(layout: &Layout) -> c_int {
+        if layout.align() <= MIN_ALIGN && layout.align() <= layout.size() { 0 } else { layout.align().trailing_zeros() as c_int }
+    }
+
+    pub struct Jemalloc;
+
+    // SAFETY: all requests are passed on to jemalloc with their size and alignment.
+    unsafe impl GlobalAlloc for Jemalloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            // SAFETY: `layout` has a non-zero size, as GlobalAlloc guarantees.
+            unsafe { mallocx(layout.size(), flags(&layout)).cast() }
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            // SAFETY: see `alloc`.
+            unsafe { mallocx(layout.size(), flags(&layout) | MALLOCX_ZERO).cast() }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            // SAFETY: `ptr` was allocated with `layout`, as GlobalAlloc guarantees.
+            unsafe { sdallocx(ptr.cast(), layout.size(), flags(&layout)) }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            // SAFETY: see `dealloc`, the alignment stays the same.
+            unsafe { rallocx(ptr.cast(), new_size, flags(&layout)).cast() }
+        }
+    }
+
+    /// Reads a `size_t` statistic such as `stats.allocated`.
+    fn read(name: &[u8]) -> Option<u64> {
+        let (mut value, mut len) = (0usize, size_of::<usize>());
+        // SAFETY: `name` is NUL terminated and `value` is a size_t of `len` bytes.
+        let result = unsafe { mallctl(name.as_ptr().cast(), (&mut value as *mut usize).cast(), &mut len, ptr::null_mut(), 0) };
+        (result == 0).then_some(value as u64)
+    }
+
+    pub fn stats() -> Stats {
+        // statistics are cached until the epoch is advanced
+        let (mut epoch, mut len) = (1u64, size_of::<u64>());
+        // SAFETY: the name is NUL terminated, the epoch is a u64 of `len` bytes read and written by mallctl.
+        unsafe { mallctl(c"epoch".as_ptr(), (&mut epoch as *mut u64).cast(), &mut len, (&mut epoch as *mut u64).cast(), len); }
+        Stats { allocated: read(b"stats.allocated\0"), resident: read(b"stats.resident\0") }
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static ALLOCATOR: jemalloc::Jemalloc = jemalloc::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+mod mimalloc {
+    use std::alloc::GlobalAlloc;
+    use std::alloc::Layout;
+    use std::ffi::c_void;
+
+    use super::Stats;
+
+    #[link(name = "mimalloc")]
+    unsafe extern "C" {
+        fn mi_malloc_aligned(size: usize, alignment: usize) -> *mut c_void;
+        fn mi_zalloc_aligned(size: usize, alignment: usize) -> *mut c_void;
+        fn mi_realloc_aligned(ptr: *mut c_void, size: usize, alignment: usize) -> *mut c_void;
+        fn mi_free(ptr: *mut c_void);
+        fn mi_process_info(
+            elapsed_msecs: *mut usize, user_msecs: *mut usize, system_msecs: *mut usize, current_rss: *mut usize,
+            peak_rss: *mut usize, current_commit: *mut usize, peak_commit: *mut usize, page_faults: *mut usize
+        );
+    }
+
+    pub struct Mimalloc;
+
+    // SAFETY: all requests are passed on to mimalloc with their size and alignment.
+    unsafe impl GlobalAlloc for Mimalloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            // SAFETY: `layout` has a non-zero size and a power of two alignment, as GlobalAlloc guarantees.
+            unsafe { mi_malloc_aligned(layout.size(), layout.align()).cast() }
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            // SAFETY: see `alloc`.
+            unsafe { mi_zalloc_aligned(layout.size(), layout.align()).cast() }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+            // SAFETY: `ptr` was allocated by mimalloc.
+            unsafe { mi_free(ptr.cast()) }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            // SAFETY: `ptr` was allocated by mimalloc with the same alignment.
+            unsafe { mi_realloc_aligned(ptr.cast(), new_size, layout.align()).cast() }
+        }
+    }
+
+    /// mimalloc only reports the memory it committed, not how much of it is allocated.
+    pub fn stats() -> Stats {
+        let mut info = [0usize; 8];
+        let [elapsed, user, system, rss, peak_rss, commit, peak_commit, faults] = &mut info;
+        // SAFETY: every out pointer is a valid size_t.
+        unsafe { mi_process_info(elapsed, user, system, rss, peak_rss, commit, peak_commit, faults); }
+        Stats { allocated: None, resident: Some(info[5] as u64) }
+    }
+}
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static ALLOCATOR: mimalloc::Mimalloc = mimalloc::Mimalloc;
+
+/// Current statistics of the allocator in use.
+pub fn stats() -> Stats {
+    #[cfg(feature = "jemalloc")]
+    return jemalloc::stats();
+    #[cfg(feature = "mimalloc")]
+    return mimalloc::stats();
+    #[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+    return system_stats();
+}
+
+/// glibc's `mallinfo2`, summed over all arenas.
+#[cfg(all(not(any(feature = "jemalloc", feature = "mimalloc")), target_os = "linux", target_env = "gnu"))]
+fn system_stats() -> Stats {
+    // SAFETY: plain call without arguments, it only reads the allocator's state.
+    let info = unsafe { libc::mallinfo2() };
+    Stats { allocated: Some((info.uordblks + info.hblkhd) as u64), resident: Some((info.arena + info.hblkhd) as u64) }
+}
+
+#[cfg(all(not(any(feature = "jemalloc", feature = "mimalloc")), not(all(target_os = "linux", target_env = "gnu"))))]
+fn system_stats() -> Stats {
+    Stats { allocated: None, resident: None }
+}
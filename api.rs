@@ -0,0 +1,243 @@
+//! JSON admin API over HTTP, every request needs `Authorization: Bearer <api_token>`.
+//! `GET /` serves a small web dashboard built on top of the API, which asks for the token.
+//!
+//! - `GET /clients` lists all connected clients, `ip` is their address without the port (what `POST /bans` takes)
+//! - `DELETE /clients/{id}?reason=...` kicks a client
+//! - `GET /bans` lists all bans, `POST /bans?address=<ip|cidr>` adds one and `DELETE /bans?address=<ip|cidr>` lifts it
+//! - `POST /bans/reload` re-reads the ban file, the allow and deny lists and the filter rules, then kicks clients that may not connect anymore
+//! - `GET /ratelimits` lists rate limit overrides, `PUT /ratelimits?target=<id|ip>&max_rate=<n>` sets one
+//!   and `DELETE /ratelimits?target=<id|ip>` removes it
+//! - `POST /announce` sends the (plain text) body to all clients as an announcement
+//! - `POST /pause` stops accepting new connections, `POST /resume` starts again
+//! - `POST /drain?seconds=<n>` drains the server, see `drain::start`
+//! - `GET /stats` returns all server counters, `GET /logs` the most recent log lines
+//! - `GET /config` returns the runtime settings, `PUT /config` changes them, e.g. `{"max_rate": 16000}`
+
+use std::sync::atomic::Ordering;
+
+use crate::auth;
+use crate::bans::Network;
+use crate::drain;
+use crate::http;
+use crate::http::Request;
+use crate::http::Response;
+use crate::http::json_string;
+use crate::logging;
+use crate::logging::format_time;
+use crate::registry;
+use crate::state::RateTarget;
+use crate::state::SharedState;
+use crate::state::State;
+
+fn json(status: u16, body: String) -> Response {
+    Response::new(status, "application/json", body + "\n")
+}
+
+fn json_error(status: u16, message: &str) -> Response {
+    json(status, format!("{{\"error\":{}}}", json_string(message)))
+}
+
+/// The page served at `/` and `/dashboard`, it calls the API with the token it asks for.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+fn handle(request: &Request, token: &str, state: &SharedState) -> Response {
+    if request.method == "GET" && (request.path == "/" || request.path == "/dashboard") {
+        return Response::new(200, "text/html; charset=utf-8", DASHBOARD);
+    }
+
+    let authorized = request.header("authorization").and_then(|v| v.strip_prefix("Bearer ")).is_some_and(|t| auth::constant_time_eq(t.trim().as_bytes(), token.as_bytes()));
+    if !authorized { return json_error(401, "missing or invalid bearer token"); }
+
+    let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+
+    match (request.method.as_str(), path) {
+        ("GET", "/clients") => clients(state),
+        ("GET", "/stats") => stats(state),
+        ("GET", "/bans") => bans(state),
+        ("GET", "/ratelimits") => rate_overrides(state),
+        ("PUT" | "DELETE", "/ratelimits") => match http::query_param(query, "target").as_deref().map(RateTarget::parse) {
+            Some(Some(target)) if request.method == "DELETE" => match state.set_rate_override(target, None, "REST API") {
+                true => rate_overrides(state),
+                false => json_error(404, &format!("{} has no rate limit override", target))
+            },
+            Some(Some(target)) => match http::query_param(query, "max_rate").map(|v| v.parse::<i32>()) {
+                Some(Ok(n)) if n >= 0 => {
+                    state.set_rate_override(target, Some(n), "REST API");
+                    rate_overrides(state)
+                },
+                _ => json_error(400, "max_rate must be a number >= 0")
+            },
+            _ => json_error(400, "target must be a client ID or an IP address")
+        },
+        ("GET", "/logs") => {
+            let lines: Vec<String> = logging::recent().iter().map(|l| json_string(l)).collect();
+            json(200, format!("[{}]", lines.join(",")))
+        },
+        ("POST", "/pause" | "/resume") if state.draining.load(Ordering::SeqCst) => json_error(409, "the server is draining"),
+        ("POST", "/drain") => match http::query_param(query, "seconds").map(|v| v.parse::<i32>()) {
+            Some(Err(_)) => json_error(400, "invalid seconds parameter"),
+            seconds => {
+                let seconds = seconds.and_then(Result::ok).unwrap_or(state.drain_timeout);
+                if drain::start(state, seconds, "REST API") { json(200, format!("{{\"draining\":true,\"seconds\":{}}}", seconds)) }
+                else { json_error(409, "the server is already draining") }
+            }
+        },
+        ("POST", "/pause" | "/resume") => {
+            state.set_paused(path == "/pause", "REST API");
+            json(200, format!("{{\"paused\":{}}}", state.paused.load(Ordering::SeqCst)))
+        },
+        ("POST", "/announce") => match std::str::from_utf8(&request.body).map(str::trim) {
+            Ok("") => json_error(400, "the body must contain the message"),
+            Ok(message) => match state.announce(message, "REST API") {
+                Ok(sent) => json(200, format!("{{\"sent\":{}}}", sent)),
+                Err(e) => json_error(409, &e)
+            },
+            Err(_) => json_error(400, "body is not UTF-8")
+        },
+        ("POST" | "DELETE", "/bans") => match http::query_param(query, "address").map(|v| v.parse::<Network>()) {
+            Some(Ok(network)) if request.method == "POST" => match state.ban(network, "REST API") {
+                Ok(kicked) => json(200, format!("{{\"banned\":{},\"kicked\":{}}}", json_string(&network.to_string()), kicked)),
+                Err(e) => json_error(500, &format!("banned {} but {}", network, e))
+            },
+            Some(Ok(network)) => match state.unban(&network, "REST API") {
+                Ok(true) => json(200, format!("{{\"unbanned\":{}}}", json_string(&network.to_string()))),
+                Ok(false) => json_error(404, &format!("{} is not banned", network)),
+                Err(e) => json_error(500, &e)
+            },
+            Some(Err(e)) => json_error(400, &e),
+            None => json_error(400, "missing address parameter")
+        },
+        ("POST", "/bans/reload") => match state.reload("REST API") {
+            Ok((bans, kicked)) => json(200, format!("{{\"bans\":{},\"kicked\":{}}}", bans, kicked)),
+            Err(e) => json_error(500, &e)
+        },
+        ("GET", "/config") => json(200, config_json(state)),
+        ("PUT", "/config") => put_config(request, state),
+        ("DELETE", _) if path.starts_with("/clients/") => match path["/clients/".len()..].parse::<i32>() {
+            Ok(id) if state.kick(id, &http::query_param(query, "reason").unwrap_or_default(), "REST API") => json(200, format!("{{\"kicked\":{}}}", id)),
+            Ok(id) => json_error(404, &format!("no client with ID {}", id)),
+            Err(_) => json_error(400, "invalid client ID")
+        },
+        (_, "/clients" | "/stats" | "/config" | "/bans" | "/bans/reload" | "/announce" | "/pause" | "/resume" | "/drain" | "/logs" | "/ratelimits") => json_error(405, "method not allowed"),
+        _ if path.starts_with("/clients/") => json_error(405, "method not allowed"),
+        _ => json_error(404, "not found")
+    }
+}
+
+fn clients(state: &State) -> Response {
+    let Some(clients) = registry::snapshot(&state.connections) else { return json_error(503, "could not lock connections"); };
+
+    let entries: Vec<String> = clients.iter().map(|c| {
+        let features: Vec<String> = c.meta.features.iter().map(|f| json_string(f)).collect();
+        format!(
+            "{{\"id\":{},\"session\":{},\"address\":{},\"ip\":{},\"transport\":{},\"features\":[{}],\"identity\":{},\"role\":{},\"connected_at\":{},\"last_activity\":{},\
+            \"packets_received\":{},\"bytes_received\":{},\"packets_sent\":{},\"bytes_sent\":{},\"rate_limit_drops\":{}}}",
+            c.id, json_string(&c.meta.session), json_string(&c.meta.addr.to_string()), json_string(&c.meta.addr.ip().to_string()),
+            json_string(c.meta.transport), features.join(","),
+            c.meta.identity.as_ref().map(|i| json_string(&i.name)).unwrap_or("null".to_string()),
+            c.meta.identity.as_ref().map(|i| json_string(i.role.name())).unwrap_or("null".to_string()),
+            json_string(&format_time(c.meta.connected_at)), json_string(&format_time(c.last_activity())),
+            c.traffic.packets_received.load(Ordering::Relaxed), c.traffic.bytes_received.load(Ordering::Relaxed),
+            c.traffic.packets_sent.load(Ordering::Relaxed), c.traffic.bytes_sent.load(Ordering::Relaxed),
+            c.traffic.rate_limit_drops.load(Ordering::Relaxed)
+        )
+    }).collect();
+
+    json(200, format!("[{}]", entries.join(",")))
+}
+
+fn stats(state: &State) -> Response {
+    json(200, state.metrics.snapshot(state.started.elapsed()).to_json())
+}
+
+fn bans(state: &State) -> Response {
+    let Ok(bans) = state.bans.lock() else { return json_error(503, "could not lock ban list"); };
+
+    let entries: Vec<String> = bans.networks().iter().map(|n| json_string(&n.to_string())).collect();
+    json(200, format!("[{}]", entries.join(",")))
+}
+
+fn rate_overrides(state: &State) -> Response {
+    let Ok(overrides) = state.rate_overrides.lock() else { return json_error(503, "could not lock rate limit overrides"); };
+
+    let entries: Vec<String> = overrides.iter().map(|(target, limit)| match target {
+        RateTarget::Client(id) => format!("{{\"client\":{},\"max_rate\":{}}}", id, limit),
+        RateTarget::Address(ip) => format!("{{\"address\":{},\"max_rate\":{}}}", json_string(&ip.to_string()), limit)
+    }).collect();
+    json(200, format!("[{}]", entries.join(",")))
+}
+
+fn config_json(state: &State) -> String {
+    let settings: Vec<String> = state.settings().iter().map(|(key, value)| format!("\"{}\":{}", key, value)).collect();
+    format!("{{{}}}", settings.join(","))
+}
+
+/// Accepts a JSON object of integer settings, the whole body and all keys are validated before any is applied.
+fn put_config(request: &Request, state: &State) -> Response {
+    let Ok(body) = std::str::from_utf8(&request.body) else { return json_error(400, "body is not UTF-8"); };
+    let settings = match parse_settings(body) {
+        Ok(settings) => settings,
+        Err(e) => return json_error(400, &e)
+    };
+
+    for (key, value) in &settings {
+        if let Err(e) = state.check_setting(key, *value) { return json_error(400, &e); }
+    }
+
+    for (key, value) in settings {
+        let _ = state.set(&key, value, "REST API");
+    }
+
+    json(200, config_json(state))
+}
+
+/// The settings of a `PUT /config` body, sorted by key, an error unless it is a JSON object with at least one
+/// member and only non-negative integers that fit.
+fn parse_settings(body: &str) -> Result<Vec<(String, i32)>, String> {
+    let members = auth::parse_object(body).ok_or("expected a JSON object like {\"max_rate\": 16000}")?;
+    if members.is_empty() { return Err("expected a JSON object like {\"max_rate\": 16000}".to_string()); }
+
+    let mut settings = Vec::with_capacity(members.len());
+    for (key, value) in members {
+        match value {
+            auth::Value::Integer(n) if n <= i32::MAX as u64 => settings.push((key, n as i32)),
+            auth::Value::Integer(n) => return Err(format!("{} is out of range ({})", key, n)),
+            _ => return Err(format!("{} must be a non-negative integer", key))
+        }
+    }
+    settings.sort();
+    Ok(settings)
+}
+
+/// Starts the API on `address:port`.
+pub fn serve(address: &str, port: i32, token: String, state: SharedState) -> std::io::Result<()> {
+    http::serve(address, port, move |request| handle(request, &token, &state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_are_read_from_a_json_object() {
+        assert_eq!(parse_settings(r#"{"max_rate": 16000, "max_players":20}"#), Ok(vec![("max_players".to_string(), 20), ("max_rate".to_string(), 16000)]));
+        assert_eq!(parse_settings(&format!(r#" {{ "max_rate" : {} }} "#, i32::MAX)), Ok(vec![("max_rate".to_string(), i32::MAX)]));
+    }
+
+    #[test]
+    fn anything_but_integers_is_refused() {
+        for body in [r#"{"max_rate": 1.5}"#, r#"{"max_rate": 1e3}"#, r#"{"max_rate": -1}"#, r#"{"max_rate": "x", "max_players": 5}"#,
+            r#"{"max_rate": {"max_players": 5}}"#, r#"{"max_rate": [5]}"#, r#"{"max_rate": null}"#, r#"{"max_rate": true}"#] {
+            assert!(parse_settings(body).unwrap_err().contains("must be a non-negative integer"), "{}", body);
+        }
+        assert_eq!(parse_settings(r#"{"max_rate": 2147483648}"#), Err("max_rate is out of range (2147483648)".to_string()));
+        assert!(parse_settings(r#"{"max_rate": 99999999999999999999}"#).is_err());
+    }
+
+    #[test]
+    fn only_a_json_object_is_accepted() {
+        for body in ["", "{}", "[]", "max_rate=5", r#"{"max_rate": 5"#, r#"{"max_rate": 5} x"#, r#"{"max_rate": 5, "max_rate": 6}"#, r#"[{"max_rate": 5}]"#] {
+            assert!(parse_settings(body).is_err(), "{}", body);
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use std::fmt;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::logging::timestamp;
+
+static AUDIT_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Opens the audit log at `path` in append-only mode.
+pub fn open(path: &str) -> std::io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    if let Ok(mut f) = AUDIT_FILE.lock() { *f = Some(file); }
+    Ok(())
+}
+
+/// Appends one event line, e.g. `2025-09-14T18:03:21Z disconnect id=12345 session=<uuid> addr=1.2.3.4:50000 reason=closed`.
+pub fn record(event: &str, client: Option<(i32, &str)>, addr: &SocketAddr, details: fmt::Arguments) {
+    let Ok(mut file) = AUDIT_FILE.lock() else { return; };
+    let Some(file) = file.as_mut() else { return; };
+
+    let id = client.map(|(id, session)| format!(" id={} session={}", id, session)).unwrap_or_default();
+    let details = details.to_string();
+    let details = if details.is_empty() { details } else { format!(" {}", details) };
+
+    let _ = file.write_all(format!("{} {}{} addr={}{}\n", timestamp(), event, id, addr, details).as_bytes());
+}
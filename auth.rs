@@ -0,0 +1,628 @@
+//! Client authentication: with a `secret` or `token_secret` configured, the first frame of a
+//! connection must carry the shared secret or a signed token.
+//!
+//! With a challenge, the server first sends a random nonce and the secret itself is no longer
+//! accepted, only `HMAC-SHA256(secret, nonce)`, so a captured handshake can't be replayed.
+//!
+//! Tokens are JWTs signed with HMAC-SHA256 (`HS256`) using `token_secret`. The claims used are
+//! `sub` (the identity, required), `role` (`player`, `spectator` or `admin`, default `player`),
+//! the optional `exp` and `nbf` unix timestamps and `id`, the client id for the `token` id allocator.
+//! With a challenge a token is bound to it as well: the client sends `header.claims.<proof>`, the
+//! proof being `HMAC-SHA256(signature, nonce)` of the token's decoded signature, which is never sent.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use rand::Rng;
+
+use crate::http::json_string;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    /// Sends and receives like any client.
+    Player,
+    /// Receives everything, but its own packets are not relayed.
+    Spectator,
+    /// Like a player, but never rate limited.
+    Admin
+}
+
+impl Role {
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Player => "player",
+            Role::Spectator => "spectator",
+            Role::Admin => "admin"
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Role> {
+        [Role::Player, Role::Spectator, Role::Admin].into_iter().find(|r| r.name() == s)
+    }
+}
+
+/// Who a client proved to be with its token.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+    /// The id it asks for, see `ids::FromToken`.
+    pub id: Option<i32>
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.role.name())
+    }
+}
+
+/// Compares in constant time, so a secret can't be guessed byte by byte from response times.
+pub fn constant_time_eq(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len() && given.iter().zip(expected).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// A fresh nonce for a challenge.
+pub fn challenge() -> [u8; 32] {
+    rand::rng().random()
+}
+
+/// Checks the payload of a client's first frame, answering the challenge `nonce` if one was sent.
+/// Returns the identity for a valid token, `None` for the shared secret and an error describing why anything else was refused.
+pub fn authenticate(secret: &str, token_secret: &str, nonce: Option<&[u8]>, payload: &[u8]) -> Result<Option<Identity>, String> {
+    if !secret.is_empty() {
+        let accepted = match nonce {
+            Some(nonce) => constant_time_eq(payload, &hmac_sha256(secret.as_bytes(), nonce)),
+            None => constant_time_eq(payload, secret.as_bytes())
+        };
+        if accepted { return Ok(None); }
+    }
+    if token_secret.is_empty() { return Err(if nonce.is_some() { "wrong challenge response" } else { "wrong secret" }.to_string()); }
+
+    let token = std::str::from_utf8(payload).map_err(|_| "token is not UTF-8".to_string())?;
+    verify_token(token_secret, token, nonce, unix_now()).map(Some)
+}
+
+/// Validates a `header.claims.signature` JWT at time `now` (unix seconds), or `header.claims.proof` for the
+/// challenge `nonce`, see the module doc.
+fn verify_token(token_secret: &str, token: &str, nonce: Option<&[u8]>, now: u64) -> Result<Identity, String> {
+    let mut parts = token.trim().split('.');
+    let (Some(header), Some(claims), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err("not a token".to_string());
+    };
+
+    let mut expected = hmac_sha256(token_secret.as_bytes(), format!("{}.{}", header, claims).as_bytes());
+    if let Some(nonce) = nonce { expected = hmac_sha256(&expected, nonce); }
+    let signature = base64url_decode(signature).ok_or("invalid signature encoding")?;
+    if !constant_time_eq(&signature, &expected) { return Err("invalid signature".to_string()); }
+
+    let header = String::from_utf8(base64url_decode(header).ok_or("invalid header encoding")?).map_err(|_| "invalid header")?;
+    let header = parse_object(&header).ok_or("invalid header")?;
+    if claim_string(&header, "alg")? != Some("HS256") { return Err("unsupported algorithm".to_string()); }
+
+    let claims = String::from_utf8(base64url_decode(claims).ok_or("invalid claims encoding")?).map_err(|_| "invalid claims")?;
+    let claims = parse_object(&claims).ok_or("invalid claims")?;
+    if let Some(exp) = claim_number(&claims, "exp")? && now >= exp { return Err("token expired".to_string()); }
+    if let Some(nbf) = claim_number(&claims, "nbf")? && now < nbf { return Err("token not valid yet".to_string()); }
+
+    let name = claim_string(&claims, "sub")?.filter(|s| !s.is_empty()).ok_or("token has no sub claim")?.to_string();
+    let role = match claim_string(&claims, "role")? {
+        Some(role) => Role::parse(role).ok_or(format!("unknown role {}", role))?,
+        None => Role::Player
+    };
+    let id = match claim_number(&claims, "id")? {
+        Some(id) if id == 0 || id > i32::MAX as u64 => return Err(format!("invalid id {}", id)),
+        id => id.map(|id| id as i32)
+    };
+    Ok(Identity { name, role, id })
+}
+
+/// Creates a token for `name`, valid for `seconds` (0 = forever), e.g. for testing or simple setups.
+pub fn issue_token(token_secret: &str, name: &str, role: Role, seconds: u64) -> String {
+    let header = base64url_encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+
+    let mut claims = format!("{{\"sub\":{},\"role\":\"{}\"", json_string(name), role.name());
+    if seconds != 0 { claims += &format!(",\"exp\":{}", unix_now() + seconds); }
+    claims.push('}');
+    let claims = base64url_encode(claims.as_bytes());
+
+    let signature = hmac_sha256(token_secret.as_bytes(), format!("{}.{}", header, claims).as_bytes());
+    format!("{}.{}.{}", header, claims, base64url_encode(&signature))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// A member of a JSON object (the header, the claims or a `PUT /config` body), nested objects and arrays are
+/// checked but not kept.
+#[derive(PartialEq, Debug)]
+pub(crate) enum Value {
+    String(String),
+    /// A number without sign, fraction or exponent that fits.
+    Integer(u64),
+    Other
+}
+
+/// The claim `name` if it is there, an error if it isn't a string.
+fn claim_string<'a>(claims: &'a HashMap<String, Value>, name: &str) -> Result<Option<&'a str>, String> {
+    match claims.get(name) {
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(_) => Err(format!("invalid {} claim", name)),
+        None => Ok(None)
+    }
+}
+
+/// The claim `name` if it is there, an error if it isn't a non-negative integer.
+fn claim_number(claims: &HashMap<String, Value>, name: &str) -> Result<Option<u64>, String> {
+    match claims.get(name) {
+        Some(Value::Integer(n)) => Ok(Some(*n)),
+        Some(_) => Err(format!("invalid {} claim", name)),
+        None => Ok(None)
+    }
+}
+
+/// Deepest nesting of objects and arrays in a token or a `PUT /config` body.
+const MAX_DEPTH: usize = 16;
+
+/// The members of a JSON object, `None` if `json` is anything else or has a key twice, so a claim
+/// can't be read from another place than a verifier reading the last or the first of them would.
+pub(crate) fn parse_object(json: &str) -> Option<HashMap<String, Value>> {
+    let mut parser = Parser { bytes: json.as_bytes(), at: 0 };
+    parser.whitespace();
+    parser.expect(b'{')?;
+    let mut members = HashMap::new();
+
+    parser.whitespace();
+    if parser.peek() == Some(b'}') {
+        parser.at += 1;
+    } else {
+        loop {
+            parser.whitespace();
+            let key = parser.string()?;
+            parser.whitespace();
+            parser.expect(b':')?;
+            let value = parser.value(1)?;
+            if members.insert(key, value).is_some() { return None; }
+            parser.whitespace();
+            match parser.next()? {
+                b',' => continue,
+                b'}' => break,
+                _ => return None
+            }
+        }
+    }
+
+    parser.whitespace();
+    (parser.at == parser.bytes.len()).then_some(members)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    at: usize
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.at).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.at += 1;
+        Some(byte)
+    }
+
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        (self.next()? == byte).then_some(())
+    }
+
+    fn whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) { self.at += 1; }
+    }
+
+    fn value(&mut self, depth: usize) -> Option<Value> {
+        if depth > MAX_DEPTH { return None; }
+        self.whitespace();
+        match self.peek()? {
+            b'"' => self.string().map(Value::String),
+            b'{' | b'[' => {
+                let close = if self.next()? == b'{' { b'}' } else { b']' };
+                self.whitespace();
+                if self.peek() == Some(close) {
+                    self.at += 1;
+                    return Some(Value::Other);
+                }
+                loop {
+                    if close == b'}' {
+                        self.whitespace();
+                        self.string()?;
+                        self.whitespace();
+                        self.expect(b':')?;
+                    }
+                    self.value(depth + 1)?;
+                    self.whitespace();
+                    match self.next()? {
+                        b',' => continue,
+                        byte if byte == close => return Some(Value::Other),
+                        _ => return None
+                    }
+                }
+            },
+            b't' => self.literal("true"),
+            b'f' => self.literal("false"),
+            b'n' => self.literal("null"),
+            _ => self.number()
+        }
+    }
+
+    fn literal(&mut self, word: &str) -> Option<Value> {
+        if !self.bytes[self.at..].starts_with(word.as_bytes()) { return None; }
+        self.at += word.len();
+        Some(Value::Other)
+    }
+
+    fn number(&mut self) -> Option<Value> {
+        let start = self.at;
+        let digits = |parser: &mut Parser| {
+            let from = parser.at;
+            while parser.peek().is_some_and(|b| b.is_ascii_digit()) { parser.at += 1; }
+            parser.at > from
+        };
+
+        let negative = self.peek() == Some(b'-');
+        if negative { self.at += 1; }
+        let integer = self.at;
+        if !digits(self) { return None; }
+        if self.bytes[integer] == b'0' && self.at - integer > 1 { return None; }
+        let mut plain = !negative;
+        if self.peek() == Some(b'.') {
+            self.at += 1;
+            if !digits(self) { return None; }
+            plain = false;
+        }
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.at += 1;
+            if matches!(self.peek(), Some(b'+' | b'-')) { self.at += 1; }
+            if !digits(self) { return None; }
+            plain = false;
+        }
+
+        let text = std::str::from_utf8(&self.bytes[start..self.at]).ok()?;
+        Some(match plain.then(|| text.parse::<u64>().ok()).flatten() {
+            Some(n) => Value::Integer(n),
+            None => Value::Other
+        })
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.expect(b'"')?;
+        let mut out = String::new();
+        loop {
+            match self.next()? {
+                b'"' => return Some(out),
+                b'\\' => match self.next()? {
+                    b'"' => out.push('"'),
+                    b'\\' => out.push('\\'),
+                    b'/' => out.push('/'),
+                    b'b' => out.push('\u{8}'),
+                    b'f' => out.push('\u{c}'),
+                    b'n' => out.push('\n'),
+                    b'r' => out.push('\r'),
+                    b't' => out.push('\t'),
+                    b'u' => {
+                        let high = self.hex4()?;
+                        let code = match high {
+                            0xd800..=0xdbff => {
+                                self.expect(b'\\')?;
+                                self.expect(b'u')?;
+                                let low = self.hex4()?;
+                                if !(0xdc00..=0xdfff).contains(&low) { return None; }
+                                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                            },
+                            0xdc00..=0xdfff => return None,
+                            code => code
+                        };
+                        out.push(char::from_u32(code)?);
+                    },
+                    _ => return None
+                },
+                byte if byte < 0x20 => return None,
+                byte if byte < 0x80 => out.push(byte as char),
+                _ => {
+                    // the input is a str, so the whole UTF-8 sequence is there
+                    let start = self.at - 1;
+                    while self.peek().is_some_and(|b| b & 0xc0 == 0x80) { self.at += 1; }
+                    out.push_str(std::str::from_utf8(&self.bytes[start..self.at]).ok()?);
+                }
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = self.bytes.get(self.at..self.at + 4)?;
+        self.at += 4;
+        u32::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok().filter(|_| digits.iter().all(u8::is_ascii_hexdigit))
+    }
+}
+
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+fn base64url_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |acc, (i, b)| acc | (*b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(BASE64URL[(n >> (18 - 6 * i) & 63) as usize] as char);
+        }
+    }
+    out
+}
+
+/// Decodes unpadded base64url, as used by JWTs (trailing `=` are tolerated). Only the canonical encoding
+/// is accepted, so a signature can't be changed without changing it.
+fn base64url_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut out = Vec::new();
+    let (mut acc, mut bits) = (0u32, 0);
+
+    for c in s.bytes() {
+        acc = acc << 6 | BASE64URL.iter().position(|b| *b == c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    // a single character left over, or bits set that aren't part of any byte
+    if bits >= 6 || acc & ((1 << bits) - 1) != 0 { return None; }
+    Some(out)
+}
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 { block[..32].copy_from_slice(&sha256(key)); } else { block[..key.len()].copy_from_slice(key); }
+
+    let inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).chain(message.iter().copied()).collect();
+    let outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).chain(sha256(&inner)).collect();
+    sha256(&outer)
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 { padded.push(0); }
+    padded.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in padded.chunks(64) {
+        let mut w = [0u32; 64];
+        for i in 0..16 { w[i] = u32::from_be_bytes([chunk[4 * i], chunk[4 * i + 1], chunk[4 * i + 2], chunk[4 * i + 3]]); }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            hh = g; g = f; f = e; e = d.wrapping_add(t1);
+            d = c; c = b; b = a; a = t1.wrapping_add(t2);
+        }
+
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) { *state = state.wrapping_add(value); }
+    }
+
+    let mut out = [0u8; 32];
+    for (i, word) in h.iter().enumerate() { out[4 * i..4 * i + 4].copy_from_slice(&word.to_be_bytes()); }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// A token with exactly this header and these claims.
+    fn sign(secret: &str, header: &str, claims: &str) -> String {
+        let input = format!("{}.{}", base64url_encode(header.as_bytes()), base64url_encode(claims.as_bytes()));
+        let signature = hmac_sha256(secret.as_bytes(), input.as_bytes());
+        format!("{}.{}", input, base64url_encode(&signature))
+    }
+
+    fn token(claims: &str) -> String {
+        sign("secret", r#"{"alg":"HS256","typ":"JWT"}"#, claims)
+    }
+
+    fn verify(token: &str) -> Result<Identity, String> {
+        verify_token("secret", token, None, NOW)
+    }
+
+    #[test]
+    fn sha256_known_answers() {
+        assert_eq!(hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        assert_eq!(hex(&sha256(&vec![b'a'; 1_000_000])), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+        // padding that needs a second block
+        assert_eq!(hex(&sha256(&[b'a'; 56])), "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a");
+    }
+
+    #[test]
+    fn hmac_sha256_known_answers() {
+        // RFC 4231 test cases 1, 2 and 6 (a key longer than the block)
+        assert_eq!(hex(&hmac_sha256(&[0x0b; 20], b"Hi There")), "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+        assert_eq!(hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert_eq!(hex(&hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn base64url_round_trips() {
+        // RFC 4648 test vectors, without padding
+        for (plain, encoded) in [("", ""), ("f", "Zg"), ("fo", "Zm8"), ("foo", "Zm9v"), ("foob", "Zm9vYg"), ("fooba", "Zm9vYmE"), ("foobar", "Zm9vYmFy")] {
+            assert_eq!(base64url_encode(plain.as_bytes()), encoded);
+            assert_eq!(base64url_decode(encoded).as_deref(), Some(plain.as_bytes()));
+        }
+        assert_eq!(base64url_encode(&[0xfb, 0xff]), "-_8");
+        assert_eq!(base64url_decode("-_8").as_deref(), Some(&[0xfb, 0xff][..]));
+        assert_eq!(base64url_decode("Zm8=").as_deref(), Some(&b"fo"[..]));
+    }
+
+    #[test]
+    fn base64url_rejects_non_canonical_input() {
+        assert!(base64url_decode("Zm9").is_none(), "bits left over");
+        assert!(base64url_decode("Z").is_none(), "a single character");
+        assert!(base64url_decode("Zh").is_none(), "Zg with a bit set that isn't part of the byte");
+        assert!(base64url_decode("Zm+v").is_none(), "not base64url");
+        assert!(base64url_decode("Zm/v").is_none(), "not base64url");
+    }
+
+    #[test]
+    fn rfc7515_hs256_signature() {
+        // RFC 7515 appendix A.1
+        let key = base64url_decode("AyM1SysPpbyDfgZld3umj1qzKObwVMkoqQ-EstJQLr_T-1qS0gZH75aKtMN3Yj0iPS4hcgUuTwjAzZr1Z9CAow").unwrap();
+        let input = "eyJ0eXAiOiJKV1QiLA0KICJhbGciOiJIUzI1NiJ9.eyJpc3MiOiJqb2UiLA0KICJleHAiOjEzMDA4MTkzODAsDQogImh0dHA6Ly9leGFtcGxlLmNvbS9pc19yb290Ijp0cnVlfQ";
+        assert_eq!(base64url_encode(&hmac_sha256(&key, input.as_bytes())), "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk");
+    }
+
+    #[test]
+    fn valid_tokens_are_accepted() {
+        let identity = verify(&token(r#"{"sub":"alice","role":"admin","id":42,"exp":1700000001,"nbf":1700000000}"#)).unwrap();
+        assert_eq!((identity.name.as_str(), identity.role, identity.id), ("alice", Role::Admin, Some(42)));
+
+        let identity = verify(&token(r#"{"sub":"bob"}"#)).unwrap();
+        assert_eq!((identity.name.as_str(), identity.role, identity.id), ("bob", Role::Player, None));
+
+        let identity = verify_token("secret", &issue_token("secret", "carol \"c\"", Role::Spectator, 0), None, NOW).unwrap();
+        assert_eq!((identity.name.as_str(), identity.role), ("carol \"c\"", Role::Spectator));
+    }
+
+    #[test]
+    fn expired_and_not_yet_valid_tokens_are_refused() {
+        assert_eq!(verify(&token(r#"{"sub":"alice","exp":1700000000}"#)).err().as_deref(), Some("token expired"));
+        assert_eq!(verify(&token(r#"{"sub":"alice","exp":1600000000}"#)).err().as_deref(), Some("token expired"));
+        assert_eq!(verify(&token(r#"{"sub":"alice","nbf":1700000001}"#)).err().as_deref(), Some("token not valid yet"));
+        assert!(verify(&token(r#"{"sub":"alice","exp":"never"}"#)).is_err());
+        assert!(verify(&token(r#"{"sub":"alice","exp":1.7e10}"#)).is_err());
+        assert!(verify(&token(r#"{"sub":"alice","exp":-1}"#)).is_err());
+    }
+
+    #[test]
+    fn tampered_tokens_are_refused() {
+        let valid = token(r#"{"sub":"alice","role":"player"}"#);
+        let (input, signature) = valid.rsplit_once('.').unwrap();
+
+        let mut flipped = signature.to_string();
+        let last = if flipped.ends_with('A') { "B" } else { "A" };
+        flipped.replace_range(flipped.len() - 1.., last);
+        assert!(verify(&format!("{}.{}", input, flipped)).is_err());
+
+        let (header, _) = input.split_once('.').unwrap();
+        let admin = base64url_encode(br#"{"sub":"alice","role":"admin"}"#);
+        assert_eq!(verify(&format!("{}.{}.{}", header, admin, signature)).err().as_deref(), Some("invalid signature"));
+
+        assert_eq!(verify_token("other", &valid, None, NOW).err().as_deref(), Some("invalid signature"));
+        assert!(verify(&format!("{}.", input)).is_err());
+        assert!(verify(&format!("{}.{}.x", input, signature)).is_err());
+        assert!(verify("not a token").is_err());
+    }
+
+    #[test]
+    fn only_hs256_is_accepted() {
+        assert_eq!(verify(&sign("secret", r#"{"alg":"none"}"#, r#"{"sub":"alice"}"#)).err().as_deref(), Some("unsupported algorithm"));
+        assert_eq!(verify(&sign("secret", r#"{"typ":"JWT"}"#, r#"{"sub":"alice"}"#)).err().as_deref(), Some("unsupported algorithm"));
+        assert!(verify(&sign("secret", r#"{"alg":"HS256","alg":"none"}"#, r#"{"sub":"alice"}"#)).is_err());
+    }
+
+    #[test]
+    fn claims_are_read_from_the_top_level_object_only() {
+        // a claim in a nested object or inside a string doesn't count
+        let identity = verify(&token(r#"{"meta":{"sub":"admin","role":"admin"},"sub":"bob"}"#)).unwrap();
+        assert_eq!((identity.name.as_str(), identity.role), ("bob", Role::Player));
+        let identity = verify(&token(r#"{"note":"\"role\":\"admin\"","sub":"bob"}"#)).unwrap();
+        assert_eq!(identity.role, Role::Player);
+        assert_eq!(verify(&token(r#"{"list":["sub"],"x":{"sub":"eve"}}"#)).err().as_deref(), Some("token has no sub claim"));
+        assert_eq!(verify(&token(r#"{"sub":"bob"}"#)).unwrap().name, "bob");
+    }
+
+    #[test]
+    fn duplicate_and_malformed_claims_are_refused() {
+        assert_eq!(verify(&token(r#"{"sub":"alice","sub":"admin"}"#)).err().as_deref(), Some("invalid claims"));
+        assert_eq!(verify(&token(r#"{"sub":"alice","role":"player","role":"admin"}"#)).err().as_deref(), Some("invalid claims"));
+        for claims in [r#"{"sub":"alice""#, r#"{"sub":"alice"} x"#, r#"["sub","alice"]"#, r#"{"sub":"alice",}"#, r#"{"sub":alice}"#,
+            r#"{"sub":"alice","n":01}"#, r#"{"sub":"a\qb"}"#, r#"{"sub":"\ud800"}"#, "{\"sub\":\"a\nb\"}", ""] {
+            assert_eq!(verify(&token(claims)).err().as_deref(), Some("invalid claims"), "{:?}", claims);
+        }
+        assert!(verify(&token(r#"{"sub":42}"#)).is_err());
+        assert!(verify(&token(r#"{"sub":""}"#)).is_err());
+        assert!(verify(&token(r#"{"sub":"alice","role":"root"}"#)).is_err());
+        assert!(verify(&token(r#"{"sub":"alice","id":0}"#)).is_err());
+        assert!(verify(&token(r#"{"sub":"alice","id":2147483648}"#)).is_err());
+        let deep = format!("{{\"sub\":\"alice\",\"x\":{}{}}}", "[".repeat(100), "]".repeat(100));
+        assert_eq!(verify(&token(&deep)).err().as_deref(), Some("invalid claims"));
+    }
+
+    /// `token` bound to the challenge `nonce`, as a client answers it.
+    fn bind(token: &str, nonce: &[u8]) -> String {
+        let (input, signature) = token.rsplit_once('.').unwrap();
+        format!("{}.{}", input, base64url_encode(&hmac_sha256(&base64url_decode(signature).unwrap(), nonce)))
+    }
+
+    #[test]
+    fn the_secret_is_accepted_without_a_challenge() {
+        assert_eq!(authenticate("secret", "", None, b"secret"), Ok(None));
+        assert_eq!(authenticate("secret", "", None, b"wrong").err().as_deref(), Some("wrong secret"));
+        assert_eq!(authenticate("secret", "", None, b"").err().as_deref(), Some("wrong secret"));
+    }
+
+    #[test]
+    fn a_challenge_takes_its_hmac_only() {
+        let nonce = challenge();
+        assert_eq!(authenticate("secret", "", Some(&nonce), &hmac_sha256(b"secret", &nonce)), Ok(None));
+        assert_eq!(authenticate("secret", "", Some(&nonce), b"secret").err().as_deref(), Some("wrong challenge response"));
+        assert_eq!(authenticate("secret", "", Some(&nonce), &hmac_sha256(b"secret", &challenge())).err().as_deref(), Some("wrong challenge response"));
+        assert_eq!(authenticate("secret", "", Some(&nonce), &hmac_sha256(b"other", &nonce)).err().as_deref(), Some("wrong challenge response"));
+    }
+
+    #[test]
+    fn a_challenge_binds_tokens_to_it() {
+        let nonce = challenge();
+        let token = issue_token("tokens", "dave", Role::Admin, 0);
+        assert_eq!(authenticate("secret", "tokens", None, token.as_bytes()).map(|i| i.map(|i| i.name)), Ok(Some("dave".to_string())));
+
+        let identity = authenticate("secret", "tokens", Some(&nonce), bind(&token, &nonce).as_bytes()).unwrap().unwrap();
+        assert_eq!((identity.name.as_str(), identity.role), ("dave", Role::Admin));
+
+        // a captured handshake, with or without the challenge, is worth nothing for another nonce
+        assert_eq!(authenticate("secret", "tokens", Some(&nonce), token.as_bytes()).err().as_deref(), Some("invalid signature"));
+        assert_eq!(authenticate("secret", "tokens", Some(&challenge()), bind(&token, &nonce).as_bytes()).err().as_deref(), Some("invalid signature"));
+        let forged = issue_token("other", "dave", Role::Admin, 0);
+        assert_eq!(authenticate("secret", "tokens", Some(&nonce), bind(&forged, &nonce).as_bytes()).err().as_deref(), Some("invalid signature"));
+    }
+}
@@ -0,0 +1,296 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+#[cfg(unix)]
+use std::sync::atomic::AtomicBool;
+#[cfg(unix)]
+use std::sync::atomic::Ordering;
+#[cfg(unix)]
+use std::thread;
+#[cfg(unix)]
+use std::time::Duration;
+
+use crate::storage::Storage;
+#[cfg(unix)]
+use crate::logging::error;
+#[cfg(unix)]
+use crate::state::SharedState;
+
+/// A single address or a CIDR network, e.g. `203.0.113.7` or `203.0.113.0/24`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8
+}
+
+impl Network {
+    /// IPv4 networks also contain the IPv4-mapped IPv6 addresses (`::ffff:203.0.113.7`) of a dual-stack listener.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = &match ip {
+            IpAddr::V6(v6) if self.addr.is_ipv4() => v6.to_ipv4_mapped().map_or(*ip, IpAddr::V4),
+            _ => *ip
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Network, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None)
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| format!("invalid address {}", addr))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            None => max,
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= max).ok_or(format!("invalid prefix length {}", prefix))?
+        };
+        Ok(Network { addr, prefix })
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let max = if self.addr.is_ipv4() { 32 } else { 128 };
+        if self.prefix == max { write!(f, "{}", self.addr) } else { write!(f, "{}/{}", self.addr, self.prefix) }
+    }
+}
+
+/// Banned networks, kept in the storage under `key` (one per line, `#` starts a comment) so they survive restarts.
+pub struct BanList {
+    storage: Arc<dyn Storage>,
+    key: String,
+    networks: Vec<Network>
+}
+
+impl BanList {
+    /// Loads the list stored under `key`, none stored is an empty list. An empty key keeps bans in memory only.
+    pub fn load(storage: Arc<dyn Storage>, key: &str) -> Result<BanList, String> {
+        let mut list = BanList { storage, key: key.to_string(), networks: Vec::new() };
+        if key.is_empty() { return Ok(list); }
+
+        let content = match list.storage.load(key) {
+            Ok(Some(c)) => String::from_utf8(c).map_err(|_| "not UTF-8".to_string())?,
+            Ok(None) => return Ok(list),
+            Err(e) => return Err(e.to_string())
+        };
+
+        for (n, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() { continue; }
+            list.networks.push(line.parse::<Network>().map_err(|e| format!("line {}: {}", n + 1, e))?);
+        }
+        Ok(list)
+    }
+
+    /// Replaces the list with the one stored now, e.g. after the file was edited by hand.
+    /// The list is left untouched if it can't be read, or if it is only kept in memory (empty key).
+    pub fn reload(&mut self) -> Result<(), String> {
+        if self.key.is_empty() { return Ok(()); }
+
+        *self = BanList::load(Arc::clone(&self.storage), &self.key)?;
+        Ok(())
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|n| n.contains(ip))
+    }
+
+    pub fn networks(&self) -> &[Network] {
+        &self.networks
+    }
+
+    /// Returns false if the network was already banned.
+    pub fn add(&mut self, network: Network) -> std::io::Result<bool> {
+        if self.networks.contains(&network) { return Ok(false); }
+        self.networks.push(network);
+        self.save().map(|_| true)
+    }
+
+    /// Returns false if the network wasn't banned.
+    pub fn remove(&mut self, network: &Network) -> std::io::Result<bool> {
+        let before = self.networks.len();
+        self.networks.retain(|n| n != network);
+        if self.networks.len() == before { return Ok(false); }
+        self.save().map(|_| true)
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        if self.key.is_empty() { return Ok(()); }
+
+        let content: String = self.networks.iter().map(|n| format!("{}\n", n)).collect();
+        self.storage.store(&self.key, content.as_bytes())
+    }
+}
+
+/// Networks from the `allow` and `deny` settings. With an allow list only its networks may
+/// connect, the deny list is checked after it.
+#[derive(Clone)]
+pub struct AccessList {
+    allow: Vec<Network>,
+    deny: Vec<Network>
+}
+
+impl AccessList {
+    /// Parses two lists of addresses or CIDR networks separated by commas or spaces.
+    pub fn parse(allow: &str, deny: &str) -> Result<AccessList, String> {
+        let parse = |list: &str, name: &str| list.split([',', ' ']).filter(|s| !s.is_empty())
+            .map(|s| s.parse::<Network>().map_err(|e| format!("{} list: {}", name, e)))
+            .collect::<Result<Vec<Network>, String>>();
+        Ok(AccessList { allow: parse(allow, "allow")?, deny: parse(deny, "deny")? })
+    }
+
+    pub fn permits(&self, ip: &IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|n| n.contains(ip))) && !self.deny.iter().any(|n| n.contains(ip))
+    }
+
+    pub fn allow(&self) -> &[Network] {
+        &self.allow
+    }
+
+    pub fn deny(&self) -> &[Network] {
+        &self.deny
+    }
+}
+
+#[cfg(unix)]
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGHUP reload the ban file and the allow and deny lists, see `State::reload`.
+#[cfg(unix)]
+pub fn watch_signal(state: SharedState) {
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe { libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t); }
+
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(250));
+        if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) && let Err(e) = state.reload("SIGHUP") {
+            error!("Could not reload, {}!", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(s: &str) -> Network {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn zero_prefix_contains_every_address_of_its_family() {
+        assert!(network("0.0.0.0/0").contains(&ip("203.0.113.7")));
+        assert!(network("0.0.0.0/0").contains(&ip("255.255.255.255")));
+        assert!(network("::/0").contains(&ip("2001:db8::1")));
+        assert!(!network("0.0.0.0/0").contains(&ip("2001:db8::1")));
+        assert!(!network("::/0").contains(&ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn full_prefix_contains_only_the_address() {
+        assert!(network("203.0.113.7/32").contains(&ip("203.0.113.7")));
+        assert!(!network("203.0.113.7/32").contains(&ip("203.0.113.8")));
+        assert!(network("2001:db8::1/128").contains(&ip("2001:db8::1")));
+        assert!(!network("2001:db8::1/128").contains(&ip("2001:db8::2")));
+        assert!(network("203.0.113.7") == network("203.0.113.7/32"));
+        assert!(network("2001:db8::1") == network("2001:db8::1/128"));
+    }
+
+    #[test]
+    fn prefix_matches_the_network_bits_only() {
+        let net = network("203.0.113.0/24");
+        assert!(net.contains(&ip("203.0.113.0")));
+        assert!(net.contains(&ip("203.0.113.255")));
+        assert!(!net.contains(&ip("203.0.114.0")));
+        assert!(network("10.0.0.0/9").contains(&ip("10.127.255.255")));
+        assert!(!network("10.0.0.0/9").contains(&ip("10.128.0.0")));
+        assert!(network("2001:db8::/32").contains(&ip("2001:db8:ffff::1")));
+        assert!(!network("2001:db8::/32").contains(&ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn prefix_longer_than_the_address_is_rejected() {
+        assert!("203.0.113.7/33".parse::<Network>().is_err());
+        assert!("2001:db8::1/129".parse::<Network>().is_err());
+        assert!("203.0.113.7/255".parse::<Network>().is_err());
+        assert!("203.0.113.7/256".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn ipv4_networks_contain_mapped_addresses() {
+        assert!(network("203.0.113.0/24").contains(&ip("::ffff:203.0.113.7")));
+        assert!(!network("203.0.113.0/24").contains(&ip("::ffff:203.0.114.7")));
+        assert!(network("203.0.113.7").contains(&ip("::ffff:203.0.113.7")));
+        // only mapped addresses, not IPv4-compatible ones or others with the same low bits
+        assert!(!network("203.0.113.0/24").contains(&ip("::203.0.113.7")));
+        assert!(!network("203.0.113.0/24").contains(&ip("64:ff9b::203.0.113.7")));
+        assert!(network("::ffff:203.0.113.0/120").contains(&ip("::ffff:203.0.113.7")));
+    }
+
+    #[test]
+    fn malformed_input_is_rejected() {
+        for input in ["", "/24", "203.0.113", "203.0.113.256", "203.0.113.7/", "203.0.113.7/-1", "203.0.113.7/a",
+            "203.0.113.7/24/8", " 203.0.113.7", "2001:db8:::1", "example.com", "203.0.113.7 /24"] {
+            assert!(input.parse::<Network>().is_err(), "{:?} should not parse", input);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for input in ["203.0.113.7", "203.0.113.0/24", "0.0.0.0/0", "2001:db8::/32", "::/0", "2001:db8::1"] {
+            assert_eq!(network(input).to_string(), input);
+        }
+    }
+
+    fn listed(list: &BanList) -> Vec<String> {
+        list.networks().iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn bans_are_kept_in_the_storage() {
+        let storage: Arc<dyn Storage> = Arc::new(crate::storage::Memory::default());
+        storage.store("bans", b"# banned by hand\n203.0.113.7\n\n2001:db8::/32 # a whole network\n").unwrap();
+        let mut list = BanList::load(Arc::clone(&storage), "bans").unwrap();
+        assert_eq!(listed(&list), ["203.0.113.7", "2001:db8::/32"]);
+
+        assert!(list.add(network("10.0.0.0/8")).unwrap());
+        assert!(!list.add(network("10.0.0.0/8")).unwrap());
+        assert!(list.remove(&network("203.0.113.7")).unwrap());
+        assert_eq!(storage.load("bans").unwrap().as_deref(), Some(&b"2001:db8::/32\n10.0.0.0/8\n"[..]));
+        assert!(list.contains(&ip("10.1.2.3")) && !list.contains(&ip("203.0.113.7")));
+
+        storage.store("bans", b"192.0.2.1\n").unwrap();
+        list.reload().unwrap();
+        assert_eq!(listed(&list), ["192.0.2.1"]);
+        storage.store("bans", b"not a network\n").unwrap();
+        assert_eq!(list.reload().unwrap_err(), "line 1: invalid address not a network");
+        assert_eq!(listed(&list), ["192.0.2.1"]);
+
+        assert!(BanList::load(storage, "none stored").unwrap().networks().is_empty());
+    }
+}
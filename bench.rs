@@ -0,0 +1,195 @@
+//! `echoserver bench`: synthetic clients against a running server, reporting throughput, relay latency
+//! percentiles and drops, so performance changes can be measured.
+//!
+//! Every client sends `--rate` packets per second of `--size` bytes, each carrying the time it was sent
+//! and the index of its sender. Latency is measured from sending to a client until another one receives
+//! it, packets that never arrive (e.g. dropped by the rate limit or a full send queue) count as dropped.
+
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use echoserver::BUFFER_SIZE;
+use echoserver::EchoClient;
+use echoserver::Packet;
+
+/// Sending time (nanoseconds since the bench started) and sender index at the start of every payload.
+const HEADER: usize = 12;
+/// How long clients keep receiving after the last packets were sent.
+const GRACE: Duration = Duration::from_secs(1);
+
+const USAGE: &str = "usage: echoserver bench [host:port] [--clients N] [--rate N] [--size N] [--duration SECONDS] [--secret SECRET]";
+
+struct Options {
+    target: String,
+    clients: usize,
+    /// Packets per second per client.
+    rate: u32,
+    /// Payload bytes per packet.
+    size: usize,
+    duration: Duration,
+    /// Sent as the first frame when the server requires authentication.
+    secret: String
+}
+
+/// What one client's reader saw.
+#[derive(Default)]
+struct Received {
+    packets: u64,
+    bytes: u64,
+    /// Relay latencies in microseconds.
+    latencies: Vec<u64>,
+    /// Whether it got its own packets back, i.e. the server has mirror enabled.
+    mirrored: bool
+}
+
+fn parse(args: &[String]) -> Result<Options, String> {
+    let mut options = Options { target: "127.0.0.1:45565".to_string(), clients: 200, rate: 50, size: 128, duration: Duration::from_secs(10), secret: String::new() };
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg.as_str(), None)
+        };
+        if !name.starts_with("--") {
+            options.target = if name.contains(':') { name.to_string() } else { format!("127.0.0.1:{}", name) };
+            continue;
+        }
+
+        let value = inline.or_else(|| args.next().cloned()).ok_or(format!("{} needs a value", name))?;
+        let number = || value.parse::<u64>().map_err(|_| format!("invalid {} {}", name, value));
+        match name {
+            "--clients" => options.clients = number()? as usize,
+            "--rate" => options.rate = number()? as u32,
+            "--size" => options.size = number()? as usize,
+            "--duration" => options.duration = Duration::from_secs(number()?),
+            "--secret" => options.secret = value,
+            _ => return Err(format!("unknown option {}", name))
+        }
+    }
+
+    if options.clients < 2 { return Err("--clients has to be at least 2".to_string()); }
+    if options.rate == 0 { return Err("--rate has to be at least 1".to_string()); }
+    if options.size < HEADER || options.size + 4 > BUFFER_SIZE { return Err(format!("--size has to be between {} and {}", HEADER, BUFFER_SIZE - 4)); }
+    Ok(options)
+}
+
+/// Sends `rate` packets per second until `duration` passed, returns how many were sent.
+fn send(client: EchoClient, index: u32, options: &Options, started: Instant) -> u64 {
+    let interval = Duration::from_secs(1) / options.rate;
+    let mut payload = vec![0u8; options.size];
+    payload[8..HEADER].copy_from_slice(&index.to_le_bytes());
+
+    let mut sent = 0;
+    let mut next = Instant::now();
+    while next < started + options.duration {
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+        payload[..8].copy_from_slice(&(started.elapsed().as_nanos() as u64).to_le_bytes());
+        if client.send(&payload).is_err() { break; }
+        sent += 1;
+        next += interval;
+    }
+    sent
+}
+
+/// Reads packets until `done` is set, skipping control packets.
+fn receive(mut client: EchoClient, index: u32, started: Instant, done: &AtomicBool) -> Received {
+    let _ = client.set_read_timeout(Some(Duration::from_millis(100)));
+    let mut received = Received::default();
+
+    while !done.load(Ordering::SeqCst) {
+        let payload = match client.recv() {
+            Ok(Some(Packet::Data(payload))) => payload,
+            Ok(Some(Packet::Control(..))) => continue,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+            Ok(None) | Err(_) => break
+        };
+
+        if payload.len() >= HEADER {
+            let sent_at = u64::from_le_bytes(payload[..8].try_into().unwrap_or_default());
+            let sender = u32::from_le_bytes(payload[8..HEADER].try_into().unwrap_or_default());
+            received.packets += 1;
+            received.bytes += payload.len() as u64 + 4;
+            received.latencies.push((started.elapsed().as_nanos() as u64).saturating_sub(sent_at) / 1000);
+            received.mirrored |= sender == index;
+        }
+    }
+    received
+}
+
+fn percentile(sorted: &[u64], percent: usize) -> u64 {
+    if sorted.is_empty() { return 0; }
+    sorted[(sorted.len() * percent / 100).min(sorted.len() - 1)]
+}
+
+/// Runs the bench with the arguments after `bench`, returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let options = match parse(args) {
+        Ok(options) => Arc::new(options),
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return 2;
+        }
+    };
+
+    println!("Connecting {} clients to {}...", options.clients, options.target);
+    let mut clients = Vec::new();
+    for _ in 0..options.clients {
+        let mut client = match EchoClient::connect(&options.target) {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("Could not connect to {} ({}), {} client(s) connected.", options.target, e, clients.len());
+                return 1;
+            }
+        };
+        if !options.secret.is_empty() && client.authenticate(options.secret.as_bytes()).is_err() {
+            eprintln!("Could not authenticate with {}.", options.target);
+            return 1;
+        }
+        clients.push(client);
+    }
+    // let every client join before anything is sent, the first packets would miss the later clients
+    thread::sleep(Duration::from_millis(500));
+
+    println!("Sending {} packets/s of {} bytes per client for {}s...", options.rate, options.size, options.duration.as_secs());
+    let started = Instant::now();
+    let done = Arc::new(AtomicBool::new(false));
+    let mut senders = Vec::new();
+    let mut receivers = Vec::new();
+
+    for (index, client) in clients.into_iter().enumerate() {
+        let Ok(reader) = client.try_clone() else { continue; };
+        let done = Arc::clone(&done);
+        receivers.push(thread::spawn(move || receive(reader, index as u32, started, &done)));
+        let options = Arc::clone(&options);
+        senders.push(thread::spawn(move || send(client, index as u32, &options, started)));
+    }
+
+    let sent: u64 = senders.into_iter().map(|sender| sender.join().unwrap_or(0)).sum();
+    thread::sleep(GRACE);
+    done.store(true, Ordering::SeqCst);
+    let received: Vec<Received> = receivers.into_iter().filter_map(|receiver| receiver.join().ok()).collect();
+
+    let elapsed = options.duration.as_secs_f64().max(0.001);
+    let packets: u64 = received.iter().map(|r| r.packets).sum();
+    let bytes: u64 = received.iter().map(|r| r.bytes).sum();
+    let mirrored = received.iter().any(|r| r.mirrored);
+    let recipients = options.clients as u64 - if mirrored { 0 } else { 1 };
+    let expected = sent * recipients;
+    let mut latencies: Vec<u64> = received.into_iter().flat_map(|r| r.latencies).collect();
+    latencies.sort_unstable();
+
+    println!("Sent          = {} packets ({:.0}/s)", sent, sent as f64 / elapsed);
+    println!("Received      = {} packets ({:.0}/s, {:.0} bytes/s), {} recipient(s) each{}", packets, packets as f64 / elapsed, bytes as f64 / elapsed, recipients, if mirrored { " with mirror" } else { "" });
+    println!("Dropped       = {} ({:.2}%)", expected.saturating_sub(packets), expected.saturating_sub(packets) as f64 * 100.0 / expected.max(1) as f64);
+    println!(
+        "Latency       = p50 {}us, p90 {}us, p99 {}us, max {}us",
+        percentile(&latencies, 50), percentile(&latencies, 90), percentile(&latencies, 99), latencies.last().copied().unwrap_or(0)
+    );
+    0
+}
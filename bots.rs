@@ -0,0 +1,235 @@
+//! `echoserver bots`: long-lived synthetic clients against a running server for soak tests, to surface
+//! what only shows after hours (memory growing with every packet or connection, ids running out).
+//!
+//! Every bot switches at random every few seconds between idling, steady traffic, bursts, large packets
+//! and reconnecting, and reconnects whenever the server disconnects it. Totals are printed every
+//! `--interval` seconds, until `--duration` passed or ctrl+c.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use rand::Rng;
+
+use echoserver::BUFFER_SIZE;
+use echoserver::EchoClient;
+
+/// How often bots check whether to stop while idling or waiting to reconnect.
+const TICK: Duration = Duration::from_millis(100);
+/// How long a bot keeps to its pattern, at most.
+const MAX_PHASE_SECONDS: u64 = 10;
+
+const USAGE: &str = "usage: echoserver bots [host:port] [--bots N] [--duration SECONDS] [--interval SECONDS] [--secret SECRET]";
+
+struct Options {
+    target: String,
+    bots: usize,
+    /// `None` = until ctrl+c.
+    duration: Option<Duration>,
+    /// Between two reports.
+    interval: Duration,
+    /// Sent as the first frame when the server requires authentication.
+    secret: String
+}
+
+/// Totals of all bots.
+#[derive(Default)]
+struct Stats {
+    connected: AtomicI64,
+    sent: AtomicU64,
+    received: AtomicU64,
+    reconnects: AtomicU64,
+    /// Times a bot lost its connection without reconnecting on purpose.
+    disconnects: AtomicU64,
+    failed_connects: AtomicU64
+}
+
+/// What a bot does for a while.
+#[derive(Clone, Copy)]
+enum Pattern {
+    Idle,
+    /// 20 small packets per second.
+    Steady,
+    /// 200 packets at once, then idle.
+    Burst,
+    /// 5 packets per second close to the largest frame.
+    Large,
+    /// Leave and join again.
+    Reconnect
+}
+
+const PATTERNS: [Pattern; 5] = [Pattern::Idle, Pattern::Steady, Pattern::Burst, Pattern::Large, Pattern::Reconnect];
+
+fn parse(args: &[String]) -> Result<Options, String> {
+    let mut options = Options { target: "127.0.0.1:45565".to_string(), bots: 50, duration: None, interval: Duration::from_secs(10), secret: String::new() };
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg.as_str(), None)
+        };
+        if !name.starts_with("--") {
+            options.target = if name.contains(':') { name.to_string() } else { format!("127.0.0.1:{}", name) };
+            continue;
+        }
+
+        let value = inline.or_else(|| args.next().cloned()).ok_or(format!("{} needs a value", name))?;
+        let number = || value.parse::<u64>().map_err(|_| format!("invalid {} {}", name, value));
+        match name {
+            "--bots" => options.bots = number()? as usize,
+            "--duration" => options.duration = Some(Duration::from_secs(number()?)).filter(|d| !d.is_zero()),
+            "--interval" => options.interval = Duration::from_secs(number()?),
+            "--secret" => options.secret = value,
+            _ => return Err(format!("unknown option {}", name))
+        }
+    }
+
+    if options.bots == 0 { return Err("--bots has to be at least 1".to_string()); }
+    if options.interval.is_zero() { return Err("--interval has to be at least 1".to_string()); }
+    Ok(options)
+}
+
+/// Sleeps for `duration` unless the bots are stopped first, returns whether they are still running.
+fn sleep(duration: Duration, running: &AtomicBool) -> bool {
+    let until = Instant::now() + duration;
+    while running.load(Ordering::SeqCst) && Instant::now() < until {
+        thread::sleep(TICK.min(until.saturating_duration_since(Instant::now())));
+    }
+    running.load(Ordering::SeqCst)
+}
+
+/// Connects until it works or the bots are stopped, counting what it receives in `stats`.
+fn connect(options: &Options, stats: &Arc<Stats>, running: &AtomicBool) -> Option<EchoClient> {
+    while running.load(Ordering::SeqCst) {
+        let client = EchoClient::connect(&options.target).and_then(|mut client| {
+            if !options.secret.is_empty() { client.authenticate(options.secret.as_bytes())?; }
+            let reader = client.try_clone()?;
+            let received = Arc::clone(stats);
+            reader.on_packet(move |_| { received.received.fetch_add(1, Ordering::Relaxed); });
+            Ok(client)
+        });
+        match client {
+            Ok(client) => {
+                stats.connected.fetch_add(1, Ordering::Relaxed);
+                return Some(client);
+            },
+            Err(_) => {
+                stats.failed_connects.fetch_add(1, Ordering::Relaxed);
+                sleep(Duration::from_secs(1), running);
+            }
+        }
+    }
+    None
+}
+
+/// Sends `count` packets of `sizes` bytes spread over `phase`, false if the connection was lost.
+fn send(client: &EchoClient, count: u64, sizes: std::ops::RangeInclusive<usize>, phase: Duration, stats: &Stats, running: &AtomicBool) -> bool {
+    let mut rng = rand::rng();
+    let interval = phase / count.max(1) as u32;
+    for _ in 0..count {
+        let payload: Vec<u8> = (0..rng.random_range(sizes.clone())).map(|_| rng.random()).collect();
+        if client.send(&payload).is_err() { return false; }
+        stats.sent.fetch_add(1, Ordering::Relaxed);
+        if !sleep(interval, running) { break; }
+    }
+    true
+}
+
+/// One bot, until the bots are stopped.
+fn bot(options: &Options, stats: &Arc<Stats>, running: &AtomicBool) {
+    let Some(mut client) = connect(options, stats, running) else { return; };
+
+    while running.load(Ordering::SeqCst) {
+        let (pattern, seconds) = {
+            let mut rng = rand::rng();
+            (PATTERNS[rng.random_range(0..PATTERNS.len())], rng.random_range(1..=MAX_PHASE_SECONDS))
+        };
+        let phase = Duration::from_secs(seconds);
+        let connected = match pattern {
+            Pattern::Idle => { sleep(phase, running); true },
+            Pattern::Steady => send(&client, seconds * 20, 16..=256, phase, stats, running),
+            Pattern::Burst => {
+                let connected = send(&client, 200, 16..=256, Duration::ZERO, stats, running);
+                sleep(phase, running);
+                connected
+            },
+            Pattern::Large => send(&client, seconds * 5, BUFFER_SIZE / 2..=BUFFER_SIZE - 4, phase, stats, running),
+            Pattern::Reconnect => {
+                stats.reconnects.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        };
+
+        if !connected {
+            if !matches!(pattern, Pattern::Reconnect) { stats.disconnects.fetch_add(1, Ordering::Relaxed); }
+            let _ = client.close();
+            stats.connected.fetch_sub(1, Ordering::Relaxed);
+            match connect(options, stats, running) {
+                Some(reconnected) => client = reconnected,
+                None => return
+            }
+        }
+    }
+
+    let _ = client.close();
+    stats.connected.fetch_sub(1, Ordering::Relaxed);
+}
+
+fn report(elapsed: Duration, options: &Options, stats: &Stats) {
+    let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+    println!(
+        "[{:>6}s] {}/{} bots connected, {} sent, {} received, {} reconnects, {} disconnects, {} failed connects",
+        elapsed.as_secs(), stats.connected.load(Ordering::Relaxed), options.bots, get(&stats.sent), get(&stats.received),
+        get(&stats.reconnects), get(&stats.disconnects), get(&stats.failed_connects)
+    );
+}
+
+/// Runs the bots with the arguments after `bots`, returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let options = match parse(args) {
+        Ok(options) => Arc::new(options),
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return 2;
+        }
+    };
+
+    let running = Arc::new(AtomicBool::new(true));
+    {
+        let running = Arc::clone(&running);
+        if ctrlc::set_handler(move || running.store(false, Ordering::SeqCst)).is_err() {
+            eprintln!("Could not register ctrlc listener, exiting!");
+            return 1;
+        }
+    }
+
+    println!("Starting {} bots against {}{}...", options.bots, options.target, match options.duration {
+        Some(duration) => format!(" for {}s", duration.as_secs()),
+        None => ", ctrl+c to stop".to_string()
+    });
+    let started = Instant::now();
+    let stats = Arc::new(Stats::default());
+    let bots: Vec<_> = (0..options.bots).map(|_| {
+        let (options, stats, running) = (Arc::clone(&options), Arc::clone(&stats), Arc::clone(&running));
+        thread::spawn(move || bot(&options, &stats, &running))
+    }).collect();
+
+    let mut next_report = started + options.interval;
+    while running.load(Ordering::SeqCst) {
+        if options.duration.is_some_and(|duration| started.elapsed() >= duration) { running.store(false, Ordering::SeqCst); }
+        if Instant::now() >= next_report {
+            report(started.elapsed(), &options, &stats);
+            next_report += options.interval;
+        }
+        thread::sleep(TICK);
+    }
+
+    for bot in bots { let _ = bot.join(); }
+    report(started.elapsed(), &options, &stats);
+    0
+}
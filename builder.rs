@@ -0,0 +1,166 @@
+//! `Server::builder()`: configuring an embedded server in code, with a setter for every option of
+//! `config.yaml` and the command line, named like them.
+
+use std::sync::Arc;
+
+use crate::Server;
+use crate::ServerConfig;
+use crate::clock::Clock;
+use crate::codec::Codec;
+use crate::events::ClientInfo;
+use crate::events::EventHandler;
+use crate::events::Hooks;
+use crate::events::PacketFilter;
+use crate::events::Verdict;
+use crate::ids::IdAllocator;
+use crate::middleware::Middleware;
+use crate::ratelimit::RateLimiter;
+use crate::storage::Storage;
+use crate::transport::Transport;
+
+/// A `ServerConfig` being built, starting from the defaults. Nothing is checked until `build`, which
+/// handles invalid values like the config file does.
+#[derive(Clone, Default)]
+pub struct ServerBuilder {
+    config: ServerConfig,
+    hooks: Hooks
+}
+
+/// A setter per option taking its value as is.
+macro_rules! setters {
+    ($($name:ident: $type:ty),* $(,)?) => {
+        impl ServerBuilder {
+            $(
+                #[doc = concat!("Sets `", stringify!($name), "`, see `config.yaml`.")]
+                pub fn $name(mut self, value: $type) -> ServerBuilder {
+                    self.config.$name = value;
+                    self
+                }
+            )*
+        }
+    };
+}
+
+/// A setter per text option, taking anything that converts into a `String`.
+macro_rules! string_setters {
+    ($($name:ident),* $(,)?) => {
+        impl ServerBuilder {
+            $(
+                #[doc = concat!("Sets `", stringify!($name), "`, see `config.yaml`.")]
+                pub fn $name(mut self, value: impl Into<String>) -> ServerBuilder {
+                    self.config.$name = value.into();
+                    self
+                }
+            )*
+        }
+    };
+}
+
+setters!(
+    port: i32, mirror: bool, max_players: i32, reserved_slots: i32, max_per_ip: i32, accept_rate: i32,
+    accept_burst: i32, max_packets: i32, rate_burst: i32, rate_violations: i32, auto_ban: i32, auto_ban_drops: i32,
+    max_rate: i32, total_rate: i32, debug_print: bool, log_rotate_size: i32, log_rotate_interval: i32,
+    log_retention: i32, metrics_port: i32, statsd_interval: i32, statsd_tags: bool, stats_interval: i32,
+    admin_port: i32, otlp_sample_percent: i32, health_port: i32, slow_client_ms: i32, handshake_timeout: i32,
+    io_threads: i32, accept_cpu: i32, fanout_threads: i32, fanout_min: i32, coalesce_ms: i32, chaos_delay: i32,
+    chaos_jitter: i32, chaos_reorder_percent: i32, chaos_loss_percent: i32, federation_port: i32, standby_port: i32,
+    send_queue_limit: i32, memory_limit: i32, write_timeout: i32, resume_grace: i32, resume_buffer: i32,
+    resume_packets: i32, resume_max_age: i32, control_packets: bool, trace_packets: i32, api_port: i32,
+    journal_rotate_size: i32, journal_rotate_interval: i32, journal_retention: i32, journal_compress: bool,
+    drain_timeout: i32, shutdown_timeout: i32, stdin_console: bool, auth_challenge: bool, seed: i32
+);
+
+string_setters!(
+    rate_limiter, rate_policy, log_file, statsd_address, statsd_prefix, admin_address, otlp_endpoint, otlp_service_name,
+    audit_log, record, journal, io_mode, worker_cpus, send_queue_policy, syslog, syslog_facility, log_level, crash_dump,
+    api_address, api_token, storage, ban_file, filter_file, middleware, unix_socket, codec, unix_socket_codec, control_socket,
+    control_socket_mode, user, group, secret, token_secret, id_allocator, allow, deny, tls_psk, tls_psk_identity,
+    federation_peers, federation_secret, federation_discovery, nats_url, nats_subject, kafka_brokers, kafka_topic, standby_of, standby_secret
+);
+
+impl ServerBuilder {
+    /// Starts from `config` instead of the defaults, e.g. `ServerConfig::load()`.
+    pub fn from_config(config: ServerConfig) -> ServerBuilder {
+        ServerBuilder { config, hooks: Hooks::default() }
+    }
+
+    /// The configuration built so far.
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Adds callbacks for clients joining, leaving and sending packets, called in the order they were added.
+    pub fn event_handler(mut self, handler: impl EventHandler + 'static) -> ServerBuilder {
+        self.hooks.handlers.push(Arc::new(handler));
+        self
+    }
+
+    /// Registers a step of the middleware chain under `name`, where `middleware` lists it or, if it doesn't,
+    /// after the steps it lists. A name registered again replaces the earlier middleware.
+    pub fn add_middleware(mut self, name: impl Into<String>, middleware: impl Middleware + 'static) -> ServerBuilder {
+        let name = name.into();
+        self.hooks.middleware.retain(|(registered, _)| *registered != name);
+        self.hooks.middleware.push((name, Arc::new(middleware)));
+        self
+    }
+
+    /// Checks every packet with `filter` before it is relayed, for validation that doesn't need to change packets.
+    /// It is the `packet_filter` step of the middleware chain, a second filter replaces the first.
+    pub fn packet_filter(self, filter: impl Fn(&ClientInfo, &[u8]) -> Verdict + Send + Sync + 'static) -> ServerBuilder {
+        self.add_middleware("packet_filter", PacketFilter(filter))
+    }
+
+    /// Picks client ids with `allocator` instead of the built-in one named by `id_allocator`.
+    pub fn with_id_allocator(mut self, allocator: impl IdAllocator + 'static) -> ServerBuilder {
+        self.hooks.id_allocator = Some(Arc::new(allocator));
+        self
+    }
+
+    /// Rate limits clients with `limiter` instead of the built-in one named by `rate_limiter`.
+    pub fn with_rate_limiter(mut self, limiter: impl RateLimiter + 'static) -> ServerBuilder {
+        self.hooks.rate_limiter = Some(Arc::new(limiter));
+        self
+    }
+
+    /// Rate limits clients by the time of `clock` instead of real time, e.g. a `clock::ManualClock` a test advances.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> ServerBuilder {
+        self.hooks.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Keeps the bans and other persistent state in `storage` instead of the built-in one named by `storage`.
+    pub fn with_storage(mut self, storage: impl Storage + 'static) -> ServerBuilder {
+        self.hooks.storage = Some(Arc::new(storage));
+        self
+    }
+
+    /// Frames the packets of the TCP listener with `codec` instead of the built-in one named by `codec`.
+    pub fn with_codec(mut self, codec: impl Codec + 'static) -> ServerBuilder {
+        self.hooks.codec = Some(Arc::new(codec));
+        self
+    }
+
+    /// Also accepts clients from `transport`, e.g. WebSocket connections, each on a thread of its own.
+    pub fn add_transport(mut self, transport: impl Transport + 'static) -> ServerBuilder {
+        self.hooks.transports.push((Arc::new(transport), None));
+        self
+    }
+
+    /// `add_transport` for clients framing their packets with `codec`.
+    pub fn add_transport_with_codec(mut self, transport: impl Transport + 'static, codec: impl Codec + 'static) -> ServerBuilder {
+        self.hooks.transports.push((Arc::new(transport), Some(Arc::new(codec))));
+        self
+    }
+
+    /// Makes `reload` read the allow and deny lists from `source`, e.g. `ServerConfig::load` for a server
+    /// configured like the binary. Without it they stay as they were built.
+    pub fn reload_config(mut self, source: impl Fn() -> ServerConfig + Send + Sync + 'static) -> ServerBuilder {
+        self.hooks.config_source = Some(Arc::new(source));
+        self
+    }
+
+    /// Starts the server with the configuration built, see `Server::new`.
+    pub fn build(self) -> Result<Server, String> {
+        Server::start(self.config, self.hooks)
+    }
+}
@@ -0,0 +1,152 @@
+//! Bad network conditions on purpose (`chaos_delay`, `chaos_jitter`, `chaos_reorder_percent`,
+//! `chaos_loss_percent`), so game clients can be tested against lag and loss with nothing but the relay.
+//! The router rolls the dice for every recipient of a relayed packet on its own, like separate network
+//! paths would, and adds the latency an operator gave the recipient (`State::set_latency`). Delayed
+//! frames wait on the chaos thread and are queued in the outbox once they are due. Control frames (kick
+//! notices, throttling and so on) are never delayed or lost.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::Ordering as AtomicOrdering;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use rand::Rng;
+
+use crate::metrics::Metrics;
+use crate::random;
+use crate::registry::Client;
+use crate::registry::Outbox;
+use crate::registry::SharedConnections;
+
+/// Extra time a packet picked for reordering is held back, so the ones sent after it overtake it.
+const REORDER_HOLD: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Copy)]
+pub struct Settings {
+    /// Milliseconds every packet is held back.
+    pub delay: i32,
+    /// Up to this many milliseconds more or less than `delay`, picked for every packet.
+    pub jitter: i32,
+    /// Percent of packets held back `REORDER_HOLD` longer.
+    pub reorder_percent: i32,
+    /// Percent of packets dropped.
+    pub loss_percent: i32
+}
+
+impl Settings {
+    pub fn enabled(&self) -> bool {
+        self.delay > 0 || self.jitter > 0 || self.reorder_percent > 0 || self.loss_percent > 0
+    }
+
+    /// How it is configured, for the startup log.
+    pub fn describe(&self) -> String {
+        if !self.enabled() { return "disabled".to_string(); }
+        format!("{}ms ±{}ms delay, {}% reordered, {}% lost", self.delay.max(0), self.jitter.max(0), self.reorder_percent.max(0), self.loss_percent.max(0))
+    }
+}
+
+/// A frame waiting on the chaos thread.
+struct Delayed {
+    due: Instant,
+    /// Frames due at the same time go out in the order they were delayed.
+    seq: u64,
+    id: i32,
+    outbox: Arc<Outbox>,
+    frame: Arc<[u8]>
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Delayed) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl Eq for Delayed { }
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Delayed) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    /// Reversed, so the heap's top is the frame due first.
+    fn cmp(&self, other: &Delayed) -> Ordering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+pub struct Chaos {
+    settings: Settings,
+    metrics: Arc<Metrics>,
+    delayed: mpsc::Sender<Delayed>,
+    /// Only touched by the router thread.
+    seq: u64
+}
+
+impl Chaos {
+    /// Starts the chaos thread, it ends with the process.
+    pub fn spawn(settings: Settings, connections: SharedConnections, metrics: Arc<Metrics>) -> std::io::Result<Chaos> {
+        let (delayed, receiver) = mpsc::channel::<Delayed>();
+        thread::Builder::new().name("chaos".to_string()).spawn(move || deliver(&connections, receiver))?;
+        Ok(Chaos { settings, metrics, delayed, seq: 0 })
+    }
+
+    /// Whether frames have to pass `send`, otherwise they can be queued right away.
+    pub fn applies(&self, connections: &HashMap<i32, Client>) -> bool {
+        self.settings.enabled() || connections.values().any(|c| c.latency_ms.load(AtomicOrdering::Relaxed) > 0)
+    }
+
+    /// Queues `frame` for client `id` after its delay, or drops it. Returns false if it was dropped or couldn't be queued.
+    pub fn send(&mut self, id: i32, client: &Client, frame: &Arc<[u8]>) -> bool {
+        let Settings { delay, jitter, reorder_percent, loss_percent } = self.settings;
+        // without the chaos settings there's nothing to roll, which keeps draws of a seeded generator the same
+        let (lost, offset, reordered) = match self.settings.enabled() {
+            true => random::with(|rng| (
+                rng.random_range(0..100) < loss_percent,
+                if jitter > 0 { rng.random_range(-jitter..=jitter) } else { 0 },
+                rng.random_range(0..100) < reorder_percent
+            )),
+            false => (false, 0, false)
+        };
+        if lost {
+            Metrics::add(&self.metrics.chaos_drops, 1);
+            return false;
+        }
+
+        let mut wait = Duration::from_millis((delay.max(0) + offset).max(0) as u64 + client.latency_ms.load(AtomicOrdering::Relaxed) as u64);
+        if reordered { wait += REORDER_HOLD; }
+        if wait.is_zero() { return client.send(Arc::clone(frame), true); }
+        self.seq += 1;
+        self.delayed.send(Delayed { due: Instant::now() + wait, seq: self.seq, id, outbox: Arc::clone(&client.outbox), frame: Arc::clone(frame) }).is_ok()
+    }
+}
+
+/// Queues every delayed frame once it is due.
+fn deliver(connections: &SharedConnections, receiver: mpsc::Receiver<Delayed>) {
+    let mut waiting = BinaryHeap::new();
+    loop {
+        let next = match waiting.peek() {
+            Some(Delayed { due, .. }) => receiver.recv_timeout(due.saturating_duration_since(Instant::now())).map_err(|e| e == mpsc::RecvTimeoutError::Disconnected),
+            None => receiver.recv().map_err(|_| true)
+        };
+        match next {
+            Ok(delayed) => waiting.push(delayed),
+            Err(true) => return,
+            Err(false) => { }
+        }
+
+        let now = Instant::now();
+        while waiting.peek().is_some_and(|d| d.due <= now) {
+            let Some(Delayed { id, outbox, frame, .. }) = waiting.pop() else { break };
+            if outbox.push(frame, true) { continue; }
+            // the client is gone or its queue overflowed, see `Client::send`
+            let connections = connections.read().unwrap_or_else(|e| e.into_inner());
+            if let Some(client) = connections.get(&id).filter(|c| Arc::ptr_eq(&c.outbox, &outbox)) { client.disconnect_if_failed(); }
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! `echoserver check`: probes a running server (this one, a fork or a reimplementation) for conformance
+//! with the protocol: the handshake, frames split over several writes or sharing one, the smallest and
+//! largest frames, malformed sizes, truncated frames and bursts. Prints a line per probe and exits with
+//! 1 if any failed.
+//!
+//! A listening client stays connected for the whole check and every probe sends from a new connection,
+//! so a probe that gets its connection closed doesn't affect the next ones.
+
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use echoserver::BUFFER_SIZE;
+use echoserver::EchoClient;
+use echoserver::Packet;
+
+/// How long the listener waits for a packet that should arrive, and a probe for the server to close its connection.
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// How long the listener waits for packets that shouldn't arrive, and until leftovers of a probe are read.
+const QUIET: Duration = Duration::from_millis(500);
+/// Packets sent at once by the burst probe.
+const BURST: usize = 200;
+
+const USAGE: &str = "usage: echoserver check [host:port] [--secret SECRET] [--handshake-timeout SECONDS]";
+
+struct Options {
+    target: String,
+    /// Sent as the first frame of every connection when the server requires authentication.
+    secret: String,
+    /// The server's `handshake_timeout` in seconds, 0 = not probed.
+    handshake_timeout: u64
+}
+
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    Skip(String)
+}
+
+type Probe = fn(&Options, &mut EchoClient) -> Outcome;
+
+/// In the order they run, the others are skipped if the first one fails.
+const PROBES: [(&str, Probe); 12] = [
+    ("Handshake", handshake),
+    ("Split frame", split_frame),
+    ("Shared write", shared_write),
+    ("Empty packet", empty_packet),
+    ("Largest frame", largest_frame),
+    ("Oversized", oversized),
+    ("Undersized", undersized),
+    ("Negative size", negative_size),
+    ("Truncated", truncated),
+    ("Burst", burst),
+    ("Bad secret", bad_secret),
+    ("Silent client", silent_client)
+];
+
+fn parse(args: &[String]) -> Result<Options, String> {
+    let mut options = Options { target: "127.0.0.1:45565".to_string(), secret: String::new(), handshake_timeout: 0 };
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg.as_str(), None)
+        };
+        if !name.starts_with("--") {
+            options.target = if name.contains(':') { name.to_string() } else { format!("127.0.0.1:{}", name) };
+            continue;
+        }
+
+        let value = inline.or_else(|| args.next().cloned()).ok_or(format!("{} needs a value", name))?;
+        match name {
+            "--secret" => options.secret = value,
+            "--handshake-timeout" => options.handshake_timeout = value.parse::<u64>().map_err(|_| format!("invalid {} {}", name, value))?,
+            _ => return Err(format!("unknown option {}", name))
+        }
+    }
+    Ok(options)
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = ((payload.len() + 4) as i32).to_le_bytes().to_vec();
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// A new connection that sent the secret if there is one.
+fn open(options: &Options) -> Result<TcpStream, String> {
+    let mut stream = TcpStream::connect(&options.target).map_err(|e| format!("could not connect ({})", e))?;
+    let _ = stream.set_nodelay(true);
+    let _ = stream.set_read_timeout(Some(TIMEOUT));
+    if !options.secret.is_empty() { stream.write_all(&frame(options.secret.as_bytes())).map_err(|e| format!("could not send the secret ({})", e))?; }
+    Ok(stream)
+}
+
+fn send(stream: &mut TcpStream, bytes: &[u8]) -> Result<(), String> {
+    stream.write_all(bytes).map_err(|e| format!("could not send ({})", e))
+}
+
+/// Whether the server closes `stream` within `timeout`, whatever it sends before.
+fn closed(stream: &mut TcpStream, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0u8; BUFFER_SIZE];
+    while Instant::now() < deadline {
+        let _ = stream.set_read_timeout(Some(deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1))));
+        match stream.read(&mut buffer) {
+            Ok(0) => return true,
+            Ok(_) => continue,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => return false,
+            Err(_) => return true
+        }
+    }
+    false
+}
+
+/// The next packets the listener receives, at most `count`, waiting `timeout` for each. Control packets are skipped.
+fn receive(listener: &mut EchoClient, count: usize, timeout: Duration) -> Vec<Vec<u8>> {
+    let _ = listener.set_read_timeout(Some(timeout));
+    let mut received = Vec::new();
+    while received.len() < count {
+        match listener.recv() {
+            Ok(Some(Packet::Data(payload))) => received.push(payload),
+            Ok(Some(Packet::Control(..))) => continue,
+            _ => break
+        }
+    }
+    received
+}
+
+/// Passes if the listener receives `payloads` in order.
+fn expect(listener: &mut EchoClient, payloads: &[&[u8]], passed: &str) -> Outcome {
+    let received = receive(listener, payloads.len(), TIMEOUT);
+    if received.len() < payloads.len() { return Outcome::Fail(format!("the other client received {} of {} packets", received.len(), payloads.len())); }
+    match payloads.iter().zip(&received).position(|(expected, received)| expected != received) {
+        Some(n) => Outcome::Fail(format!("packet {} arrived changed ({} bytes instead of {})", n + 1, received[n].len(), payloads[n].len())),
+        None => Outcome::Pass(passed.to_string())
+    }
+}
+
+/// Passes if the server closes the connection after it sent `bytes` and nothing reaches the listener.
+fn refused(options: &Options, listener: &mut EchoClient, bytes: &[u8], passed: &str) -> Outcome {
+    let mut stream = match open(options) {
+        Ok(stream) => stream,
+        Err(e) => return Outcome::Fail(e)
+    };
+    if let Err(e) = send(&mut stream, bytes) { return Outcome::Fail(e); }
+    if !closed(&mut stream, TIMEOUT) { return Outcome::Fail(format!("the connection was still open after {}s", TIMEOUT.as_secs())); }
+    match receive(listener, 1, QUIET).first() {
+        Some(payload) => Outcome::Fail(format!("the other client received {} bytes of it", payload.len())),
+        None => Outcome::Pass(passed.to_string())
+    }
+}
+
+/// Sends `bytes` from a new connection and checks that the listener receives `payloads`.
+fn relayed(options: &Options, listener: &mut EchoClient, bytes: &[u8], payloads: &[&[u8]], passed: &str) -> Outcome {
+    let mut stream = match open(options) {
+        Ok(stream) => stream,
+        Err(e) => return Outcome::Fail(e)
+    };
+    if let Err(e) = send(&mut stream, bytes) { return Outcome::Fail(e); }
+    expect(listener, payloads, passed)
+}
+
+fn handshake(options: &Options, listener: &mut EchoClient) -> Outcome {
+    relayed(options, listener, &frame(b"check handshake"), &[b"check handshake"], "a client joined and its packet reached another one")
+}
+
+fn split_frame(options: &Options, listener: &mut EchoClient) -> Outcome {
+    let mut stream = match open(options) {
+        Ok(stream) => stream,
+        Err(e) => return Outcome::Fail(e)
+    };
+    let bytes = frame(b"check split frame");
+    for part in [&bytes[..2], &bytes[2..9], &bytes[9..]] {
+        if let Err(e) = send(&mut stream, part) { return Outcome::Fail(e); }
+        thread::sleep(Duration::from_millis(100));
+    }
+    expect(listener, &[b"check split frame"], "a frame written in three parts arrived whole")
+}
+
+fn shared_write(options: &Options, listener: &mut EchoClient) -> Outcome {
+    let bytes = [frame(b"check one"), frame(b"check two"), frame(b"check three")].concat();
+    relayed(options, listener, &bytes, &[b"check one", b"check two", b"check three"], "three frames in one write arrived as three packets")
+}
+
+fn empty_packet(options: &Options, listener: &mut EchoClient) -> Outcome {
+    relayed(options, listener, &frame(b""), &[b""], "a frame of size 4 was relayed")
+}
+
+fn largest_frame(options: &Options, listener: &mut EchoClient) -> Outcome {
+    let payload: Vec<u8> = (0..BUFFER_SIZE - 4).map(|n| n as u8).collect();
+    relayed(options, listener, &frame(&payload), &[&payload], &format!("a frame of size {} was relayed", BUFFER_SIZE))
+}
+
+fn oversized(options: &Options, listener: &mut EchoClient) -> Outcome {
+    let mut bytes = ((BUFFER_SIZE + 1) as i32).to_le_bytes().to_vec();
+    bytes.resize(BUFFER_SIZE + 1, 0);
+    refused(options, listener, &bytes, &format!("a frame of size {} got the connection closed", BUFFER_SIZE + 1))
+}
+
+fn undersized(options: &Options, listener: &mut EchoClient) -> Outcome {
+    refused(options, listener, &[3, 0, 0, 0, 0, 0, 0, 0], "a frame of size 3 got the connection closed")
+}
+
+fn negative_size(options: &Options, listener: &mut EchoClient) -> Outcome {
+    refused(options, listener, &[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0], "a frame of size -1 got the connection closed")
+}
+
+fn truncated(options: &Options, listener: &mut EchoClient) -> Outcome {
+    let mut stream = match open(options) {
+        Ok(stream) => stream,
+        Err(e) => return Outcome::Fail(e)
+    };
+    let bytes = frame(&[7u8; 100]);
+    if let Err(e) = send(&mut stream, &bytes[..14]) { return Outcome::Fail(e); }
+    let _ = stream.shutdown(Shutdown::Both);
+    match receive(listener, 1, QUIET).first() {
+        Some(payload) => Outcome::Fail(format!("the other client received {} bytes of a frame that never ended", payload.len())),
+        None => Outcome::Pass("a frame cut off by the connection closing was not relayed".to_string())
+    }
+}
+
+fn burst(options: &Options, listener: &mut EchoClient) -> Outcome {
+    let mut stream = match open(options) {
+        Ok(stream) => stream,
+        Err(e) => return Outcome::Fail(e)
+    };
+    let bytes: Vec<u8> = (0..BURST).flat_map(|_| frame(&[1u8; 256])).collect();
+    if let Err(e) = send(&mut stream, &bytes) { return Outcome::Fail(e); }
+    let relayed = receive(listener, BURST, QUIET).len();
+
+    // whatever the rate limit let through, the sender either keeps relaying or was disconnected for flooding
+    thread::sleep(Duration::from_secs(1));
+    if send(&mut stream, &frame(b"check after burst")).is_err() || closed(&mut stream, Duration::from_millis(100)) {
+        return Outcome::Pass(format!("{} of {} packets relayed, then the sender was disconnected", relayed, BURST));
+    }
+    loop {
+        match receive(listener, 1, TIMEOUT).pop() {
+            Some(payload) if payload == b"check after burst" => return Outcome::Pass(format!("{} of {} packets relayed, the sender kept relaying afterwards", relayed, BURST)),
+            Some(_) => continue, // a late packet of the burst
+            None => return Outcome::Fail(format!("{} of {} packets relayed, nothing the sender sent afterwards arrived", relayed, BURST))
+        }
+    }
+}
+
+fn bad_secret(options: &Options, listener: &mut EchoClient) -> Outcome {
+    if options.secret.is_empty() { return Outcome::Skip("needs --secret".to_string()); }
+    let mut stream = match TcpStream::connect(&options.target) {
+        Ok(stream) => stream,
+        Err(e) => return Outcome::Fail(format!("could not connect ({})", e))
+    };
+    let wrong = format!("{}-wrong", options.secret);
+    if let Err(e) = send(&mut stream, &[frame(wrong.as_bytes()), frame(b"check bad secret")].concat()) { return Outcome::Fail(e); }
+    if !closed(&mut stream, TIMEOUT) { return Outcome::Fail("a client with a wrong secret stayed connected".to_string()); }
+    match receive(listener, 1, QUIET).first() {
+        Some(_) => Outcome::Fail("a packet of a client with a wrong secret was relayed".to_string()),
+        None => Outcome::Pass("a client with a wrong secret was refused".to_string())
+    }
+}
+
+fn silent_client(options: &Options, _listener: &mut EchoClient) -> Outcome {
+    if options.handshake_timeout == 0 { return Outcome::Skip("needs --handshake-timeout".to_string()); }
+    let mut stream = match TcpStream::connect(&options.target) {
+        Ok(stream) => stream,
+        Err(e) => return Outcome::Fail(format!("could not connect ({})", e))
+    };
+    let timeout = Duration::from_secs(options.handshake_timeout);
+    match closed(&mut stream, timeout + TIMEOUT) {
+        true => Outcome::Pass(format!("a client that sent nothing was disconnected within {}s", options.handshake_timeout + TIMEOUT.as_secs())),
+        false => Outcome::Fail(format!("a client that sent nothing was still connected after {}s", options.handshake_timeout + TIMEOUT.as_secs()))
+    }
+}
+
+/// The client that receives what the probes send. It sends a packet itself, so the server doesn't
+/// disconnect it for staying silent during the handshake.
+fn listen(options: &Options) -> Result<EchoClient, String> {
+    let mut listener = EchoClient::connect(&options.target).map_err(|e| format!("could not connect to {} ({})", options.target, e))?;
+    if !options.secret.is_empty() { listener.authenticate(options.secret.as_bytes()).map_err(|e| format!("could not send the secret ({})", e))?; }
+    listener.send(b"check listener").map_err(|e| format!("could not send ({})", e))?;
+    receive(&mut listener, usize::MAX, QUIET); // its own packet, with mirror
+    Ok(listener)
+}
+
+/// Runs the check with the arguments after `check`, returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let options = match parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return 2;
+        }
+    };
+    let mut listener = match listen(&options) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not start the check: {}.", e);
+            return 1;
+        }
+    };
+
+    println!("Checking {}...", options.target);
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    let mut relaying = true;
+    for (n, (name, probe)) in PROBES.iter().enumerate() {
+        let outcome = if relaying { probe(&options, &mut listener) } else { Outcome::Skip("nothing is relayed".to_string()) };
+        if n == 0 { relaying = matches!(outcome, Outcome::Pass(_)); }
+        match outcome {
+            Outcome::Pass(detail) => { passed += 1; println!("{:<14}= ok, {}", name, detail); },
+            Outcome::Fail(detail) => { failed += 1; println!("{:<14}= FAILED, {}", name, detail); },
+            Outcome::Skip(detail) => { skipped += 1; println!("{:<14}= skipped, {}", name, detail); }
+        }
+        receive(&mut listener, usize::MAX, QUIET); // leftovers, e.g. late packets of a burst
+    }
+
+    println!("{} passed, {} failed, {} skipped.", passed, failed, skipped);
+    if failed > 0 { 1 } else { 0 }
+}
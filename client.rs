@@ -0,0 +1,148 @@
+//! `EchoClient`: the client side of the protocol, so Rust game clients, tests and `bench` don't have
+//! to implement the framing (and the handshake with a `secret`) themselves.
+
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::thread;
+use std::time::Duration;
+
+use crate::BUFFER_SIZE;
+use crate::auth;
+use crate::control;
+use crate::control::Kind;
+
+/// Something the server sent.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Packet {
+    /// A packet relayed from another client (or this one, with `mirror`), without its size prefix.
+    Data(Vec<u8>),
+    /// A control packet of the server with its body, see `control`. Packets with the magic but a kind
+    /// this version doesn't know are `Data`.
+    Control(Kind, Vec<u8>)
+}
+
+/// A connection to an echoserver: `connect`, `send` packets and read them with `recv`, `packets` or `on_packet`.
+pub struct EchoClient {
+    stream: TcpStream,
+    /// Bytes read that don't make up a whole frame yet.
+    buffer: Vec<u8>
+}
+
+impl EchoClient {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<EchoClient> {
+        let stream = TcpStream::connect(addr)?;
+        let _ = stream.set_nodelay(true);
+        Ok(EchoClient { stream, buffer: Vec::new() })
+    }
+
+    /// Sends the first frame for a server with `secret` or `token_secret`: the secret itself or a token.
+    pub fn authenticate(&mut self, credential: &[u8]) -> io::Result<()> {
+        self.send(credential)
+    }
+
+    /// Waits for the `Challenge` of a server with `auth_challenge` and answers it with `secret`.
+    /// Packets before it are skipped, there are none from a server that sends one.
+    pub fn answer_challenge(&mut self, secret: &str) -> io::Result<()> {
+        loop {
+            match self.recv()? {
+                Some(Packet::Control(Kind::Challenge, nonce)) => return self.send(&auth::hmac_sha256(secret.as_bytes(), &nonce)),
+                Some(_) => continue,
+                None => return Err(io::Error::new(ErrorKind::UnexpectedEof, "closed before the challenge"))
+            }
+        }
+    }
+
+    /// Asks a server with `resume_grace` to resume the session of `token` (from the last `Resume` packet of
+    /// that session), as the first packet of a new connection. `received` counts the `Data` packets received
+    /// in the session so far, a `Resumed` packet and the ones after arrive next if it worked.
+    pub fn resume(&self, token: &[u8], received: u64) -> io::Result<()> {
+        self.send(&[&control::MAGIC[..], &[Kind::Resume as u8], token, &received.to_le_bytes()].concat())
+    }
+
+    /// Sends one packet, `payload` without the size prefix.
+    pub fn send(&self, payload: &[u8]) -> io::Result<()> {
+        if payload.len() + 4 > BUFFER_SIZE {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("packets can be at most {} bytes", BUFFER_SIZE - 4)));
+        }
+        let mut frame = Vec::with_capacity(payload.len() + 4);
+        frame.extend_from_slice(&((payload.len() + 4) as i32).to_le_bytes());
+        frame.extend_from_slice(payload);
+        (&self.stream).write_all(&frame)
+    }
+
+    /// Waits for the next packet, `None` once the server closed the connection. A read timeout
+    /// (`set_read_timeout`) returns `WouldBlock` or `TimedOut`, a part of a packet read until then is kept.
+    pub fn recv(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            if self.buffer.len() >= 4 {
+                let size = i32::from_le_bytes([self.buffer[0], self.buffer[1], self.buffer[2], self.buffer[3]]);
+                if size < 4 || size as usize > BUFFER_SIZE {
+                    return Err(io::Error::new(ErrorKind::InvalidData, format!("invalid packet size {}", size)));
+                }
+                if self.buffer.len() >= size as usize {
+                    let payload: Vec<u8> = self.buffer.drain(..size as usize).skip(4).collect();
+                    return Ok(Some(packet(payload)));
+                }
+            }
+
+            let read = self.buffer.len();
+            self.buffer.resize(read + BUFFER_SIZE, 0);
+            let result = self.stream.read(&mut self.buffer[read..]);
+            self.buffer.truncate(read + *result.as_ref().unwrap_or(&0));
+            match result {
+                Ok(0) if self.buffer.is_empty() => return Ok(None),
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "closed in the middle of a packet")),
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    /// The packets until the server closes the connection or an error, which is the last item.
+    pub fn packets(&mut self) -> impl Iterator<Item = io::Result<Packet>> + '_ {
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            if failed { return None; }
+            let next = self.recv().transpose();
+            failed = matches!(next, Some(Err(_)));
+            next
+        })
+    }
+
+    /// Calls `callback` with every packet on a thread of its own until the connection closes, the thread
+    /// returns the error that ended it, if any. Keep a `try_clone` to send.
+    pub fn on_packet(mut self, mut callback: impl FnMut(Packet) + Send + 'static) -> thread::JoinHandle<io::Result<()>> {
+        thread::spawn(move || {
+            for packet in self.packets() { callback(packet?); }
+            Ok(())
+        })
+    }
+
+    /// Another handle to the connection, e.g. to send from one thread while another receives. Packets
+    /// should only be received from one of them.
+    pub fn try_clone(&self) -> io::Result<EchoClient> {
+        Ok(EchoClient { stream: self.stream.try_clone()?, buffer: Vec::new() })
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Closes the connection for every handle, a blocked `recv` returns `None`.
+    pub fn close(&self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Both)
+    }
+}
+
+fn packet(payload: Vec<u8>) -> Packet {
+    match payload.strip_prefix(&control::MAGIC).and_then(|rest| Some((Kind::parse(*rest.first()?)?, rest[1..].to_vec()))) {
+        Some((kind, body)) => Packet::Control(kind, body),
+        None => Packet::Data(payload)
+    }
+}
@@ -0,0 +1,51 @@
+//! The time rate limits go by (`ServerBuilder::with_clock`). A simulation or an integration test can
+//! drive it with a `ManualClock`, so whether a packet passes depends on the steps it takes rather than on
+//! how fast the machine runs. Timeouts and the `delay` rate policy's waiting always use real time.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// Real time, the default.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Time that only passes when `advance` is called. Clones share it, so one can be passed to the
+/// builder and another kept to advance it.
+#[derive(Clone)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed: Arc<Mutex<Duration>>
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock { start: Instant::now(), elapsed: Arc::default() }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap_or_else(|e| e.into_inner()) += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,388 @@
+//! How packets are framed on the wire (`codec`, `unix_socket_codec`, `ServerBuilder::with_codec`).
+//! Inside the server every packet is a length-prefixed frame, a connection of a listener with another
+//! codec is wrapped in a `CodecConnection` translating between the two, so it is served like any other.
+//!
+//! Built in are `length-prefixed` (the default, see the README), `lines` (newline terminated text) and
+//! `varint` (a LEB128 payload length prefix, as in protobuf streams).
+
+use std::io;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::net::Shutdown;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::BUFFER_SIZE;
+use crate::transport::Connection;
+
+/// Bytes a frame may take on the wire beyond its payload, more without a complete frame is an error.
+const MAX_OVERHEAD: usize = 16;
+
+/// Splits the bytes a client sends into packets and frames the packets sent to it.
+pub trait Codec: Send + Sync {
+    /// The first packet in `buffer`: the range of its payload and how many bytes the frame takes. `None`
+    /// if the frame isn't complete yet, an error if the bytes can't be a frame (the client is disconnected).
+    fn decode(&self, buffer: &[u8]) -> io::Result<Option<(Range<usize>, usize)>>;
+
+    /// Appends the frame of `payload` to `out`. Returns false without appending anything if the codec
+    /// can't carry the payload, the packet is then not sent to this client.
+    fn encode(&self, payload: &[u8], out: &mut Vec<u8>) -> bool;
+}
+
+/// The server's own framing: the whole frame size as i32 LE, then the payload.
+pub struct LengthPrefixed;
+
+impl Codec for LengthPrefixed {
+    fn decode(&self, buffer: &[u8]) -> io::Result<Option<(Range<usize>, usize)>> {
+        let Some(size) = buffer.first_chunk::<4>().map(|size| i32::from_le_bytes(*size)) else { return Ok(None); };
+        if size < 4 { return Err(io::Error::new(ErrorKind::InvalidData, format!("packet too small ({})", size))); }
+        let size = size as usize;
+        Ok((buffer.len() >= size).then_some((4..size, size)))
+    }
+
+    fn encode(&self, payload: &[u8], out: &mut Vec<u8>) -> bool {
+        out.extend_from_slice(&((payload.len() + 4) as i32).to_le_bytes());
+        out.extend_from_slice(payload);
+        true
+    }
+}
+
+/// One packet per line, ending in `\n` (a `\r` before it is dropped). Packets containing a newline
+/// can't be sent to these clients.
+pub struct Lines;
+
+impl Codec for Lines {
+    fn decode(&self, buffer: &[u8]) -> io::Result<Option<(Range<usize>, usize)>> {
+        let Some(end) = buffer.iter().position(|b| *b == b'\n') else { return Ok(None); };
+        let payload_end = if end > 0 && buffer[end - 1] == b'\r' { end - 1 } else { end };
+        Ok(Some((0..payload_end, end + 1)))
+    }
+
+    fn encode(&self, payload: &[u8], out: &mut Vec<u8>) -> bool {
+        if payload.contains(&b'\n') { return false; }
+        out.extend_from_slice(payload);
+        out.push(b'\n');
+        true
+    }
+}
+
+/// The payload length as an unsigned LEB128 varint, then the payload.
+pub struct Varint;
+
+impl Codec for Varint {
+    fn decode(&self, buffer: &[u8]) -> io::Result<Option<(Range<usize>, usize)>> {
+        let mut length = 0usize;
+        for (i, byte) in buffer.iter().enumerate().take(5) {
+            length |= ((byte & 0x7f) as usize) << (7 * i);
+            if byte & 0x80 == 0 {
+                let start = i + 1;
+                return Ok((buffer.len() >= start + length).then_some((start..start + length, start + length)));
+            }
+        }
+        if buffer.len() >= 5 { return Err(io::Error::new(ErrorKind::InvalidData, "packet length longer than 5 bytes")); }
+        Ok(None)
+    }
+
+    fn encode(&self, payload: &[u8], out: &mut Vec<u8>) -> bool {
+        let mut length = payload.len();
+        while length >= 0x80 {
+            out.push((length as u8 & 0x7f) | 0x80);
+            length >>= 7;
+        }
+        out.push(length as u8);
+        out.extend_from_slice(payload);
+        true
+    }
+}
+
+/// The codec named `name` (`lines` or `varint`), `Some(None)` for `length-prefixed`, which needs no translation.
+pub fn parse(name: &str) -> Option<Option<Arc<dyn Codec>>> {
+    match name {
+        "length-prefixed" => Some(None),
+        "lines" => Some(Some(Arc::new(Lines))),
+        "varint" => Some(Some(Arc::new(Varint))),
+        _ => None
+    }
+}
+
+/// `stream` as a connection of a listener with `codec`, unchanged for length-prefixed listeners (`None`).
+pub fn wrap(stream: Box<dyn Connection>, codec: &Option<Arc<dyn Codec>>) -> Box<dyn Connection> {
+    match codec {
+        Some(codec) => Box::new(CodecConnection::new(stream, Arc::clone(codec))),
+        None => stream
+    }
+}
+
+/// A connection of a listener with its own codec. Reads give the packets it received as length-prefixed
+/// frames, the length-prefixed frames written to it are sent framed by the codec. Its clones share the
+/// bytes read and written that aren't a whole frame yet.
+pub struct CodecConnection {
+    inner: Box<dyn Connection>,
+    codec: Arc<dyn Codec>,
+    shared: Arc<Buffers>
+}
+
+#[derive(Default)]
+struct Buffers {
+    /// Bytes received that aren't a whole frame yet, and the frames to be read (length-prefixed).
+    read: Mutex<(Vec<u8>, Vec<u8>)>,
+    /// Bytes written that aren't a whole length-prefixed frame yet.
+    write: Mutex<Vec<u8>>
+}
+
+impl CodecConnection {
+    pub fn new(inner: Box<dyn Connection>, codec: Arc<dyn Codec>) -> CodecConnection {
+        CodecConnection { inner, codec, shared: Arc::default() }
+    }
+}
+
+impl Connection for CodecConnection {
+    fn transport(&self) -> &'static str {
+        self.inner.transport()
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut guard = self.shared.read.lock().unwrap_or_else(|e| e.into_inner());
+        let (received, frames) = &mut *guard;
+
+        while frames.is_empty() {
+            let mut chunk = [0u8; BUFFER_SIZE];
+            let n = self.inner.read(&mut chunk)?;
+            if n == 0 { return Ok(0); }
+            received.extend_from_slice(&chunk[..n]);
+
+            let mut consumed = 0;
+            while let Some((payload, length)) = self.codec.decode(&received[consumed..])? {
+                let payload = &received[consumed + payload.start..consumed + payload.end];
+                frames.extend_from_slice(&((payload.len() + 4) as i32).to_le_bytes());
+                frames.extend_from_slice(payload);
+                consumed += length;
+            }
+            received.drain(..consumed);
+            if received.len() > BUFFER_SIZE + MAX_OVERHEAD {
+                return Err(io::Error::new(ErrorKind::InvalidData, "packet too large"));
+            }
+        }
+
+        let n = buffer.len().min(frames.len());
+        buffer[..n].copy_from_slice(&frames[..n]);
+        frames.drain(..n);
+        Ok(n)
+    }
+
+    fn write(&self, bytes: &[u8]) -> io::Result<usize> {
+        let mut pending = self.shared.write.lock().unwrap_or_else(|e| e.into_inner());
+        pending.extend_from_slice(bytes);
+
+        let mut encoded = Vec::new();
+        let mut consumed = 0;
+        while let Some((payload, length)) = LengthPrefixed.decode(&pending[consumed..])? {
+            // packets the codec can't carry are skipped
+            let _ = self.codec.encode(&pending[consumed + payload.start..consumed + payload.end], &mut encoded);
+            consumed += length;
+        }
+        pending.drain(..consumed);
+
+        (&*self.inner).write_all(&encoded)?;
+        Ok(bytes.len())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(CodecConnection { inner: self.inner.try_clone()?, codec: Arc::clone(&self.codec), shared: Arc::clone(&self.shared) }))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn unsent_bytes(&self) -> Option<usize> {
+        self.inner.unsent_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// A connection that receives `reads` one after the other, then the end of the stream, and keeps what is written.
+    #[derive(Clone, Default)]
+    struct Scripted {
+        reads: Arc<Mutex<VecDeque<Vec<u8>>>>,
+        written: Arc<Mutex<Vec<u8>>>
+    }
+
+    impl Connection for Scripted {
+        fn transport(&self) -> &'static str { "test" }
+
+        fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+            let mut reads = self.reads.lock().unwrap();
+            let Some(mut chunk) = reads.pop_front() else { return Ok(0); };
+            let n = chunk.len().min(buffer.len());
+            buffer[..n].copy_from_slice(&chunk[..n]);
+            if n < chunk.len() { reads.push_front(chunk.split_off(n)); }
+            Ok(n)
+        }
+
+        fn write(&self, bytes: &[u8]) -> io::Result<usize> {
+            self.written.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn try_clone(&self) -> io::Result<Box<dyn Connection>> { Ok(Box::new(self.clone())) }
+        fn shutdown(&self, _how: Shutdown) -> io::Result<()> { Ok(()) }
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> { Ok(()) }
+        fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> { Ok(()) }
+        fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> { Ok(()) }
+    }
+
+    /// What a `CodecConnection` with `codec` reads from a client sending `reads`, as payloads.
+    fn received(codec: impl Codec + 'static, reads: &[&[u8]]) -> io::Result<Vec<Vec<u8>>> {
+        let inner = Scripted { reads: Arc::new(Mutex::new(reads.iter().map(|r| r.to_vec()).collect())), ..Scripted::default() };
+        let connection = CodecConnection::new(Box::new(inner), Arc::new(codec));
+        let mut frames = Vec::new();
+        let mut buffer = [0u8; 7];
+        loop {
+            match connection.read(&mut buffer)? {
+                0 => break,
+                n => frames.extend_from_slice(&buffer[..n])
+            }
+        }
+        let mut payloads = Vec::new();
+        let mut rest = &frames[..];
+        while let Some((payload, length)) = LengthPrefixed.decode(rest)? {
+            payloads.push(rest[payload].to_vec());
+            rest = &rest[length..];
+        }
+        assert!(rest.is_empty());
+        Ok(payloads)
+    }
+
+    fn encoded(codec: &dyn Codec, payload: &[u8]) -> Option<Vec<u8>> {
+        let mut out = vec![0xee];
+        codec.encode(payload, &mut out).then(|| out.split_off(1))
+    }
+
+    #[test]
+    fn varint_lengths() {
+        assert_eq!(Varint.decode(&[0]).unwrap(), Some((1..1, 1)));
+        assert_eq!(Varint.decode(&[3, b'a', b'b', b'c', b'x']).unwrap(), Some((1..4, 4)));
+        // 300 = 0b10_0101100
+        let mut buffer = vec![0xac, 0x02];
+        assert_eq!(Varint.decode(&buffer).unwrap(), None);
+        buffer.extend(vec![7; 300]);
+        assert_eq!(Varint.decode(&buffer).unwrap(), Some((2..302, 302)));
+        assert_eq!(Varint.decode(&[0xff, 0xff, 0xff, 0x7f]).unwrap(), None);
+        assert_eq!(Varint.decode(&[0x80]).unwrap(), None);
+        assert_eq!(Varint.decode(&[]).unwrap(), None);
+    }
+
+    #[test]
+    fn varint_lengths_longer_than_5_bytes_are_refused() {
+        assert_eq!(Varint.decode(&[0x80, 0x80, 0x80, 0x80, 0x80]).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(Varint.decode(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x01]).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(Varint.decode(&[0x80, 0x80, 0x80, 0x80, 0x01]).unwrap(), None);
+    }
+
+    #[test]
+    fn lines_end_in_a_newline() {
+        assert_eq!(Lines.decode(b"abc\r\nnext").unwrap(), Some((0..3, 5)));
+        assert_eq!(Lines.decode(b"abc\nnext").unwrap(), Some((0..3, 4)));
+        assert_eq!(Lines.decode(b"a\rb\n").unwrap(), Some((0..3, 4)));
+        assert_eq!(Lines.decode(b"\n").unwrap(), Some((0..0, 1)));
+        assert_eq!(Lines.decode(b"\r\n").unwrap(), Some((0..0, 2)));
+        assert_eq!(Lines.decode(b"abc\r").unwrap(), None);
+    }
+
+    #[test]
+    fn length_prefixed_frames() {
+        assert_eq!(LengthPrefixed.decode(&[6, 0, 0, 0, 1, 2, 3]).unwrap(), Some((4..6, 6)));
+        assert_eq!(LengthPrefixed.decode(&[6, 0, 0, 0, 1]).unwrap(), None);
+        assert_eq!(LengthPrefixed.decode(&[6, 0, 0]).unwrap(), None);
+        assert!(LengthPrefixed.decode(&[3, 0, 0, 0]).is_err());
+        assert!(LengthPrefixed.decode(&(-1i32).to_le_bytes()).is_err());
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        let codecs: [&dyn Codec; 3] = [&LengthPrefixed, &Lines, &Varint];
+        for codec in codecs {
+            for size in [0, 1, 127, 128, 300, 16_383, 16_384, BUFFER_SIZE] {
+                let payload: Vec<u8> = (0..size).map(|n| b'a' + (n % 26) as u8).collect();
+                let frame = encoded(codec, &payload).unwrap();
+                let (range, length) = codec.decode(&frame).unwrap().unwrap();
+                assert_eq!((&frame[range], length), (&payload[..], frame.len()));
+            }
+        }
+        assert_eq!(encoded(&Varint, &[1; 300]).unwrap()[..2], [0xac, 0x02]);
+        assert_eq!(encoded(&Lines, b"abc").unwrap(), b"abc\n");
+    }
+
+    #[test]
+    fn lines_refuse_payloads_with_a_newline() {
+        assert_eq!(encoded(&Lines, b"two\nlines"), None);
+        assert_eq!(encoded(&Lines, b"\n"), None);
+        assert_eq!(encoded(&Lines, b"carriage\rreturn").unwrap(), b"carriage\rreturn\n");
+    }
+
+    #[test]
+    fn frames_split_across_reads() {
+        assert_eq!(received(Lines, &[b"hel", b"lo\r", b"\nworld\n"]).unwrap(), [b"hello".to_vec(), b"world".to_vec()]);
+        assert_eq!(received(Varint, &[&[0xac], &[0x02], &[9; 100], &[9; 200]]).unwrap(), [vec![9; 300]]);
+    }
+
+    #[test]
+    fn several_frames_in_one_read() {
+        assert_eq!(received(Lines, &[b"a\nbb\r\n\nccc\n"]).unwrap(), [b"a".to_vec(), b"bb".to_vec(), b"".to_vec(), b"ccc".to_vec()]);
+        assert_eq!(received(Varint, &[&[1, b'x', 0, 2, b'y', b'z', 3, b'p']]).unwrap(), [b"x".to_vec(), b"".to_vec(), b"yz".to_vec()]);
+        // the last one never completes, at the end of the stream it is dropped
+        assert_eq!(received(Lines, &[b"done\npartial"]).unwrap(), [b"done".to_vec()]);
+    }
+
+    #[test]
+    fn incomplete_frames_are_capped() {
+        let at_cap = vec![b'a'; BUFFER_SIZE + MAX_OVERHEAD];
+        assert!(received(Lines, &[&at_cap]).unwrap().is_empty());
+        let over = vec![b'a'; BUFFER_SIZE + MAX_OVERHEAD + 1];
+        assert_eq!(received(Lines, &[&over[..BUFFER_SIZE], &over[BUFFER_SIZE..], b"\n"]).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        // a length that would take the frame beyond the cap fails as soon as that much arrived
+        let mut huge = vec![0xff, 0xff, 0xff, 0x7f];
+        huge.extend(vec![0; BUFFER_SIZE + MAX_OVERHEAD]);
+        assert_eq!(received(Varint, &[&huge]).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(received(Varint, &[&[0x80; 5]]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn writes_are_framed_by_the_codec() {
+        let inner = Scripted::default();
+        let connection = CodecConnection::new(Box::new(inner.clone()), Arc::new(Lines));
+        let mut frames = Vec::new();
+        for payload in [&b"one"[..], b"not\nsent", b"two"] { LengthPrefixed.encode(payload, &mut frames); }
+
+        // a frame split across writes is sent once it is complete
+        assert_eq!(connection.write(&frames[..5]).unwrap(), 5);
+        assert!(inner.written.lock().unwrap().is_empty());
+        assert_eq!(connection.write(&frames[5..]).unwrap(), frames.len() - 5);
+        assert_eq!(*inner.written.lock().unwrap(), b"one\ntwo\n");
+    }
+}
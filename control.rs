@@ -0,0 +1,53 @@
+//! Server-originated control packets.
+//!
+//! Control packets use the normal framing (`[size: i32 LE][payload]`) with a payload of
+//! `[MAGIC: 4 bytes][kind: u8][body]`, so clients that know the magic can tell them apart
+//! from relayed game data. They are only sent when `control_packets` is enabled, except for
+//! `Challenge`, which clients can't authenticate without, and `Resume` and `Resumed`.
+
+pub const MAGIC: [u8; 4] = *b"ECSV";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Kind {
+    /// Body: the byte rate limit as i32 LE. Sent once when a client starts being throttled.
+    Throttled = 1,
+    /// Body: the reason as UTF-8 (may be empty). Sent right before an operator disconnects the client.
+    Kicked = 2,
+    /// Body: the sender ID as i32 LE (always `registry::SERVER_ID`) followed by the message as UTF-8.
+    /// Sent to everyone when an operator makes an announcement.
+    Announcement = 3,
+    /// Body: seconds until the server stops as i32 LE. Sent repeatedly while the server drains.
+    Draining = 4,
+    /// Body: a random nonce. Sent as the first frame of every connection when `auth_challenge` is enabled.
+    Challenge = 5,
+    /// No body. Sent when the server stops reading from a client because a recipient's send queue is
+    /// full (`backpressure` queue policy), once each time.
+    SlowDown = 6,
+    /// Body: a token (`resume::TOKEN` bytes) followed by the client's ID as i32 LE. Sent as the first packet
+    /// after joining when `resume_grace` is set. The client sends it back with the number of the last packet
+    /// it received (u64 LE) instead of the ID as the first packet of a new connection to resume, see `resume`.
+    Resume = 7,
+    /// Body: the resumed ID as i32 LE followed by the number of the first replayed packet as u64 LE.
+    /// Sent when a client resumed, before the packets it missed.
+    Resumed = 8
+}
+
+impl Kind {
+    /// The kind numbered `n`, `None` for kinds this version doesn't know.
+    pub fn parse(n: u8) -> Option<Kind> {
+        [Kind::Throttled, Kind::Kicked, Kind::Announcement, Kind::Draining, Kind::Challenge, Kind::SlowDown, Kind::Resume, Kind::Resumed].into_iter().find(|k| *k as u8 == n)
+    }
+}
+
+/// Builds a complete control frame including the size prefix.
+pub fn frame(kind: Kind, body: &[u8]) -> Vec<u8> {
+    let size = (4 + MAGIC.len() + 1 + body.len()) as i32;
+
+    let mut frame = Vec::with_capacity(size as usize);
+    frame.extend_from_slice(&size.to_le_bytes());
+    frame.extend_from_slice(&MAGIC);
+    frame.push(kind as u8);
+    frame.extend_from_slice(body);
+    frame
+}
@@ -0,0 +1,63 @@
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::Shutdown;
+use std::panic;
+use std::sync::TryLockError;
+use std::thread;
+
+use crate::logging::error;
+use crate::logging::format_time;
+use crate::logging::timestamp;
+use crate::registry::Client;
+use crate::state::SharedState;
+
+/// Replaces the default panic output: logs the panic with a backtrace, appends a dump of all
+/// connections to `dump_path` (if not empty), closes every client socket and stops the server.
+pub fn install_hook(dump_path: String, state: SharedState) {
+    panic::set_hook(Box::new(move |info| {
+        let thread = thread::current();
+        let backtrace = Backtrace::force_capture();
+        error!("Thread '{}' panicked: {}\n{}", thread.name().unwrap_or("<unnamed>"), info, backtrace);
+
+        let mut dump = String::new();
+        let _ = writeln!(dump, "=== {} panic in thread '{}' ===", timestamp(), thread.name().unwrap_or("<unnamed>"));
+        let _ = writeln!(dump, "{}\n\n{}\n", info, backtrace);
+
+        // the panicking thread may be the one holding the lock, so never block on it here
+        match state.connections.try_read() {
+            Ok(connections) => dump_connections(&mut dump, &connections),
+            Err(TryLockError::Poisoned(e)) => dump_connections(&mut dump, &e.into_inner()),
+            Err(TryLockError::WouldBlock) => {
+                let _ = writeln!(dump, "Connections are locked by the panicking thread, no client state available.");
+            }
+        }
+
+        if !dump_path.is_empty() {
+            match OpenOptions::new().create(true).append(true).open(&dump_path) {
+                Ok(mut file) => {
+                    let _ = writeln!(file, "{}", dump);
+                    error!("Diagnostic dump written to {}, shutting down.", dump_path);
+                },
+                Err(e) => error!("Could not write diagnostic dump to {} ({})!", dump_path, e)
+            }
+        }
+
+        state.stop();
+    }));
+}
+
+/// Appends one line per client to `dump` and shuts down its socket.
+fn dump_connections(dump: &mut String, connections: &HashMap<i32, Client>) {
+    let _ = writeln!(dump, "{} client(s) connected:", connections.len());
+
+    for (id, client) in connections.iter() {
+        let _ = writeln!(
+            dump, "{} session={} addr={} connected={} features=[{}] {}",
+            id, client.meta.session, client.meta.addr, format_time(client.meta.connected_at), client.meta.features.join(","), client.traffic.summary()
+        );
+        let _ = client.stream.shutdown(Shutdown::Both);
+    }
+}
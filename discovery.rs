@@ -0,0 +1,175 @@
+//! Finding federation peers on the local network (`federation_discovery`), so servers don't need each
+//! other in `federation_peers`. Every server with a `federation_port` sends a beacon to a UDP
+//! multicast group every `INTERVAL`; the others link to the address it came from. Of two servers that
+//! both send beacons only the one with the lower instance links to the other, and a discovered peer is
+//! given up once its beacons stopped for `EXPIRY`.
+//!
+//! Beacons are `MAGIC`, the instance (u64 LE), the federation port (u16 LE) and their HMAC-SHA256 under
+//! `federation_secret`, so servers of other clusters on the same network are ignored. The link itself
+//! is authenticated again, see `federation`.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::auth;
+use crate::federation::Federation;
+use crate::logging::debug;
+use crate::logging::error;
+use crate::logging::info;
+
+const MAGIC: &[u8; 8] = b"ECHODSC1";
+const BEACON: usize = MAGIC.len() + 8 + 2 + 32;
+/// Time between two beacons.
+const INTERVAL: Duration = Duration::from_secs(2);
+/// A peer that sent no beacon for this long is no longer linked to.
+const EXPIRY: Duration = Duration::from_secs(10);
+
+/// Sends beacons for `port` (0 = only listens) to the multicast `group` and links to the servers heard there until `running` is cleared.
+pub fn start(group: &str, port: i32, federation: Arc<Federation>, running: Arc<AtomicBool>) -> io::Result<()> {
+    let group: SocketAddrV4 = group.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid federation_discovery {}, expected an IPv4 multicast group:port", group)))?;
+    if !group.ip().is_multicast() { return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a multicast address", group.ip()))); }
+
+    let socket = bind(group.port())?;
+    socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_read_timeout(Some(INTERVAL))?;
+
+    let beacon = (port > 0).then(|| beacon(federation.instance(), port as u16, federation.secret()));
+
+    thread::Builder::new().name("discovery".to_string()).spawn(move || {
+        let heard: Arc<Mutex<HashMap<SocketAddr, Instant>>> = Arc::default();
+        let mut sent = None::<Instant>;
+        let mut buffer = [0u8; BEACON + 1];
+
+        while running.load(Ordering::SeqCst) {
+            if let Some(beacon) = &beacon && sent.is_none_or(|sent| sent.elapsed() >= INTERVAL) {
+                if let Err(e) = socket.send_to(beacon, group) { debug!("Could not send a discovery beacon ({}).", e); }
+                sent = Some(Instant::now());
+            }
+
+            let Ok((n, from)) = socket.recv_from(&mut buffer) else { continue; };
+            let Some((instance, port)) = parse(&buffer[..n], federation.secret()) else { continue; };
+            // the lower instance links, so two servers that hear each other get one link, unless this one can't be linked to
+            if instance == federation.instance() || beacon.is_some() && instance < federation.instance() { continue; }
+
+            let peer = SocketAddr::new(from.ip(), port);
+            let Ok(mut peers) = heard.lock() else { break; };
+            if peers.insert(peer, Instant::now()).is_some() { continue; }
+            info!("Discovered federation peer {}.", peer);
+
+            let (heard, running) = (Arc::clone(&heard), Arc::clone(&running));
+            let linked = federation.link(peer.to_string(), move || {
+                let Ok(mut peers) = heard.lock() else { return false; };
+                if peers.get(&peer).is_some_and(|seen| seen.elapsed() < EXPIRY) && running.load(Ordering::SeqCst) { return true; }
+                peers.remove(&peer);
+                info!("Federation peer {} sends no more beacons, not linking to it anymore.", peer);
+                false
+            });
+            if let Err(e) = linked { error!("Could not link to federation peer {} ({})!", peer, e); }
+        }
+    })?;
+    Ok(())
+}
+
+/// The beacon announcing `instance` with its federation `port`, signed with `secret`.
+fn beacon(instance: u64, port: u16, secret: &str) -> Vec<u8> {
+    let mut beacon = [&MAGIC[..], &instance.to_le_bytes(), &port.to_le_bytes()].concat();
+    let mac = auth::hmac_sha256(secret.as_bytes(), &beacon);
+    beacon.extend_from_slice(&mac);
+    beacon
+}
+
+/// The instance and federation port of a beacon, `None` if it isn't one or not signed with `secret`.
+fn parse(beacon: &[u8], secret: &str) -> Option<(u64, u16)> {
+    if beacon.len() != BEACON || &beacon[..MAGIC.len()] != MAGIC { return None; }
+    let (body, mac) = beacon.split_at(BEACON - 32);
+    if !auth::constant_time_eq(mac, &auth::hmac_sha256(secret.as_bytes(), body)) { return None; }
+    let instance = u64::from_le_bytes(body[MAGIC.len()..MAGIC.len() + 8].try_into().ok()?);
+    let port = u16::from_le_bytes(body[MAGIC.len() + 8..].try_into().ok()?);
+    Some((instance, port))
+}
+
+/// A UDP socket on `port` that other servers on the same host can bind as well.
+#[cfg(target_os = "linux")]
+fn bind(port: u16) -> io::Result<UdpSocket> {
+    use std::os::fd::FromRawFd;
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 { return Err(io::Error::last_os_error()); }
+    // owned right away, so the socket is closed on every error below
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    let on: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let result = unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, option, &on as *const libc::c_int as *const libc::c_void, size_of::<libc::c_int>() as libc::socklen_t) };
+        if result != 0 { return Err(io::Error::last_os_error()); }
+    }
+
+    let address = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t, sin_port: port.to_be(),
+        sin_addr: libc::in_addr { s_addr: u32::from(Ipv4Addr::UNSPECIFIED).to_be() }, sin_zero: [0; 8]
+    };
+    let result = unsafe { libc::bind(fd, &address as *const libc::sockaddr_in as *const libc::sockaddr, size_of::<libc::sockaddr_in>() as libc::socklen_t) };
+    if result != 0 { return Err(io::Error::last_os_error()); }
+    Ok(socket)
+}
+
+/// Elsewhere only one server per host can discover its peers.
+#[cfg(not(target_os = "linux"))]
+fn bind(port: u16) -> io::Result<UdpSocket> {
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_beacons_are_read() {
+        let signed = beacon(0x0123_4567_89ab_cdef, 45700, "secret");
+        assert_eq!(signed.len(), BEACON);
+        assert_eq!(parse(&signed, "secret"), Some((0x0123_4567_89ab_cdef, 45700)));
+        assert_eq!(parse(&beacon(u64::MAX, 1, ""), ""), Some((u64::MAX, 1)));
+    }
+
+    #[test]
+    fn a_wrong_secret_is_ignored() {
+        assert_eq!(parse(&beacon(7, 45700, "secret"), "other"), None);
+        assert_eq!(parse(&beacon(7, 45700, "secret"), ""), None);
+    }
+
+    #[test]
+    fn tampered_beacons_are_ignored() {
+        let signed = beacon(7, 45700, "secret");
+        for at in [MAGIC.len(), MAGIC.len() + 8, BEACON - 1] {
+            let mut tampered = signed.clone();
+            tampered[at] ^= 1;
+            assert_eq!(parse(&tampered, "secret"), None, "byte {}", at);
+        }
+    }
+
+    #[test]
+    fn beacons_of_another_length_or_magic_are_ignored() {
+        let signed = beacon(7, 45700, "secret");
+        assert_eq!(parse(&signed[..BEACON - 1], "secret"), None);
+        assert_eq!(parse(&[&signed[..], &[0]].concat(), "secret"), None);
+        assert_eq!(parse(&[], "secret"), None);
+
+        // signed like a beacon, but of another version
+        let mut body = [&b"ECHODSC0"[..], &7u64.to_le_bytes(), &45700u16.to_le_bytes()].concat();
+        let mac = auth::hmac_sha256(b"secret", &body);
+        body.extend_from_slice(&mac);
+        assert_eq!(parse(&body, "secret"), None);
+    }
+}
@@ -0,0 +1,78 @@
+//! Graceful drain: refuse new connections, count down with `Draining` control packets and stop
+//! the server once everyone left or the deadline passed.
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::control;
+use crate::logging::info;
+use crate::state::SharedState;
+
+/// Countdown packets go out every this many seconds, and every second at the very end.
+const NOTIFY_INTERVAL: u64 = 10;
+const NOTIFY_FINAL: u64 = 5;
+
+/// Starts draining with a deadline of `seconds`, returns false if the server is already draining.
+pub fn start(state: &SharedState, seconds: i32, source: &str) -> bool {
+    if state.draining.swap(true, Ordering::SeqCst) { return false; }
+    state.paused.store(true, Ordering::SeqCst);
+
+    let seconds = seconds.max(0) as u64;
+    info!("Draining over the {}, stopping in at most {}s.", source, seconds);
+
+    let state = Arc::clone(state);
+    thread::spawn(move || {
+        let deadline = Instant::now() + Duration::from_secs(seconds);
+        let mut last_notified = None;
+
+        loop {
+            let clients = state.connections.read().map(|c| c.len()).unwrap_or(0);
+            if clients == 0 {
+                info!("Drain complete, all clients left.");
+                break;
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                info!("Drain deadline passed, closing the remaining {} connection(s).", clients);
+                break;
+            }
+
+            let left = remaining.as_secs_f64().ceil() as u64;
+            let due = left <= NOTIFY_FINAL || last_notified.is_none_or(|n: u64| n - left >= NOTIFY_INTERVAL);
+            if state.control_packets && due && last_notified != Some(left) {
+                state.broadcast(&control::frame(control::Kind::Draining, &(left as i32).to_le_bytes()));
+                last_notified = Some(left);
+            }
+
+            thread::sleep(Duration::from_millis(200).min(remaining));
+        }
+
+        state.stop();
+    });
+
+    true
+}
+
+#[cfg(unix)]
+static DRAIN_REQUESTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_sigusr1(_: libc::c_int) {
+    DRAIN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGUSR1 start a drain with a deadline of `seconds`.
+#[cfg(unix)]
+pub fn watch_signal(state: SharedState, seconds: i32) {
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe { libc::signal(libc::SIGUSR1, on_sigusr1 as *const () as libc::sighandler_t); }
+
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(250));
+        if DRAIN_REQUESTED.swap(false, Ordering::SeqCst) { start(&state, seconds, "SIGUSR1"); }
+    });
+}
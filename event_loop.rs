@@ -0,0 +1,591 @@
+//! The `poll` and `uring` I/O modes: a fixed pool of event loop threads (`io_threads`) serves every
+//! connection, in place of a thread per client. New connections go to the loops in turn and stay on theirs.
+//!
+//! In poll mode sockets are non-blocking, the frames queued in a client's outbox are written together
+//! (up to `COALESCE_BYTES`) by the event loop as far as the socket takes them and the rest once poll(2)
+//! reports it writable again. In uring mode (Linux only) every connection has a receive and at most one
+//! send operation in flight on an io_uring instead, all of them submitted and completed with a single
+//! system call per loop iteration, see `run_uring`.
+//!
+//! Slow to receive is not detected (`slow_client_ms`) as writes never wait. The `delay` rate policy
+//! drops packets instead, holding them would stall every client. TLS connections still get a thread
+//! each for the encryption, see `tls::accept`.
+
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+#[cfg(target_os = "linux")]
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::BUFFER_SIZE;
+use crate::COALESCE_BYTES;
+use crate::Connection;
+use crate::Handshake;
+use crate::ServerConfig;
+use crate::affinity;
+use crate::authenticate;
+use crate::frame_size;
+use crate::held;
+use crate::join;
+use crate::leave;
+#[cfg(target_os = "linux")]
+use crate::logging::error;
+use crate::logging::warning;
+use crate::metrics::DisconnectReason;
+use crate::needs_auth;
+use crate::otlp;
+use crate::read_error;
+use crate::relay;
+use crate::registry::Outbox;
+use crate::registry::Outgoing;
+use crate::sent;
+use crate::start_handshake;
+use crate::state::IpSlot;
+use crate::state::SharedState;
+use crate::state::State;
+use crate::trace;
+#[cfg(target_os = "linux")]
+use crate::uring::Ring;
+#[cfg(target_os = "linux")]
+use crate::uring::Sqe;
+#[cfg(target_os = "linux")]
+use crate::uring::Timespec;
+
+/// A connection accepted by the accept loop, on its way to the event loop.
+struct Incoming {
+    stream: TcpStream,
+    addr: SocketAddr,
+    ip_slot: IpSlot,
+    span: Option<otlp::Span>
+}
+
+/// One event loop thread of the pool.
+struct Worker {
+    sender: mpsc::Sender<Incoming>,
+    /// Written to after every new connection and queued frame, so the event loop doesn't wait for its poll timeout.
+    wake: Arc<UnixStream>
+}
+
+/// Hands new connections to the event loop threads.
+#[derive(Clone)]
+pub struct EventLoop {
+    workers: Arc<[Worker]>,
+    /// The worker that gets the next connection.
+    next: Arc<AtomicUsize>
+}
+
+impl EventLoop {
+    /// Serves `stream` from now on, the connection counts towards `max_per_ip` until it closes.
+    pub fn add(&self, stream: TcpStream, addr: SocketAddr, ip_slot: IpSlot, span: Option<otlp::Span>) {
+        let worker = &self.workers[self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len()];
+        if worker.sender.send(Incoming { stream, addr, ip_slot, span }).is_ok() {
+            let _ = (&*worker.wake).write(&[1]);
+        }
+    }
+}
+
+/// Starts `threads` event loop threads (at least one), they stop once the server is no longer running.
+/// With `uring` they use io_uring, or poll if it can't be set up (e.g. an older kernel or a seccomp
+/// profile that blocks it). Each thread is pinned to one of `cpus` in turn, if there are any.
+pub fn spawn(config: ServerConfig, state: SharedState, threads: usize, uring: bool, cpus: &[usize]) -> std::io::Result<EventLoop> {
+    let mut workers = Vec::new();
+    #[cfg(target_os = "linux")]
+    let mut uring = uring;
+    #[cfg(not(target_os = "linux"))]
+    let _ = uring;
+
+    for n in 0..threads.max(1) {
+        let (wake, woken) = UnixStream::pair()?;
+        wake.set_nonblocking(true)?;
+        woken.set_nonblocking(true)?;
+
+        let wake = Arc::new(wake);
+        let (sender, receiver) = mpsc::channel();
+        workers.push(Worker { sender, wake: Arc::clone(&wake) });
+
+        let (config, state) = (config.clone(), Arc::clone(&state));
+        let cpu = (!cpus.is_empty()).then(|| cpus[n % cpus.len()]);
+        #[cfg(target_os = "linux")]
+        if uring {
+            match Ring::new(RING_ENTRIES) {
+                Ok(ring) => {
+                    woken.set_nonblocking(false)?;
+                    thread::Builder::new().name(format!("event-loop-{}", n)).spawn(move || {
+                        pin(cpu);
+                        run_uring(ring, receiver, wake, woken, config, state)
+                    })?;
+                    continue;
+                },
+                Err(e) => {
+                    error!("Could not set up io_uring ({}), using poll!", e);
+                    uring = false;
+                }
+            }
+        }
+        thread::Builder::new().name(format!("event-loop-{}", n)).spawn(move || {
+            pin(cpu);
+            run(receiver, wake, woken, config, state)
+        })?;
+    }
+    Ok(EventLoop { workers: workers.into(), next: Arc::new(AtomicUsize::new(0)) })
+}
+
+/// Pins the calling event loop thread to `cpu`, see `worker_cpus`.
+fn pin(cpu: Option<usize>) {
+    if let Some(cpu) = cpu && let Err(e) = affinity::pin(&[cpu]) {
+        warning!("Could not pin {} to CPU {} ({})!", thread::current().name().unwrap_or("event loop"), cpu, e);
+    }
+}
+
+enum Stage {
+    /// Waiting for the first frame, only when authenticating.
+    Handshake(Handshake),
+    Joined(Connection),
+    /// Already closed and logged, about to be removed.
+    Closed
+}
+
+/// What reading from an entry led to, see `Entry::receive`.
+enum Outcome {
+    Open,
+    /// The peer closed the connection or reading failed, see `Entry::close`.
+    Failed(Option<std::io::Error>),
+    /// The connection was refused or the client left, nothing left to do.
+    Ended
+}
+
+struct Entry {
+    stream: TcpStream,
+    addr: SocketAddr,
+    _ip_slot: IpSlot,
+    /// Bytes received that don't make up a complete frame yet.
+    buffer: Vec<u8>,
+    unsent: Unsent,
+    stage: Stage
+}
+
+impl Entry {
+    fn new(incoming: Incoming, wake: &Arc<UnixStream>, config: &ServerConfig, state: &State) -> Option<Entry> {
+        let Incoming { stream, addr, ip_slot, span } = incoming;
+        let _ = stream.set_nonblocking(true);
+
+        let handshake = start_handshake(&stream, config, state, span)?;
+        let stage = match needs_auth(config) {
+            true => Stage::Handshake(handshake),
+            false => Stage::Joined(join(&stream, addr, config, state, handshake, None, outbox(wake, state))?)
+        };
+        Some(Entry { stream, addr, _ip_slot: ip_slot, buffer: Vec::new(), unsent: Unsent::default(), stage })
+    }
+
+    /// Whether reading has to pause, see `held`.
+    fn held(&mut self, config: &ServerConfig, state: &State) -> bool {
+        match &mut self.stage {
+            Stage::Joined(conn) => held(conn, config, state),
+            _ => false
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        match &self.stage {
+            Stage::Handshake(handshake) => handshake.deadline,
+            Stage::Joined(conn) => conn.deadline,
+            Stage::Closed => None
+        }
+    }
+
+    /// Writes queued frames until the socket takes no more, returns whether some are left.
+    fn flush(&mut self, state: &State) -> Result<bool, std::io::Error> {
+        let Stage::Joined(conn) = &self.stage else { return Ok(false); };
+        write_some(&self.stream, &mut self.unsent, conn, state)
+    }
+
+    /// How long the socket hasn't taken any of the queued frames.
+    fn stalled_for(&self) -> Option<Duration> {
+        self.unsent.stalled.map(|t| t.elapsed())
+    }
+
+    /// Lets `conn` leave, writing what is still queued for it (e.g. the reason it was kicked) if the socket takes it right away.
+    fn leave(&mut self, conn: Connection, state: &State, reason: DisconnectReason) {
+        let _ = write_some(&self.stream, &mut self.unsent, &conn, state);
+        leave(conn, state, reason);
+    }
+
+    /// Reads what arrived and handles every complete frame.
+    fn read(&mut self, wake: &Arc<UnixStream>, config: &ServerConfig, state: &State) -> Outcome {
+        let mut chunk = [0u8; BUFFER_SIZE];
+        match (&self.stream).read(&mut chunk) {
+            Ok(0) => Outcome::Failed(None),
+            Ok(n) => self.receive(&chunk[..n], wake, config, state),
+            Err(e) if e.kind() == ErrorKind::Interrupted || e.kind() == ErrorKind::WouldBlock => Outcome::Open,
+            Err(e) => Outcome::Failed(Some(e))
+        }
+    }
+
+    /// Handles every complete frame once `data` arrived.
+    fn receive(&mut self, data: &[u8], wake: &Arc<UnixStream>, config: &ServerConfig, state: &State) -> Outcome {
+        self.buffer.extend_from_slice(data);
+        while self.buffer.len() >= 4 {
+            let size_bytes = [self.buffer[0], self.buffer[1], self.buffer[2], self.buffer[3]];
+            let content_size = match &self.stage {
+                Stage::Handshake(_) => match i32::from_le_bytes(size_bytes) {
+                    size if size < 4 || size as usize > BUFFER_SIZE => return Outcome::Failed(None),
+                    size => (size - 4) as usize
+                },
+                Stage::Joined(conn) => match frame_size(conn, state, size_bytes) {
+                    Ok(n) => n,
+                    Err(reason) => {
+                        if let Stage::Joined(conn) = std::mem::replace(&mut self.stage, Stage::Closed) { self.leave(conn, state, reason); }
+                        return Outcome::Ended;
+                    }
+                },
+                Stage::Closed => return Outcome::Ended
+            };
+            if self.buffer.len() < 4 + content_size { break; }
+            let content = &self.buffer[4..4 + content_size];
+
+            self.stage = match std::mem::replace(&mut self.stage, Stage::Closed) {
+                Stage::Handshake(handshake) => {
+                    trace::frame("Received", handshake.id, &handshake.session, &[&size_bytes, content]);
+                    let Some((handshake, identity)) = authenticate(&self.stream, &self.addr, config, state, handshake, Ok(content.to_vec())) else { return Outcome::Ended; };
+                    match join(&self.stream, self.addr, config, state, handshake, identity, outbox(wake, state)) {
+                        Some(conn) => Stage::Joined(conn),
+                        None => return Outcome::Ended
+                    }
+                },
+                Stage::Joined(mut conn) => {
+                    conn.deadline = None;
+                    if let Some(reason) = relay(&mut conn, config, state, size_bytes, content, otlp::sample(), false) {
+                        self.leave(conn, state, reason);
+                        return Outcome::Ended;
+                    }
+                    Stage::Joined(conn)
+                },
+                Stage::Closed => return Outcome::Ended
+            };
+            self.buffer.drain(..4 + content_size);
+        }
+        Outcome::Open
+    }
+
+    /// Ends the connection after reading failed with `error` (`None` when the peer or the server closed it).
+    fn close(mut self, config: &ServerConfig, state: &State, error: Option<std::io::Error>) {
+        match std::mem::replace(&mut self.stage, Stage::Closed) {
+            Stage::Handshake(handshake) => { let _ = authenticate(&self.stream, &self.addr, config, state, handshake, Err(error)); },
+            Stage::Joined(conn) => {
+                let reason = read_error(&conn, &state.running, error);
+                self.leave(conn, state, reason);
+            },
+            Stage::Closed => { }
+        }
+    }
+}
+
+/// Queued frames copied together for one write, until the socket took all of them.
+#[derive(Default)]
+struct Unsent {
+    bytes: Vec<u8>,
+    written: usize,
+    frames: Vec<Outgoing>,
+    /// Since when the socket took none of `bytes`.
+    stalled: Option<Instant>,
+    /// A uring send operation is writing `bytes`, nothing else may write to the socket until it completed.
+    in_flight: bool
+}
+
+/// Takes the next frames queued for `conn` once all of `unsent` was written, returns whether there is anything to write.
+fn refill(unsent: &mut Unsent, conn: &Connection, state: &State) -> bool {
+    if unsent.written == unsent.bytes.len() {
+        for outgoing in unsent.frames.drain(..) { sent(&state.metrics, conn.id, &conn.session, &conn.traffic, &outgoing); }
+        unsent.bytes.clear();
+        unsent.written = 0;
+
+        while unsent.bytes.len() < COALESCE_BYTES && let Some(outgoing) = conn.outbox.try_next() {
+            unsent.bytes.extend_from_slice(&outgoing.frame);
+            unsent.frames.push(outgoing);
+        }
+        if unsent.bytes.is_empty() { unsent.stalled = None; }
+    }
+    unsent.written < unsent.bytes.len()
+}
+
+/// Writes the frames queued for `conn` until the socket takes no more, returns whether some are left.
+fn write_some(stream: &TcpStream, unsent: &mut Unsent, conn: &Connection, state: &State) -> Result<bool, std::io::Error> {
+    if unsent.in_flight { return Ok(true); }
+    loop {
+        if !refill(unsent, conn, state) { return Ok(false); }
+
+        match send_now(stream, &unsent.bytes[unsent.written..]) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                unsent.written += n;
+                unsent.stalled = None;
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {
+                unsent.stalled.get_or_insert_with(Instant::now);
+                return Ok(true);
+            },
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// Writes as much of `bytes` as the socket takes right away, the sockets of the uring mode are blocking.
+#[cfg(target_os = "linux")]
+fn send_now(stream: &TcpStream, bytes: &[u8]) -> Result<usize, std::io::Error> {
+    // SAFETY: `bytes` is valid for `bytes.len()` bytes.
+    let n = unsafe { libc::send(stream.as_raw_fd(), bytes.as_ptr().cast(), bytes.len(), libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL) };
+    if n < 0 { return Err(std::io::Error::last_os_error()); }
+    Ok(n as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_now(mut stream: &TcpStream, bytes: &[u8]) -> Result<usize, std::io::Error> {
+    stream.write(bytes)
+}
+
+/// Outbox of a client served by the event loop, waking it up whenever frames get queued.
+fn outbox(wake: &Arc<UnixStream>, state: &State) -> Arc<Outbox> {
+    let wake = Arc::clone(wake);
+    state.outbox(Some(Box::new(move || { let _ = (&*wake).write(&[1]); })))
+}
+
+fn run(receiver: mpsc::Receiver<Incoming>, wake: Arc<UnixStream>, woken: UnixStream, config: ServerConfig, state: SharedState) {
+    let mut entries: Vec<Entry> = Vec::new();
+    let write_timeout = (config.write_timeout > 0).then(|| Duration::from_millis(config.write_timeout as u64));
+
+    loop {
+        while let Ok(incoming) = receiver.try_recv() {
+            if let Some(entry) = Entry::new(incoming, &wake, &config, &state) { entries.push(entry); }
+        }
+
+        if !state.running.load(Ordering::SeqCst) {
+            for entry in entries.drain(..) { entry.close(&config, &state, None); }
+            return;
+        }
+
+        let mut unsent = Vec::with_capacity(entries.len());
+        entries = entries.into_iter().filter_map(|mut entry| match entry.flush(&state) {
+            Ok(_) if entry.stalled_for().zip(write_timeout).is_some_and(|(stalled, timeout)| stalled >= timeout) => {
+                if let Stage::Joined(conn) = &entry.stage { conn.outbox.fail(DisconnectReason::WriteTimeout); }
+                entry.close(&config, &state, None);
+                None
+            },
+            Ok(left) => {
+                unsent.push(left);
+                Some(entry)
+            },
+            Err(e) => {
+                entry.close(&config, &state, Some(e));
+                None
+            }
+        }).collect();
+
+        let readable = entries.iter_mut().map(|e| (e.stream.as_raw_fd(), !e.held(&config, &state)));
+        let mut fds: Vec<libc::pollfd> = std::iter::once((woken.as_raw_fd(), true)).chain(readable).zip(std::iter::once(false).chain(unsent))
+            .map(|((fd, read), unsent)| libc::pollfd { fd, events: if read { libc::POLLIN } else { 0 } | if unsent { libc::POLLOUT } else { 0 }, revents: 0 })
+            .collect();
+        // SAFETY: `fds` is a valid array of `fds.len()` pollfd structs.
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 100) };
+        if result < 0 { continue; }
+
+        if fds[0].revents != 0 {
+            let mut drain = [0u8; 64];
+            while (&woken).read(&mut drain).is_ok_and(|n| n > 0) { }
+        }
+
+        let now = Instant::now();
+        entries = entries.into_iter().zip(&fds[1..]).filter_map(|(mut entry, fd)| {
+            if fd.revents & !libc::POLLOUT != 0 {
+                match entry.read(&wake, &config, &state) {
+                    Outcome::Open => { },
+                    Outcome::Failed(e) => {
+                        entry.close(&config, &state, e);
+                        return None;
+                    },
+                    Outcome::Ended => return None
+                }
+            }
+            if entry.deadline().is_some_and(|d| now >= d) {
+                entry.close(&config, &state, Some(ErrorKind::TimedOut.into()));
+                return None;
+            }
+            Some(entry)
+        }).collect();
+    }
+}
+
+/// Submission queue size of each event loop's ring.
+#[cfg(target_os = "linux")]
+const RING_ENTRIES: u32 = 256;
+
+/// `user_data` of the receive on the wake socket, connections use `index << 1` for their receive and
+/// `index << 1 | 1` for their send.
+#[cfg(target_os = "linux")]
+const WAKE_TAG: u64 = u64::MAX;
+/// `user_data` of the timeout that makes the loop check deadlines at least every 100 ms.
+#[cfg(target_os = "linux")]
+const TICK_TAG: u64 = u64::MAX - 1;
+
+/// A connection served by `run_uring`. It is kept until its last operation completed, the kernel may
+/// still be writing into its buffers.
+#[cfg(target_os = "linux")]
+struct Slot {
+    /// `None` once the connection was closed.
+    entry: Option<Entry>,
+    /// What the receive operation reads into.
+    chunk: Box<[u8]>,
+    receiving: bool,
+    /// What a send operation was still writing when the connection was closed.
+    orphan: Option<Unsent>
+}
+
+#[cfg(target_os = "linux")]
+impl Slot {
+    fn busy(&self) -> bool {
+        self.receiving || self.orphan.is_some() || self.entry.as_ref().is_some_and(|entry| entry.unsent.in_flight)
+    }
+
+    /// Closes the connection, then shuts its socket down so the operations still in flight on it complete.
+    fn close(&mut self, config: &ServerConfig, state: &State, error: Option<std::io::Error>) {
+        let Some(mut entry) = self.entry.take() else { return; };
+        if entry.unsent.in_flight {
+            // nothing may be written after the bytes the kernel is still sending
+            let unsent = Unsent { in_flight: true, ..Unsent::default() };
+            self.orphan = Some(std::mem::replace(&mut entry.unsent, unsent));
+        }
+        let socket = entry.stream.try_clone();
+        entry.close(config, state, error);
+        if let Ok(socket) = socket { let _ = socket.shutdown(Shutdown::Both); }
+    }
+
+    fn received(&mut self, result: i32, wake: &Arc<UnixStream>, config: &ServerConfig, state: &State) {
+        self.receiving = false;
+        let Some(entry) = &mut self.entry else { return; };
+        let outcome = match result {
+            0 => Outcome::Failed(None),
+            n if n > 0 => entry.receive(&self.chunk[..n as usize], wake, config, state),
+            e if -e == libc::EINTR || -e == libc::EAGAIN => Outcome::Open,
+            e => Outcome::Failed(Some(std::io::Error::from_raw_os_error(-e)))
+        };
+        match outcome {
+            Outcome::Open => { },
+            Outcome::Failed(e) => self.close(config, state, e),
+            // already closed, this only shuts the socket down
+            Outcome::Ended => self.close(config, state, None)
+        }
+    }
+
+    fn sent(&mut self, result: i32, config: &ServerConfig, state: &State) {
+        if self.orphan.take().is_some() { return; }
+        let Some(entry) = &mut self.entry else { return; };
+        entry.unsent.in_flight = false;
+        match result {
+            0 => self.close(config, state, Some(ErrorKind::WriteZero.into())),
+            n if n > 0 => {
+                entry.unsent.written += n as usize;
+                entry.unsent.stalled = None;
+            },
+            e if -e == libc::EINTR || -e == libc::EAGAIN => { },
+            e => self.close(config, state, Some(std::io::Error::from_raw_os_error(-e)))
+        }
+    }
+}
+
+/// The event loop of the uring mode: every joined connection always has a receive in flight and a send
+/// whenever frames are queued for it, submitting them and taking their completions takes one system
+/// call per iteration however many clients are served.
+#[cfg(target_os = "linux")]
+fn run_uring(mut ring: Ring, receiver: mpsc::Receiver<Incoming>, wake: Arc<UnixStream>, woken: UnixStream, config: ServerConfig, state: SharedState) {
+    let mut slots: Vec<Option<Slot>> = Vec::new();
+    let write_timeout = (config.write_timeout > 0).then(|| Duration::from_millis(config.write_timeout as u64));
+    let tick = Timespec { tv_sec: 0, tv_nsec: 100_000_000 };
+    let mut drain = [0u8; 64];
+    let (mut waiting_wake, mut waiting_tick) = (false, false);
+
+    loop {
+        while let Ok(incoming) = receiver.try_recv() {
+            let Some(entry) = Entry::new(incoming, &wake, &config, &state) else { continue; };
+            let _ = entry.stream.set_nonblocking(false);
+            let slot = Slot { entry: Some(entry), chunk: vec![0u8; BUFFER_SIZE].into(), receiving: false, orphan: None };
+            match slots.iter().position(Option::is_none) {
+                Some(index) => slots[index] = Some(slot),
+                None => slots.push(Some(slot))
+            }
+        }
+
+        let running = state.running.load(Ordering::SeqCst);
+        if !running {
+            // stop once nothing is in flight anymore, the kernel may still use the buffers until then
+            let _ = woken.shutdown(Shutdown::Both);
+            if !waiting_wake && !waiting_tick && slots.iter().flatten().all(|slot| !slot.busy()) { return; }
+        }
+
+        let now = Instant::now();
+        for (index, cell) in slots.iter_mut().enumerate() {
+            let Some(slot) = cell else { continue; };
+            if !running { slot.close(&config, &state, None); }
+            if let Some(entry) = &mut slot.entry {
+                if entry.deadline().is_some_and(|d| now >= d) {
+                    slot.close(&config, &state, Some(ErrorKind::TimedOut.into()));
+                } else if entry.stalled_for().zip(write_timeout).is_some_and(|(stalled, timeout)| stalled >= timeout) {
+                    if let Stage::Joined(conn) = &entry.stage { conn.outbox.fail(DisconnectReason::WriteTimeout); }
+                    slot.close(&config, &state, None);
+                }
+            }
+
+            if let Some(entry) = &mut slot.entry {
+                if let Stage::Joined(conn) = &entry.stage && !entry.unsent.in_flight && refill(&mut entry.unsent, conn, &state) {
+                    let unsent = &mut entry.unsent;
+                    let bytes = &unsent.bytes[unsent.written..];
+                    // SAFETY: `bytes` isn't touched while `in_flight` is set, and kept in `orphan` if the connection closes before the send completed.
+                    if unsafe { ring.push(Sqe::send(entry.stream.as_raw_fd(), bytes.as_ptr(), bytes.len(), (index as u64) << 1 | 1)) }.is_ok() {
+                        unsent.in_flight = true;
+                        unsent.stalled.get_or_insert(now);
+                    }
+                }
+                // SAFETY: `chunk` lives as long as the slot, which is kept until the receive completed.
+                if !slot.receiving && !entry.held(&config, &state) && unsafe { ring.push(Sqe::recv(entry.stream.as_raw_fd(), slot.chunk.as_mut_ptr(), slot.chunk.len(), (index as u64) << 1)) }.is_ok() {
+                    slot.receiving = true;
+                }
+            }
+            if slot.entry.is_none() && !slot.busy() { *cell = None; }
+        }
+
+        // SAFETY: `drain` and `tick` outlive the loop, which only returns once neither operation is in flight.
+        if !waiting_wake && running && unsafe { ring.push(Sqe::recv(woken.as_raw_fd(), drain.as_mut_ptr(), drain.len(), WAKE_TAG)) }.is_ok() {
+            waiting_wake = true;
+        }
+        if !waiting_tick && unsafe { ring.push(Sqe::timeout(&tick, TICK_TAG)) }.is_ok() {
+            waiting_tick = true;
+        }
+
+        if let Err(e) = ring.submit(1) {
+            error!("Could not submit to io_uring ({}), retrying!", e);
+            thread::sleep(Duration::from_millis(100));
+        }
+        while let Some(completion) = ring.next() {
+            match completion.user_data {
+                WAKE_TAG => waiting_wake = false,
+                TICK_TAG => waiting_tick = false,
+                tag => {
+                    let Some(Some(slot)) = slots.get_mut((tag >> 1) as usize) else { continue; };
+                    match tag & 1 {
+                        0 => slot.received(completion.result, &wake, &config, &state),
+                        _ => slot.sent(completion.result, &config, &state)
+                    }
+                }
+            }
+        }
+    }
+}
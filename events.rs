@@ -0,0 +1,103 @@
+//! Callbacks for embedders (`ServerBuilder::event_handler`), so custom logic such as game rules runs
+//! inside the relay. They are called on the thread serving the client (its own, or an event loop
+//! thread serving many), so they should return quickly.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::ServerConfig;
+use crate::clock::Clock;
+use crate::codec::Codec;
+use crate::ids::IdAllocator;
+use crate::middleware::Flow;
+use crate::middleware::Middleware;
+use crate::ratelimit::RateLimiter;
+use crate::storage::Storage;
+use crate::transport::Transport;
+
+/// A connected client as the callbacks see it.
+pub struct ClientInfo {
+    pub id: i32,
+    /// Random id of the connection, as in the logs and the audit log.
+    pub session: String,
+    pub addr: SocketAddr,
+    /// Name of the user the client authenticated as with a token, `None` without one.
+    pub user: Option<String>
+}
+
+/// Called as clients come and go and send packets, every method does nothing by default.
+pub trait EventHandler: Send + Sync {
+    /// A client joined, before any of its packets are relayed.
+    fn on_connect(&self, _client: &ClientInfo) { }
+
+    /// A client left, `reason` is the label of the `disconnects` metric (e.g. `closed`, `kicked`).
+    fn on_disconnect(&self, _client: &ClientInfo, _reason: &str) { }
+
+    /// A packet passed the rate limit and the filter rules and is about to be relayed, return false to drop it.
+    fn on_packet(&self, _client: &ClientInfo, _payload: &[u8]) -> bool {
+        true
+    }
+}
+
+/// What a packet filter (`ServerBuilder::packet_filter`) decides for a packet.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Verdict {
+    Allow,
+    /// Not relayed.
+    Drop,
+    /// Not relayed and the sender is disconnected.
+    Disconnect
+}
+
+/// A packet filter as a step of the middleware chain, named `packet_filter`.
+pub struct PacketFilter<F>(pub F);
+
+impl<F: Fn(&ClientInfo, &[u8]) -> Verdict + Send + Sync> Middleware for PacketFilter<F> {
+    fn process(&self, client: &ClientInfo, payload: &[u8]) -> Flow {
+        match (self.0)(client, payload) {
+            Verdict::Allow => Flow::Pass,
+            Verdict::Drop => Flow::Consume,
+            Verdict::Disconnect => Flow::Reject("blocked by filter".to_string())
+        }
+    }
+}
+
+/// A transport with the codec of its clients, `None` for length-prefixed.
+pub type Listener = (Arc<dyn Transport>, Option<Arc<dyn Codec>>);
+
+/// The embedder's callbacks, in the order they were added.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    pub handlers: Vec<Arc<dyn EventHandler>>,
+    /// Named steps for the middleware chain, see `middleware::chain`.
+    pub middleware: Vec<(String, Arc<dyn Middleware>)>,
+    /// Replaces the `id_allocator` setting.
+    pub id_allocator: Option<Arc<dyn IdAllocator>>,
+    /// Replaces the `rate_limiter` setting.
+    pub rate_limiter: Option<Arc<dyn RateLimiter>>,
+    /// Replaces the system clock for rate limits.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Replaces the `storage` setting.
+    pub storage: Option<Arc<dyn Storage>>,
+    /// Replaces the `codec` setting.
+    pub codec: Option<Arc<dyn Codec>>,
+    /// Accepted from besides the TCP listener.
+    pub transports: Vec<Listener>,
+    /// Where `reload` reads the allow and deny lists from, they stay as configured without one.
+    pub config_source: Option<Arc<dyn Fn() -> ServerConfig + Send + Sync>>
+}
+
+impl Hooks {
+    pub fn connected(&self, client: &ClientInfo) {
+        for handler in &self.handlers { handler.on_connect(client); }
+    }
+
+    pub fn disconnected(&self, client: &ClientInfo, reason: &str) {
+        for handler in &self.handlers { handler.on_disconnect(client, reason); }
+    }
+
+    /// Whether every handler lets the packet through, the first one refusing it ends the checks.
+    pub fn packet(&self, client: &ClientInfo, payload: &[u8]) -> bool {
+        self.handlers.iter().all(|handler| handler.on_packet(client, payload))
+    }
+}
@@ -0,0 +1,78 @@
+//! Spreading the recipients of a broadcast over a small pool of threads (`fanout_threads`), so queueing a
+//! packet for hundreds of clients doesn't serialize on the router thread.
+//!
+//! Jobs only hold the recipients' outboxes, never the registry lock: a worker waiting for a read lock
+//! behind a waiting writer while the router holds one would deadlock.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::registry::Outbox;
+
+/// A share of one broadcast, see `Fanout::send`.
+struct Job {
+    frame: Arc<[u8]>,
+    recipients: Vec<(i32, Arc<Outbox>)>,
+    /// Gets the IDs of the recipients the frame couldn't be queued for.
+    done: mpsc::Sender<Vec<i32>>
+}
+
+pub struct Fanout {
+    workers: Vec<mpsc::Sender<Job>>,
+    /// The worker that gets the next job.
+    next: AtomicUsize,
+    /// Broadcasts to fewer clients are queued by the router thread alone.
+    pub min_recipients: usize
+}
+
+impl Fanout {
+    /// Starts `threads` worker threads, they end with the process.
+    pub fn spawn(threads: usize, min_recipients: usize) -> std::io::Result<Fanout> {
+        let mut workers = Vec::new();
+        for n in 0..threads {
+            let (sender, receiver) = mpsc::channel::<Job>();
+            thread::Builder::new().name(format!("fanout-{}", n)).spawn(move || {
+                for job in receiver {
+                    let _ = job.done.send(queue(&job.frame, job.recipients));
+                }
+            })?;
+            workers.push(sender);
+        }
+        Ok(Fanout { workers, next: AtomicUsize::new(0), min_recipients })
+    }
+
+    /// Queues `frame` for all `recipients`, one share per worker and one on the calling thread, and
+    /// waits until every share is done. Returns the IDs of the recipients the frame couldn't be queued for.
+    pub fn send(&self, frame: &Arc<[u8]>, mut recipients: Vec<(i32, Arc<Outbox>)>) -> Vec<i32> {
+        let share = recipients.len().div_ceil(self.workers.len() + 1);
+        let (done, results) = mpsc::channel();
+        let mut jobs = 0;
+        let mut missed = Vec::new();
+
+        while recipients.len() > share {
+            let job = Job { frame: Arc::clone(frame), recipients: recipients.split_off(recipients.len() - share), done: done.clone() };
+            let worker = &self.workers[self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len()];
+            match worker.send(job) {
+                Ok(()) => jobs += 1,
+                Err(mpsc::SendError(job)) => missed.extend(queue(frame, job.recipients))
+            }
+        }
+
+        drop(done);
+        missed.extend(queue(frame, recipients));
+        for _ in 0..jobs {
+            match results.recv() {
+                Ok(ids) => missed.extend(ids),
+                Err(_) => break
+            }
+        }
+        missed
+    }
+}
+
+fn queue(frame: &Arc<[u8]>, recipients: Vec<(i32, Arc<Outbox>)>) -> Vec<i32> {
+    recipients.into_iter().filter(|(_, outbox)| !outbox.push(Arc::clone(frame), true)).map(|(id, _)| id).collect()
+}
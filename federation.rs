@@ -0,0 +1,406 @@
+//! Relaying between servers (`federation_port`, `federation_peers`), so players connecting to the
+//! server closest to them still share one session. Every packet a local client sends is forwarded over
+//! the links to the peers, which deliver it to their clients like one of their own and pass it on to
+//! their other peers. Packets carry the instance that received them first and a sequence number, an
+//! instance delivers and forwards each only once, so any topology works, rings and meshes included.
+//!
+//! Links are TCP connections, made to every listed peer and accepted on `federation_port`, and carry
+//! the same framing as clients (`[size: i32 LE][payload]`). Both ends send a `Hello` (`MAGIC`, their
+//! instance and a nonce), then prove they know `federation_secret` with an HMAC-SHA256 of
+//! `MAGIC ‖ prover instance ‖ verifier instance ‖ verifier nonce ‖ prover nonce`, see `proof`. The end that
+//! connected proves first, the other only answers once that checked out, so neither ever signs a nonce for
+//! someone who hasn't proven anything. Then only `Relayed` frames follow:
+//! `[origin instance: u64 LE][sequence: u64 LE][client frame]`.
+
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use rand::Rng;
+
+use crate::BUFFER_SIZE;
+use crate::auth;
+use crate::logging::debug;
+use crate::logging::info;
+use crate::logging::warning;
+use crate::registry::SERVER_ID;
+use crate::router::Router;
+
+/// Start of the `Hello` frame, the version changes with the link protocol.
+const MAGIC: &[u8; 8] = b"ECHOFED2";
+/// Origin and sequence in front of a relayed client frame.
+const HEADER: usize = 16;
+/// Packets remembered to recognize them when they come around again.
+const SEEN: usize = 65536;
+/// Frames waiting for a slow link, more are dropped for it.
+const LINK_QUEUE: usize = 4096;
+/// How long a peer gets for its `Hello` and its answer.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often link threads check whether the server is stopping.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// Wait before connecting to a peer again.
+const RETRY: Duration = Duration::from_secs(2);
+
+/// The packets delivered already, by origin and sequence.
+#[derive(Default)]
+struct Seen {
+    set: HashSet<(u64, u64)>,
+    order: VecDeque<(u64, u64)>
+}
+
+impl Seen {
+    /// Remembers a packet, false if it was seen before.
+    fn insert(&mut self, key: (u64, u64)) -> bool {
+        if !self.set.insert(key) { return false; }
+        self.order.push_back(key);
+        if self.order.len() > SEEN && let Some(oldest) = self.order.pop_front() { self.set.remove(&oldest); }
+        true
+    }
+}
+
+/// The writer thread's queue of a link that is up.
+struct Link {
+    id: u64,
+    writer: mpsc::SyncSender<Arc<[u8]>>
+}
+
+pub struct Federation {
+    /// Random id of this server, never seeded so two instances with the same `seed` don't look alike.
+    instance: u64,
+    secret: String,
+    router: Router,
+    running: Arc<AtomicBool>,
+    next_seq: AtomicU64,
+    next_link: AtomicU64,
+    links: Mutex<Vec<Link>>,
+    seen: Mutex<Seen>,
+    /// Nonces of the handshakes in progress, a peer sending one of them back is refused.
+    issued: Mutex<HashSet<[u8; 32]>>
+}
+
+impl Federation {
+    /// Listens on `port` (0 = only connects out) and keeps a link to each of `peers`. Its threads end once `running` is cleared.
+    pub fn start(port: i32, peers: &str, secret: &str, router: Router, running: Arc<AtomicBool>) -> io::Result<Arc<Federation>> {
+        let federation = Arc::new(Federation {
+            instance: rand::rng().random(), secret: secret.to_string(), router, running,
+            next_seq: AtomicU64::new(0), next_link: AtomicU64::new(0), links: Mutex::new(Vec::new()), seen: Mutex::new(Seen::default()),
+            issued: Mutex::new(HashSet::new())
+        });
+
+        if port != 0 {
+            let listener = TcpListener::bind(format!("0.0.0.0:{}", port))?;
+            let federation = Arc::clone(&federation);
+            thread::Builder::new().name("federation".to_string()).spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let federation = Arc::clone(&federation);
+                    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_else(|_| "unknown".to_string());
+                    let _ = thread::Builder::new().name("federation-link".to_string()).spawn(move || { federation.serve(stream, &peer, false); });
+                }
+            })?;
+        }
+
+        for peer in peers.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (federation, peer) = (Arc::clone(&federation), peer.to_string());
+            thread::Builder::new().name("federation-link".to_string()).spawn(move || federation.dial(&peer, &|| true))?;
+        }
+        Ok(federation)
+    }
+
+    /// This server's instance, to tell its links apart from ones to others.
+    pub fn instance(&self) -> u64 {
+        self.instance
+    }
+
+    /// The secret links are authenticated with.
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    /// Keeps a link to `peer` in a thread of its own while `wanted` returns true, see `discovery`.
+    pub fn link(self: &Arc<Self>, peer: String, wanted: impl Fn() -> bool + Send + 'static) -> io::Result<()> {
+        let federation = Arc::clone(self);
+        thread::Builder::new().name("federation-link".to_string()).spawn(move || federation.dial(&peer, &wanted)).map(|_| ())
+    }
+
+    /// Sends a frame received from a local client to every peer.
+    pub fn forward(&self, frame: &[u8]) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut seen) = self.seen.lock() { seen.insert((self.instance, seq)); }
+
+        let mut message = Vec::with_capacity(4 + HEADER + frame.len());
+        message.extend_from_slice(&((4 + HEADER + frame.len()) as i32).to_le_bytes());
+        message.extend_from_slice(&self.instance.to_le_bytes());
+        message.extend_from_slice(&seq.to_le_bytes());
+        message.extend_from_slice(frame);
+        self.send(&Arc::from(message), None);
+    }
+
+    /// Queues a message on every link but `except`.
+    fn send(&self, message: &Arc<[u8]>, except: Option<u64>) {
+        let Ok(links) = self.links.lock() else { return; };
+        for link in links.iter().filter(|link| Some(link.id) != except) {
+            if link.writer.try_send(Arc::clone(message)).is_err() { debug!("Federation link {} is full, dropped a packet for it.", link.id); }
+        }
+    }
+
+    /// Keeps a link to `peer` until the server stops or it isn't `wanted` anymore.
+    fn dial(&self, peer: &str, wanted: &dyn Fn() -> bool) {
+        while self.running.load(Ordering::SeqCst) && wanted() {
+            match TcpStream::connect(peer) {
+                Ok(stream) => if !self.serve(stream, peer, true) { return; },
+                Err(e) => debug!("Could not connect to federation peer {} ({}), retrying.", peer, e)
+            }
+            thread::sleep(RETRY);
+        }
+    }
+
+    /// Runs a link until it closes, returns false if the peer is this server itself and shouldn't be tried again.
+    /// `dialed` is whether this end connected.
+    fn serve(&self, stream: TcpStream, peer: &str, dialed: bool) -> bool {
+        let _ = stream.set_nodelay(true);
+        match handshake(&stream, self.secret.as_bytes(), self.instance, &self.issued, dialed, &self.running) {
+            Ok(()) => { },
+            Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                warning!("Federation peer {} is this server itself, not linking to it!", peer);
+                return false;
+            },
+            Err(e) => {
+                warning!("Federation handshake with {} failed ({}), closing the link.", peer, e);
+                return true;
+            }
+        }
+
+        let link = self.next_link.fetch_add(1, Ordering::Relaxed);
+        let (writer, queue) = mpsc::sync_channel::<Arc<[u8]>>(LINK_QUEUE);
+        let Ok(mut output) = stream.try_clone() else { return true; };
+        let spawned = thread::Builder::new().name("federation-writer".to_string()).spawn(move || {
+            for message in queue {
+                if output.write_all(&message).is_err() { break; }
+            }
+            let _ = output.shutdown(std::net::Shutdown::Both);
+        });
+        if spawned.is_err() { return true; }
+        if let Ok(mut links) = self.links.lock() { links.push(Link { id: link, writer }); }
+        info!("Federation link {} to {} is up.", link, peer);
+
+        let result = self.receive(&stream, link);
+        if let Ok(mut links) = self.links.lock() { links.retain(|l| l.id != link); }
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+        info!("Federation link {} to {} is down ({}).", link, peer, match result { Ok(()) => "closed".to_string(), Err(e) => e.to_string() });
+        true
+    }
+
+    /// Delivers and passes on what arrives over a link until it closes.
+    fn receive(&self, stream: &TcpStream, link: u64) -> io::Result<()> {
+        loop {
+            let message = read_frame(stream, None, &self.running)?;
+            if message.len() < HEADER + 4 { return Err(io::Error::new(ErrorKind::InvalidData, "short relayed frame")); }
+            if i32::from_le_bytes(message[HEADER..HEADER + 4].try_into().unwrap_or_default()) as usize != message.len() - HEADER {
+                debug!("Dropped a malformed packet from federation link {}.", link);
+                continue;
+            }
+            let origin = u64::from_le_bytes(message[..8].try_into().unwrap_or_default());
+            let seq = u64::from_le_bytes(message[8..HEADER].try_into().unwrap_or_default());
+            if !self.seen.lock().map(|mut seen| seen.insert((origin, seq))).unwrap_or(false) { continue; }
+
+            // the sender is on another server, so every local client gets it
+            if !self.router.publish(SERVER_ID, Arc::from(&message[HEADER..]), None) { return Ok(()); }
+            let mut forwarded = Vec::with_capacity(4 + message.len());
+            forwarded.extend_from_slice(&((4 + message.len()) as i32).to_le_bytes());
+            forwarded.extend_from_slice(&message);
+            self.send(&Arc::from(forwarded), Some(link));
+        }
+    }
+}
+
+/// Proves to the peer that this end knows the secret and checks that it does too, the end that `dialed` proves first.
+/// Fails with `AddrInUse` if the peer is this server itself.
+fn handshake(mut stream: &TcpStream, secret: &[u8], instance: u64, issued: &Mutex<HashSet<[u8; 32]>>, dialed: bool, running: &AtomicBool) -> io::Result<()> {
+    let nonce = auth::challenge();
+    if let Ok(mut issued) = issued.lock() { issued.insert(nonce); }
+    let result = (|| {
+        stream.write_all(&frame(&[&MAGIC[..], &instance.to_le_bytes(), &nonce].concat()))?;
+
+        let deadline = Some(Instant::now() + HANDSHAKE_TIMEOUT);
+        let hello = read_frame(stream, deadline, running)?;
+        if hello.len() != MAGIC.len() + 8 + nonce.len() || &hello[..MAGIC.len()] != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not an echoserver federation peer"));
+        }
+        let peer = u64::from_le_bytes(hello[MAGIC.len()..MAGIC.len() + 8].try_into().unwrap_or_default());
+        if peer == instance { return Err(ErrorKind::AddrInUse.into()); }
+        let peer_nonce = &hello[MAGIC.len() + 8..];
+        if issued.lock().map(|issued| issued.iter().any(|n| n[..] == *peer_nonce)).unwrap_or(true) {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "the peer sent a nonce of this server back"));
+        }
+
+        let mine = proof(secret, instance, peer, peer_nonce, &nonce);
+        if dialed { stream.write_all(&frame(&mine))?; }
+        let theirs = read_frame(stream, deadline, running)?;
+        if !auth::constant_time_eq(&theirs, &proof(secret, peer, instance, &nonce, peer_nonce)) {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "wrong federation_secret"));
+        }
+        if !dialed { stream.write_all(&frame(&mine))?; }
+        Ok(())
+    })();
+    if let Ok(mut issued) = issued.lock() { issued.remove(&nonce); }
+    result
+}
+
+/// What `prover` sends `verifier` to prove it knows the secret, bound to both nonces and to who proves what to whom,
+/// so it is worth nothing on another link or in the other direction.
+fn proof(secret: &[u8], prover: u64, verifier: u64, verifier_nonce: &[u8], prover_nonce: &[u8]) -> [u8; 32] {
+    auth::hmac_sha256(secret, &[&MAGIC[..], &prover.to_le_bytes(), &verifier.to_le_bytes(), verifier_nonce, prover_nonce].concat())
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    [&((payload.len() + 4) as i32).to_le_bytes()[..], payload].concat()
+}
+
+/// Reads one frame's payload, giving up at `deadline` or once the server stops.
+fn read_frame(mut stream: &TcpStream, deadline: Option<Instant>, running: &AtomicBool) -> io::Result<Vec<u8>> {
+    let mut read_exact = |buffer: &mut [u8]| -> io::Result<()> {
+        let mut read = 0;
+        while read < buffer.len() {
+            if !running.load(Ordering::SeqCst) { return Err(io::Error::new(ErrorKind::Interrupted, "the server is stopping")); }
+            let timeout = match deadline {
+                Some(deadline) if deadline <= Instant::now() => return Err(ErrorKind::TimedOut.into()),
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()).min(READ_TIMEOUT),
+                None => READ_TIMEOUT
+            };
+            let _ = stream.set_read_timeout(Some(timeout));
+            match stream.read(&mut buffer[read..]) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "closed by the peer")),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(())
+    };
+
+    let mut size = [0u8; 4];
+    read_exact(&mut size)?;
+    let size = i32::from_le_bytes(size);
+    if size < 4 || size as usize > 4 + HEADER + BUFFER_SIZE { return Err(io::Error::new(ErrorKind::InvalidData, format!("invalid frame size {}", size))); }
+    let mut payload = vec![0u8; size as usize - 4];
+    read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"federation secret";
+
+    /// Both ends of a TCP connection, the one that connected first.
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dialer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (dialer, listener.accept().unwrap().0)
+    }
+
+    /// A server's end of a handshake, on a thread of its own.
+    fn server(stream: TcpStream, secret: &'static [u8], instance: u64, issued: Arc<Mutex<HashSet<[u8; 32]>>>, dialed: bool) -> thread::JoinHandle<io::Result<()>> {
+        thread::spawn(move || handshake(&stream, secret, instance, &issued, dialed, &AtomicBool::new(true)))
+    }
+
+    fn issued() -> Arc<Mutex<HashSet<[u8; 32]>>> {
+        Arc::new(Mutex::new(HashSet::new()))
+    }
+
+    /// Reads the `Hello` of a server as `(instance, nonce)`.
+    fn hello(stream: &TcpStream) -> (u64, Vec<u8>) {
+        let hello = read_frame(stream, None, &AtomicBool::new(true)).unwrap();
+        (u64::from_le_bytes(hello[MAGIC.len()..MAGIC.len() + 8].try_into().unwrap()), hello[MAGIC.len() + 8..].to_vec())
+    }
+
+    fn send(mut stream: &TcpStream, payload: &[u8]) {
+        stream.write_all(&frame(payload)).unwrap();
+    }
+
+    #[test]
+    fn links_with_the_same_secret() {
+        let (dialer, acceptor) = pair();
+        let accepting = server(acceptor, SECRET, 1, issued(), false);
+        let dialing = server(dialer, SECRET, 2, issued(), true);
+        accepting.join().unwrap().unwrap();
+        dialing.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn refuses_a_wrong_secret_without_proving_anything() {
+        let (dialer, acceptor) = pair();
+        let accepting = server(acceptor, SECRET, 1, issued(), false);
+        let dialing = server(dialer, b"another secret", 2, issued(), true);
+        assert_eq!(accepting.join().unwrap().unwrap_err().kind(), ErrorKind::PermissionDenied);
+        // the acceptor closed the link instead of answering with its proof
+        assert_eq!(dialing.join().unwrap().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn recognizes_itself() {
+        let issued = issued();
+        let (dialer, acceptor) = pair();
+        let accepting = server(acceptor, SECRET, 1, Arc::clone(&issued), false);
+        let dialing = server(dialer, SECRET, 1, issued, true);
+        assert_eq!(accepting.join().unwrap().unwrap_err().kind(), ErrorKind::AddrInUse);
+        assert_eq!(dialing.join().unwrap().unwrap_err().kind(), ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn refuses_its_own_nonce() {
+        let issued = issued();
+        let (attacker, first) = pair();
+        let _first = server(first, SECRET, 1, Arc::clone(&issued), false);
+        let (_, nonce) = hello(&attacker);
+
+        let (second_attacker, second) = pair();
+        let second = server(second, SECRET, 1, issued, false);
+        hello(&second_attacker);
+        send(&second_attacker, &[&MAGIC[..], &7u64.to_le_bytes(), &nonce].concat());
+        assert_eq!(second.join().unwrap().unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn a_proof_is_worth_nothing_on_another_link() {
+        let issued = issued();
+        // the attacker connects and would have to prove it knows the secret for the server's nonce
+        let (attacker, accepted) = pair();
+        let victim = server(accepted, SECRET, 1, Arc::clone(&issued), false);
+        hello(&attacker);
+        send(&attacker, &[&MAGIC[..], &7u64.to_le_bytes(), &[3u8; 32]].concat());
+
+        // the server connecting to it, as to a peer it was given, proves first
+        let (dialed, attacker_side) = pair();
+        let _prover = server(dialed, SECRET, 1, issued, true);
+        hello(&attacker_side);
+        send(&attacker_side, &[&MAGIC[..], &7u64.to_le_bytes(), &[4u8; 32]].concat());
+        let proof = read_frame(&attacker_side, None, &AtomicBool::new(true)).unwrap();
+
+        send(&attacker, &proof);
+        assert_eq!(victim.join().unwrap().unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn proofs_depend_on_the_direction() {
+        let (a, b) = ([1u8; 32], [2u8; 32]);
+        assert_eq!(proof(SECRET, 1, 2, &b, &a), proof(SECRET, 1, 2, &b, &a));
+        assert_ne!(proof(SECRET, 1, 2, &b, &a), proof(SECRET, 2, 1, &a, &b));
+        assert_ne!(proof(SECRET, 1, 2, &b, &a), proof(SECRET, 1, 2, &a, &b));
+        assert_ne!(proof(SECRET, 1, 2, &b, &a), proof(b"other", 1, 2, &b, &a));
+    }
+}
@@ -0,0 +1,119 @@
+//! Payload filter rules from `filter_file`, checked in order against every packet before it is relayed.
+//!
+//! One rule per line, `#` starts a comment: `<action> <pattern> [replacement]` with the action
+//! `drop`, `replace` or `disconnect` and the pattern either `re:<regex>` (matched against the raw
+//! bytes, spaces must be written as `\s` or `\x20`) or `hex:<bytes>`. The replacement is the rest
+//! of the line, or `hex:<bytes>`.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::fs;
+use regex::bytes::Regex;
+
+pub enum Action {
+    /// Don't relay the packet.
+    Drop,
+    /// Replace every match and relay the result.
+    Replace(Vec<u8>),
+    /// Don't relay the packet and close the connection.
+    Disconnect
+}
+
+pub struct Rule {
+    pattern: Regex,
+    action: Action,
+    /// The rule as written in the file, for log messages.
+    text: String
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// What to do with a packet after all rules were checked.
+pub enum Verdict<'a> {
+    /// Relay this payload, changed by `replace` rules if any matched.
+    Relay(Cow<'a, [u8]>),
+    Drop(&'a Rule),
+    Disconnect(&'a Rule)
+}
+
+/// The rules from `path`, an empty path means no rules.
+pub struct FilterList {
+    path: String,
+    rules: Vec<Rule>
+}
+
+impl FilterList {
+    /// Loads the rules from `path`, any invalid rule fails the whole file.
+    pub fn load(path: &str) -> Result<FilterList, String> {
+        let mut list = FilterList { path: path.to_string(), rules: Vec::new() };
+        if path.is_empty() { return Ok(list); }
+
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        for (n, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() { continue; }
+            list.rules.push(parse_rule(line).map_err(|e| format!("line {}: {}", n + 1, e))?);
+        }
+        Ok(list)
+    }
+
+    /// Reads the file again, the current rules are kept if that fails.
+    pub fn reload(&self) -> Result<FilterList, String> {
+        FilterList::load(&self.path)
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn check<'a>(&'a self, payload: &'a [u8]) -> Verdict<'a> {
+        let mut payload = Cow::Borrowed(payload);
+
+        for rule in &self.rules {
+            if !rule.pattern.is_match(&payload) { continue; }
+            match &rule.action {
+                Action::Drop => return Verdict::Drop(rule),
+                Action::Disconnect => return Verdict::Disconnect(rule),
+                Action::Replace(with) => payload = Cow::Owned(rule.pattern.replace_all(&payload, regex::bytes::NoExpand(with)).into_owned())
+            }
+        }
+        Verdict::Relay(payload)
+    }
+}
+
+fn parse_rule(line: &str) -> Result<Rule, String> {
+    let (action, rest) = line.split_once(char::is_whitespace).ok_or("missing pattern")?;
+    let rest = rest.trim_start();
+    let (pattern, replacement) = rest.split_once(char::is_whitespace).map(|(p, r)| (p, r.trim())).unwrap_or((rest, ""));
+
+    let pattern = match (pattern.strip_prefix("re:"), pattern.strip_prefix("hex:")) {
+        (Some(re), _) => Regex::new(re).map_err(|e| format!("invalid regex ({})", e))?,
+        (_, Some(hex)) => {
+            let bytes = parse_hex(hex).ok_or("invalid hex pattern")?;
+            Regex::new(&bytes.iter().map(|b| format!("(?-u:\\x{:02x})", b)).collect::<String>()).map_err(|e| e.to_string())?
+        },
+        _ => return Err(format!("pattern {} must start with re: or hex:", pattern))
+    };
+
+    let action = match action {
+        "drop" => Action::Drop,
+        "disconnect" => Action::Disconnect,
+        "replace" => Action::Replace(match replacement.strip_prefix("hex:") {
+            Some(hex) => parse_hex(hex).ok_or("invalid hex replacement")?,
+            None => replacement.as_bytes().to_vec()
+        }),
+        _ => return Err(format!("unknown action {}", action))
+    };
+    if !matches!(action, Action::Replace(_)) && !replacement.is_empty() { return Err("only replace takes a replacement".to_string()); }
+
+    Ok(Rule { pattern, action, text: line.to_string() })
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.is_empty() || !s.len().is_multiple_of(2) { return None; }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
@@ -0,0 +1,280 @@
+//! A small gzip (RFC 1952) compressor for closed journal segments, so they can be read with `zcat` or
+//! `zgrep`. It is a single DEFLATE block with the fixed Huffman codes and LZ77 matches found through a
+//! hash chain, far from the ratio of `gzip -9` but enough for the repetitive text of the journal.
+
+/// Distance back the matches may reach, the DEFLATE window.
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Candidates checked for each position, more find longer matches but take longer.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+/// Base length and extra bits of the length symbols 257 to 285.
+const LENGTHS: [(u16, u8); 29] = [
+    (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 0), (11, 1), (13, 1), (15, 1), (17, 1), (19, 2), (23, 2), (27, 2),
+    (31, 2), (35, 3), (43, 3), (51, 3), (59, 3), (67, 4), (83, 4), (99, 4), (115, 4), (131, 5), (163, 5), (195, 5), (227, 5), (258, 0)
+];
+/// Base distance and extra bits of the distance symbols 0 to 29.
+const DISTANCES: [(u16, u8); 30] = [
+    (1, 0), (2, 0), (3, 0), (4, 0), (5, 1), (7, 1), (9, 2), (13, 2), (17, 3), (25, 3), (33, 4), (49, 4), (65, 5), (97, 5), (129, 6),
+    (193, 6), (257, 7), (385, 7), (513, 8), (769, 8), (1025, 9), (1537, 9), (2049, 10), (3073, 10), (4097, 11), (6145, 11),
+    (8193, 12), (12289, 12), (16385, 13), (24577, 13)
+];
+
+/// `data` as a complete gzip file.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Bits { bytes: vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255], pending: 0, count: 0 };
+    out.write(1, 1); // final block
+    out.write(1, 2); // fixed Huffman codes
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW];
+    let mut i = 0;
+    while i < data.len() {
+        let (length, distance) = longest_match(data, i, &head, &prev);
+        let step = if length >= MIN_MATCH {
+            out.length(length);
+            out.distance(distance);
+            length
+        } else {
+            out.literal(data[i]);
+            1
+        };
+        for at in i..(i + step).min(data.len().saturating_sub(MIN_MATCH - 1)) {
+            let h = hash(&data[at..at + MIN_MATCH]);
+            prev[at % WINDOW] = head[h];
+            head[h] = at;
+        }
+        i += step;
+    }
+    out.symbol(256);
+
+    let mut bytes = out.finish();
+    bytes.extend_from_slice(&crc32(data).to_le_bytes());
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes
+}
+
+/// The longest earlier match of the bytes at `i` as `(length, distance)`, a length under `MIN_MATCH` if there is none.
+fn longest_match(data: &[u8], i: usize, head: &[usize], prev: &[usize]) -> (usize, usize) {
+    if i + MIN_MATCH > data.len() { return (0, 0); }
+    let limit = (data.len() - i).min(MAX_MATCH);
+    let (mut best, mut distance) = (0, 0);
+    let mut candidate = head[hash(&data[i..i + MIN_MATCH])];
+
+    for _ in 0..MAX_CHAIN {
+        if candidate == usize::MAX || candidate >= i || i - candidate > WINDOW { break; }
+        let length = data[candidate..].iter().zip(&data[i..i + limit]).take_while(|(a, b)| a == b).count();
+        if length > best {
+            (best, distance) = (length, i - candidate);
+            if length == limit { break; }
+        }
+        let next = prev[candidate % WINDOW];
+        // the slot was reused by a later position, the chain ends here
+        if next != usize::MAX && next >= candidate { break; }
+        candidate = next;
+    }
+    (best, distance)
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    (value.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// CRC-32 (IEEE), the checksum of gzip files.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 { crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg()); }
+    }
+    !crc
+}
+
+/// DEFLATE output, bits are packed starting with the least significant one.
+struct Bits {
+    bytes: Vec<u8>,
+    pending: u64,
+    count: u32
+}
+
+impl Bits {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.pending |= (value as u64) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.bytes.push(self.pending as u8);
+            self.pending >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which starts with its most significant bit.
+    fn code(&mut self, code: u32, bits: u32) {
+        self.write(code.reverse_bits() >> (32 - bits), bits);
+    }
+
+    /// A literal/length symbol with its fixed code.
+    fn symbol(&mut self, symbol: u16) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol as u32, 8),
+            144..=255 => self.code(0x190 + (symbol - 144) as u32, 9),
+            256..=279 => self.code((symbol - 256) as u32, 7),
+            _ => self.code(0xc0 + (symbol - 280) as u32, 8)
+        }
+    }
+
+    fn literal(&mut self, byte: u8) {
+        self.symbol(byte as u16);
+    }
+
+    fn length(&mut self, length: usize) {
+        let index = LENGTHS.iter().rposition(|(base, _)| *base as usize <= length).unwrap_or(0);
+        let (base, extra) = LENGTHS[index];
+        self.symbol(257 + index as u16);
+        self.write((length - base as usize) as u32, extra as u32);
+    }
+
+    fn distance(&mut self, distance: usize) {
+        let index = DISTANCES.iter().rposition(|(base, _)| *base as usize <= distance).unwrap_or(0);
+        let (base, extra) = DISTANCES[index];
+        self.code(index as u32, 5);
+        self.write((distance - base as usize) as u32, extra as u32);
+    }
+
+    /// The bytes with the last one padded.
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 { self.bytes.push(self.pending as u8); }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads DEFLATE bits, starting with the least significant one.
+    struct Reader<'a> {
+        bytes: &'a [u8],
+        at: usize
+    }
+
+    impl Reader<'_> {
+        fn bits(&mut self, count: u32) -> u32 {
+            let mut value = 0;
+            for n in 0..count {
+                let bit = self.bytes[self.at / 8] >> (self.at % 8) & 1;
+                value |= (bit as u32) << n;
+                self.at += 1;
+            }
+            value
+        }
+
+        /// A Huffman code of `count` more bits appended to `code`.
+        fn code(&mut self, mut code: u32, count: u32) -> u32 {
+            for _ in 0..count { code = code << 1 | self.bits(1); }
+            code
+        }
+
+        fn symbol(&mut self) -> u16 {
+            let code = self.code(0, 7);
+            if code < 0x18 { return 256 + code as u16; }
+            let code = self.code(code, 1);
+            match code {
+                0x30..=0xbf => (code - 0x30) as u16,
+                0xc0..=0xc7 => (280 + code - 0xc0) as u16,
+                _ => (144 + self.code(code, 1) - 0x190) as u16
+            }
+        }
+    }
+
+    /// Decodes the single fixed Huffman block `compress` writes.
+    fn inflate(deflate: &[u8]) -> Vec<u8> {
+        let mut reader = Reader { bytes: deflate, at: 0 };
+        assert_eq!(reader.bits(1), 1, "final block");
+        assert_eq!(reader.bits(2), 1, "fixed Huffman codes");
+        let mut out = Vec::new();
+        loop {
+            match reader.symbol() {
+                literal @ 0..=255 => out.push(literal as u8),
+                256 => break,
+                symbol => {
+                    let (base, extra) = LENGTHS[(symbol - 257) as usize];
+                    let length = base as usize + reader.bits(extra as u32) as usize;
+                    let (base, extra) = DISTANCES[reader.code(0, 5) as usize];
+                    let distance = base as usize + reader.bits(extra as u32) as usize;
+                    for _ in 0..length { out.push(out[out.len() - distance]); }
+                }
+            }
+        }
+        out
+    }
+
+    /// Checks the header and trailer of `compressed` and returns what it decompresses to.
+    fn decompress(compressed: &[u8]) -> Vec<u8> {
+        assert_eq!(compressed[..10], [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255]);
+        let (deflate, trailer) = compressed[10..].split_at(compressed.len() - 18);
+        let data = inflate(deflate);
+        assert_eq!(trailer[..4], crc32(&data).to_le_bytes());
+        assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+        data
+    }
+
+    fn journal(lines: usize) -> Vec<u8> {
+        (0..lines).map(|n| format!("2025-09-14T18:03:21Z id={} session=0f6a addr=1.2.3.4:50000 size=12 payload=0001020304{:04x}\n", n % 7, n)).collect::<String>().into_bytes()
+    }
+
+    #[test]
+    fn crc32_known_answers() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+    }
+
+    #[test]
+    fn empty_input() {
+        let compressed = compress(b"");
+        assert_eq!(compressed.len(), 20);
+        assert_eq!(decompress(&compressed), b"");
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut binary: Vec<u8> = (0..=255).collect();
+        binary.extend((0..100_000u32).map(|n| (n.wrapping_mul(2_654_435_761) >> 24) as u8));
+        // matches of every length and across the whole window
+        let mut long = journal(2000);
+        long.extend(vec![b'x'; 1000]);
+        long.extend_from_slice(&binary[..40_000]);
+        long.extend_from_slice(&binary[..40_000]);
+        for data in [b"a".to_vec(), b"aaaa".to_vec(), b"abcabcabcabd".to_vec(), binary, long] {
+            assert_eq!(decompress(&compress(&data)), data);
+        }
+    }
+
+    #[test]
+    fn compresses_the_journal() {
+        let data = journal(5000);
+        let compressed = compress(&data);
+        assert!(compressed.len() < data.len() / 3, "{} of {} bytes", compressed.len(), data.len());
+    }
+
+    #[test]
+    fn gzip_reads_it() {
+        use std::io::Write;
+        use std::process::Command;
+        use std::process::Stdio;
+
+        let data = journal(3000);
+        let Ok(mut gzip) = Command::new("gzip").arg("-dc").stdin(Stdio::piped()).stdout(Stdio::piped()).spawn() else { return; };
+        let mut stdin = gzip.stdin.take().unwrap();
+        let compressed = compress(&data);
+        let writer = std::thread::spawn(move || stdin.write_all(&compressed));
+        let output = gzip.wait_with_output().unwrap();
+        writer.join().unwrap().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, data);
+    }
+}
@@ -0,0 +1,150 @@
+//! `Server::handle`: runtime control of an embedded server in code, what the admin console and the REST
+//! API can do. Changes are logged like theirs, naming the `server handle` as their source.
+
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use crate::BUFFER_SIZE;
+use crate::bans::Network;
+use crate::drain;
+use crate::events::ClientInfo;
+use crate::logging::info;
+use crate::metrics::MetricsSnapshot;
+use crate::registry;
+use crate::state::RateTarget;
+use crate::state::SharedState;
+
+const SOURCE: &str = "server handle";
+
+/// A connected client with its traffic, as in the admin console's `list` and `info`.
+pub struct ClientStatus {
+    pub client: ClientInfo,
+    /// See `transport::Connection::transport`.
+    pub transport: &'static str,
+    pub connected_at: SystemTime,
+    pub last_activity: SystemTime,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    /// Packets dropped by the rate limit.
+    pub rate_limit_drops: u64
+}
+
+/// Controls a running server from any thread, cheap to clone.
+#[derive(Clone)]
+pub struct ServerHandle {
+    state: SharedState
+}
+
+impl ServerHandle {
+    pub(crate) fn new(state: SharedState) -> ServerHandle {
+        ServerHandle { state }
+    }
+
+    /// The connected clients, sorted by id.
+    pub fn list_clients(&self) -> Vec<ClientStatus> {
+        registry::snapshot(&self.state.connections).unwrap_or_default().into_iter().map(|c| ClientStatus {
+            last_activity: c.last_activity(),
+            client: ClientInfo { id: c.id, session: c.meta.session, addr: c.meta.addr, user: c.meta.identity.map(|i| i.name) },
+            transport: c.meta.transport,
+            connected_at: c.meta.connected_at,
+            packets_received: c.traffic.packets_received.load(Ordering::Relaxed),
+            bytes_received: c.traffic.bytes_received.load(Ordering::Relaxed),
+            packets_sent: c.traffic.packets_sent.load(Ordering::Relaxed),
+            bytes_sent: c.traffic.bytes_sent.load(Ordering::Relaxed),
+            rate_limit_drops: c.traffic.rate_limit_drops.load(Ordering::Relaxed)
+        }).collect()
+    }
+
+    /// Disconnects client `id`, sending it `reason` first if `control_packets` is enabled. False if there is no such client.
+    pub fn kick(&self, id: i32, reason: &str) -> bool {
+        self.state.kick(id, reason, SOURCE)
+    }
+
+    /// Bans an address or network (e.g. `203.0.113.0/24`) and kicks its clients, returns how many were kicked.
+    pub fn ban(&self, network: &str) -> Result<usize, String> {
+        self.state.ban(network.parse::<Network>()?, SOURCE)
+    }
+
+    /// Lifts a ban, false if there was none.
+    pub fn unban(&self, network: &str) -> Result<bool, String> {
+        self.state.unban(&network.parse::<Network>()?, SOURCE)
+    }
+
+    /// Sends `payload` to every client like a relayed packet (from no client), returns how many it was queued for.
+    pub fn broadcast(&self, payload: &[u8]) -> Result<usize, String> {
+        let sent = self.state.broadcast(&frame(payload)?);
+        info!("Packet of size {} sent to {} client(s) over the {}.", payload.len() + 4, sent, SOURCE);
+        Ok(sent)
+    }
+
+    /// Sends `payload` to client `id` only, false if there is no such client or its send queue is full.
+    pub fn send(&self, id: i32, payload: &[u8]) -> Result<bool, String> {
+        let frame = frame(payload)?;
+        let Ok(connections) = self.state.connections.read() else { return Err("could not lock connections".to_string()); };
+        Ok(connections.get(&id).is_some_and(|client| client.send(frame.into(), false)))
+    }
+
+    /// Sends an `Announcement` control packet to every client, needs `control_packets`.
+    pub fn announce(&self, message: &str) -> Result<usize, String> {
+        self.state.announce(message, SOURCE)
+    }
+
+    /// Current value of every limit `set_limit` can change.
+    pub fn limits(&self) -> Vec<(&'static str, i32)> {
+        self.state.settings()
+    }
+
+    /// Changes `max_players`, `max_per_ip`, `max_rate`, `max_packets`, `total_rate` or `slow_client_ms`
+    /// for current and future clients, like the console's `set`.
+    pub fn set_limit(&self, key: &str, value: i32) -> Result<(), String> {
+        self.state.set(key, value, SOURCE)
+    }
+
+    /// Gives a client (`target` is its id) or every client from an address its own `max_rate`, or removes
+    /// that override with `None`. Returns false when removing one that didn't exist.
+    pub fn set_rate_override(&self, target: &str, limit: Option<i32>) -> Result<bool, String> {
+        let Some(target) = RateTarget::parse(target) else { return Err(format!("{} is neither a client id nor an address", target)); };
+        if limit.is_some_and(|n| n < 0) { return Err("rate limits must not be negative".to_string()); }
+        Ok(self.state.set_rate_override(target, limit, SOURCE))
+    }
+
+    /// Delays the packets relayed to client `id` by `ms` milliseconds (0 = not at all), like a player with a
+    /// high ping. False if there is no such client.
+    pub fn set_latency(&self, id: i32, ms: u32) -> bool {
+        self.state.set_latency(id, ms, SOURCE)
+    }
+
+    /// Refuses (true) or accepts new connections again, false if nothing changed or the server is draining.
+    pub fn set_paused(&self, paused: bool) -> bool {
+        self.state.set_paused(paused, SOURCE)
+    }
+
+    /// Re-reads `ban_file`, `filter_file` and the allow and deny lists (see `ServerBuilder::reload_config`),
+    /// returns the bans loaded and the clients kicked.
+    pub fn reload(&self) -> Result<(usize, usize), String> {
+        self.state.reload(SOURCE)
+    }
+
+    /// Stops accepting clients and shuts down once they left or after `seconds`, false if already draining.
+    pub fn drain(&self, seconds: i32) -> bool {
+        drain::start(&self.state, seconds, SOURCE)
+    }
+
+    /// The metrics now, see `Server::metrics`.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.state.metrics.snapshot(self.state.started.elapsed())
+    }
+
+    /// Makes `Server::run` shut down and return, like `Server::stop`.
+    pub fn shutdown(&self) {
+        info!("Shutdown requested over the {}.", SOURCE);
+        self.state.stop();
+    }
+}
+
+fn frame(payload: &[u8]) -> Result<Vec<u8>, String> {
+    if payload.len() + 4 > BUFFER_SIZE { return Err(format!("packets can be at most {} bytes", BUFFER_SIZE - 4)); }
+    Ok(((payload.len() + 4) as i32).to_le_bytes().iter().chain(payload).copied().collect())
+}
@@ -0,0 +1,288 @@
+use std::fmt::Write as _;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Largest request body that is read, anything above is rejected.
+const MAX_BODY: usize = 65536;
+/// Longest request line or header line, anything above is rejected.
+const MAX_LINE: usize = 8192;
+/// Most headers read, a request with more is rejected.
+const MAX_HEADERS: usize = 64;
+/// Time a client has to send the whole request, however slowly the bytes come in.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Connections served at once, more are closed right away.
+const MAX_CONNECTIONS: usize = 64;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Header names are lowercase.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Response {
+        Response { status, content_type, body: body.into() }
+    }
+
+    pub fn text(status: u16, body: impl Into<Vec<u8>>) -> Response {
+        Response::new(status, "text/plain; charset=utf-8", body)
+    }
+
+    pub fn not_found() -> Response {
+        Response::text(404, "Not Found\n")
+    }
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown"
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let mut reader = BufReader::new(stream);
+
+    let line = read_line(&mut reader, stream, deadline)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(&mut reader, stream, deadline)?;
+        if line.trim_end().is_empty() { break; }
+        if headers.len() == MAX_HEADERS { return None; }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    let length = headers.iter().find(|(n, _)| n == "content-length").and_then(|(_, v)| v.parse::<usize>().ok()).unwrap_or(0);
+    if length > MAX_BODY { return None; }
+    let mut body = vec![0u8; length];
+    let mut read = 0;
+    while read < length {
+        until(stream, deadline)?;
+        match reader.read(&mut body[read..]).ok()? {
+            0 => return None,
+            n => read += n
+        }
+    }
+
+    Some(Request { method, path, headers, body })
+}
+
+/// Reads a line of at most `MAX_LINE` bytes before `deadline`, an empty one at the end of the stream.
+fn read_line(reader: &mut BufReader<&TcpStream>, stream: &TcpStream, deadline: Instant) -> Option<String> {
+    let mut line = String::new();
+    loop {
+        until(stream, deadline)?;
+        let limit = (MAX_LINE + 1 - line.len()) as u64;
+        if reader.by_ref().take(limit).read_line(&mut line).ok()? == 0 || line.ends_with('\n') { break; }
+        if line.len() > MAX_LINE { return None; }
+    }
+    Some(line)
+}
+
+/// Lets the next read wait until `deadline` at most, `None` once that has passed.
+fn until(stream: &TcpStream, deadline: Instant) -> Option<()> {
+    let left = deadline.checked_duration_since(Instant::now()).filter(|left| !left.is_zero())?;
+    stream.set_read_timeout(Some(left)).ok()
+}
+
+fn handle_connection(mut stream: TcpStream, handler: &(dyn Fn(&Request) -> Response + Send + Sync)) {
+    let _ = stream.set_write_timeout(Some(Duration::from_millis(5000)));
+
+    let response = match read_request(&stream) {
+        Some(request) => handler(&request),
+        None => Response::text(400, "Bad Request\n")
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status, status_text(response.status), response.content_type, response.body.len()
+    );
+    let _ = stream.write_all(head.as_bytes());
+    let _ = stream.write_all(&response.body);
+}
+
+/// Binds `address:port` and serves every incoming HTTP request with `handler` on a background thread, up to
+/// `MAX_CONNECTIONS` at once.
+pub fn serve(address: &str, port: i32, handler: impl Fn(&Request) -> Response + Send + Sync + 'static) -> std::io::Result<()> {
+    let listener = TcpListener::bind(format!("{}:{}", address, port))?;
+    let handler = Arc::new(handler);
+    let open = Arc::new(AtomicUsize::new(0));
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if open.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+                open.fetch_sub(1, Ordering::Relaxed);
+                continue;
+            }
+            let handler = Arc::clone(&handler);
+            let slot = Slot(Arc::clone(&open));
+            thread::spawn(move || {
+                let _slot = slot;
+                handle_connection(stream, handler.as_ref());
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// A connection counted against `MAX_CONNECTIONS`, until it is dropped (also when the handler panics).
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Quotes and escapes `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); },
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Looks up `name` in a query string (`a=1&b=2`) and percent-decodes its value.
+pub fn query_param(query: &str, name: &str) -> Option<String> {
+    let value = query.split('&').find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))?;
+
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if let Some(Ok(b)) = value.get(i + 1..i + 3).map(|h| u8::from_str_radix(h, 16)) => {
+                decoded.push(b);
+                i += 2;
+            },
+            b => decoded.push(b)
+        }
+        i += 1;
+    }
+
+    Some(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses what a client sends as one request, the client closing its side after `request`.
+    fn parse(request: Vec<u8>) -> Option<Request> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let _ = stream.write_all(&request);
+            let _ = stream.shutdown(std::net::Shutdown::Write);
+            stream
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let parsed = read_request(&stream);
+        drop(client.join());
+        parsed
+    }
+
+    #[test]
+    fn reads_a_request() {
+        let request = parse(b"PUT /config HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\nbody".to_vec()).unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/config");
+        assert_eq!(request.header("host"), Some("x"));
+        assert_eq!(request.body, b"body");
+    }
+
+    #[test]
+    fn rejects_long_lines() {
+        let mut request = b"GET /".to_vec();
+        request.extend(vec![b'a'; MAX_LINE]);
+        request.extend(b" HTTP/1.1\r\n\r\n");
+        assert!(parse(request).is_none());
+
+        let mut request = b"GET / HTTP/1.1\r\nX: ".to_vec();
+        request.extend(vec![b'a'; MAX_LINE]);
+        request.extend(b"\r\n\r\n");
+        assert!(parse(request).is_none());
+
+        let mut request = b"GET / HTTP/1.1\r\nX: ".to_vec();
+        request.extend(vec![b'a'; MAX_LINE - 6]);
+        request.extend(b"\r\n\r\n");
+        assert_eq!(parse(request).unwrap().header("x").map(str::len), Some(MAX_LINE - 6));
+    }
+
+    #[test]
+    fn rejects_too_many_headers() {
+        let request = |count: usize| {
+            let mut request = b"GET / HTTP/1.1\r\n".to_vec();
+            for n in 0..count { request.extend(format!("X-{}: {}\r\n", n, n).as_bytes()); }
+            request.extend(b"\r\n");
+            request
+        };
+        assert_eq!(parse(request(MAX_HEADERS)).unwrap().headers.len(), MAX_HEADERS);
+        assert!(parse(request(MAX_HEADERS + 1)).is_none());
+    }
+
+    #[test]
+    fn rejects_large_and_short_bodies() {
+        assert!(parse(format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1).into_bytes()).is_none());
+        assert!(parse(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort".to_vec()).is_none());
+    }
+
+    #[test]
+    fn gives_up_on_slow_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let started = Instant::now();
+        assert!(read_request(&stream).is_none());
+        assert!(started.elapsed() >= REQUEST_TIMEOUT && started.elapsed() < REQUEST_TIMEOUT * 2);
+    }
+}
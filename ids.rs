@@ -0,0 +1,76 @@
+//! How clients get their ids (`id_allocator`). A new connection is allocated one before it
+//! authenticates, an allocator may replace it once it did, e.g. with the account id from its token.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use rand::Rng;
+
+use crate::random;
+
+/// Ids the built-in allocators hand out, `registry::SERVER_ID` is never one of them.
+pub const FIRST_ID: i32 = 10000;
+pub const LAST_ID: i32 = 16383;
+
+/// Random picks before `Random` looks for a free id in order.
+const RANDOM_TRIES: usize = 64;
+
+/// Picks client ids, ids must be positive.
+pub trait IdAllocator: Send + Sync {
+    /// The id of a new connection, `None` if none is left. `taken` tells whether an id belongs to a
+    /// connected client, it is called with the connections locked.
+    fn allocate(&self, taken: &dyn Fn(i32) -> bool) -> Option<i32>;
+
+    /// The id a client keeps once it authenticated, the one it was allocated by default. `token_id` is
+    /// the `id` claim of the token it authenticated with. A client whose id is taken by then is refused.
+    fn authenticated(&self, allocated: i32, _token_id: Option<i32>) -> i32 {
+        allocated
+    }
+}
+
+/// A random free id, the default.
+pub struct Random;
+
+impl IdAllocator for Random {
+    fn allocate(&self, taken: &dyn Fn(i32) -> bool) -> Option<i32> {
+        random::with(|rng| (0..RANDOM_TRIES).map(|_| rng.random_range(FIRST_ID..=LAST_ID)).find(|id| !taken(*id)))
+            .or_else(|| (FIRST_ID..=LAST_ID).find(|id| !taken(*id)))
+    }
+}
+
+/// The next free id after the last one handed out, starting over at `FIRST_ID` after `LAST_ID`.
+pub struct Sequential {
+    /// Locked so connections handshaking at the same time get different ids.
+    next: Mutex<i32>
+}
+
+impl IdAllocator for Sequential {
+    fn allocate(&self, taken: &dyn Fn(i32) -> bool) -> Option<i32> {
+        let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+        let id = (*next..=LAST_ID).chain(FIRST_ID..*next).find(|id| !taken(*id))?;
+        *next = if id == LAST_ID { FIRST_ID } else { id + 1 };
+        Some(id)
+    }
+}
+
+/// The `id` claim of the client's token, a random id for clients without one.
+pub struct FromToken;
+
+impl IdAllocator for FromToken {
+    fn allocate(&self, taken: &dyn Fn(i32) -> bool) -> Option<i32> {
+        Random.allocate(taken)
+    }
+
+    fn authenticated(&self, allocated: i32, token_id: Option<i32>) -> i32 {
+        token_id.unwrap_or(allocated)
+    }
+}
+
+/// The built-in allocator named `name` (`random`, `sequential` or `token`).
+pub fn parse(name: &str) -> Option<Arc<dyn IdAllocator>> {
+    match name {
+        "random" => Some(Arc::new(Random)),
+        "sequential" => Some(Arc::new(Sequential { next: Mutex::new(FIRST_ID) })),
+        "token" => Some(Arc::new(FromToken)),
+        _ => None
+    }
+}
@@ -0,0 +1,356 @@
+//! Journal of relayed packets (`journal`), so community servers can look up what was relayed after an
+//! abuse report. Every packet a client sends that is relayed (after the rate limit and the middleware
+//! chain) is appended to the current segment in `journal` as one line, in the format of the audit log:
+//! `2025-09-14T18:03:21Z id=12345 session=<uuid> addr=1.2.3.4:50000 size=42 payload=<hex>`.
+//!
+//! Segments are named after the time they were started and only ever appended to. A new one is started
+//! once the current one is `journal_rotate_size` bytes large or `journal_rotate_interval` seconds old,
+//! the closed one is compressed to `.gz` with `journal_compress` and the oldest beyond `journal_retention`
+//! are deleted. Lines are handed to a thread of their own and written out at least every `FLUSH`, the
+//! broadcast never waits for the disk: while the queue is full, packets are not journaled and counted in
+//! `journal_drops`.
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use crate::gzip;
+use crate::logging::debug;
+use crate::logging::error;
+use crate::logging::format_time;
+use crate::logging::info;
+use crate::metrics::Metrics;
+
+/// Packets waiting to be written, more are dropped.
+const QUEUE: usize = 65536;
+/// Longest time a journaled packet waits in the write buffer.
+const FLUSH: Duration = Duration::from_secs(1);
+const PREFIX: &str = "journal-";
+
+/// Segments and their limits, see `Journal::start`.
+#[derive(Clone)]
+pub struct Settings {
+    pub directory: String,
+    /// Bytes after which a new segment is started, 0 = no limit.
+    pub segment_size: u64,
+    /// Age after which a new segment is started, zero = no limit.
+    pub segment_interval: Duration,
+    /// Closed segments kept, the oldest are deleted beyond it, 0 = all.
+    pub retention: usize,
+    /// Whether closed segments are compressed.
+    pub compress: bool
+}
+
+enum Message {
+    Entry(Entry),
+    /// Write everything out and confirm it, see `Journal::flush`.
+    Flush(mpsc::Sender<()>)
+}
+
+/// A relayed packet on its way to the journal.
+struct Entry {
+    time: SystemTime,
+    id: i32,
+    session: String,
+    addr: SocketAddr,
+    frame: Arc<[u8]>
+}
+
+pub struct Journal {
+    queue: mpsc::SyncSender<Message>,
+    metrics: Arc<Metrics>
+}
+
+impl Journal {
+    /// Opens a new segment in the journal directory (creating it) and starts the threads writing to it and
+    /// compressing the closed ones, they end with the process. Closed segments from earlier runs are compressed
+    /// now if they weren't.
+    pub fn start(settings: Settings, metrics: Arc<Metrics>) -> io::Result<Journal> {
+        fs::create_dir_all(&settings.directory)?;
+        let mut segment = Segment::open(&settings)?;
+        let closed = match settings.compress {
+            true => Some(compressor(settings.clone())?),
+            false => None
+        };
+        let leftover: Vec<PathBuf> = segments(&settings.directory)?.into_iter().filter(|p| p.extension().is_some_and(|e| e == "log") && *p != segment.path).collect();
+        match &closed {
+            Some(closed) if !leftover.is_empty() => for path in leftover { let _ = closed.send(path); },
+            _ => prune(&settings)
+        }
+        info!("Journaling relayed packets to {}.", segment.path.display());
+
+        let (queue, messages) = mpsc::sync_channel::<Message>(QUEUE);
+        thread::Builder::new().name("journal".to_string()).spawn(move || {
+            loop {
+                let entry = match messages.recv_timeout(FLUSH) {
+                    Ok(Message::Entry(entry)) => entry,
+                    Ok(Message::Flush(done)) => {
+                        segment.flush();
+                        let _ = done.send(());
+                        continue;
+                    },
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        segment.flush();
+                        continue;
+                    },
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break
+                };
+                if segment.full(&settings) {
+                    match Segment::open(&settings) {
+                        Ok(next) => {
+                            let mut full = std::mem::replace(&mut segment, next);
+                            full.flush();
+                            debug!("Closed journal segment {}.", full.path.display());
+                            match &closed {
+                                Some(closed) => { let _ = closed.send(full.path); },
+                                None => prune(&settings)
+                            }
+                        },
+                        Err(e) => error!("Could not start a new journal segment in {} ({}), continuing in {}!", settings.directory, e, segment.path.display())
+                    }
+                }
+                segment.append(&entry);
+            }
+            segment.flush();
+        })?;
+        Ok(Journal { queue, metrics })
+    }
+
+    /// Journals a frame relayed from client `id`.
+    pub fn record(&self, id: i32, session: &str, addr: SocketAddr, frame: &Arc<[u8]>) {
+        let entry = Entry { time: SystemTime::now(), id, session: session.to_string(), addr, frame: Arc::clone(frame) };
+        if self.queue.try_send(Message::Entry(entry)).is_err() { Metrics::add(&self.metrics.journal_drops, 1); }
+    }
+
+    /// Writes out the packets journaled so far, waiting at most a second, called on shutdown.
+    pub fn flush(&self) {
+        let (done, flushed) = mpsc::channel();
+        if self.queue.send(Message::Flush(done)).is_ok() { let _ = flushed.recv_timeout(Duration::from_secs(1)); }
+    }
+}
+
+/// The segment being written.
+struct Segment {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    opened: Instant
+}
+
+impl Segment {
+    /// Creates a new segment named after the current time.
+    fn open(settings: &Settings) -> io::Result<Segment> {
+        let name = format!("{}{}", PREFIX, format_time(SystemTime::now()).replace(':', "-"));
+        let directory = Path::new(&settings.directory);
+        let numbered = |n: u32| match n {
+            0 => directory.join(format!("{}.log", name)),
+            n => directory.join(format!("{}-{}.log", name, n))
+        };
+        // other segments started within the same second, the new one comes after them even if the first were deleted
+        let mut n = match segments(&settings.directory)?.last().map(|p| order(p)) {
+            Some((time, last)) if time == name => last + 1,
+            _ => 0
+        };
+        let mut path = numbered(n);
+        while path.exists() || path.with_extension("log.gz").exists() {
+            n += 1;
+            path = numbered(n);
+        }
+        let file = OpenOptions::new().create_new(true).append(true).open(&path)?;
+        Ok(Segment { path, file: BufWriter::new(file), size: 0, opened: Instant::now() })
+    }
+
+    fn full(&self, settings: &Settings) -> bool {
+        settings.segment_size != 0 && self.size >= settings.segment_size
+            || !settings.segment_interval.is_zero() && self.opened.elapsed() >= settings.segment_interval
+    }
+
+    fn append(&mut self, entry: &Entry) {
+        let payload = entry.frame.get(4..).unwrap_or_default();
+        let mut line = format!("{} id={} session={} addr={} size={} payload=", format_time(entry.time), entry.id, entry.session, entry.addr, entry.frame.len());
+        for byte in payload { line.push_str(&format!("{:02x}", byte)); }
+        line.push('\n');
+
+        match self.file.write_all(line.as_bytes()) {
+            Ok(()) => self.size += line.len() as u64,
+            Err(e) => error!("Could not write to the journal {} ({})!", self.path.display(), e)
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.file.flush() { error!("Could not write to the journal {} ({})!", self.path.display(), e); }
+    }
+}
+
+/// The segments in `directory`, oldest first.
+fn segments(directory: &str) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory)?.flatten().map(|e| e.path())
+        .filter(|p| p.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with(PREFIX) && (n.ends_with(".log") || n.ends_with(".log.gz"))))
+        .collect();
+    paths.sort_by_cached_key(|p| order(p));
+    Ok(paths)
+}
+
+/// Sorts segments by the time they were started, then by the number of the ones started within the same second.
+fn order(path: &Path) -> (String, u32) {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let name = name.trim_end_matches(".gz").trim_end_matches(".log");
+    match name.rsplit_once("Z-").and_then(|(time, n)| Some((time, n.parse().ok()?))) {
+        Some((time, n)) => (format!("{}Z", time), n),
+        None => (name.to_string(), 0)
+    }
+}
+
+/// Starts the thread that compresses each closed segment sent to it to `<segment>.gz`, deletes it once that is
+/// complete and then the oldest beyond the retention, one after the other so they can't get in each other's way.
+fn compressor(settings: Settings) -> io::Result<mpsc::Sender<PathBuf>> {
+    let (closed, paths) = mpsc::channel::<PathBuf>();
+    thread::Builder::new().name("journal-gzip".to_string()).spawn(move || {
+        for path in paths {
+            let compressed = path.with_extension("log.gz");
+            let partial = path.with_extension("log.gz.part");
+            let result = fs::read(&path).and_then(|data| fs::write(&partial, gzip::compress(&data))).and_then(|_| fs::rename(&partial, &compressed));
+            match result {
+                Ok(()) => {
+                    if let Err(e) = fs::remove_file(&path) { error!("Could not delete the compressed journal segment {} ({})!", path.display(), e); }
+                    debug!("Compressed journal segment {}.", compressed.display());
+                },
+                Err(e) => {
+                    let _ = fs::remove_file(&partial);
+                    error!("Could not compress the journal segment {} ({}), keeping it uncompressed!", path.display(), e);
+                }
+            }
+            prune(&settings);
+        }
+    })?;
+    Ok(closed)
+}
+
+/// Deletes the oldest closed segments beyond the retention, the current one (the newest) is not counted.
+fn prune(settings: &Settings) {
+    if settings.retention == 0 { return; }
+    let Ok(paths) = segments(&settings.directory) else { return; };
+    // a segment that was just compressed is there twice for a moment
+    let mut names: Vec<String> = paths.iter().filter_map(|p| p.to_str()).map(|p| p.trim_end_matches(".gz").to_string()).collect();
+    names.dedup();
+    let closed = names.len().saturating_sub(1);
+    for name in names.iter().take(closed.saturating_sub(settings.retention)) {
+        for path in [PathBuf::from(name), PathBuf::from(format!("{}.gz", name))] {
+            if path.exists() && let Err(e) = fs::remove_file(&path) { error!("Could not delete the old journal segment {} ({})!", path.display(), e); }
+        }
+        debug!("Deleted old journal segment {}.", name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(name: &str, segment_size: u64, retention: usize, compress: bool) -> Settings {
+        let directory = std::env::temp_dir().join(format!("echoserver-journal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        Settings { directory: directory.to_string_lossy().into_owned(), segment_size, segment_interval: Duration::ZERO, retention, compress }
+    }
+
+    fn frame(payload: &[u8]) -> Arc<[u8]> {
+        let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(payload);
+        frame.into()
+    }
+
+    fn names(settings: &Settings) -> Vec<String> {
+        segments(&settings.directory).unwrap().iter().map(|p| p.file_name().unwrap().to_string_lossy().into_owned()).collect()
+    }
+
+    /// Waits for the compressor, which works on its own, until `done` holds for the segment names.
+    fn wait(settings: &Settings, done: impl Fn(&[String]) -> bool) -> Vec<String> {
+        let started = Instant::now();
+        loop {
+            let names = names(settings);
+            if done(&names) || started.elapsed() > Duration::from_secs(5) { return names; }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn journals_relayed_packets() {
+        let settings = settings("lines", 0, 0, false);
+        let journal = Journal::start(settings.clone(), Arc::new(Metrics::default())).unwrap();
+        journal.record(7, "0f6a", "1.2.3.4:50000".parse().unwrap(), &frame(&[0, 1, 0xab]));
+        journal.flush();
+
+        let names = names(&settings);
+        assert_eq!(names.len(), 1);
+        let text = fs::read_to_string(Path::new(&settings.directory).join(&names[0])).unwrap();
+        assert!(text.ends_with(" id=7 session=0f6a addr=1.2.3.4:50000 size=7 payload=0001ab\n"), "{}", text);
+        fs::remove_dir_all(&settings.directory).unwrap();
+    }
+
+    #[test]
+    fn rotates_and_keeps_the_retention() {
+        let settings = settings("retention", 1, 2, false);
+        let journal = Journal::start(settings.clone(), Arc::new(Metrics::default())).unwrap();
+        for id in 0..5 { journal.record(id, "0f6a", "1.2.3.4:50000".parse().unwrap(), &frame(b"x")); }
+        journal.flush();
+
+        // every packet fills a segment, the current one is kept besides the two newest closed ones
+        let names = names(&settings);
+        assert_eq!(names.len(), 3, "{:?}", names);
+        let ids: Vec<String> = names.iter().map(|name| {
+            let text = fs::read_to_string(Path::new(&settings.directory).join(name)).unwrap();
+            text.split(' ').nth(1).unwrap().to_string()
+        }).collect();
+        assert_eq!(ids, ["id=2", "id=3", "id=4"], "{:?}", names);
+        fs::remove_dir_all(&settings.directory).unwrap();
+    }
+
+    #[test]
+    fn compresses_closed_segments() {
+        let settings = settings("compress", 1, 2, true);
+        let journal = Journal::start(settings.clone(), Arc::new(Metrics::default())).unwrap();
+        for id in 0..4 { journal.record(id, "0f6a", "1.2.3.4:50000".parse().unwrap(), &frame(b"x")); }
+        journal.flush();
+
+        let names = wait(&settings, |names| names.len() == 3 && names.iter().filter(|n| n.ends_with(".gz")).count() == 2);
+        assert!(names[0].ends_with(".log.gz") && names[1].ends_with(".log.gz") && names[2].ends_with(".log"), "{:?}", names);
+        let compressed = fs::read(Path::new(&settings.directory).join(&names[0])).unwrap();
+        assert_eq!(compressed[..2], [0x1f, 0x8b]);
+        assert!(!fs::read_dir(&settings.directory).unwrap().flatten().any(|e| e.path().extension().is_some_and(|e| e == "part")));
+        fs::remove_dir_all(&settings.directory).unwrap();
+    }
+
+    #[test]
+    fn compresses_what_an_earlier_run_left() {
+        let settings = settings("leftover", 0, 0, true);
+        fs::create_dir_all(&settings.directory).unwrap();
+        let leftover = Path::new(&settings.directory).join("journal-2025-09-14T18-03-21Z.log");
+        fs::write(&leftover, "line\n").unwrap();
+        let _journal = Journal::start(settings.clone(), Arc::new(Metrics::default())).unwrap();
+
+        let names = wait(&settings, |names| names[0].ends_with(".gz"));
+        assert_eq!(names[0], "journal-2025-09-14T18-03-21Z.log.gz");
+        assert_eq!(names.len(), 2);
+        fs::remove_dir_all(&settings.directory).unwrap();
+    }
+
+    #[test]
+    fn orders_segments_of_the_same_second() {
+        let mut paths: Vec<PathBuf> = ["journal-2025-09-14T18-03-22Z.log", "journal-2025-09-14T18-03-21Z-10.log.gz", "journal-2025-09-14T18-03-21Z-2.log.gz", "journal-2025-09-14T18-03-21Z.log.gz"]
+            .iter().map(PathBuf::from).collect();
+        paths.sort_by_cached_key(|p| order(p));
+        let names: Vec<&str> = paths.iter().map(|p| p.to_str().unwrap()).collect();
+        assert_eq!(names, ["journal-2025-09-14T18-03-21Z.log.gz", "journal-2025-09-14T18-03-21Z-2.log.gz", "journal-2025-09-14T18-03-21Z-10.log.gz", "journal-2025-09-14T18-03-22Z.log"]);
+    }
+}
@@ -0,0 +1,515 @@
+//! Publishing every relayed packet to a Kafka topic (`kafka_brokers`, `kafka_topic`) for analytics and
+//! replay pipelines. The relay only hands packets to a bounded queue; the kafka thread collects them for
+//! up to `LINGER`, sends them to the partition leaders in one produce request each and waits for the
+//! leaders' acknowledgement (`acks=1`). Packets that don't fit into the queue, e.g. while no broker is
+//! reachable, are dropped and counted in `kafka_drops`, the live broadcast never waits for Kafka.
+//!
+//! Records are keyed by the sender's ID, so each sender's packets stay in order on one partition, carry
+//! the time the packet was relayed as their timestamp and the packet's content (without its size) as
+//! their value. Only the plain text protocol without SASL is spoken: `Metadata` v4, `Produce` v3 and
+//! uncompressed v2 record batches, which every broker since 0.11 understands.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::logging::debug;
+use crate::logging::info;
+use crate::logging::warning;
+use crate::metrics::Metrics;
+
+/// Packets waiting for the kafka thread, more are dropped.
+const QUEUE: usize = 65536;
+/// How long packets are collected before they are sent.
+const LINGER: Duration = Duration::from_millis(100);
+/// Most bytes of records sent to one partition at once, below the brokers' default `message.max.bytes`.
+const MAX_BATCH: usize = 900_000;
+/// How long a broker gets to connect and to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before looking for the brokers again.
+const RETRY: Duration = Duration::from_secs(2);
+
+const API_PRODUCE: i16 = 0;
+const API_METADATA: i16 = 3;
+const CLIENT_ID: &str = "echoserver";
+
+/// A relayed packet waiting to be published.
+struct Record {
+    sender: i32,
+    /// Milliseconds since the epoch.
+    timestamp: i64,
+    /// The frame as relayed, the size in front is left out of the record.
+    frame: Arc<[u8]>
+}
+
+pub struct Kafka {
+    queue: mpsc::SyncSender<Record>,
+    metrics: Arc<Metrics>
+}
+
+impl Kafka {
+    /// Starts the kafka thread publishing to `topic` on the cluster of `brokers` (`host:port` separated by commas). It ends once `running` is cleared.
+    pub fn start(brokers: &str, topic: &str, metrics: Arc<Metrics>, running: Arc<AtomicBool>) -> io::Result<Kafka> {
+        let brokers: Vec<String> = brokers.split(',').map(str::trim).filter(|b| !b.is_empty()).map(str::to_string).collect();
+        if brokers.is_empty() { return Err(io::Error::new(ErrorKind::InvalidInput, "no kafka_brokers")); }
+        if topic.is_empty() || topic.len() > 249 || !topic.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid kafka_topic \"{}\"", topic)));
+        }
+
+        let (queue, receiver) = mpsc::sync_channel::<Record>(QUEUE);
+        let (sink_metrics, topic) = (Arc::clone(&metrics), topic.to_string());
+        thread::Builder::new().name("kafka".to_string()).spawn(move || {
+            Producer { brokers, topic, metrics: sink_metrics, cluster: None, correlation: 0 }.run(&receiver, &running);
+        })?;
+        Ok(Kafka { queue, metrics })
+    }
+
+    /// Queues a relayed frame from client `sender`, dropping it if the queue is full.
+    pub fn publish(&self, sender: i32, frame: &Arc<[u8]>) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64);
+        if self.queue.try_send(Record { sender, timestamp, frame: Arc::clone(frame) }).is_err() { Metrics::add(&self.metrics.kafka_drops, 1); }
+    }
+}
+
+/// Where the topic's partitions are led.
+struct Cluster {
+    /// The leader's node id of every partition, by partition index.
+    leaders: Vec<i32>,
+    /// Open connections by node id.
+    nodes: HashMap<i32, TcpStream>
+}
+
+/// The kafka thread's side.
+struct Producer {
+    brokers: Vec<String>,
+    topic: String,
+    metrics: Arc<Metrics>,
+    cluster: Option<Cluster>,
+    correlation: i32
+}
+
+impl Producer {
+    fn run(&mut self, receiver: &mpsc::Receiver<Record>, running: &AtomicBool) {
+        let mut pending: Vec<Record> = Vec::new();
+        let mut retry_at = Instant::now();
+        let mut reachable = true;
+        while running.load(Ordering::SeqCst) {
+            match receiver.recv_timeout(LINGER) {
+                Ok(record) => pending.push(record),
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return
+            }
+            let lingering = Instant::now() + LINGER;
+            while Instant::now() < lingering && let Ok(record) = receiver.recv_timeout(lingering.saturating_duration_since(Instant::now())) { pending.push(record); }
+
+            if self.cluster.is_none() && Instant::now() >= retry_at {
+                match self.connect() {
+                    Ok(cluster) => {
+                        info!("Publishing to Kafka topic {} ({} partition(s)).", self.topic, cluster.leaders.len());
+                        self.cluster = Some(cluster);
+                        reachable = true;
+                    },
+                    Err(e) if reachable => {
+                        warning!("Could not reach the Kafka brokers ({}), dropping packets until they are back!", e);
+                        (retry_at, reachable) = (Instant::now() + RETRY, false);
+                    },
+                    Err(e) => {
+                        debug!("Could not reach the Kafka brokers ({}), retrying.", e);
+                        retry_at = Instant::now() + RETRY;
+                    }
+                }
+            }
+            if self.cluster.is_some() && let Err(e) = self.produce(&pending) {
+                warning!("Could not publish {} packet(s) to Kafka ({}), looking up the brokers again!", pending.len(), e);
+                self.cluster = None;
+            }
+            if self.cluster.is_none() { Metrics::add(&self.metrics.kafka_drops, pending.len() as u64); }
+            pending.clear();
+        }
+    }
+
+    /// Looks up the topic's partition leaders on the first broker that answers and connects to them.
+    fn connect(&mut self) -> io::Result<Cluster> {
+        let mut last_error = io::Error::new(ErrorKind::NotFound, "no broker answered");
+        for broker in self.brokers.clone() {
+            match self.metadata(&broker) {
+                Ok((nodes, leaders)) => {
+                    let mut cluster = Cluster { leaders, nodes: HashMap::new() };
+                    for leader in cluster.leaders.clone() {
+                        if cluster.nodes.contains_key(&leader) { continue; }
+                        let address = nodes.get(&leader).ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("unknown leader {}", leader)))?;
+                        cluster.nodes.insert(leader, open(address)?);
+                    }
+                    return Ok(cluster);
+                },
+                Err(e) => {
+                    debug!("Kafka broker {} did not answer ({}).", broker, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Sends a `Metadata` request for the topic, creating it if the brokers allow it. Returns the addresses of all nodes and the leader of every partition.
+    fn metadata(&mut self, broker: &str) -> io::Result<(HashMap<i32, String>, Vec<i32>)> {
+        let mut stream = open(broker)?;
+        let mut body = Vec::new();
+        body.extend_from_slice(&1i32.to_be_bytes());
+        put_string(&mut body, &self.topic);
+        body.push(1); // allow_auto_topic_creation
+        let mut response = self.request(&mut stream, API_METADATA, 4, &body)?;
+
+        response.i32()?; // throttle_time_ms
+        let mut nodes = HashMap::new();
+        for _ in 0..response.count()? {
+            let (node, host, port) = (response.i32()?, response.string()?, response.i32()?);
+            response.string()?; // rack
+            nodes.insert(node, format!("{}:{}", host, port));
+        }
+        response.string()?; // cluster_id
+        response.i32()?; // controller_id
+
+        for _ in 0..response.count()? {
+            let (error, name) = (response.i16()?, response.string()?);
+            response.bytes(1)?; // is_internal
+            let mut leaders = Vec::new();
+            for _ in 0..response.count()? {
+                let (partition_error, partition, leader) = (response.i16()?, response.i32()?, response.i32()?);
+                for _ in 0..2 { // replica_nodes, isr_nodes
+                    let count = response.count()?;
+                    response.bytes(count * 4)?;
+                }
+                if partition_error != 0 && partition_error != 9 { return Err(kafka_error("partition", partition_error)); } // 9 = a replica is missing, the leader works
+                if leader < 0 { return Err(io::Error::new(ErrorKind::NotFound, format!("partition {} has no leader", partition))); }
+                leaders.push((partition, leader));
+            }
+            if name != self.topic { continue; }
+            if error != 0 { return Err(kafka_error("topic", error)); }
+            if leaders.is_empty() { return Err(io::Error::new(ErrorKind::NotFound, "the topic has no partitions")); }
+            leaders.sort();
+            return Ok((nodes, leaders.into_iter().map(|(_, leader)| leader).collect()));
+        }
+        Err(io::Error::new(ErrorKind::NotFound, "the topic is missing"))
+    }
+
+    /// Sends `records` to their partitions' leaders, one produce request for each.
+    fn produce(&mut self, records: &[Record]) -> io::Result<()> {
+        let Some(cluster) = self.cluster.as_ref() else { return Err(ErrorKind::NotConnected.into()) };
+        let partitions = cluster.leaders.len() as i32;
+        let mut by_partition: HashMap<i32, Vec<&Record>> = HashMap::new();
+        for record in records.iter() { by_partition.entry(record.sender.rem_euclid(partitions)).or_default().push(record); }
+
+        let mut by_leader: HashMap<i32, Vec<(i32, Vec<u8>)>> = HashMap::new();
+        for (partition, records) in by_partition {
+            let mut batch_start = 0;
+            let mut size = 0;
+            for (i, record) in records.iter().enumerate() {
+                size += record.frame.len() + 32;
+                if size > MAX_BATCH && i > batch_start {
+                    by_leader.entry(cluster.leaders[partition as usize]).or_default().push((partition, record_batch(&records[batch_start..i])));
+                    (batch_start, size) = (i, record.frame.len() + 32);
+                }
+            }
+            by_leader.entry(cluster.leaders[partition as usize]).or_default().push((partition, record_batch(&records[batch_start..])));
+        }
+
+        for (leader, batches) in by_leader {
+            // a partition listed more than once is fine, each batch is appended in order
+            let mut body = Vec::new();
+            body.extend_from_slice(&(-1i16).to_be_bytes()); // transactional_id = null
+            body.extend_from_slice(&1i16.to_be_bytes()); // acks
+            body.extend_from_slice(&(TIMEOUT.as_millis() as i32).to_be_bytes());
+            body.extend_from_slice(&1i32.to_be_bytes());
+            put_string(&mut body, &self.topic);
+            body.extend_from_slice(&(batches.len() as i32).to_be_bytes());
+            for (partition, batch) in &batches {
+                body.extend_from_slice(&partition.to_be_bytes());
+                body.extend_from_slice(&(batch.len() as i32).to_be_bytes());
+                body.extend_from_slice(batch);
+            }
+
+            let mut stream = match self.cluster.as_mut().and_then(|c| c.nodes.remove(&leader)) {
+                Some(stream) => stream,
+                None => return Err(ErrorKind::NotConnected.into())
+            };
+            let mut response = self.request(&mut stream, API_PRODUCE, 3, &body)?;
+            if let Some(cluster) = self.cluster.as_mut() { cluster.nodes.insert(leader, stream); }
+
+            for _ in 0..response.count()? {
+                response.string()?;
+                for _ in 0..response.count()? {
+                    let (partition, error) = (response.i32()?, response.i16()?);
+                    response.bytes(16)?; // base_offset, log_append_time_ms
+                    if error != 0 { return Err(kafka_error(&format!("partition {}", partition), error)); }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a request and reads its response, returning what follows the correlation id.
+    fn request(&mut self, stream: &mut TcpStream, api: i16, version: i16, body: &[u8]) -> io::Result<Response> {
+        self.correlation = self.correlation.wrapping_add(1);
+        let mut message = Vec::with_capacity(14 + CLIENT_ID.len() + body.len());
+        message.extend_from_slice(&((10 + CLIENT_ID.len() + body.len()) as i32).to_be_bytes());
+        message.extend_from_slice(&api.to_be_bytes());
+        message.extend_from_slice(&version.to_be_bytes());
+        message.extend_from_slice(&self.correlation.to_be_bytes());
+        put_string(&mut message, CLIENT_ID);
+        message.extend_from_slice(body);
+        stream.write_all(&message)?;
+
+        let mut size = [0u8; 4];
+        stream.read_exact(&mut size)?;
+        let size = i32::from_be_bytes(size);
+        if !(4..=64 * 1024 * 1024).contains(&size) { return Err(io::Error::new(ErrorKind::InvalidData, format!("invalid response size {}", size))); }
+        let mut response = Response { data: vec![0u8; size as usize], at: 0 };
+        stream.read_exact(&mut response.data)?;
+        if response.i32()? != self.correlation { return Err(io::Error::new(ErrorKind::InvalidData, "response to another request")); }
+        Ok(response)
+    }
+}
+
+/// A response being parsed, every read fails once it runs out.
+struct Response {
+    data: Vec<u8>,
+    at: usize
+}
+
+impl Response {
+    fn bytes(&mut self, n: usize) -> io::Result<&[u8]> {
+        let end = self.at.checked_add(n).filter(|end| *end <= self.data.len()).ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "truncated response"))?;
+        let bytes = &self.data[self.at..end];
+        self.at = end;
+        Ok(bytes)
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_be_bytes(self.bytes(2)?.try_into().unwrap_or_default()))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.bytes(4)?.try_into().unwrap_or_default()))
+    }
+
+    /// An array's length, null arrays are empty.
+    fn count(&mut self) -> io::Result<usize> {
+        Ok(self.i32()?.max(0) as usize)
+    }
+
+    /// A nullable string, null is empty.
+    fn string(&mut self) -> io::Result<String> {
+        let length = self.i16()?.max(0) as usize;
+        Ok(String::from_utf8_lossy(self.bytes(length)?).into_owned())
+    }
+}
+
+fn open(address: &str) -> io::Result<TcpStream> {
+    let address = address.to_socket_addrs()?.next().ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("{} does not resolve", address)))?;
+    let stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    let _ = stream.set_nodelay(true);
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+fn kafka_error(what: &str, code: i16) -> io::Error {
+    io::Error::other(format!("{} error code {}", what, code))
+}
+
+fn put_string(buffer: &mut Vec<u8>, s: &str) {
+    buffer.extend_from_slice(&(s.len() as i16).to_be_bytes());
+    buffer.extend_from_slice(s.as_bytes());
+}
+
+/// Zigzag varint, as record fields are encoded.
+fn put_varint(buffer: &mut Vec<u8>, value: i64) {
+    let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    while zigzag >= 0x80 {
+        buffer.push((zigzag as u8) | 0x80);
+        zigzag >>= 7;
+    }
+    buffer.push(zigzag as u8);
+}
+
+/// An uncompressed v2 record batch of `records`.
+fn record_batch(records: &[&Record]) -> Vec<u8> {
+    let base = records.first().map_or(0, |r| r.timestamp);
+    let mut body = Vec::new();
+    body.extend_from_slice(&0i16.to_be_bytes()); // attributes: no compression, create time
+    body.extend_from_slice(&(records.len() as i32 - 1).to_be_bytes()); // last_offset_delta
+    body.extend_from_slice(&base.to_be_bytes());
+    body.extend_from_slice(&records.iter().map(|r| r.timestamp).max().unwrap_or(base).to_be_bytes());
+    body.extend_from_slice(&(-1i64).to_be_bytes()); // producer_id
+    body.extend_from_slice(&(-1i16).to_be_bytes()); // producer_epoch
+    body.extend_from_slice(&(-1i32).to_be_bytes()); // base_sequence
+    body.extend_from_slice(&(records.len() as i32).to_be_bytes());
+
+    let mut record = Vec::new();
+    for (offset, r) in records.iter().enumerate() {
+        let key = r.sender.to_string();
+        let value = r.frame.get(4..).unwrap_or_default();
+        record.clear();
+        record.push(0); // attributes
+        put_varint(&mut record, r.timestamp - base);
+        put_varint(&mut record, offset as i64);
+        put_varint(&mut record, key.len() as i64);
+        record.extend_from_slice(key.as_bytes());
+        put_varint(&mut record, value.len() as i64);
+        record.extend_from_slice(value);
+        put_varint(&mut record, 0); // headers
+        put_varint(&mut body, record.len() as i64);
+        body.extend_from_slice(&record);
+    }
+
+    let mut batch = Vec::with_capacity(21 + body.len());
+    batch.extend_from_slice(&0i64.to_be_bytes()); // base_offset
+    batch.extend_from_slice(&((9 + body.len()) as i32).to_be_bytes()); // batch_length, after this field
+    batch.extend_from_slice(&(-1i32).to_be_bytes()); // partition_leader_epoch
+    batch.push(2); // magic
+    batch.extend_from_slice(&crc32c(&body).to_be_bytes());
+    batch.extend_from_slice(&body);
+    batch
+}
+
+/// CRC-32C (Castagnoli), the checksum of v2 record batches.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 { crc = (crc >> 1) ^ (0x82F6_3B78 & (crc & 1).wrapping_neg()); }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(value: i64) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        put_varint(&mut buffer, value);
+        buffer
+    }
+
+    /// Reads a zigzag varint at `at`, moving past it.
+    fn read_varint(data: &[u8], at: &mut usize) -> i64 {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = data[*at];
+            *at += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 { return (value >> 1) as i64 ^ -((value & 1) as i64); }
+            shift += 7;
+        }
+    }
+
+    fn record(sender: i32, timestamp: i64, payload: &[u8]) -> Record {
+        let mut frame = ((payload.len() + 4) as i32).to_le_bytes().to_vec();
+        frame.extend_from_slice(payload);
+        Record { sender, timestamp, frame: frame.into() }
+    }
+
+    #[test]
+    fn crc32c_known_answers() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[0; 32]), 0x8a91_36aa);
+    }
+
+    #[test]
+    fn zigzag_varints() {
+        assert_eq!(varint(0), [0x00]);
+        assert_eq!(varint(-1), [0x01]);
+        assert_eq!(varint(1), [0x02]);
+        assert_eq!(varint(63), [0x7e]);
+        assert_eq!(varint(-64), [0x7f]);
+        assert_eq!(varint(64), [0x80, 0x01]);
+        assert_eq!(varint(-65), [0x81, 0x01]);
+        assert_eq!(varint(150), [0xac, 0x02]);
+        for value in [i64::MIN, i64::MIN + 1, -300, 300, i64::MAX] {
+            assert_eq!(read_varint(&varint(value), &mut 0), value);
+        }
+        assert_eq!(varint(i64::MIN).len(), 10);
+    }
+
+    #[test]
+    fn record_batches() {
+        let (first, second) = (record(7, 1_700_000_000_000, b"hello"), record(42, 1_700_000_000_250, b""));
+        let batch = record_batch(&[&first, &second]);
+
+        assert_eq!(batch[..8], 0i64.to_be_bytes());
+        let length = i32::from_be_bytes(batch[8..12].try_into().unwrap()) as usize;
+        assert_eq!(length, batch.len() - 12, "batch_length counts everything after it");
+        assert_eq!(batch[12..16], (-1i32).to_be_bytes());
+        assert_eq!(batch[16], 2);
+        // the checksum covers everything after it
+        assert_eq!(batch[17..21], crc32c(&batch[21..]).to_be_bytes());
+
+        let body = &batch[21..];
+        assert_eq!(body[..2], 0i16.to_be_bytes());
+        assert_eq!(body[2..6], 1i32.to_be_bytes());
+        assert_eq!(body[6..14], 1_700_000_000_000i64.to_be_bytes());
+        assert_eq!(body[14..22], 1_700_000_000_250i64.to_be_bytes());
+        assert_eq!(body[22..36], [0xff; 14]);
+        assert_eq!(body[36..40], 2i32.to_be_bytes());
+
+        let mut at = 40;
+        for (offset, (sender, delta, value)) in [("7", 0, &b"hello"[..]), ("42", 250, &b""[..])].into_iter().enumerate() {
+            let length = read_varint(body, &mut at) as usize;
+            let end = at + length;
+            assert_eq!(body[at], 0);
+            at += 1;
+            assert_eq!(read_varint(body, &mut at), delta);
+            assert_eq!(read_varint(body, &mut at), offset as i64);
+            let key = read_varint(body, &mut at) as usize;
+            assert_eq!(&body[at..at + key], sender.as_bytes());
+            at += key;
+            let size = read_varint(body, &mut at) as usize;
+            assert_eq!(&body[at..at + size], value);
+            at += size;
+            assert_eq!(read_varint(body, &mut at), 0);
+            assert_eq!(at, end);
+        }
+        assert_eq!(at, body.len());
+    }
+
+    #[test]
+    fn responses_fail_on_truncated_data() {
+        let mut data = 7i32.to_be_bytes().to_vec();
+        data.extend_from_slice(&(-3i16).to_be_bytes());
+        data.extend_from_slice(&5i16.to_be_bytes());
+        data.extend_from_slice(b"top");
+        let mut response = Response { data, at: 0 };
+        assert_eq!(response.i32().unwrap(), 7);
+        // a null string is empty
+        assert_eq!(response.string().unwrap(), "");
+        assert_eq!(response.string().unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let mut response = Response { data: vec![0, 0, 1], at: 0 };
+        assert!(response.i32().is_err());
+        assert_eq!(response.i16().unwrap(), 0);
+        assert!(response.i16().is_err());
+        assert!(response.bytes(usize::MAX).is_err());
+        assert_eq!(response.bytes(1).unwrap(), [1]);
+        assert!(response.count().is_err());
+
+        let mut response = Response { data: [(-1i32).to_be_bytes(), 3i32.to_be_bytes()].concat(), at: 0 };
+        assert_eq!(response.count().unwrap(), 0);
+        assert_eq!(response.count().unwrap(), 3);
+    }
+}
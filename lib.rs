@@ -0,0 +1,2238 @@
+//! A TCP relay for game clients: every length-prefixed packet a client sends is passed on to everyone
+//! else connected. `echoserver` (`main.rs`) is a thin binary around `Server`, which can be embedded the
+//! same way, see the README.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::ErrorKind;
+use std::io::IsTerminal;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use regex::Regex;
+
+mod admin;
+mod affinity;
+mod allocator;
+mod api;
+mod audit;
+mod auth;
+mod bans;
+mod builder;
+mod chaos;
+mod client;
+pub mod clock;
+pub mod codec;
+pub mod control;
+mod crash;
+mod discovery;
+mod drain;
+mod events;
+#[cfg(unix)]
+mod event_loop;
+mod fanout;
+mod federation;
+mod filter;
+mod handle;
+mod http;
+mod gzip;
+mod ids;
+mod journal;
+mod kafka;
+pub mod logging;
+mod metrics;
+mod middleware;
+mod nats;
+mod otlp;
+#[cfg(unix)]
+mod privileges;
+mod random;
+mod ratelimit;
+pub mod recording;
+mod registry;
+mod resume;
+mod router;
+mod standby;
+mod state;
+mod statsd;
+mod storage;
+#[cfg(feature = "tls-psk")]
+mod tls;
+mod trace;
+pub mod transport;
+#[cfg(target_os = "linux")]
+mod uring;
+
+use logging::debug;
+use logging::error;
+use logging::info;
+use logging::warning;
+use metrics::DisconnectReason;
+use metrics::Metrics;
+use metrics::Traffic;
+use middleware::Stage;
+use otlp::Value;
+use bans::AccessList;
+use bans::BanList;
+use codec::Codec;
+use events::Hooks;
+use events::Listener;
+use chaos::Chaos;
+use fanout::Fanout;
+use federation::Federation;
+use filter::FilterList;
+use journal::Journal;
+use kafka::Kafka;
+use nats::Nats;
+use registry::Client;
+use registry::ClientMeta;
+use registry::Outbox;
+use registry::Outgoing;
+use registry::QueuePolicy;
+use registry::SharedConnections;
+use resume::Resume;
+use resume::Retention;
+use router::Router;
+use state::IpSlot;
+use state::RatePolicy;
+use state::SharedState;
+use state::State;
+
+pub use builder::ServerBuilder;
+pub use client::EchoClient;
+pub use client::Packet;
+pub use events::ClientInfo;
+pub use events::EventHandler;
+pub use events::Verdict;
+pub use handle::ClientStatus;
+pub use handle::ServerHandle;
+pub use metrics::MetricsSnapshot;
+pub use ids::IdAllocator;
+pub use ratelimit::ClientRateLimiter;
+pub use ratelimit::Limits;
+pub use ratelimit::RateLimiter;
+pub use middleware::Flow;
+pub use middleware::Middleware;
+pub use storage::Storage;
+
+/// Largest frame (size prefix included) a client may send.
+pub const BUFFER_SIZE: usize = 2048;
+/// Most bytes of queued frames written to a client at once.
+const COALESCE_BYTES: usize = 16 * 1024;
+
+/// Packets over the rate limit count towards `auto_ban_drops` and `rate_violations` for this long.
+const VIOLATION_WINDOW: Duration = Duration::from_secs(10);
+
+/// Longest a client thread holds a packet while `State::memory_pressure` applies before shedding it.
+const MEMORY_WAIT: Duration = Duration::from_secs(1);
+
+/// Reads wake up this often to check whether the server is still running.
+const READ_TIMEOUT: Duration = Duration::from_millis(5000);
+
+/// Every setting of the server, see `config.yaml` and the README for what they do.
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub port: i32,
+    pub unix_socket: String,
+    pub codec: String,
+    pub unix_socket_codec: String,
+    pub mirror: bool,
+    pub max_players: i32,
+    pub reserved_slots: i32,
+    pub max_per_ip: i32,
+    pub accept_rate: i32,
+    pub accept_burst: i32,
+    pub max_packets: i32,
+    pub rate_burst: i32,
+    pub rate_policy: String,
+    pub rate_violations: i32,
+    pub auto_ban: i32,
+    pub auto_ban_drops: i32,
+    pub max_rate: i32,
+    pub total_rate: i32,
+    pub debug_print: bool,
+    pub log_file: String,
+    pub log_rotate_size: i32,
+    pub log_rotate_interval: i32,
+    pub log_retention: i32,
+    pub metrics_port: i32,
+    pub statsd_address: String,
+    pub statsd_prefix: String,
+    pub statsd_interval: i32,
+    pub statsd_tags: bool,
+    pub stats_interval: i32,
+    pub admin_address: String,
+    pub admin_port: i32,
+    pub otlp_endpoint: String,
+    pub otlp_service_name: String,
+    pub otlp_sample_percent: i32,
+    pub audit_log: String,
+    pub record: String,
+    pub journal: String,
+    pub journal_rotate_size: i32,
+    pub journal_rotate_interval: i32,
+    pub journal_retention: i32,
+    pub journal_compress: bool,
+    pub health_port: i32,
+    pub slow_client_ms: i32,
+    pub handshake_timeout: i32,
+    pub io_mode: String,
+    pub io_threads: i32,
+    pub accept_cpu: i32,
+    pub worker_cpus: String,
+    pub fanout_threads: i32,
+    pub fanout_min: i32,
+    pub chaos_delay: i32,
+    pub chaos_jitter: i32,
+    pub chaos_reorder_percent: i32,
+    pub chaos_loss_percent: i32,
+    pub federation_port: i32,
+    pub federation_peers: String,
+    pub federation_secret: String,
+    pub federation_discovery: String,
+    pub nats_url: String,
+    pub nats_subject: String,
+    pub kafka_brokers: String,
+    pub kafka_topic: String,
+    pub standby_port: i32,
+    pub standby_of: String,
+    pub standby_secret: String,
+    pub coalesce_ms: i32,
+    pub send_queue_limit: i32,
+    pub send_queue_policy: String,
+    pub memory_limit: i32,
+    pub write_timeout: i32,
+    pub resume_grace: i32,
+    pub resume_buffer: i32,
+    pub resume_packets: i32,
+    pub resume_max_age: i32,
+    pub control_packets: bool,
+    pub syslog: String,
+    pub syslog_facility: String,
+    pub trace_packets: i32,
+    pub log_level: String,
+    pub crash_dump: String,
+    pub api_address: String,
+    pub api_port: i32,
+    pub api_token: String,
+    pub storage: String,
+    pub ban_file: String,
+    pub filter_file: String,
+    pub middleware: String,
+    pub drain_timeout: i32,
+    pub shutdown_timeout: i32,
+    pub stdin_console: bool,
+    pub control_socket: String,
+    pub control_socket_mode: String,
+    pub user: String,
+    pub group: String,
+    pub secret: String,
+    pub auth_challenge: bool,
+    pub token_secret: String,
+    pub id_allocator: String,
+    pub seed: i32,
+    pub rate_limiter: String,
+    pub allow: String,
+    pub deny: String,
+    pub tls_psk: String,
+    pub tls_psk_identity: String
+}
+
+fn read_config_from_args(config: &mut ServerConfig) {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    for arg in &args {
+        if arg == "--no-mirror" {
+            config.mirror = false;
+        } else if arg == "--debug" {
+            config.debug_print = true;
+        } else if let Some(v) = arg.strip_prefix("--log-level=") {
+            config.log_level = v.to_string();
+        } else if let Ok(p) = arg.parse::<i32>() {
+            config.port = p;
+        } else if let Some(v) = arg.strip_prefix("--max-players=") && let Ok(n) = v.parse::<i32>() {
+            config.max_players = n;
+        } else if let Some(v) = arg.strip_prefix("--accept-rate=") && let Ok(n) = v.parse::<i32>() {
+            config.accept_rate = n;
+        } else if let Some(v) = arg.strip_prefix("--accept-burst=") && let Ok(n) = v.parse::<i32>() {
+            config.accept_burst = n;
+        } else if let Some(v) = arg.strip_prefix("--reserved-slots=") && let Ok(n) = v.parse::<i32>() {
+            config.reserved_slots = n;
+        } else if let Some(v) = arg.strip_prefix("--max-per-ip=") && let Ok(n) = v.parse::<i32>() {
+            config.max_per_ip = n;
+        } else if let Some(v) = arg.strip_prefix("--auto-ban=") && let Ok(n) = v.parse::<i32>() {
+            config.auto_ban = n;
+        } else if let Some(v) = arg.strip_prefix("--auto-ban-drops=") && let Ok(n) = v.parse::<i32>() {
+            config.auto_ban_drops = n;
+        } else if let Some(v) = arg.strip_prefix("--max-packets=") && let Ok(n) = v.parse::<i32>() {
+            config.max_packets = n;
+        } else if let Some(v) = arg.strip_prefix("--rate-burst=") && let Ok(n) = v.parse::<i32>() {
+            config.rate_burst = n;
+        } else if let Some(v) = arg.strip_prefix("--rate-limiter=") {
+            config.rate_limiter = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--rate-policy=") {
+            config.rate_policy = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--rate-violations=") && let Ok(n) = v.parse::<i32>() {
+            config.rate_violations = n;
+        } else if let Some(v) = arg.strip_prefix("--io-mode=") {
+            config.io_mode = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--io-threads=") && let Ok(n) = v.parse::<i32>() {
+            config.io_threads = n;
+        } else if let Some(v) = arg.strip_prefix("--accept-cpu=") && let Ok(n) = v.parse::<i32>() {
+            config.accept_cpu = n;
+        } else if let Some(v) = arg.strip_prefix("--worker-cpus=") {
+            config.worker_cpus = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--fanout-threads=") && let Ok(n) = v.parse::<i32>() {
+            config.fanout_threads = n;
+        } else if let Some(v) = arg.strip_prefix("--fanout-min=") && let Ok(n) = v.parse::<i32>() {
+            config.fanout_min = n;
+        } else if let Some(v) = arg.strip_prefix("--chaos-delay=") && let Ok(n) = v.parse::<i32>() {
+            config.chaos_delay = n;
+        } else if let Some(v) = arg.strip_prefix("--chaos-jitter=") && let Ok(n) = v.parse::<i32>() {
+            config.chaos_jitter = n;
+        } else if let Some(v) = arg.strip_prefix("--chaos-reorder-percent=") && let Ok(n) = v.parse::<i32>() {
+            config.chaos_reorder_percent = n;
+        } else if let Some(v) = arg.strip_prefix("--chaos-loss-percent=") && let Ok(n) = v.parse::<i32>() {
+            config.chaos_loss_percent = n;
+        } else if let Some(v) = arg.strip_prefix("--federation-port=") && let Ok(n) = v.parse::<i32>() {
+            config.federation_port = n;
+        } else if let Some(v) = arg.strip_prefix("--federation-peers=") {
+            config.federation_peers = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--federation-secret=") {
+            config.federation_secret = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--federation-discovery=") {
+            config.federation_discovery = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--nats-url=") {
+            config.nats_url = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--nats-subject=") {
+            config.nats_subject = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--kafka-brokers=") {
+            config.kafka_brokers = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--kafka-topic=") {
+            config.kafka_topic = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--standby-port=") && let Ok(n) = v.parse::<i32>() {
+            config.standby_port = n;
+        } else if let Some(v) = arg.strip_prefix("--standby-of=") {
+            config.standby_of = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--standby-secret=") {
+            config.standby_secret = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--send-queue-limit=") && let Ok(n) = v.parse::<i32>() {
+            config.send_queue_limit = n;
+        } else if let Some(v) = arg.strip_prefix("--send-queue-policy=") {
+            config.send_queue_policy = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--memory-limit=") && let Ok(n) = v.parse::<i32>() {
+            config.memory_limit = n;
+        } else if let Some(v) = arg.strip_prefix("--write-timeout=") && let Ok(n) = v.parse::<i32>() {
+            config.write_timeout = n;
+        } else if let Some(v) = arg.strip_prefix("--resume-grace=") && let Ok(n) = v.parse::<i32>() {
+            config.resume_grace = n;
+        } else if let Some(v) = arg.strip_prefix("--resume-buffer=") && let Ok(n) = v.parse::<i32>() {
+            config.resume_buffer = n;
+        } else if let Some(v) = arg.strip_prefix("--resume-packets=") && let Ok(n) = v.parse::<i32>() {
+            config.resume_packets = n;
+        } else if let Some(v) = arg.strip_prefix("--resume-max-age=") && let Ok(n) = v.parse::<i32>() {
+            config.resume_max_age = n;
+        } else if let Some(v) = arg.strip_prefix("--coalesce-ms=") && let Ok(n) = v.parse::<i32>() {
+            config.coalesce_ms = n;
+        } else if let Some(v) = arg.strip_prefix("--handshake-timeout=") && let Ok(n) = v.parse::<i32>() {
+            config.handshake_timeout = n;
+        } else if let Some(v) = arg.strip_prefix("--max-rate=") && let Ok(n) = v.parse::<i32>() {
+            config.max_rate = n;
+        } else if let Some(v) = arg.strip_prefix("--total-rate=") && let Ok(n) = v.parse::<i32>() {
+            config.total_rate = n;
+        } else if let Some(v) = arg.strip_prefix("--log-file=") {
+            config.log_file = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--syslog=") {
+            config.syslog = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--syslog-facility=") {
+            config.syslog_facility = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--log-rotate-size=") && let Ok(n) = v.parse::<i32>() {
+            config.log_rotate_size = n;
+        } else if let Some(v) = arg.strip_prefix("--log-rotate-interval=") && let Ok(n) = v.parse::<i32>() {
+            config.log_rotate_interval = n;
+        } else if let Some(v) = arg.strip_prefix("--log-retention=") && let Ok(n) = v.parse::<i32>() {
+            config.log_retention = n;
+        } else if let Some(v) = arg.strip_prefix("--metrics-port=") && let Ok(n) = v.parse::<i32>() {
+            config.metrics_port = n;
+        } else if let Some(v) = arg.strip_prefix("--statsd-address=") {
+            config.statsd_address = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--statsd-prefix=") {
+            config.statsd_prefix = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--statsd-interval=") && let Ok(n) = v.parse::<i32>() {
+            config.statsd_interval = n;
+        } else if arg == "--statsd-tags" {
+            config.statsd_tags = true;
+        } else if let Some(v) = arg.strip_prefix("--stats-interval=") && let Ok(n) = v.parse::<i32>() {
+            config.stats_interval = n;
+        } else if let Some(v) = arg.strip_prefix("--admin-address=") {
+            config.admin_address = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--admin-port=") && let Ok(n) = v.parse::<i32>() {
+            config.admin_port = n;
+        } else if let Some(v) = arg.strip_prefix("--unix-socket=") {
+            config.unix_socket = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--codec=") {
+            config.codec = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--unix-socket-codec=") {
+            config.unix_socket_codec = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--control-socket=") {
+            config.control_socket = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--control-socket-mode=") {
+            config.control_socket_mode = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--user=") {
+            config.user = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--group=") {
+            config.group = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--api-address=") {
+            config.api_address = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--api-port=") && let Ok(n) = v.parse::<i32>() {
+            config.api_port = n;
+        } else if let Some(v) = arg.strip_prefix("--api-token=") {
+            config.api_token = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--otlp-endpoint=") {
+            config.otlp_endpoint = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--otlp-service-name=") {
+            config.otlp_service_name = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--otlp-sample-percent=") && let Ok(n) = v.parse::<i32>() {
+            config.otlp_sample_percent = n;
+        } else if let Some(v) = arg.strip_prefix("--shutdown-timeout=") && let Ok(n) = v.parse::<i32>() {
+            config.shutdown_timeout = n;
+        } else if let Some(v) = arg.strip_prefix("--drain-timeout=") && let Ok(n) = v.parse::<i32>() {
+            config.drain_timeout = n;
+        } else if let Some(v) = arg.strip_prefix("--ban-file=") {
+            config.ban_file = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--storage=") {
+            config.storage = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--filter-file=") {
+            config.filter_file = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--middleware=") {
+            config.middleware = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--crash-dump=") {
+            config.crash_dump = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--audit-log=") {
+            config.audit_log = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--record=") {
+            config.record = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--journal=") {
+            config.journal = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--journal-rotate-size=") && let Ok(n) = v.parse::<i32>() {
+            config.journal_rotate_size = n;
+        } else if let Some(v) = arg.strip_prefix("--journal-rotate-interval=") && let Ok(n) = v.parse::<i32>() {
+            config.journal_rotate_interval = n;
+        } else if let Some(v) = arg.strip_prefix("--journal-retention=") && let Ok(n) = v.parse::<i32>() {
+            config.journal_retention = n;
+        } else if arg == "--journal-compress" {
+            config.journal_compress = true;
+        } else if let Some(v) = arg.strip_prefix("--health-port=") && let Ok(n) = v.parse::<i32>() {
+            config.health_port = n;
+        } else if let Some(v) = arg.strip_prefix("--slow-client-ms=") && let Ok(n) = v.parse::<i32>() {
+            config.slow_client_ms = n;
+        } else if arg == "--no-console" {
+            config.stdin_console = false;
+        } else if let Some(v) = arg.strip_prefix("--secret=") {
+            config.secret = v.to_string();
+        } else if arg == "--auth-challenge" {
+            config.auth_challenge = true;
+        } else if let Some(v) = arg.strip_prefix("--token-secret=") {
+            config.token_secret = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--id-allocator=") {
+            config.id_allocator = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--seed=") && let Ok(n) = v.parse::<i32>() {
+            config.seed = n;
+        } else if let Some(v) = arg.strip_prefix("--tls-psk=") {
+            config.tls_psk = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--tls-psk-identity=") {
+            config.tls_psk_identity = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--allow=") {
+            config.allow = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--deny=") {
+            config.deny = v.to_string();
+        } else if arg == "--control-packets" {
+            config.control_packets = true;
+        } else if arg == "--trace-packets" {
+            config.trace_packets = 0;
+        } else if let Some(v) = arg.strip_prefix("--trace-packets=") && let Ok(n) = v.parse::<i32>() {
+            config.trace_packets = n;
+        }
+    }
+}
+
+fn read_config_from_file(path: &Path, config: &mut ServerConfig) {
+    if !path.exists() { return; }
+    
+    // read file
+    let config_file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => {
+            warning!("Could not read config file!");
+            return;
+        }
+    };
+    let mut content = String::new();
+    match BufReader::new(config_file).read_to_string(&mut content) {
+        Ok(_) => {},
+        Err(_) => {
+            error!("Could not read config file!");
+            return;
+        }
+    };
+
+    read_config_int(&content, "port", &mut config.port);
+    read_config_string(&content, "unix_socket", &mut config.unix_socket);
+    read_config_string(&content, "codec", &mut config.codec);
+    read_config_string(&content, "unix_socket_codec", &mut config.unix_socket_codec);
+    read_config_bool(&content, "mirror", &mut config.mirror);
+    read_config_int(&content, "max_players", &mut config.max_players);
+    read_config_int(&content, "reserved_slots", &mut config.reserved_slots);
+    read_config_int(&content, "max_per_ip", &mut config.max_per_ip);
+    read_config_int(&content, "accept_rate", &mut config.accept_rate);
+    read_config_int(&content, "accept_burst", &mut config.accept_burst);
+    read_config_int(&content, "max_rate", &mut config.max_rate);
+    read_config_int(&content, "max_packets", &mut config.max_packets);
+    read_config_int(&content, "rate_burst", &mut config.rate_burst);
+    read_config_string(&content, "rate_limiter", &mut config.rate_limiter);
+    read_config_string(&content, "rate_policy", &mut config.rate_policy);
+    read_config_int(&content, "rate_violations", &mut config.rate_violations);
+    read_config_int(&content, "auto_ban", &mut config.auto_ban);
+    read_config_int(&content, "auto_ban_drops", &mut config.auto_ban_drops);
+    read_config_int(&content, "total_rate", &mut config.total_rate);
+    read_config_int(&content, "write_timeout", &mut config.write_timeout);
+    read_config_int(&content, "resume_grace", &mut config.resume_grace);
+    read_config_int(&content, "resume_buffer", &mut config.resume_buffer);
+    read_config_int(&content, "resume_packets", &mut config.resume_packets);
+    read_config_int(&content, "resume_max_age", &mut config.resume_max_age);
+    read_config_int(&content, "coalesce_ms", &mut config.coalesce_ms);
+    read_config_int(&content, "send_queue_limit", &mut config.send_queue_limit);
+    read_config_string(&content, "send_queue_policy", &mut config.send_queue_policy);
+    read_config_int(&content, "memory_limit", &mut config.memory_limit);
+    read_config_int(&content, "handshake_timeout", &mut config.handshake_timeout);
+    read_config_string(&content, "io_mode", &mut config.io_mode);
+    read_config_int(&content, "io_threads", &mut config.io_threads);
+    read_config_int(&content, "accept_cpu", &mut config.accept_cpu);
+    read_config_string(&content, "worker_cpus", &mut config.worker_cpus);
+    read_config_int(&content, "fanout_threads", &mut config.fanout_threads);
+    read_config_int(&content, "fanout_min", &mut config.fanout_min);
+    read_config_int(&content, "chaos_delay", &mut config.chaos_delay);
+    read_config_int(&content, "chaos_jitter", &mut config.chaos_jitter);
+    read_config_int(&content, "chaos_reorder_percent", &mut config.chaos_reorder_percent);
+    read_config_int(&content, "chaos_loss_percent", &mut config.chaos_loss_percent);
+    read_config_int(&content, "federation_port", &mut config.federation_port);
+    read_config_string(&content, "federation_peers", &mut config.federation_peers);
+    read_config_string(&content, "federation_secret", &mut config.federation_secret);
+    read_config_string(&content, "federation_discovery", &mut config.federation_discovery);
+    read_config_string(&content, "nats_url", &mut config.nats_url);
+    read_config_string(&content, "nats_subject", &mut config.nats_subject);
+    read_config_string(&content, "kafka_brokers", &mut config.kafka_brokers);
+    read_config_string(&content, "kafka_topic", &mut config.kafka_topic);
+    read_config_int(&content, "standby_port", &mut config.standby_port);
+    read_config_string(&content, "standby_of", &mut config.standby_of);
+    read_config_string(&content, "standby_secret", &mut config.standby_secret);
+    read_config_bool(&content, "debug_print", &mut config.debug_print);
+    read_config_int(&content, "trace_packets", &mut config.trace_packets);
+    read_config_string(&content, "log_level", &mut config.log_level);
+    read_config_string(&content, "crash_dump", &mut config.crash_dump);
+    read_config_string(&content, "storage", &mut config.storage);
+    read_config_string(&content, "ban_file", &mut config.ban_file);
+    read_config_string(&content, "filter_file", &mut config.filter_file);
+    read_config_string(&content, "middleware", &mut config.middleware);
+    read_config_int(&content, "drain_timeout", &mut config.drain_timeout);
+    read_config_int(&content, "shutdown_timeout", &mut config.shutdown_timeout);
+    read_config_bool(&content, "stdin_console", &mut config.stdin_console);
+    read_config_string(&content, "control_socket", &mut config.control_socket);
+    read_config_string(&content, "control_socket_mode", &mut config.control_socket_mode);
+    read_config_string(&content, "user", &mut config.user);
+    read_config_string(&content, "group", &mut config.group);
+    read_config_string(&content, "api_address", &mut config.api_address);
+    read_config_int(&content, "api_port", &mut config.api_port);
+    read_config_string(&content, "api_token", &mut config.api_token);
+    read_config_string(&content, "log_file", &mut config.log_file);
+    read_config_int(&content, "log_rotate_size", &mut config.log_rotate_size);
+    read_config_int(&content, "log_rotate_interval", &mut config.log_rotate_interval);
+    read_config_int(&content, "log_retention", &mut config.log_retention);
+    read_config_string(&content, "syslog", &mut config.syslog);
+    read_config_string(&content, "syslog_facility", &mut config.syslog_facility);
+    read_config_int(&content, "metrics_port", &mut config.metrics_port);
+    read_config_string(&content, "statsd_address", &mut config.statsd_address);
+    read_config_string(&content, "statsd_prefix", &mut config.statsd_prefix);
+    read_config_int(&content, "statsd_interval", &mut config.statsd_interval);
+    read_config_bool(&content, "statsd_tags", &mut config.statsd_tags);
+    read_config_int(&content, "stats_interval", &mut config.stats_interval);
+    read_config_string(&content, "admin_address", &mut config.admin_address);
+    read_config_int(&content, "admin_port", &mut config.admin_port);
+    read_config_string(&content, "otlp_endpoint", &mut config.otlp_endpoint);
+    read_config_string(&content, "otlp_service_name", &mut config.otlp_service_name);
+    read_config_int(&content, "otlp_sample_percent", &mut config.otlp_sample_percent);
+    read_config_string(&content, "audit_log", &mut config.audit_log);
+    read_config_string(&content, "record", &mut config.record);
+    read_config_string(&content, "journal", &mut config.journal);
+    read_config_int(&content, "journal_rotate_size", &mut config.journal_rotate_size);
+    read_config_int(&content, "journal_rotate_interval", &mut config.journal_rotate_interval);
+    read_config_int(&content, "journal_retention", &mut config.journal_retention);
+    read_config_bool(&content, "journal_compress", &mut config.journal_compress);
+    read_config_int(&content, "health_port", &mut config.health_port);
+    read_config_int(&content, "slow_client_ms", &mut config.slow_client_ms);
+    read_config_bool(&content, "control_packets", &mut config.control_packets);
+    read_config_string(&content, "secret", &mut config.secret);
+    read_config_bool(&content, "auth_challenge", &mut config.auth_challenge);
+    read_config_string(&content, "token_secret", &mut config.token_secret);
+    read_config_string(&content, "id_allocator", &mut config.id_allocator);
+    read_config_int(&content, "seed", &mut config.seed);
+    read_config_string(&content, "tls_psk", &mut config.tls_psk);
+    read_config_string(&content, "tls_psk_identity", &mut config.tls_psk_identity);
+    read_config_string(&content, "allow", &mut config.allow);
+    read_config_string(&content, "deny", &mut config.deny);
+}
+
+/// Looks up `key = value` at the start of a line, ignoring optional quotes and trailing comments.
+fn read_config_value<'a>(content: &'a str, key: &str) -> Option<&'a str> {
+    let pattern = format!(r##"(?m)^\s*{}\s*=[ \t]*"?([^"#\r\n]*?)"?[ \t]*(?:#.*)?$"##, regex::escape(key));
+    let regex = match Regex::new(&pattern) {
+        Ok(r) => r,
+        Err(_) => {
+            error!("Could not create regex!");
+            return None;
+        }
+    };
+
+    regex.captures(content).and_then(|c| c.get(1)).map(|v| v.as_str())
+}
+
+fn read_config_int(content: &str, key: &str, value: &mut i32) {
+    if let Some(i) = read_config_value(content, key).and_then(|v| v.parse::<i32>().ok()) {
+        *value = i;
+    }
+}
+
+fn read_config_bool(content: &str, key: &str, value: &mut bool) {
+    match read_config_value(content, key) {
+        Some("true") => *value = true,
+        Some("false") => *value = false,
+        _ => {}
+    }
+}
+
+fn read_config_string(content: &str, key: &str, value: &mut String) {
+    if let Some(v) = read_config_value(content, key) {
+        *value = v.to_string();
+    }
+}
+
+/// A new connection until it joins, see `start_handshake`.
+struct Handshake {
+    id: i32,
+    session: String,
+    tag: String,
+    /// Nonce of the challenge the client has to answer, if one was sent.
+    nonce: Option<[u8; 32]>,
+    /// The first frame (the secret or token when authenticating) has to arrive before this.
+    deadline: Option<Instant>,
+    span: Option<otlp::Span>,
+    handshake_span: Option<otlp::Span>
+}
+
+/// A client that joined, with the state of its rate limit.
+struct Connection {
+    id: i32,
+    session: String,
+    tag: String,
+    addr: SocketAddr,
+    role: auth::Role,
+    traffic: Arc<Traffic>,
+    kicked: Arc<AtomicBool>,
+    outbox: Arc<Outbox>,
+    /// What the embedder's callbacks get to see of it.
+    info: ClientInfo,
+    span: Option<otlp::Span>,
+    /// Set until the first frame arrived if the client joined without authenticating.
+    deadline: Option<Instant>,
+    /// Rate limit of `max_rate` and `max_packets`, see `ratelimit`.
+    limiter: Box<dyn ClientRateLimiter>,
+    throttled: bool,
+    /// Whether reading from it is paused, see `held`.
+    held: bool,
+    violations: VecDeque<Instant>,
+    /// What the client can resume its session with once it dropped, see `resume`.
+    resume_token: Option<[u8; resume::TOKEN]>
+}
+
+/// Serves one client on its own thread, the default `io_mode`.
+fn handle_client(stream: Box<dyn transport::Connection>, addr: SocketAddr, config: ServerConfig, state: SharedState, span: Option<otlp::Span>) {
+    let running = &state.running;
+    let stream = &*stream;
+
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+    let _ = stream.set_write_timeout((config.write_timeout > 0).then(|| Duration::from_millis(config.write_timeout as u64)));
+
+    let Some(mut handshake) = start_handshake(stream, &config, &state, span) else { return; };
+
+    let mut identity = None;
+    if needs_auth(&config) { // authenticate, the first frame must be the secret or a token
+        let mut size_bytes = [0u8; 4];
+        let payload = match read_bytes(stream, &mut size_bytes, running, handshake.deadline) {
+            Ok(_) => match i32::from_le_bytes(size_bytes) {
+                size if size < 4 || size as usize > BUFFER_SIZE => Err(None),
+                size => {
+                    let mut payload = vec![0u8; (size - 4) as usize];
+                    read_bytes(stream, &mut payload, running, handshake.deadline).map(|_| payload)
+                }
+            },
+            Err(e) => Err(e)
+        };
+        if let Ok(payload) = &payload { trace::frame("Received", handshake.id, &handshake.session, &[&size_bytes, payload]); }
+
+        let Some(authenticated) = authenticate(stream, &addr, &config, &state, handshake, payload) else { return; };
+        (handshake, identity) = authenticated;
+        let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+    }
+
+    let outbox = state.outbox(None);
+    let Some(mut conn) = join(stream, addr, &config, &state, handshake, identity, Arc::clone(&outbox)) else { return; };
+
+    let writer = stream.try_clone().and_then(|writer| {
+        let (id, session, traffic, state) = (conn.id, conn.session.clone(), Arc::clone(&conn.traffic), Arc::clone(&state));
+        let coalesce = Duration::from_millis(config.coalesce_ms.max(0) as u64);
+        thread::Builder::new().name(format!("writer-{}", id)).spawn(move || write_queued(writer, outbox, id, session, traffic, state, coalesce))
+    });
+    if let Err(e) = writer {
+        error!("{} - Could not start writer thread ({}), closing connection.", conn.tag, e);
+        leave(conn, &state, DisconnectReason::Error);
+        return;
+    }
+
+    // reused for every frame, frame_size rejects any that wouldn't fit
+    let mut content_buffer = vec![0u8; BUFFER_SIZE];
+    let reason = loop {
+        while held(&mut conn, &config, &state) && running.load(Ordering::SeqCst) && !conn.kicked.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // read size
+        let mut size_bytes = [0u8; 4];
+        if let Err(e) = read_bytes(stream, &mut size_bytes, running, conn.deadline) { break read_error(&conn, running, e); }
+
+        let content_size = match frame_size(&conn, &state, size_bytes) {
+            Ok(n) => n,
+            Err(reason) => break reason
+        };
+
+        let sampled = otlp::sample();
+        let mut read_span = if sampled { otlp::start("read_frame", conn.span.as_ref()) } else { None };
+        if let Some(s) = read_span.as_mut() { s.set("frame.size", Value::Int(content_size as i64 + 4)); }
+
+        // read content
+        let content_bytes = &mut content_buffer[..content_size];
+        if let Err(e) = read_bytes(stream, content_bytes, running, conn.deadline) { break read_error(&conn, running, e); }
+        if conn.deadline.take().is_some() { let _ = stream.set_read_timeout(Some(READ_TIMEOUT)); }
+
+        otlp::end(read_span);
+
+        if let Some(reason) = relay(&mut conn, &config, &state, size_bytes, content_bytes, sampled, true) { break reason; }
+    };
+
+    leave(conn, &state, reason);
+}
+
+fn needs_auth(config: &ServerConfig) -> bool {
+    !config.secret.is_empty() || !config.token_secret.is_empty()
+}
+
+/// Rolls an ID for a new connection and sends it the challenge if there is one.
+fn start_handshake(stream: &dyn transport::Connection, config: &ServerConfig, state: &State, span: Option<otlp::Span>) -> Option<Handshake> {
+    let handshake_span = otlp::start("handshake", span.as_ref());
+
+    let id = { // allocate id
+        let conns = match state.connections.read() {
+            Ok(c) => c,
+            Err(_) => {
+                error!("Could not lock connections, closing thread!");
+                return None;
+            }
+        };
+
+        let parked = |id| state.resume.as_ref().is_some_and(|resume| resume.holds(id));
+        match state.ids.allocate(&|id| id == registry::SERVER_ID || conns.contains_key(&id) || parked(id)) {
+            Some(id) => id,
+            None => {
+                warning!("No client id is left for a new connection, closing it.");
+                Metrics::add(&state.metrics.connections_rejected, 1);
+                return None;
+            }
+        }
+    };
+
+    let session = registry::new_session_id();
+    let tag = registry::log_tag(id, &session);
+    let deadline = (config.handshake_timeout > 0).then(|| Instant::now() + Duration::from_millis(config.handshake_timeout as u64));
+
+    let nonce = (needs_auth(config) && config.auth_challenge).then(auth::challenge);
+    if let Some(nonce) = &nonce {
+        let frame = control::frame(control::Kind::Challenge, nonce);
+        let mut conn = stream;
+        if conn.write_all(&frame).is_ok() { trace::frame("Sent", id, &session, &[&frame]); }
+    }
+
+    Some(Handshake { id, session, tag, nonce, deadline, span, handshake_span })
+}
+
+/// Checks the first frame of a connection that has to authenticate, refusing the connection if that fails.
+/// Returns the identity of a client that sent a token.
+fn authenticate(
+    stream: &dyn transport::Connection, addr: &SocketAddr, config: &ServerConfig, state: &State, mut handshake: Handshake, payload: Result<Vec<u8>, Option<std::io::Error>>
+) -> Option<(Handshake, Option<auth::Identity>)> {
+    let result = match payload {
+        Ok(p) => auth::authenticate(&config.secret, &config.token_secret, handshake.nonce.as_ref().map(|n| &n[..]), &p).map_err(|e| ("auth_failed", e)),
+        Err(Some(e)) if e.kind() == ErrorKind::TimedOut => Err(("handshake_timeout", "no frame within the handshake timeout".to_string())),
+        Err(_) => Err(("auth_failed", "no valid frame".to_string()))
+    };
+
+    match result {
+        Ok(identity) => {
+            handshake.deadline = None;
+            Some((handshake, identity))
+        },
+        Err((reason, e)) => {
+            warning!("{} - Authentication from {} failed ({}), closing connection.", handshake.tag, addr, e);
+            refuse(stream, addr, state, handshake, reason, &format!("authentication failed: {}", e));
+            None
+        }
+    }
+}
+
+/// Closes a connection that may not join, telling it why (`farewell`) if control packets are enabled.
+fn refuse(stream: &dyn transport::Connection, addr: &SocketAddr, state: &State, handshake: Handshake, reason: &str, farewell: &str) {
+    Metrics::add(&state.metrics.connections_rejected, 1);
+    audit::record("reject", None, addr, format_args!("reason={}", reason));
+
+    let mut conn = stream;
+    if let Some(frame) = state.kick_frame(farewell) && conn.write_all(&frame).is_ok() {
+        trace::frame("Sent", handshake.id, &handshake.session, &[&frame]);
+    }
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+
+    otlp::end(handshake.handshake_span);
+    otlp::end(handshake.span);
+}
+
+/// Adds a client that passed the handshake to the connections, refusing it if only reserved slots are left.
+/// Frames for it are queued in `outbox`, the caller has to write them.
+fn join(
+    stream: &dyn transport::Connection, addr: SocketAddr, config: &ServerConfig, state: &State, mut handshake: Handshake, identity: Option<auth::Identity>, outbox: Arc<Outbox>
+) -> Option<Connection> {
+    let role = identity.as_ref().map(|i| i.role).unwrap_or(auth::Role::Player);
+
+    let traffic = Arc::new(Traffic::default());
+    traffic.last_activity_ms.store(registry::now_ms(), Ordering::Relaxed);
+    let kicked = Arc::new(AtomicBool::new(false));
+    let resume_token: Option<[u8; resume::TOKEN]> = state.resume.as_ref().map(|_| auth::challenge());
+
+    { // add to connections
+        let mut _connections = match state.connections.write() {
+            Ok(c) => c,
+            Err(_) => {
+                error!("Could not lock connections, closing thread!");
+                return None;
+            }
+        };
+
+        let id = state.ids.authenticated(handshake.id, identity.as_ref().and_then(|i| i.id));
+        if id != handshake.id {
+            debug!("{} - Continues with id {}.", handshake.tag, id);
+            handshake.id = id;
+            handshake.tag = registry::log_tag(id, &handshake.session);
+        }
+        if id <= registry::SERVER_ID || _connections.contains_key(&id) {
+            drop(_connections);
+            warning!("{} - Id {} is already in use, closing connection.", handshake.tag, id);
+            refuse(stream, &addr, state, handshake, "id_in_use", "id in use");
+            return None;
+        }
+
+        if !state.has_room(_connections.len(), role) {
+            drop(_connections);
+            warning!("{} - Server is full for {} (only reserved slots are left), closing connection.", handshake.tag, addr);
+            refuse(stream, &addr, state, handshake, "server_full", "server full");
+            return None;
+        }
+
+        let mut _stream = match stream.try_clone() {
+            Ok(s) => s,
+            Err(_) => {
+                error!("Could not clone stream, closing thread!");
+                return None;
+            }
+        };
+
+        let mut features = Vec::new();
+        if config.mirror { features.push("mirror"); }
+        if config.control_packets { features.push("control_packets"); }
+
+        let (id, session, tag) = (handshake.id, &handshake.session, &handshake.tag);
+        let meta = ClientMeta { session: session.clone(), addr, connected_at: SystemTime::now(), transport: stream.transport(), features, identity: identity.clone() };
+        _connections.insert(id, Client { stream: _stream, outbox: Arc::clone(&outbox), meta, traffic: Arc::clone(&traffic), kicked: Arc::clone(&kicked), latency_ms: AtomicU32::new(0) });
+        // queued before the router can queue anything for it
+        if let Some(token) = &resume_token { outbox.push(Arc::from(control::frame(control::Kind::Resume, &[&token[..], &id.to_le_bytes()].concat())), false); }
+        match &identity {
+            Some(identity) => {
+                info!("{} - Joined from {} as {}.", tag, addr, identity);
+                audit::record("connect", Some((id, session)), &addr, format_args!("user={} role={}", identity.name, identity.role.name()));
+            },
+            None => {
+                info!("{} - Joined from {}.", tag, addr);
+                audit::record("connect", Some((id, session)), &addr, format_args!(""));
+            }
+        }
+    }
+
+    state.metrics.connected();
+
+    let Handshake { id, session, tag, deadline, mut span, mut handshake_span, .. } = handshake;
+    let info = ClientInfo { id, session: session.clone(), addr, user: identity.map(|i| i.name) };
+    let limiter = state.rate_limiter.client(&info);
+    state.hooks.connected(&info);
+    if let Some(s) = span.as_mut() {
+        s.set("client.id", Value::Int(id as i64));
+        s.set("session.id", Value::Text(session.clone()));
+        s.set("net.peer.addr", Value::Text(addr.to_string()));
+    }
+    if let Some(s) = handshake_span.as_mut() { s.set("client.id", Value::Int(id as i64)); }
+    otlp::end(handshake_span);
+
+    Some(Connection {
+        id, session, tag, addr, role, traffic, kicked, outbox, info, span, deadline,
+        limiter, throttled: false, held: false, violations: VecDeque::new(), resume_token
+    })
+}
+
+/// Reason for closing a connection after `read_bytes` failed with `error`, or writing to it failed.
+fn read_error(conn: &Connection, running: &AtomicBool, error: Option<std::io::Error>) -> DisconnectReason {
+    if let Some(reason) = conn.outbox.failure() {
+        match reason {
+            DisconnectReason::QueueFull => warning!("{} - Fell too far behind, its send queue is full, closing connection.", conn.tag),
+            _ => warning!("{} - Stopped receiving, writing to it timed out, closing connection.", conn.tag)
+        }
+        return reason;
+    }
+
+    match error {
+        Some(e) if e.kind() == ErrorKind::TimedOut => {
+            warning!("{} - Sent no frame within the handshake timeout, closing connection.", conn.tag);
+            DisconnectReason::HandshakeTimeout
+        },
+        Some(e) => {
+            error!("{} - Encountered error {}, closing thread!", conn.tag, e);
+            DisconnectReason::Error
+        },
+        None => closed_reason(running, &conn.kicked)
+    }
+}
+
+/// Checks the size prefix of a frame, returns the size of its content.
+fn frame_size(conn: &Connection, state: &State, size_bytes: [u8; 4]) -> Result<usize, DisconnectReason> {
+    let size = i32::from_le_bytes(size_bytes);
+
+    if size as usize > BUFFER_SIZE {
+        error!("{} - Packet too large ({}), closing thread!", conn.tag, size);
+        if state.auto_ban != 0 { auto_ban(state, &conn.addr, (conn.id, &conn.session), "packet too large"); }
+        return Err(DisconnectReason::PacketTooLarge);
+    }
+
+    if size < 4 {
+        error!("{} - Packet too small ({}), closing thread!", conn.tag, size);
+        if state.auto_ban != 0 { auto_ban(state, &conn.addr, (conn.id, &conn.session), "packet too small"); }
+        return Err(DisconnectReason::PacketTooSmall);
+    }
+
+    Ok((size - 4) as usize)
+}
+
+/// Rate limits and filters a frame received from `conn` and publishes it to the router (see `router`), returns why the connection has to close if it does.
+/// Without `may_wait` the `delay` rate policy drops packets instead, e.g. in the event loop where waiting would stall every client.
+fn relay(
+    conn: &mut Connection, config: &ServerConfig, state: &State, mut size_bytes: [u8; 4], content_bytes: &[u8], sampled: bool, may_wait: bool
+) -> Option<DisconnectReason> {
+    let (connections, metrics) = (&state.connections, &state.metrics);
+    let (id, addr) = (conn.id, conn.addr);
+    let size = i32::from_le_bytes(size_bytes);
+    let first = conn.traffic.packets_received.load(Ordering::Relaxed) == 0;
+
+    trace::frame("Received", id, &conn.session, &[&size_bytes, content_bytes]);
+    if !config.record.is_empty() { recording::frame(id, size_bytes, content_bytes); }
+
+    Metrics::add(&metrics.packets_received, 1);
+    Metrics::add(&metrics.bytes_received, size as u64);
+    Metrics::add(&conn.traffic.packets_received, 1);
+    Metrics::add(&conn.traffic.bytes_received, size as u64);
+    conn.traffic.last_activity_ms.store(registry::now_ms(), Ordering::Relaxed);
+
+    if first && state.resume.is_some() { // the first frame may resume a session, relayed frames are held until it arrived
+        if let Some((token, received)) = resume::request(content_bytes) {
+            if !resume(conn, state, &token, received) { conn.outbox.release(); }
+            return None;
+        }
+        conn.outbox.release();
+    }
+
+    { // throttle
+        let now = state.clock.now();
+        let (max_rate, max_packets) = match conn.role {
+            auth::Role::Admin => (0, 0),
+            _ => (state.client_rate_limit(id, &addr.ip()), state.max_packets.load(Ordering::Relaxed))
+        };
+        let limits = Limits { max_rate, max_packets, burst: Duration::from_millis(config.rate_burst.max(0) as u64) };
+        let wait = conn.limiter.check(now, size as usize, &limits);
+
+        if !wait.is_zero() {
+            if !conn.throttled {
+                audit::record("rate_limit", Some((id, &conn.session)), &addr, format_args!("max_rate={} max_packets={}", max_rate, max_packets));
+                if config.control_packets {
+                    send_to(connections, id, &control::frame(control::Kind::Throttled, &[max_rate.to_le_bytes(), max_packets.to_le_bytes()].concat()));
+                }
+            }
+            conn.throttled = true;
+
+            conn.violations.push_back(now);
+            while conn.violations.front().is_some_and(|t| now.duration_since(*t) > VIOLATION_WINDOW) { conn.violations.pop_front(); }
+            if state.auto_ban != 0 && config.auto_ban_drops != 0 && conn.violations.len() as i32 >= config.auto_ban_drops {
+                auto_ban(state, &addr, (id, &conn.session), "flooding");
+                return Some(DisconnectReason::Flooding);
+            }
+
+            match state.rate_policy {
+                RatePolicy::Disconnect if conn.violations.len() as i32 >= config.rate_violations => {
+                    warning!("{} - Exceeded the rate limit {} times in {}s, closing connection.", conn.tag, conn.violations.len(), VIOLATION_WINDOW.as_secs());
+                    if let Some(frame) = state.kick_frame("rate limit exceeded") { send_to(connections, id, &frame); }
+                    return Some(DisconnectReason::Flooding);
+                },
+                RatePolicy::Warn => { },
+                RatePolicy::Delay if may_wait => { // hold the packet (and the client's socket) until it fits the limit
+                    thread::sleep(wait);
+                    conn.limiter.check(state.clock.now(), size as usize, &limits);
+                },
+                RatePolicy::Drop | RatePolicy::Disconnect | RatePolicy::Delay => {
+                    Metrics::add(&metrics.rate_limit_drops, 1);
+                    Metrics::add(&conn.traffic.rate_limit_drops, 1);
+                    return None;
+                }
+            }
+        } else {
+            conn.throttled = false;
+        }
+        conn.limiter.record(size as usize);
+    }
+
+    if conn.role == auth::Role::Spectator {
+        debug!("{} - Not relaying packet of size {} from a spectator.", conn.tag, size);
+        return None;
+    }
+
+    let mut payload = Cow::Borrowed(content_bytes);
+    for stage in state.pipeline.iter() {
+        let flow = match stage {
+            Stage::Filter => filter(conn, state, &payload),
+            Stage::Custom(name, middleware) => custom_middleware(conn, name, middleware.as_ref(), &payload)
+        };
+        match flow {
+            Flow::Pass => { },
+            Flow::Replace(replaced) => payload = Cow::Owned(replaced),
+            Flow::Consume => return None,
+            Flow::Reject(reason) => {
+                if let Some(frame) = state.kick_frame(&reason) { send_to(connections, id, &frame); }
+                return Some(DisconnectReason::Filtered);
+            }
+        }
+    }
+    if let Cow::Owned(payload) = &payload {
+        if payload.len() + 4 > BUFFER_SIZE {
+            debug!("{} - Dropped packet that grew too large in the middleware chain.", conn.tag);
+            return None;
+        }
+        size_bytes = ((payload.len() + 4) as i32).to_le_bytes();
+    }
+    let content_bytes = payload.as_ref();
+    let size = i32::from_le_bytes(size_bytes);
+
+    if !state.hooks.packet(&conn.info, content_bytes) {
+        debug!("{} - Dropped packet refused by an event handler.", conn.tag);
+        return None;
+    }
+
+    if state.memory_pressure() { // hold the sender while the writers catch up, shed the packet if they don't
+        let held = Instant::now();
+        while may_wait && state.memory_pressure() && held.elapsed() < MEMORY_WAIT { thread::sleep(Duration::from_millis(10)); }
+        if state.memory_pressure() {
+            debug!("{} - Dropped packet of size {}, the send queues are nearly at memory_limit.", conn.tag, size);
+            Metrics::add(&metrics.memory_drops, 1);
+            return None;
+        }
+    }
+
+    { // broadcast
+        debug!("{} - Broadcasting packet of size {}.", conn.tag, size);
+
+        // size and content in a single allocation, each recipient's writer sends it with one write
+        let frame: Arc<[u8]> = size_bytes.iter().chain(content_bytes).copied().collect();
+        let broadcast_span = if sampled { otlp::start("broadcast", conn.span.as_ref()) } else { None };
+        if let Some(federation) = &state.federation { federation.forward(&frame); }
+        if let Some(nats) = &state.nats { nats.publish(&frame); }
+        if let Some(kafka) = &state.kafka { kafka.publish(id, &frame); }
+        if let Some(journal) = &state.journal { journal.record(id, &conn.session, conn.addr, &frame); }
+        if !state.router.publish(id, frame, broadcast_span) {
+            error!("Router is gone, closing thread!");
+            return Some(DisconnectReason::Error);
+        }
+    }
+
+    None
+}
+
+/// Lets `conn` take over the session parked with `token` (see `resume`) and replays what it missed after the
+/// packet numbered `received`. Returns false if there is no such session, the client goes on with its new ID then.
+fn resume(conn: &mut Connection, state: &State, token: &[u8; resume::TOKEN], received: u64) -> bool {
+    let Some(resume) = &state.resume else { return false; };
+
+    let (id, session, first, missed) = { // take the parked session's ID over
+        let mut _connections = match state.connections.write() {
+            Ok(c) => c,
+            Err(_) => {
+                error!("Could not lock connections, closing thread!");
+                return false;
+            }
+        };
+
+        let Some(parked) = resume.take(token, conn.info.user.as_deref()) else {
+            drop(_connections);
+            debug!("{} - Sent an unknown or expired resume token, continues as a new client.", conn.tag);
+            return false;
+        };
+        let resume::Parked { id, session, history, .. } = parked;
+        let Some(mut client) = _connections.remove(&conn.id) else { return false; };
+        if _connections.contains_key(&id) {
+            _connections.insert(conn.id, client);
+            drop(_connections);
+            warning!("{} - Can't resume {}, its id is in use, continues as a new client.", conn.tag, registry::log_tag(id, &session));
+            return false;
+        }
+        client.meta.session = session.clone();
+        _connections.insert(id, client);
+
+        let notice = |first: u64| control::frame(control::Kind::Resumed, &[&id.to_le_bytes()[..], &first.to_le_bytes()].concat());
+        let (first, missed) = conn.outbox.resume(notice, history, received);
+        (id, session, first, missed)
+    };
+
+    let tag = registry::log_tag(id, &session);
+    info!("{} - Resumed as {}, replaying {} missed packet(s).", conn.tag, tag, missed);
+    if first > received + 1 { warning!("{} - {} packet(s) it missed are no longer kept or don't fit into its send queue (see resume_buffer, resume_packets, resume_max_age and send_queue_limit), replaying from {}.", tag, first - received - 1, first); }
+    audit::record("resume", Some((id, &session)), &conn.addr, format_args!("from={} first={} replayed={}", conn.id, first, missed));
+    Metrics::add(&state.metrics.sessions_resumed, 1);
+
+    let info = ClientInfo { id, session: session.clone(), addr: conn.addr, user: conn.info.user.clone() };
+    let previous = std::mem::replace(&mut conn.info, info);
+    (conn.id, conn.session, conn.tag) = (id, session, tag);
+    conn.limiter = state.rate_limiter.client(&conn.info);
+    if let Some(s) = conn.span.as_mut() { s.set("client.id", Value::Int(conn.id as i64)); }
+    state.hooks.disconnected(&previous, "resumed");
+    state.hooks.connected(&conn.info);
+    true
+}
+
+/// The `filter` step of the middleware chain, checks a packet against the rules of `filter_file`.
+fn filter(conn: &Connection, state: &State, payload: &[u8]) -> Flow {
+    let filters = state.filters();
+    match filters.check(payload) {
+        filter::Verdict::Relay(Cow::Owned(replaced)) => {
+            Metrics::add(&state.metrics.filter_matches, 1);
+            Flow::Replace(replaced)
+        },
+        filter::Verdict::Relay(Cow::Borrowed(_)) => Flow::Pass,
+        filter::Verdict::Drop(rule) => {
+            debug!("{} - Dropped packet matching filter rule {}.", conn.tag, rule);
+            Metrics::add(&state.metrics.filter_matches, 1);
+            Flow::Consume
+        },
+        filter::Verdict::Disconnect(rule) => {
+            warning!("{} - Sent a packet matching filter rule {}, closing connection.", conn.tag, rule);
+            Metrics::add(&state.metrics.filter_matches, 1);
+            audit::record("filter", Some((conn.id, &conn.session)), &conn.addr, format_args!("rule=\"{}\"", rule));
+            Flow::Reject("blocked by filter".to_string())
+        }
+    }
+}
+
+/// A step of the middleware chain registered by the embedder.
+fn custom_middleware(conn: &Connection, name: &str, middleware: &dyn Middleware, payload: &[u8]) -> Flow {
+    let flow = middleware.process(&conn.info, payload);
+    match &flow {
+        Flow::Consume => debug!("{} - Packet consumed by middleware {}.", conn.tag, name),
+        Flow::Reject(reason) => {
+            warning!("{} - Packet rejected by middleware {} ({}), closing connection.", conn.tag, name, reason);
+            audit::record("middleware", Some((conn.id, &conn.session)), &conn.addr, format_args!("name={} reason=\"{}\"", name, reason));
+        },
+        Flow::Pass | Flow::Replace(_) => { }
+    }
+    flow
+}
+
+/// Whether reading from `conn` has to pause because a recipient's send queue is over its limit (`backpressure`
+/// queue policy), so the client is slowed down by TCP instead of its packets being dropped. Tells it to slow
+/// down when that starts, if control packets are enabled.
+fn held(conn: &mut Connection, config: &ServerConfig, state: &State) -> bool {
+    let held = state.backpressure();
+    if held && !conn.held {
+        debug!("{} - Not reading for now, a send queue is full.", conn.tag);
+        if config.control_packets { send_to(&state.connections, conn.id, &control::frame(control::Kind::SlowDown, &[])); }
+    }
+    conn.held = held;
+    held
+}
+
+/// Removes a client from the connections once it is gone.
+fn leave(conn: Connection, state: &State, reason: DisconnectReason) {
+    let Connection { id, session, tag, addr, traffic, outbox, info, mut span, resume_token, .. } = conn;
+    outbox.close();
+
+    state.metrics.disconnected(reason);
+    audit::record("disconnect", Some((id, &session)), &addr, format_args!(
+        "reason={} packets_in={} bytes_in={} packets_out={} bytes_out={} drops={}", reason.label(),
+        traffic.packets_received.load(Ordering::Relaxed), traffic.bytes_received.load(Ordering::Relaxed),
+        traffic.packets_sent.load(Ordering::Relaxed), traffic.bytes_sent.load(Ordering::Relaxed),
+        traffic.rate_limit_drops.load(Ordering::Relaxed)
+    ));
+
+    if let Some(s) = span.as_mut() { s.set("disconnect.reason", Value::Text(reason.label().to_string())); }
+    otlp::end(span);
+
+    { // remove from connections
+        let mut _connections = match state.connections.write() {
+            Ok(c) => c,
+            Err(_) => {
+                error!("Could not lock connections, closing thread!");
+                return;
+            }
+        };
+
+        _connections.remove(&id);
+        if let Ok(mut overrides) = state.rate_overrides.lock() { overrides.remove(&state::RateTarget::Client(id)); }
+        info!("{} - Disconnected ({}), {}.", tag, reason.label(), traffic.summary());
+
+        // parked while the connections are locked, so no packet relayed meanwhile is missing from its history
+        let dropped = matches!(reason, DisconnectReason::Closed | DisconnectReason::Error | DisconnectReason::WriteTimeout);
+        if dropped && let (Some(resume), Some(token)) = (&state.resume, resume_token) && let Some(history) = outbox.detach() {
+            resume.park(token, id, session.clone(), info.user.clone(), history);
+            debug!("{} - Can resume its session within {}s.", tag, resume.grace.as_secs());
+        }
+    }
+
+    state.hooks.disconnected(&info, reason.label());
+}
+
+/// Temporarily bans the address of a misbehaving client, which also disconnects it.
+fn auto_ban(state: &State, addr: &SocketAddr, client: (i32, &str), reason: &str) {
+    let (duration, offenses) = state.temp_ban(addr.ip(), reason);
+    audit::record("temp_ban", Some(client), addr, format_args!("reason={} seconds={} offense={}", reason.replace(' ', "_"), duration.as_secs(), offenses));
+}
+
+/// Queues a frame for a single client.
+fn send_to(connections: &SharedConnections, id: i32, frame: &[u8]) -> bool {
+    let Ok(connections) = connections.read() else { return false; };
+    let Some(client) = connections.get(&id) else { return false; };
+
+    client.send(Arc::from(frame), false)
+}
+
+/// Writes the frames queued for a client until it leaves, on its own thread in the default `io_mode`.
+/// Frames are collected (up to `COALESCE_BYTES`) until none arrived for `coalesce` and written together.
+/// Once writing fails or times out (see `write_timeout`) the socket is shut down, so the client's thread
+/// sees the end of its stream.
+fn write_queued(stream: Box<dyn transport::Connection>, outbox: Arc<Outbox>, id: i32, session: String, traffic: Arc<Traffic>, state: SharedState, coalesce: Duration) {
+    let metrics = &state.metrics;
+    let mut writer = BufWriter::with_capacity(COALESCE_BYTES, &*stream);
+    let mut buffered: Vec<Outgoing> = Vec::new();
+    let mut flush_at = None;
+    let mut took = Duration::ZERO;
+
+    loop {
+        let result = match outbox.next(flush_at) {
+            Some(outgoing) => {
+                let started = Instant::now();
+                flush_at = flush_at.or(Some(started + coalesce));
+                let written = writer.write_all(&outgoing.frame);
+                buffered.push(outgoing);
+                took += started.elapsed();
+                written
+            },
+            None if buffered.is_empty() => break,
+            None => {
+                let flushed = Instant::now();
+                let result = writer.flush();
+                took += flushed.elapsed();
+                flush_at = None;
+
+                let slow_client_ms = state.slow_client_ms.load(Ordering::Relaxed);
+                if traffic.record_write(std::mem::take(&mut took), Duration::from_millis(slow_client_ms.max(0) as u64)) {
+                    Metrics::add(&metrics.slow_client_warnings, 1);
+                    warning!(
+                        "{} - Client is slow, the last {} writes took over {}ms (average {}us, {} bytes queued).",
+                        registry::log_tag(id, &session), metrics::SLOW_STREAK, slow_client_ms, traffic.average_write_micros(),
+                        stream.unsent_bytes().map(|n| n.to_string()).unwrap_or("?".to_string())
+                    );
+                }
+                if result.is_ok() {
+                    for outgoing in buffered.drain(..) { sent(metrics, id, &session, &traffic, &outgoing); }
+                }
+                result
+            }
+        };
+
+        if let Err(e) = result {
+            if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut { outbox.fail(DisconnectReason::WriteTimeout); }
+            debug!("{} - Could not write to client ({}), closing connection.", registry::log_tag(id, &session), e);
+            break;
+        }
+    }
+
+    outbox.close();
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+}
+
+/// Records a frame that was written to client `id` completely.
+fn sent(metrics: &Metrics, id: i32, session: &str, traffic: &Traffic, outgoing: &Outgoing) {
+    trace::frame("Sent", id, session, &[&outgoing.frame]);
+    if !outgoing.relayed { return; }
+
+    let size = outgoing.frame.len() as u64;
+    Metrics::add(&metrics.packets_sent, 1);
+    Metrics::add(&metrics.bytes_sent, size);
+    Metrics::add(&traffic.packets_sent, 1);
+    Metrics::add(&traffic.bytes_sent, size);
+}
+
+/// Token bucket for new connections: `rate` per second on average, up to `burst` at once.
+struct AcceptLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+    /// Whether connections are currently being refused, so that is only logged once.
+    limited: bool
+}
+
+impl AcceptLimiter {
+    fn new(rate: i32, burst: i32) -> AcceptLimiter {
+        let burst = burst.max(1) as f64;
+        AcceptLimiter { rate: rate as f64, burst, tokens: burst, last: Instant::now(), limited: false }
+    }
+
+    /// Takes a token, returns false if the connection should be refused. A rate of 0 never refuses.
+    fn admit(&mut self) -> bool {
+        if self.rate <= 0.0 { return true; }
+
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.burst);
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            if !self.limited { warning!("New connections arrive faster than {}/s, refusing them for now.", self.rate); }
+            self.limited = true;
+            return false;
+        }
+        self.tokens -= 1.0;
+        self.limited = false;
+        true
+    }
+}
+
+/// Whether a new connection from `addr` may go on to the handshake, with `clients` connected. Takes its
+/// slot of `max_per_ip`, or returns the reason it is rejected with.
+fn admit(state: &SharedState, addr: &SocketAddr, clients: usize) -> Result<IpSlot, &'static str> {
+    if state.is_banned(&addr.ip()) { return Err("banned"); }
+    if !state.is_allowed(&addr.ip()) { return Err("not_allowed"); }
+    if state.paused.load(Ordering::SeqCst) { return Err("paused"); }
+    if state.memory_pressure() { return Err("memory"); }
+    // reserved slots are checked once the client authenticated
+    if !state.has_room(clients, auth::Role::Admin) { return Err("server_full"); }
+    state.claim_ip_slot(addr.ip()).ok_or("too_many_connections")
+}
+
+/// The accept loop of a `Transport`, serving each client on a thread of its own. It blocks in `accept`,
+/// so it only ends with the process, connections accepted while stopping are closed right away.
+fn accept_transport(transport: &dyn transport::Transport, codec: &Option<Arc<dyn Codec>>, config: &ServerConfig, state: &SharedState, client_cpus: Option<Arc<[usize]>>) {
+    let mut accept_limiter = AcceptLimiter::new(config.accept_rate, config.accept_burst);
+    loop {
+        let (stream, addr) = match transport.accept() {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Encountered error {} accepting a connection, retrying!", e);
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+        if !state.running.load(Ordering::SeqCst) { continue; }
+        if !accept_limiter.admit() {
+            Metrics::add(&state.metrics.connections_rejected, 1);
+            continue;
+        }
+
+        let connection_span = otlp::start("connection", None);
+        let accept_span = otlp::start("accept", connection_span.as_ref());
+
+        let admitted = {
+            let connections = state.connections.read().unwrap_or_else(|e| e.into_inner());
+            admit(state, &addr, connections.len())
+        };
+        let ip_slot = match admitted {
+            Ok(ip_slot) => ip_slot,
+            Err(reason) => {
+                Metrics::add(&state.metrics.connections_rejected, 1);
+                audit::record("reject", None, &addr, format_args!("reason={}", reason));
+                otlp::end(accept_span);
+                otlp::end(connection_span);
+                continue;
+            }
+        };
+        otlp::end(accept_span);
+
+        let (stream, config, state, client_cpus) = (codec::wrap(stream, codec), config.clone(), Arc::clone(state), client_cpus.clone());
+        thread::spawn(move || {
+            if let Some(cores) = &client_cpus && let Err(e) = affinity::pin(cores) { debug!("Could not pin client thread ({}).", e); }
+            let _ip_slot = ip_slot;
+            handle_client(stream, addr, config, state, connection_span)
+        });
+    }
+}
+
+/// The built-in codec named by a `codec` setting, with its name.
+fn codec_setting(name: &str) -> (Option<Arc<dyn Codec>>, &str) {
+    match codec::parse(name) {
+        Some(codec) => (codec, name),
+        None => {
+            error!("Unknown codec {}, using length-prefixed!", name);
+            (None, "length-prefixed")
+        }
+    }
+}
+
+/// Reason for a connection ending without an error: either the peer hung up or the server is stopping.
+fn closed_reason(running: &AtomicBool, kicked: &AtomicBool) -> DisconnectReason {
+    if kicked.load(Ordering::SeqCst) { DisconnectReason::Kicked }
+    else if running.load(Ordering::SeqCst) { DisconnectReason::Closed } else { DisconnectReason::Shutdown }
+}
+
+/// Waits until a connection is ready to be accepted or `State::stop` wrote to `woken`, so the caller can check
+/// whether it should stop. Outside unix the accept loop sleeps 100ms between attempts instead.
+#[cfg(unix)]
+fn wait_for_connection(listener: &TcpListener, mut woken: &std::os::unix::net::UnixStream) {
+    use std::os::fd::AsRawFd;
+
+    let mut fds = [listener.as_raw_fd(), woken.as_raw_fd()].map(|fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 });
+    // SAFETY: `fds` is a valid array of two pollfd structs.
+    unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1); }
+
+    let mut drain = [0u8; 64];
+    if fds[1].revents != 0 { while woken.read(&mut drain).is_ok_and(|n| n > 0) { } }
+}
+
+/// Reads until `buffer` is full, failing with `TimedOut` if it has not been filled by `deadline`.
+fn read_bytes(stream: &dyn transport::Connection, buffer: &mut [u8], running: &Arc<AtomicBool>, deadline: Option<Instant>) -> Result<(), Option<std::io::Error>> {
+    let mut read = 0;
+
+    while read < buffer.len() {
+        if !running.load(Ordering::SeqCst) { return Err(None); }
+        if let Some(deadline) = deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() { return Err(Some(ErrorKind::TimedOut.into())); }
+            let _ = stream.set_read_timeout(Some(left.min(READ_TIMEOUT)));
+        }
+
+        match stream.read(&mut buffer[read..]) {
+            Ok(0) => {
+                return Err(None);
+            },
+            Ok(n) => {
+                read += n;
+            },
+            Err(e) => {
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut {
+                    continue;
+                }
+
+                return Err(Some(e));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Answers `/health` (liveness) and `/ready` (readiness, 503 while full, paused or shutting down).
+fn health_response(path: &str, state: &State) -> Option<http::Response> {
+    let max_players = state.max_players.load(Ordering::Relaxed);
+    let clients = state.metrics.connected_clients.load(Ordering::Relaxed);
+    let full = !state.has_room(clients as usize, auth::Role::Player);
+
+    let status = match path {
+        "/health" => "ok",
+        "/ready" if !state.running.load(Ordering::SeqCst) => "shutting_down",
+        "/ready" if state.draining.load(Ordering::SeqCst) => "draining",
+        "/ready" if state.paused.load(Ordering::SeqCst) => "paused",
+        "/ready" if full => "full",
+        "/ready" => "ready",
+        _ => return None
+    };
+    let code = if status == "ok" || status == "ready" { 200 } else { 503 };
+
+    let body = format!(
+        "{{\"status\":\"{}\",\"clients\":{},\"max_players\":{},\"uptime_seconds\":{}}}\n",
+        status, clients, max_players, state.started.elapsed().as_secs()
+    );
+    Some(http::Response::new(code, "application/json", body))
+}
+
+impl Default for ServerConfig {
+    /// The defaults documented in `config.yaml`.
+    fn default() -> ServerConfig {
+        ServerConfig {
+            port: 45565, unix_socket: String::new(), codec: "length-prefixed".to_string(), unix_socket_codec: "length-prefixed".to_string(), mirror: true, max_players: 10, reserved_slots: 0, max_per_ip: 0, accept_rate: 0, accept_burst: 20, max_rate: 8000, max_packets: 0, rate_burst: 1000, rate_limiter: "token-bucket".to_string(), rate_policy: "drop".to_string(), rate_violations: 10, total_rate: 0, auto_ban: 0, auto_ban_drops: 100, debug_print: false,
+            log_file: String::new(), log_rotate_size: 10_000_000, log_rotate_interval: 0, log_retention: 5,
+            metrics_port: 0, statsd_address: String::new(), statsd_prefix: "echoserver".to_string(), statsd_interval: 10, statsd_tags: false,
+            stats_interval: 60, admin_address: "127.0.0.1".to_string(), admin_port: 0,
+            otlp_endpoint: String::new(), otlp_service_name: "echoserver".to_string(), otlp_sample_percent: 1,
+            audit_log: String::new(), record: String::new(),
+            journal: String::new(), journal_rotate_size: 100_000_000, journal_rotate_interval: 86400, journal_retention: 0, journal_compress: false, health_port: 0,
+            slow_client_ms: 50, handshake_timeout: 10000, io_mode: "threads".to_string(), io_threads: 0, accept_cpu: -1, worker_cpus: String::new(), fanout_threads: 0, fanout_min: 64,
+            chaos_delay: 0, chaos_jitter: 0, chaos_reorder_percent: 0, chaos_loss_percent: 0, coalesce_ms: 0, send_queue_limit: 1048576, send_queue_policy: "drop-oldest".to_string(), memory_limit: 0, write_timeout: 10000, resume_grace: 0, resume_buffer: 262144, resume_packets: 0, resume_max_age: 0, control_packets: false, syslog: String::new(), syslog_facility: "daemon".to_string(),
+            federation_port: 0, federation_peers: String::new(), federation_secret: String::new(), federation_discovery: String::new(), nats_url: String::new(), nats_subject: "echoserver".to_string(),
+            kafka_brokers: String::new(), kafka_topic: "echoserver".to_string(),
+            standby_port: 0, standby_of: String::new(), standby_secret: String::new(),
+            trace_packets: -1, log_level: "info".to_string(),
+            crash_dump: "crash_dump.txt".to_string(), api_address: "127.0.0.1".to_string(), api_port: 0, api_token: String::new(),
+            storage: "files".to_string(), ban_file: "bans.txt".to_string(), filter_file: String::new(), middleware: "filter".to_string(), drain_timeout: 60, shutdown_timeout: 2000, stdin_console: true,
+            control_socket: String::new(), control_socket_mode: "660".to_string(), user: String::new(), group: String::new(),
+            secret: String::new(), auth_challenge: false, token_secret: String::new(), id_allocator: "random".to_string(), seed: 0, allow: String::new(), deny: String::new(),
+            tls_psk: String::new(), tls_psk_identity: "echoserver".to_string()
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The defaults, overridden by `config.yaml` in the working directory and then the process arguments.
+    pub fn load() -> ServerConfig {
+        let mut config = ServerConfig::default();
+        read_config_from_file(Path::new("config.yaml"), &mut config);
+        read_config_from_args(&mut config);
+        config
+    }
+}
+
+/// A running server: its listener is bound and everything besides accepting clients started by `new`.
+/// Clients are accepted on the thread calling `run` until `stop`.
+pub struct Server {
+    config: ServerConfig,
+    state: SharedState,
+    listener: TcpListener,
+    /// Codec of `listener`, `None` for length-prefixed.
+    codec: Option<Arc<dyn Codec>>,
+    /// Accepted from by threads of their own, see `transport`.
+    transports: Vec<Listener>,
+    /// Cores client threads are pinned to, see `worker_cpus`.
+    worker_cpus: Vec<usize>,
+    /// The other end of `State::accept_wake`.
+    #[cfg(unix)]
+    accept_woken: std::os::unix::net::UnixStream,
+    #[cfg(unix)]
+    event_loop: Option<event_loop::EventLoop>
+}
+
+impl Server {
+    /// Configuration in code, starting from the defaults: `Server::builder().port(45565).mirror(false).build()`.
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// Sets up logging and binds every listener of `config`, returns why the server could not start.
+    /// A reload keeps its allow and deny lists, see `ServerBuilder::reload_config`.
+    pub fn new(config: ServerConfig) -> Result<Server, String> {
+        Server::start(config, Hooks::default())
+    }
+
+    /// `new` with the embedder's callbacks, see `ServerBuilder`.
+    fn start(config: ServerConfig, hooks: Hooks) -> Result<Server, String> {
+        if config.seed != 0 { random::seed(config.seed as u64); }
+        let chaos_settings = chaos::Settings {
+            delay: config.chaos_delay, jitter: config.chaos_jitter,
+            reorder_percent: config.chaos_reorder_percent, loss_percent: config.chaos_loss_percent
+        };
+        let rate_policy = RatePolicy::parse(&config.rate_policy).unwrap_or_else(|| {
+            error!("Unknown rate policy {}, using drop!", config.rate_policy);
+            RatePolicy::Drop
+        });
+        let (ids, ids_name): (Arc<dyn IdAllocator>, &str) = match &hooks.id_allocator {
+            Some(ids) => (Arc::clone(ids), "custom"),
+            None => match ids::parse(&config.id_allocator) {
+                Some(ids) => (ids, config.id_allocator.as_str()),
+                None => {
+                    error!("Unknown id allocator {}, using random!", config.id_allocator);
+                    (Arc::new(ids::Random), "random")
+                }
+            }
+        };
+        let (rate_limiter, rate_limiter_name): (Arc<dyn RateLimiter>, &str) = match &hooks.rate_limiter {
+            Some(limiter) => (Arc::clone(limiter), "custom"),
+            None => match ratelimit::parse(&config.rate_limiter) {
+                Some(limiter) => (limiter, config.rate_limiter.as_str()),
+                None => {
+                    error!("Unknown rate limiter {}, using token-bucket!", config.rate_limiter);
+                    (Arc::new(ratelimit::TokenBuckets), "token-bucket")
+                }
+            }
+        };
+        let (codec, codec_name) = match &hooks.codec {
+            Some(codec) => (Some(Arc::clone(codec)), "custom"),
+            None => codec_setting(&config.codec)
+        };
+        let (unix_codec, unix_codec_name) = codec_setting(&config.unix_socket_codec);
+        let (storage, storage_name): (Arc<dyn Storage>, &str) = match &hooks.storage {
+            Some(storage) => (Arc::clone(storage), "custom"),
+            None => match storage::parse(&config.storage) {
+                Some(storage) => (storage, config.storage.as_str()),
+                None => {
+                    error!("Unknown storage {}, using files!", config.storage);
+                    (Arc::new(storage::Files), "files")
+                }
+            }
+        };
+        let queue_policy = QueuePolicy::parse(&config.send_queue_policy).unwrap_or_else(|| {
+            error!("Unknown send queue policy {}, using drop-oldest!", config.send_queue_policy);
+            QueuePolicy::DropOldest
+        });
+
+        let (poll_mode, uring) = match config.io_mode.as_str() {
+            "threads" => (false, false),
+            "poll" if cfg!(unix) => (true, false),
+            "poll" => {
+                error!("The poll I/O mode is only supported on unix, using threads!");
+                (false, false)
+            },
+            "uring" if cfg!(target_os = "linux") => (true, true),
+            "uring" => {
+                error!("The uring I/O mode is only supported on Linux, using threads!");
+                (false, false)
+            },
+            mode => {
+                error!("Unknown I/O mode {}, using threads!", mode);
+                (false, false)
+            }
+        };
+        let io_threads = match config.io_threads {
+            n if n > 0 => n as usize,
+            _ => thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+        };
+        let worker_cpus = affinity::parse(&config.worker_cpus).unwrap_or_else(|e| {
+            error!("Invalid worker_cpus ({}), not pinning them!", e);
+            Vec::new()
+        });
+
+        match logging::Level::parse(&config.log_level) {
+            _ if config.debug_print => logging::set_level(logging::Level::Debug),
+            Some(level) => logging::set_level(level),
+            None => error!("Unknown log level {}, using info!", config.log_level)
+        }
+        #[cfg(unix)]
+        logging::watch_level_signal();
+
+        if !config.log_file.is_empty()
+            && let Err(e) = logging::open_file(&config.log_file, config.log_rotate_size, config.log_rotate_interval, config.log_retention) {
+            error!("Could not open log file {} ({}), logging to console only!", config.log_file, e);
+        }
+
+        if config.trace_packets >= 0 { trace::enable(config.trace_packets); }
+
+        if !config.syslog.is_empty()
+            && let Err(e) = logging::open_syslog(&config.syslog, &config.syslog_facility) {
+            error!("Could not connect to syslog {} ({})!", config.syslog, e);
+        }
+
+        if !config.audit_log.is_empty()
+            && let Err(e) = audit::open(&config.audit_log) {
+            error!("Could not open audit log {} ({}), audit logging disabled!", config.audit_log, e);
+        }
+
+        if !config.record.is_empty()
+            && let Err(e) = recording::open(&config.record) {
+            error!("Could not open recording {} ({}), recording disabled!", config.record, e);
+        }
+
+        if !config.otlp_endpoint.is_empty()
+            && let Err(e) = otlp::init(&config.otlp_endpoint, &config.otlp_service_name, config.otlp_sample_percent) {
+            error!("Could not set up OTLP export to {} ({}), tracing disabled!", config.otlp_endpoint, e);
+        }
+
+        if !config.tls_psk.is_empty() {
+            #[cfg(feature = "tls-psk")]
+            if let Err(e) = tls::init(&config.tls_psk, &config.tls_psk_identity) {
+                return Err(format!("Could not set up TLS-PSK ({})", e));
+            }
+            #[cfg(not(feature = "tls-psk"))]
+            {
+                return Err("tls_psk is set but this build has no TLS support (enable the tls-psk feature)".to_string());
+            }
+        }
+
+        if (config.standby_port != 0 || !config.standby_of.is_empty()) && config.standby_secret.is_empty() {
+            return Err("Hot standby needs a standby_secret".to_string());
+        }
+        // a standby binds the port only once the primary is gone
+        let takeover = match config.standby_of.is_empty() {
+            true => None,
+            false => {
+                info!("Waiting as the standby of {}, the port is bound once it is gone...", config.standby_of);
+                Some(standby::follow(&config.standby_of, &config.standby_secret))
+            }
+        };
+
+        let address = format!("0.0.0.0:{}", config.port);
+        let listener = match TcpListener::bind(address) {
+            Ok(l) => l,
+            Err(e) => return Err(format!("Could not bind listener on port {} ({})", config.port, e))
+        };
+
+        let _ = listener.set_nonblocking(true);
+
+        let mut transports = hooks.transports.clone();
+        #[cfg(unix)]
+        if !config.unix_socket.is_empty() {
+            match transport::UnixTransport::bind(&config.unix_socket) {
+                Ok(unix) => transports.push((Arc::new(unix), unix_codec.clone())),
+                Err(e) => return Err(format!("Could not bind unix socket {} ({})", config.unix_socket, e))
+            }
+        }
+        #[cfg(not(unix))]
+        if !config.unix_socket.is_empty() {
+            error!("Unix sockets are only supported on unix!");
+        }
+
+        // print config
+        info!("Listening on port {} with the following configuration:", listener.local_addr().map(|a| a.port() as i32).unwrap_or(config.port));
+        info!("Mirror        = {}", if config.mirror { "enabled" } else { "disabled" });
+        info!("Max players   = {}", if config.max_players == 0 { "unlimited".to_string() } else { config.max_players.to_string() });
+        info!("Reserved      = {}", if config.reserved_slots == 0 { "none".to_string() } else { format!("{} slot(s) for admin tokens", config.reserved_slots) });
+        info!("Max per IP    = {}", if config.max_per_ip == 0 { "unlimited".to_string() } else { config.max_per_ip.to_string() });
+        info!("Accept rate   = {}", if config.accept_rate == 0 { "unlimited".to_string() } else { format!("{} connections/s, bursts of {}", config.accept_rate, config.accept_burst) });
+        info!("Max byte rate = {}", if config.max_rate == 0 { "unlimited".to_string() } else { config.max_rate.to_string() });
+        info!("Max pkt rate  = {}", if config.max_packets == 0 { "unlimited".to_string() } else { format!("{} packets/s", config.max_packets) });
+        info!("Rate limiter  = {}", rate_limiter_name);
+        info!("Rate burst    = {}ms of the rate limits", config.rate_burst);
+        info!("Rate policy   = {}", match rate_policy {
+            RatePolicy::Disconnect => format!("disconnect after {} violations in {}s", config.rate_violations, VIOLATION_WINDOW.as_secs()),
+            policy => policy.name().to_string()
+        });
+        info!("Fair share    = {}", if config.total_rate == 0 { "disabled".to_string() } else { format!("{} bytes/s shared by all clients", config.total_rate) });
+        info!("Auto bans     = {}", if config.auto_ban == 0 { "disabled".to_string() } else { format!("{}s and up after malformed packets or {} rate limit drops in {}s", config.auto_ban, config.auto_ban_drops, VIOLATION_WINDOW.as_secs()) });
+        info!("Slow clients  = {}", if config.slow_client_ms == 0 { "not reported".to_string() } else { format!("writes over {}ms", config.slow_client_ms) });
+        info!("I/O mode      = {}", if poll_mode { format!("{}, {} thread(s) for all clients", if uring { "uring" } else { "poll" }, io_threads) } else { "a reader and a writer thread per client".to_string() });
+        info!("Allocator     = {}", allocator::NAME);
+        info!("CPU affinity  = {}", match (config.accept_cpu, worker_cpus.is_empty()) {
+            (..0, true) => "not pinned".to_string(),
+            (..0, false) => format!("workers on {}", config.worker_cpus),
+            (cpu, true) => format!("accept loop on {}", cpu),
+            (cpu, false) => format!("accept loop on {}, workers on {}", cpu, config.worker_cpus)
+        });
+        info!("Fan-out       = {}", if config.fanout_threads <= 0 { "disabled".to_string() } else { format!("{} thread(s) for broadcasts to at least {} clients", config.fanout_threads, config.fanout_min) });
+        info!("Chaos         = {}", chaos_settings.describe());
+        if chaos_settings.enabled() { warning!("Chaos settings are on, relayed packets are delayed and dropped on purpose!"); }
+        info!("Federation    = {}", match (config.federation_port, config.federation_peers.trim(), config.federation_discovery.as_str()) {
+            (0, "", "") => "disabled".to_string(),
+            (port, peers, discovery) => [
+                (port != 0).then(|| format!("links accepted on port {}", port)),
+                (!peers.is_empty()).then(|| format!("linked to {}", peers)),
+                (!discovery.is_empty()).then(|| format!("peers discovered on {}", discovery))
+            ].into_iter().flatten().collect::<Vec<_>>().join(", ")
+        });
+        info!("Standby       = {}", match (config.standby_port, config.standby_of.as_str()) {
+            (0, "") => "disabled".to_string(),
+            (port, "") => format!("snapshots for standbys on port {}", port),
+            (0, primary) => format!("took over from {}", primary),
+            (port, primary) => format!("took over from {}, snapshots for standbys on port {}", primary, port)
+        });
+        info!("Kafka         = {}", if config.kafka_brokers.trim().is_empty() { "disabled".to_string() } else { format!("topic {} on {}", config.kafka_topic, config.kafka_brokers) });
+        info!("NATS          = {}", if config.nats_url.is_empty() { "disabled".to_string() } else { format!("subject {} on {}", config.nats_subject, config.nats_url.rsplit_once('@').map_or(config.nats_url.as_str(), |(_, host)| host)) });
+        info!("Send queue    = {}", if config.send_queue_limit <= 0 { "unlimited".to_string() } else { format!("{} bytes per client, {} when full", config.send_queue_limit, queue_policy.name()) });
+        info!("Memory limit  = {}", if config.memory_limit <= 0 { "unlimited".to_string() } else { format!("{} bytes queued for all clients", config.memory_limit) });
+        info!("Write timeout = {}", if config.write_timeout <= 0 { "none".to_string() } else { format!("{}ms", config.write_timeout) });
+        info!("Resume        = {}", match config.resume_grace {
+            n if n <= 0 => "disabled".to_string(),
+            n => {
+                let mut kept = Vec::new();
+                if config.resume_buffer > 0 { kept.push(format!("{} bytes", config.resume_buffer)); }
+                if config.resume_packets > 0 { kept.push(format!("{} packets", config.resume_packets)); }
+                if config.resume_max_age > 0 { kept.push(format!("{}s", config.resume_max_age)); }
+                format!("within {}s, missed packets kept up to {}", n, if kept.is_empty() { "unlimited".to_string() } else { kept.join(" / ") })
+            }
+        });
+        info!("Coalescing    = {}", if config.coalesce_ms <= 0 { "frames queued while writing".to_string() } else { format!("frames queued within {}ms", config.coalesce_ms) });
+        info!("Handshake     = {}", if config.handshake_timeout == 0 { "no timeout".to_string() } else { format!("first frame within {}ms", config.handshake_timeout) });
+        info!("Control pkts  = {}", if config.control_packets { "enabled" } else { "disabled" });
+        info!("TLS-PSK       = {}", if config.tls_psk.is_empty() { "disabled".to_string() } else { format!("required (identity {})", config.tls_psk_identity) });
+        info!("Auth secret   = {}", match (config.secret.is_empty(), config.auth_challenge) {
+            (true, _) => "disabled",
+            (false, true) => "accepted as challenge response",
+            (false, false) => "accepted"
+        });
+        info!("Auth tokens   = {}", if config.token_secret.is_empty() { "disabled" } else { "accepted (HS256)" });
+        info!("Client ids    = {}", ids_name);
+        info!("Random seed   = {}", if config.seed == 0 { "none".to_string() } else { config.seed.to_string() });
+        info!("Clock         = {}", if hooks.clock.is_some() { "custom" } else { "system" });
+        if ids_name == "token" && config.token_secret.is_empty() { warning!("The token id allocator needs token_secret, clients get random ids!"); }
+        if config.reserved_slots != 0 && config.token_secret.is_empty() { warning!("Reserved slots can't be used without token_secret, nobody can present an admin token!"); }
+        info!("Log level     = {}", logging::level().name());
+        info!("Trace packets = {}", match config.trace_packets { -1 => "disabled".to_string(), 0 => "all clients".to_string(), id => format!("client {}", id) });
+        info!("Log file      = {}", if config.log_file.is_empty() { "disabled" } else { &config.log_file });
+        info!("Health port   = {}", if config.health_port == 0 { "disabled".to_string() } else { config.health_port.to_string() });
+        info!("Metrics port  = {}", if config.metrics_port == 0 { "disabled".to_string() } else { config.metrics_port.to_string() });
+        info!("StatsD        = {}", if config.statsd_address.is_empty() { "disabled" } else { &config.statsd_address });
+        info!("Stats summary = {}", if config.stats_interval == 0 { "disabled".to_string() } else { format!("every {}s", config.stats_interval) });
+        info!("Stdin console = {}", if !config.stdin_console { "disabled" } else if std::io::stdin().is_terminal() { "enabled, type 'help' for commands" } else { "disabled (stdin is not a terminal)" });
+        info!("Admin console = {}", if config.admin_port == 0 { "disabled".to_string() } else { format!("{}:{}", config.admin_address, config.admin_port) });
+        info!("Codec         = {}", codec_name);
+        info!("Unix socket   = {}", if config.unix_socket.is_empty() { "disabled".to_string() } else { format!("{} ({})", config.unix_socket, unix_codec_name) });
+        info!("Control sock  = {}", if config.control_socket.is_empty() { "disabled".to_string() } else { format!("{} (mode {})", config.control_socket, config.control_socket_mode) });
+        info!("REST API      = {}", if config.api_port == 0 { "disabled".to_string() } else { format!("{}:{}", config.api_address, config.api_port) });
+        info!("Syslog        = {}", if config.syslog.is_empty() { "disabled".to_string() } else { format!("{} ({})", config.syslog, config.syslog_facility) });
+        info!("Drain timeout = {}s", config.drain_timeout);
+        info!("Shutdown wait = {}ms", config.shutdown_timeout);
+        info!("Allow list    = {}", if config.allow.is_empty() { "disabled (everyone may connect)" } else { &config.allow });
+        info!("Deny list     = {}", if config.deny.is_empty() { "disabled" } else { &config.deny });
+        info!("Storage       = {}", storage_name);
+        info!("Ban file      = {}", if config.ban_file.is_empty() { "disabled (bans are kept in memory)" } else { &config.ban_file });
+        info!("Filter file   = {}", if config.filter_file.is_empty() { "disabled" } else { &config.filter_file });
+        let pipeline = middleware::chain(&config.middleware, &hooks.middleware);
+        info!("Middleware    = {}", if pipeline.is_empty() { "none".to_string() } else { pipeline.iter().map(Stage::name).collect::<Vec<_>>().join(", ") });
+        info!("Crash dump    = {}", if config.crash_dump.is_empty() { "disabled" } else { &config.crash_dump });
+        info!("Audit log     = {}", if config.audit_log.is_empty() { "disabled" } else { &config.audit_log });
+        info!("Recording     = {}", if config.record.is_empty() { "disabled" } else { &config.record });
+        info!("Journal       = {}", if config.journal.is_empty() { "disabled".to_string() } else {
+            format!("{}{}{}", config.journal, if config.journal_compress { ", compressed" } else { "" },
+                if config.journal_retention > 0 { format!(", last {} segments kept", config.journal_retention) } else { String::new() })
+        });
+        info!("OTLP tracing  = {}", if config.otlp_endpoint.is_empty() { "disabled".to_string() } else { format!("{} ({}% of packets)", config.otlp_endpoint, config.otlp_sample_percent) });
+        println!();
+
+        let bans = match BanList::load(storage, &config.ban_file) {
+            Ok(b) if b.networks().is_empty() => b,
+            Ok(b) => {
+                info!("Loaded {} ban(s) from {}.", b.networks().len(), config.ban_file);
+                b
+            },
+            Err(e) => return Err(format!("Could not load ban file {} ({})", config.ban_file, e))
+        };
+
+        let filters = match FilterList::load(&config.filter_file) {
+            Ok(f) if f.rules().is_empty() => f,
+            Ok(f) => {
+                info!("Loaded {} filter rule(s) from {}.", f.rules().len(), config.filter_file);
+                f
+            },
+            Err(e) => return Err(format!("Could not load filter file {} ({})", config.filter_file, e))
+        };
+
+        let access = match AccessList::parse(&config.allow, &config.deny) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Invalid {}", e))
+        };
+        // reloads read the lists from where the configuration came from, or keep them as configured
+        let load_access: Box<dyn Fn() -> Result<AccessList, String> + Send + Sync> = match hooks.config_source.clone() {
+            Some(source) => Box::new(move || {
+                let config = source();
+                AccessList::parse(&config.allow, &config.deny)
+            }),
+            None => {
+                let (allow, deny) = (config.allow.clone(), config.deny.clone());
+                Box::new(move || AccessList::parse(&allow, &deny))
+            }
+        };
+
+        #[cfg(unix)]
+        let (accept_wake, accept_woken) = match std::os::unix::net::UnixStream::pair().and_then(|(wake, woken)| {
+            wake.set_nonblocking(true)?;
+            woken.set_nonblocking(true)?;
+            Ok((wake, woken))
+        }) {
+            Ok(pair) => pair,
+            Err(e) => return Err(format!("Could not create the accept loop's wake socket ({})", e))
+        };
+
+        let connections: SharedConnections = Arc::new(RwLock::new(HashMap::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let metrics = Arc::new(Metrics::default());
+        let fanout = match config.fanout_threads {
+            n if n > 0 => match Fanout::spawn(n as usize, config.fanout_min.max(0) as usize) {
+                Ok(fanout) => Some(fanout),
+                Err(e) => {
+                    error!("Could not start the fan-out threads ({}), broadcasting from the router thread!", e);
+                    None
+                }
+            },
+            _ => None
+        };
+
+        let chaos = match Chaos::spawn(chaos_settings, Arc::clone(&connections), Arc::clone(&metrics)) {
+            Ok(chaos) => chaos,
+            Err(e) => return Err(format!("Could not start the chaos thread ({})", e))
+        };
+
+        let retention = Retention {
+            bytes: config.resume_buffer.max(0) as usize, packets: config.resume_packets.max(0) as usize, age: Duration::from_secs(config.resume_max_age.max(0) as u64)
+        };
+        let resume = (config.resume_grace > 0).then(|| Arc::new(Resume::new(Duration::from_secs(config.resume_grace as u64), retention)));
+        let router = match Router::spawn(Arc::clone(&connections), Arc::clone(&metrics), config.mirror, fanout, chaos, resume.clone()) {
+            Ok(router) => router,
+            Err(e) => return Err(format!("Could not start the router thread ({})", e))
+        };
+
+        let federated = config.federation_port != 0 || !config.federation_peers.trim().is_empty() || !config.federation_discovery.is_empty();
+        if federated && config.federation_secret.is_empty() { return Err("Federation needs a federation_secret".to_string()); }
+        let federation = match federated {
+            true => match Federation::start(config.federation_port, &config.federation_peers, &config.federation_secret, router.clone(), Arc::clone(&running)) {
+                Ok(federation) => {
+                    if !config.federation_discovery.is_empty()
+                        && let Err(e) = discovery::start(&config.federation_discovery, config.federation_port, Arc::clone(&federation), Arc::clone(&running)) {
+                        return Err(format!("Could not start federation discovery on {} ({})", config.federation_discovery, e));
+                    }
+                    Some(federation)
+                },
+                Err(e) => return Err(format!("Could not bind federation listener on port {} ({})", config.federation_port, e))
+            },
+            false => None
+        };
+        let nats = match config.nats_url.is_empty() {
+            true => None,
+            false => match Nats::start(&config.nats_url, &config.nats_subject, router.clone(), Arc::clone(&running)) {
+                Ok(nats) => Some(nats),
+                Err(e) => return Err(format!("Could not start the NATS backplane ({})", e))
+            }
+        };
+        let kafka = match config.kafka_brokers.trim().is_empty() {
+            true => None,
+            false => match Kafka::start(&config.kafka_brokers, &config.kafka_topic, Arc::clone(&metrics), Arc::clone(&running)) {
+                Ok(kafka) => Some(kafka),
+                Err(e) => return Err(format!("Could not start the Kafka sink ({})", e))
+            }
+        };
+        let journal = match config.journal.is_empty() {
+            true => None,
+            false => {
+                let settings = journal::Settings {
+                    directory: config.journal.clone(), segment_size: config.journal_rotate_size.max(0) as u64,
+                    segment_interval: Duration::from_secs(config.journal_rotate_interval.max(0) as u64),
+                    retention: config.journal_retention.max(0) as usize, compress: config.journal_compress
+                };
+                match Journal::start(settings, Arc::clone(&metrics)) {
+                    Ok(journal) => Some(journal),
+                    Err(e) => return Err(format!("Could not open the journal in {} ({})", config.journal, e))
+                }
+            }
+        };
+
+        let state: SharedState = Arc::new(State {
+            connections: Arc::clone(&connections), running: Arc::clone(&running), metrics: Arc::clone(&metrics),
+            max_players: AtomicI32::new(config.max_players), reserved_slots: config.reserved_slots, max_per_ip: AtomicI32::new(config.max_per_ip),
+            max_rate: AtomicI32::new(config.max_rate), max_packets: AtomicI32::new(config.max_packets),
+            slow_client_ms: AtomicI32::new(config.slow_client_ms), total_rate: AtomicI32::new(config.total_rate), bans: Mutex::new(bans),
+            access: Mutex::new(access), load_access, filters: Mutex::new(Arc::new(filters)),
+            control_packets: config.control_packets, paused: AtomicBool::new(false),
+            rate_overrides: Mutex::new(HashMap::new()), ip_connections: Mutex::new(HashMap::new()),
+            offenders: Mutex::new(HashMap::new()), auto_ban: config.auto_ban, rate_policy,
+            queue_limit: config.send_queue_limit.max(0) as usize, queue_policy, memory_limit: config.memory_limit.max(0) as usize, router, federation, nats, kafka, journal, resume,
+            draining: AtomicBool::new(false), drain_timeout: config.drain_timeout, started: Instant::now(),
+            token_secret: config.token_secret.clone(), ids, rate_limiter,
+            clock: hooks.clock.clone().unwrap_or_else(|| Arc::new(clock::SystemClock)), pipeline, hooks,
+            #[cfg(unix)]
+            accept_wake
+        });
+
+        crash::install_hook(config.crash_dump.clone(), Arc::clone(&state));
+
+        if let Some(snapshot) = &takeover { standby::take_over(snapshot, &state); }
+        if config.standby_port != 0 && let Err(e) = standby::serve(config.standby_port, &config.standby_secret, Arc::clone(&state)) {
+            error!("Could not bind standby listener on port {} ({})!", config.standby_port, e);
+        }
+
+        if config.metrics_port != 0 { // serve prometheus metrics and health checks
+            let state = Arc::clone(&state);
+            let served = http::serve("0.0.0.0", config.metrics_port, move |request| {
+                if request.method != "GET" { return http::Response::text(405, "Method Not Allowed\n"); }
+                if request.path == "/metrics" {
+                    return http::Response::new(200, "text/plain; version=0.0.4", state.metrics.render_prometheus());
+                }
+                health_response(&request.path, &state).unwrap_or_else(http::Response::not_found)
+            });
+            if let Err(e) = served {
+                error!("Could not bind metrics listener on port {} ({})!", config.metrics_port, e);
+            }
+        }
+
+        if config.health_port != 0 && config.health_port != config.metrics_port { // serve health checks only
+            let state = Arc::clone(&state);
+            let served = http::serve("0.0.0.0", config.health_port, move |request| {
+                if request.method != "GET" { return http::Response::text(405, "Method Not Allowed\n"); }
+                health_response(&request.path, &state).unwrap_or_else(http::Response::not_found)
+            });
+            if let Err(e) = served {
+                error!("Could not bind health check listener on port {} ({})!", config.health_port, e);
+            }
+        }
+
+        if !config.statsd_address.is_empty()
+            && let Err(e) = statsd::spawn(Arc::clone(&metrics), &config.statsd_address, &config.statsd_prefix, config.statsd_interval, config.statsd_tags) {
+            error!("Could not start StatsD exporter for {} ({})!", config.statsd_address, e);
+        }
+
+        if config.stats_interval != 0 {
+            metrics::spawn_summary(Arc::clone(&metrics), config.stats_interval);
+        }
+
+        if config.admin_port != 0
+            && let Err(e) = admin::serve(&config.admin_address, config.admin_port, Arc::clone(&state)) {
+            error!("Could not bind admin listener on {}:{} ({})!", config.admin_address, config.admin_port, e);
+        }
+
+        #[cfg(unix)]
+        if !config.control_socket.is_empty() {
+            match u32::from_str_radix(&config.control_socket_mode, 8) {
+                Ok(mode) => if let Err(e) = admin::serve_unix(&config.control_socket, mode, Arc::clone(&state)) {
+                    error!("Could not bind control socket {} ({})!", config.control_socket, e);
+                },
+                Err(_) => error!("Invalid control_socket_mode {}, expected an octal mode like 660!", config.control_socket_mode)
+            }
+        }
+        #[cfg(not(unix))]
+        if !config.control_socket.is_empty() {
+            error!("The control socket is only supported on unix!");
+        }
+
+        if config.api_port != 0 {
+            if config.api_token.is_empty() {
+                error!("The REST API needs an api_token, not starting it!");
+            } else if let Err(e) = api::serve(&config.api_address, config.api_port, config.api_token.clone(), Arc::clone(&state)) {
+                error!("Could not bind REST API listener on {}:{} ({})!", config.api_address, config.api_port, e);
+            }
+        }
+
+        // every port is bound now, nothing after this needs root
+        #[cfg(unix)]
+        if !config.user.is_empty() || !config.group.is_empty() {
+            match privileges::drop_to(&config.user, &config.group) {
+                Ok((uid, gid)) => info!("Dropped privileges, running as user {} and group {}.", uid, gid),
+                Err(e) => return Err(format!("Could not drop privileges ({})", e))
+            }
+        }
+        #[cfg(not(unix))]
+        if !config.user.is_empty() || !config.group.is_empty() {
+            error!("Dropping privileges is only supported on unix!");
+        }
+
+        let console = config.stdin_console && std::io::stdin().is_terminal();
+        if console { admin::serve_stdin(Arc::clone(&state)); }
+
+        #[cfg(unix)]
+        drain::watch_signal(Arc::clone(&state), config.drain_timeout);
+        #[cfg(unix)]
+        bans::watch_signal(Arc::clone(&state));
+
+        #[cfg(unix)]
+        let event_loop = match poll_mode {
+            false => None,
+            true => match event_loop::spawn(config.clone(), Arc::clone(&state), io_threads, uring, &worker_cpus) {
+                Ok(event_loop) => Some(event_loop),
+                Err(e) => return Err(format!("Could not start the event loop ({})", e))
+            }
+        };
+
+        Ok(Server {
+            config, state, listener, codec, transports, worker_cpus,
+            #[cfg(unix)]
+            accept_woken,
+            #[cfg(unix)]
+            event_loop
+        })
+    }
+
+    /// Accepts clients until `stop` is called, then closes every connection and returns.
+    pub fn run(&self) {
+        let Server { config, state, listener, codec, transports, worker_cpus, .. } = self;
+        let (connections, metrics, running) = (&state.connections, &state.metrics, &state.running);
+        #[cfg(unix)]
+        let (accept_woken, event_loop) = (&self.accept_woken, &self.event_loop);
+        let mut accept_limiter = AcceptLimiter::new(config.accept_rate, config.accept_burst);
+
+        // threads started from here on inherit the accept loop's core, so client threads are pinned explicitly
+        let client_cpus: Option<Arc<[usize]>> = match (config.accept_cpu >= 0, worker_cpus.is_empty()) {
+            (_, false) => Some(worker_cpus.as_slice().into()),
+            (true, true) => affinity::current().ok().map(Arc::from),
+            (false, true) => None
+        };
+        if config.accept_cpu >= 0 && let Err(e) = affinity::pin(&[config.accept_cpu as usize]) {
+            error!("Could not pin the accept loop to CPU {} ({})!", config.accept_cpu, e);
+        }
+
+        for (transport, codec) in transports {
+            let (transport, codec, config, state, client_cpus) = (Arc::clone(transport), codec.clone(), config.clone(), Arc::clone(state), client_cpus.clone());
+            thread::spawn(move || accept_transport(&*transport, &codec, &config, &state, client_cpus));
+        }
+
+        while running.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    if !accept_limiter.admit() { // refused before touching the connections lock, the stream is closed when dropped
+                        Metrics::add(&metrics.connections_rejected, 1);
+                        continue;
+                    }
+
+                    let connection_span = otlp::start("connection", None);
+                    let accept_span = otlp::start("accept", connection_span.as_ref());
+
+                    let _connections = match connections.read() {
+                        Ok(c) => c,
+                        Err(_) => {
+                            error!("Could not lock connections, exiting!");
+                            break;
+                        }
+                    };
+
+                    let ip_slot = match admit(state, &addr, _connections.len()) {
+                        Ok(ip_slot) => ip_slot,
+                        Err(reason) => {
+                            Metrics::add(&metrics.connections_rejected, 1);
+                            audit::record("reject", None, &addr, format_args!("reason={}", reason));
+                            otlp::end(accept_span);
+                            otlp::end(connection_span);
+                            continue;
+                        }
+                    };
+
+                    let state_clone = Arc::clone(state);
+                    let config_clone = config.clone();
+
+                    otlp::end(accept_span);
+
+                    // the event loop only reads length-prefixed frames, clients of another codec get a thread
+                    #[cfg(unix)]
+                    if let Some(event_loop) = &event_loop && config.tls_psk.is_empty() && codec.is_none() {
+                        event_loop.add(stream, addr, ip_slot, connection_span);
+                        continue;
+                    }
+                    #[cfg(unix)]
+                    let event_loop = event_loop.clone().filter(|_| codec.is_none());
+                    let (client_cpus, codec) = (client_cpus.clone(), codec.clone());
+
+                    thread::spawn(move || {
+                        if let Some(cores) = &client_cpus && let Err(e) = affinity::pin(cores) { debug!("Could not pin client thread ({}).", e); }
+                        #[cfg(feature = "tls-psk")]
+                        let stream = match config_clone.tls_psk.is_empty() {
+                            true => stream,
+                            false => match tls::accept(stream) {
+                                Ok(s) => s,
+                                Err(e) => {
+                                    warning!("TLS connection from {} failed ({}).", addr, e);
+                                    Metrics::add(&state_clone.metrics.connections_rejected, 1);
+                                    audit::record("reject", None, &addr, format_args!("reason=tls_failed"));
+                                    otlp::end(connection_span);
+                                    return;
+                                }
+                            }
+                        };
+                        #[cfg(unix)]
+                        if let Some(event_loop) = event_loop {
+                            event_loop.add(stream, addr, ip_slot, connection_span);
+                            return;
+                        }
+                        let _ip_slot = ip_slot;
+                        handle_client(codec::wrap(Box::new(stream), &codec), addr, config_clone, state_clone, connection_span)
+                    });
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    #[cfg(unix)]
+                    wait_for_connection(listener, accept_woken);
+                    #[cfg(not(unix))]
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+                Err(e) => {
+                    error!("Encountered error {}, exiting!", e);
+                    break;
+                }
+            }
+        }
+
+        { // shut down
+            info!("Server shutting down. Closing all connections...");
+
+            // stop reading only, so client threads finish the frame they are in and then see the end of their stream
+            for (_, client) in connections.read().unwrap_or_else(|e| e.into_inner()).iter() {
+                let _ = client.stream.shutdown(std::net::Shutdown::Read);
+            }
+
+            let deadline = Instant::now() + Duration::from_millis(config.shutdown_timeout.max(0) as u64);
+            while Instant::now() < deadline && !connections.read().map(|c| c.is_empty()).unwrap_or(true) {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            // still close everything if a panicking thread poisoned the lock
+            let _connections = connections.read().unwrap_or_else(|e| e.into_inner());
+
+            if !_connections.is_empty() {
+                warning!("{} client thread(s) did not finish within {}ms, force closing.", _connections.len(), config.shutdown_timeout);
+            }
+            for (_, client) in _connections.iter() {
+                let _ = client.stream.shutdown(std::net::Shutdown::Both);
+            }
+
+            #[cfg(unix)]
+            if !config.control_socket.is_empty() { let _ = std::fs::remove_file(&config.control_socket); }
+            #[cfg(unix)]
+            if !config.unix_socket.is_empty() { let _ = std::fs::remove_file(&config.unix_socket); }
+            recording::flush();
+            if let Some(journal) = &state.journal { journal.flush(); }
+
+            info!("Shutdown complete.");
+        }
+    }
+
+    /// Address of the TCP listener, e.g. to find the port picked for `port` 0.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Makes `run` shut down and return, it can be called from any thread.
+    pub fn stop(&self) {
+        self.state.stop();
+    }
+
+    /// The metrics now: connections, packets, bytes, drops and the uptime.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.state.metrics.snapshot(self.state.started.elapsed())
+    }
+
+    /// Runtime control from code: listing and kicking clients, sending packets, changing limits.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle::new(Arc::clone(&self.state))
+    }
+}
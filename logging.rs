@@ -0,0 +1,360 @@
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::TcpStream;
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warning,
+    Error
+}
+
+impl Level {
+    pub const ALL: [Level; 4] = [Level::Debug, Level::Info, Level::Warning, Level::Error];
+
+    fn label(self) -> &'static str {
+        match self {
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warning => "WARNING",
+            Level::Error => "ERROR"
+        }
+    }
+
+    /// Lowercase name as used in the config and the admin console.
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warning => "warning",
+            Level::Error => "error"
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Level> {
+        Level::ALL.into_iter().find(|l| l.name() == name)
+    }
+
+    fn syslog_severity(self) -> u8 {
+        match self {
+            Level::Debug => 7,
+            Level::Info => 6,
+            Level::Warning => 4,
+            Level::Error => 3
+        }
+    }
+}
+
+struct FileSink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: Instant,
+    rotate_size: u64,
+    rotate_interval: Duration,
+    retention: usize
+}
+
+static MIN_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+static FILE_SINK: Mutex<Option<FileSink>> = Mutex::new(None);
+
+enum SyslogTransport {
+    #[cfg(unix)]
+    Local(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(String, Option<TcpStream>)
+}
+
+struct SyslogSink {
+    transport: SyslogTransport,
+    facility: u8,
+    hostname: String
+}
+
+static SYSLOG_SINK: Mutex<Option<SyslogSink>> = Mutex::new(None);
+
+/// Last log lines kept in memory for the web dashboard.
+const RECENT_LINES: usize = 200;
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+impl FileSink {
+    fn open(path: &PathBuf) -> std::io::Result<(File, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok((file, size))
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn needs_rotation(&self) -> bool {
+        (self.rotate_size != 0 && self.size >= self.rotate_size)
+            || (!self.rotate_interval.is_zero() && self.opened.elapsed() >= self.rotate_interval)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        // shift path.1 -> path.2 ... and drop everything past the retention count
+        if self.retention == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.retention));
+            for i in (1..self.retention).rev() {
+                let from = self.rotated_path(i);
+                if from.exists() { fs::rename(&from, self.rotated_path(i + 1))?; }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        let (file, size) = FileSink::open(&self.path)?;
+        self.file = file;
+        self.size = size;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.needs_rotation() && let Err(e) = self.rotate() {
+            eprintln!("ERROR:: Could not rotate log file ({})!", e);
+        }
+
+        if self.file.write_all(line.as_bytes()).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+}
+
+/// Starts mirroring all log output into `path`.
+///
+/// The file is rotated once it grows past `rotate_size` bytes or has been open for
+/// `rotate_interval` seconds (0 disables either trigger), keeping `retention` old files
+/// as `path.1` (newest) to `path.N` (oldest).
+pub fn open_file(path: &str, rotate_size: i32, rotate_interval: i32, retention: i32) -> std::io::Result<()> {
+    let path = PathBuf::from(path);
+    let (file, size) = FileSink::open(&path)?;
+
+    let sink = FileSink {
+        path,
+        file,
+        size,
+        opened: Instant::now(),
+        rotate_size: rotate_size.max(0) as u64,
+        rotate_interval: Duration::from_secs(rotate_interval.max(0) as u64),
+        retention: retention.max(0) as usize
+    };
+
+    if let Ok(mut s) = FILE_SINK.lock() { *s = Some(sink); }
+    Ok(())
+}
+
+impl SyslogSink {
+    fn send(&mut self, level: Level, message: &str) {
+        let pri = self.facility * 8 + level.syslog_severity();
+        let pid = std::process::id();
+
+        match &mut self.transport {
+            #[cfg(unix)]
+            SyslogTransport::Local(socket) => {
+                let _ = socket.send(format!("<{}>echoserver[{}]: {}", pri, pid, message).as_bytes());
+            },
+            SyslogTransport::Udp(socket) => {
+                let line = format!("<{}>1 {} {} echoserver {} - - {}", pri, timestamp(), self.hostname, pid, message);
+                let _ = socket.send(line.as_bytes());
+            },
+            SyslogTransport::Tcp(address, stream) => {
+                // octet counting framing (RFC 6587), reconnect once if the connection dropped
+                let line = format!("<{}>1 {} {} echoserver {} - - {}", pri, timestamp(), self.hostname, pid, message);
+                let framed = format!("{} {}", line.len(), line);
+                for _ in 0..2 {
+                    if stream.is_none() { *stream = TcpStream::connect(address.as_str()).ok(); }
+                    match stream.as_mut().map(|s| s.write_all(framed.as_bytes())) {
+                        Some(Ok(_)) => break,
+                        _ => *stream = None
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn syslog_facility(name: &str) -> Option<u8> {
+    match name {
+        "kern" => Some(0),
+        "user" => Some(1),
+        "daemon" => Some(3),
+        "auth" => Some(4),
+        _ => name.strip_prefix("local").and_then(|n| n.parse::<u8>().ok()).filter(|n| *n <= 7).map(|n| 16 + n)
+    }
+}
+
+/// Starts sending all log output to syslog.
+///
+/// `target` is either `local` (the local daemon via `/dev/log`), `udp://host:port` or `tcp://host:port`.
+/// `facility` is one of `kern`, `user`, `daemon`, `auth` or `local0` to `local7`.
+pub fn open_syslog(target: &str, facility: &str) -> Result<(), String> {
+    let facility = syslog_facility(facility).ok_or(format!("unknown facility {}", facility))?;
+
+    let transport = if target == "local" {
+        #[cfg(unix)]
+        {
+            let socket = UnixDatagram::unbound().map_err(|e| e.to_string())?;
+            socket.connect("/dev/log").map_err(|e| e.to_string())?;
+            SyslogTransport::Local(socket)
+        }
+        #[cfg(not(unix))]
+        return Err("local syslog is only supported on unix".to_string());
+    } else if let Some(address) = target.strip_prefix("udp://") {
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+        socket.connect(address).map_err(|e| e.to_string())?;
+        SyslogTransport::Udp(socket)
+    } else if let Some(address) = target.strip_prefix("tcp://") {
+        let stream = TcpStream::connect(address).map_err(|e| e.to_string())?;
+        SyslogTransport::Tcp(address.to_string(), Some(stream))
+    } else {
+        return Err("expected local, udp://host:port or tcp://host:port".to_string());
+    };
+
+    let hostname = env::var("HOSTNAME").ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or("-".to_string());
+
+    if let Ok(mut s) = SYSLOG_SINK.lock() { *s = Some(SyslogSink { transport, facility, hostname }); }
+    Ok(())
+}
+
+pub fn level() -> Level {
+    Level::ALL[MIN_LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+/// Sets the lowest level that still gets logged, can be changed at any time.
+pub fn set_level(level: Level) {
+    MIN_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Changes the level at runtime and logs the change regardless of the new level.
+pub fn change_level(level: Level, source: &str) {
+    set_level(level);
+    emit(Level::Info, format_args!("Log level set to {} ({}).", level.name(), source));
+}
+
+#[cfg(unix)]
+static CYCLE_REQUESTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_sigusr2(_: libc::c_int) {
+    CYCLE_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGUSR2 cycle the log level (debug -> info -> warning -> error -> debug).
+#[cfg(unix)]
+pub fn watch_level_signal() {
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe { libc::signal(libc::SIGUSR2, on_sigusr2 as *const () as libc::sighandler_t); }
+
+    std::thread::spawn(|| loop {
+        std::thread::sleep(Duration::from_millis(250));
+        if CYCLE_REQUESTED.swap(false, Ordering::SeqCst) {
+            let next = Level::ALL[(level() as usize + 1) % Level::ALL.len()];
+            change_level(next, "SIGUSR2");
+        }
+    });
+}
+
+pub fn enabled(level: Level) -> bool {
+    level >= self::level()
+}
+
+pub fn write(level: Level, args: fmt::Arguments) {
+    if enabled(level) { emit(level, args); }
+}
+
+fn emit(level: Level, args: fmt::Arguments) {
+    let line = format!("{}:: {}", level.label(), args);
+
+    if level >= Level::Warning { eprintln!("{}", line); } else { println!("{}", line); }
+
+    if let Ok(mut sink) = FILE_SINK.lock() && let Some(s) = sink.as_mut() {
+        s.write_line(&format!("{} {}\n", timestamp(), line));
+    }
+
+    if let Ok(mut sink) = SYSLOG_SINK.lock() && let Some(s) = sink.as_mut() {
+        s.send(level, &args.to_string());
+    }
+
+    if let Ok(mut recent) = RECENT.lock() {
+        if recent.len() == RECENT_LINES { recent.pop_front(); }
+        recent.push_back(format!("{} {}", timestamp(), line));
+    }
+}
+
+/// Up to the last `RECENT_LINES` log lines (with timestamp), oldest first.
+pub fn recent() -> Vec<String> {
+    RECENT.lock().map(|r| r.iter().cloned().collect()).unwrap_or_default()
+}
+
+/// Formats the current time as an ISO 8601 UTC timestamp (e.g. `2025-09-14T18:03:21Z`).
+pub fn timestamp() -> String {
+    format_time(SystemTime::now())
+}
+
+pub fn format_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // civil date from days since epoch (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::logging::write($crate::logging::Level::Debug, format_args!($($arg)*)) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::logging::write($crate::logging::Level::Info, format_args!($($arg)*)) };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => { $crate::logging::write($crate::logging::Level::Warning, format_args!($($arg)*)) };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::logging::write($crate::logging::Level::Error, format_args!($($arg)*)) };
+}
+
+pub(crate) use debug;
+pub(crate) use info;
+pub(crate) use warning;
+pub(crate) use error;
@@ -0,0 +1,363 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use crate::allocator;
+use crate::logging::info;
+
+#[derive(Clone, Copy)]
+pub enum DisconnectReason {
+    Closed,
+    Error,
+    PacketTooLarge,
+    PacketTooSmall,
+    Shutdown,
+    Kicked,
+    Flooding,
+    HandshakeTimeout,
+    Filtered,
+    QueueFull,
+    WriteTimeout
+}
+
+impl DisconnectReason {
+    const ALL: [DisconnectReason; 11] = [
+        DisconnectReason::Closed,
+        DisconnectReason::Error,
+        DisconnectReason::PacketTooLarge,
+        DisconnectReason::PacketTooSmall,
+        DisconnectReason::Shutdown,
+        DisconnectReason::Kicked,
+        DisconnectReason::Flooding,
+        DisconnectReason::HandshakeTimeout,
+        DisconnectReason::Filtered,
+        DisconnectReason::QueueFull,
+        DisconnectReason::WriteTimeout
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            DisconnectReason::Closed => "closed",
+            DisconnectReason::Error => "error",
+            DisconnectReason::PacketTooLarge => "packet_too_large",
+            DisconnectReason::PacketTooSmall => "packet_too_small",
+            DisconnectReason::Shutdown => "shutdown",
+            DisconnectReason::Kicked => "kicked",
+            DisconnectReason::Flooding => "flooding",
+            DisconnectReason::HandshakeTimeout => "handshake_timeout",
+            DisconnectReason::Filtered => "filtered",
+            DisconnectReason::QueueFull => "queue_full",
+            DisconnectReason::WriteTimeout => "write_timeout"
+        }
+    }
+}
+
+/// Traffic counters of a single connection, seen from the client's side of the relay.
+#[derive(Default)]
+pub struct Traffic {
+    pub packets_received: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub packets_sent: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub rate_limit_drops: AtomicU64,
+    pub writes: AtomicU64,
+    pub write_micros: AtomicU64,
+    pub max_write_micros: AtomicU64,
+    /// Time of the last packet received from the client, see `registry::now_ms`.
+    pub last_activity_ms: AtomicU64,
+    slow_streak: AtomicU64
+}
+
+/// Consecutive slow writes after which a client counts as persistently slow.
+pub const SLOW_STREAK: u64 = 20;
+
+impl Traffic {
+    /// Records how long writing one packet to this client took.
+    ///
+    /// Returns true exactly when the client just became persistently slow, i.e. its last
+    /// `SLOW_STREAK` writes all took longer than `threshold`.
+    pub fn record_write(&self, elapsed: Duration, threshold: Duration) -> bool {
+        let us = elapsed.as_micros() as u64;
+        Metrics::add(&self.writes, 1);
+        Metrics::add(&self.write_micros, us);
+        self.max_write_micros.fetch_max(us, Ordering::Relaxed);
+
+        if threshold.is_zero() || elapsed < threshold {
+            self.slow_streak.store(0, Ordering::Relaxed);
+            return false;
+        }
+        self.slow_streak.fetch_add(1, Ordering::Relaxed) + 1 == SLOW_STREAK
+    }
+
+    pub fn is_slow(&self) -> bool {
+        self.slow_streak.load(Ordering::Relaxed) >= SLOW_STREAK
+    }
+
+    /// Average time per write in microseconds.
+    pub fn average_write_micros(&self) -> u64 {
+        self.write_micros.load(Ordering::Relaxed) / self.writes.load(Ordering::Relaxed).max(1)
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "received {} packets / {} bytes, sent {} packets / {} bytes, {} dropped by rate limit",
+            self.packets_received.load(Ordering::Relaxed), self.bytes_received.load(Ordering::Relaxed),
+            self.packets_sent.load(Ordering::Relaxed), self.bytes_sent.load(Ordering::Relaxed),
+            self.rate_limit_drops.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Upper bounds of the latency histogram buckets in microseconds, the last bucket catches everything above.
+const LATENCY_BUCKETS: [u64; 14] = [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 25000, 50000, 100000, 1000000];
+
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum: AtomicU64,
+    count: AtomicU64
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let us = duration.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS.iter().position(|b| us <= *b).unwrap_or(LATENCY_BUCKETS.len());
+        Metrics::add(&self.buckets[bucket], 1);
+        Metrics::add(&self.sum, us);
+        Metrics::add(&self.count, 1);
+    }
+
+    /// Current (non-cumulative) count of every bucket.
+    pub fn snapshot(&self) -> [u64; LATENCY_BUCKETS.len() + 1] {
+        std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed))
+    }
+
+    /// Estimates the `p`th percentile (0..=1) in microseconds as the upper bound of the bucket it falls into.
+    pub fn percentile(counts: &[u64], p: f64) -> Option<u64> {
+        let total: u64 = counts.iter().sum();
+        if total == 0 { return None; }
+
+        let target = ((total as f64 * p).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in counts.iter().enumerate() {
+            seen += n;
+            if seen >= target { return Some(*LATENCY_BUCKETS.get(i).unwrap_or(&LATENCY_BUCKETS[LATENCY_BUCKETS.len() - 1])); }
+        }
+        None
+    }
+
+    fn render_prometheus(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        let mut cumulative = 0;
+        for (i, n) in self.snapshot().iter().enumerate() {
+            cumulative += n;
+            match LATENCY_BUCKETS.get(i) {
+                Some(b) => { let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, *b as f64 / 1e6, cumulative); },
+                None => { let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative); }
+            }
+        }
+        let _ = writeln!(out, "{}_sum {}", name, self.sum.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "{}_count {}", name, self.count.load(Ordering::Relaxed));
+    }
+}
+
+/// Difference between two histogram snapshots, i.e. the observations made in between.
+pub fn snapshot_delta(now: &[u64], last: &[u64]) -> Vec<u64> {
+    now.iter().zip(last).map(|(a, b)| a - b).collect()
+}
+
+fn format_latency(us: Option<u64>) -> String {
+    match us {
+        Some(us) if us >= 1000 => format!("{}ms", us / 1000),
+        Some(us) => format!("{}us", us),
+        None => "-".to_string()
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub connected_clients: AtomicI64,
+    pub connections_total: AtomicU64,
+    pub connections_rejected: AtomicU64,
+    pub packets_received: AtomicU64,
+    pub packets_relayed: AtomicU64,
+    pub packets_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub rate_limit_drops: AtomicU64,
+    pub slow_client_warnings: AtomicU64,
+    pub temp_bans: AtomicU64,
+    pub filter_matches: AtomicU64,
+    pub queue_drops: AtomicU64,
+    pub memory_drops: AtomicU64,
+    pub chaos_drops: AtomicU64,
+    pub kafka_drops: AtomicU64,
+    pub journal_drops: AtomicU64,
+    pub sessions_resumed: AtomicU64,
+    pub history_evictions: AtomicU64,
+    /// Bytes of frames queued for all clients together, counting every client's copy of a broadcast.
+    pub buffered_bytes: AtomicU64,
+    /// Send queues over their limit under the `backpressure` policy, their senders aren't read meanwhile.
+    pub saturated_queues: AtomicU64,
+    pub broadcast_latency: Histogram,
+    disconnects: [AtomicU64; DisconnectReason::ALL.len()]
+}
+
+/// The metrics at one moment (`Server::metrics`), named as in `/metrics` without the `echoserver_` prefix and `_total` suffix.
+#[derive(Clone, Debug)]
+pub struct MetricsSnapshot {
+    pub uptime: Duration,
+    /// `(name, value)`, e.g. `connected_clients` and `buffered_bytes`.
+    pub gauges: Vec<(&'static str, u64)>,
+    /// `(name, value)` since the start, e.g. `connections`, `bytes_sent` and `rate_limit_drops`.
+    pub counters: Vec<(&'static str, u64)>,
+    /// `(reason, value)` since the start, e.g. `closed` and `kicked`.
+    pub disconnects: Vec<(&'static str, u64)>
+}
+
+impl MetricsSnapshot {
+    /// The gauge or counter `name`.
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.gauges.iter().chain(&self.counters).find(|(n, _)| *n == name).map(|(_, value)| *value)
+    }
+
+    /// As a JSON object, the gauges and counters by name with `uptime_seconds` and `disconnects` by reason.
+    pub fn to_json(&self) -> String {
+        let values: Vec<String> = self.gauges.iter().chain(&self.counters).map(|(name, value)| format!("\"{}\":{}", name, value)).collect();
+        let disconnects: Vec<String> = self.disconnects.iter().map(|(reason, value)| format!("\"{}\":{}", reason, value)).collect();
+        format!("{{\"uptime_seconds\":{},{},\"disconnects\":{{{}}}}}", self.uptime.as_secs(), values.join(","), disconnects.join(","))
+    }
+}
+
+impl Metrics {
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn connected(&self) {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+        Metrics::add(&self.connections_total, 1);
+    }
+
+    pub fn disconnected(&self, reason: DisconnectReason) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+        Metrics::add(&self.disconnects[reason as usize], 1);
+    }
+
+    /// Every gauge as `(name, description, value)`, the allocator's as far as it reports them.
+    pub fn gauges(&self) -> Vec<(&'static str, &'static str, u64)> {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let mut gauges = vec![
+            ("connected_clients", "Number of currently connected clients.", self.connected_clients.load(Ordering::Relaxed).max(0) as u64),
+            ("buffered_bytes", "Bytes of frames queued for all clients together.", get(&self.buffered_bytes)),
+            ("saturated_queues", "Send queues over their limit that hold up the senders (backpressure policy).", get(&self.saturated_queues))
+        ];
+        let allocator = allocator::stats();
+        if let Some(bytes) = allocator.allocated { gauges.push(("allocator_allocated_bytes", "Bytes the allocator handed out to the server.", bytes)); }
+        if let Some(bytes) = allocator.resident { gauges.push(("allocator_resident_bytes", "Bytes the allocator holds from the system, including free and fragmented memory.", bytes)); }
+        gauges
+    }
+
+    /// Every counter as `(name, description, value)`.
+    pub fn counters(&self) -> [(&'static str, &'static str, u64); 18] {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        [
+            ("connections", "Total accepted client connections.", get(&self.connections_total)),
+            ("connections_rejected", "Connections refused because they arrived faster than the accept rate, the server was full or paused, the address had too many connections, was banned or not allowed, authentication failed or memory_limit was nearly reached.", get(&self.connections_rejected)),
+            ("packets_received", "Total packets received from clients.", get(&self.packets_received)),
+            ("packets_relayed", "Total received packets that were broadcast to peers.", get(&self.packets_relayed)),
+            ("packets_sent", "Total packets written to clients.", get(&self.packets_sent)),
+            ("bytes_received", "Total bytes received from clients.", get(&self.bytes_received)),
+            ("bytes_sent", "Total bytes written to clients.", get(&self.bytes_sent)),
+            ("rate_limit_drops", "Total packets dropped by the rate limit.", get(&self.rate_limit_drops)),
+            ("slow_client_warnings", "Times a client became persistently slow to receive.", get(&self.slow_client_warnings)),
+            ("temp_bans", "Addresses temporarily banned for flooding or malformed packets.", get(&self.temp_bans)),
+            ("filter_matches", "Packets dropped or changed by filter rules, or that got their sender disconnected.", get(&self.filter_matches)),
+            ("queue_drops", "Frames dropped because a client's send queue was full.", get(&self.queue_drops)),
+            ("memory_drops", "Frames and packets dropped because the send queues of all clients together reached memory_limit.", get(&self.memory_drops)),
+            ("chaos_drops", "Frames dropped on purpose by chaos_loss_percent.", get(&self.chaos_drops)),
+            ("kafka_drops", "Relayed packets not published to kafka_topic because its queue was full or no broker was reachable.", get(&self.kafka_drops)),
+            ("journal_drops", "Relayed packets not appended to the journal because its queue was full.", get(&self.journal_drops)),
+            ("sessions_resumed", "Clients that reconnected within resume_grace and took their old ID and session over.", get(&self.sessions_resumed)),
+            ("history_evictions", "Packets no longer kept to replay to resuming clients because of resume_buffer, resume_packets or resume_max_age.", get(&self.history_evictions))
+        ]
+    }
+
+    /// Disconnect counts as `(reason, value)`.
+    pub fn disconnects(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        DisconnectReason::ALL.into_iter().map(|r| (r.label(), self.disconnects[r as usize].load(Ordering::Relaxed)))
+    }
+
+    /// The value of every metric now, `uptime` is passed along.
+    pub fn snapshot(&self, uptime: Duration) -> MetricsSnapshot {
+        MetricsSnapshot {
+            uptime,
+            gauges: self.gauges().into_iter().map(|(name, _, value)| (name, value)).collect(),
+            counters: self.counters().into_iter().map(|(name, _, value)| (name, value)).collect(),
+            disconnects: self.disconnects().collect()
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        for (name, help, value) in self.gauges() {
+            let _ = writeln!(out, "# HELP echoserver_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE echoserver_{} gauge", name);
+            let _ = writeln!(out, "echoserver_{} {}", name, value);
+        }
+
+        for (name, help, value) in self.counters() {
+            let _ = writeln!(out, "# HELP echoserver_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE echoserver_{}_total counter", name);
+            let _ = writeln!(out, "echoserver_{}_total {}", name, value);
+        }
+
+        let _ = writeln!(out, "# HELP echoserver_disconnects_total Total client disconnects by reason.");
+        let _ = writeln!(out, "# TYPE echoserver_disconnects_total counter");
+        for (reason, value) in self.disconnects() {
+            let _ = writeln!(out, "echoserver_disconnects_total{{reason=\"{}\"}} {}", reason, value);
+        }
+
+        self.broadcast_latency.render_prometheus(&mut out, "echoserver_broadcast_duration_seconds", "Time taken to queue one packet for all recipients.");
+
+        out
+    }
+}
+
+/// Logs a one-line traffic summary every `interval` seconds.
+pub fn spawn_summary(metrics: Arc<Metrics>, interval: i32) {
+    let interval = interval.max(1) as u64;
+
+    thread::spawn(move || {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let mut last = (load(&metrics.packets_received), load(&metrics.bytes_received), load(&metrics.bytes_sent), load(&metrics.rate_limit_drops));
+        let mut last_latency = metrics.broadcast_latency.snapshot();
+
+        loop {
+            thread::sleep(Duration::from_secs(interval));
+
+            let now = (load(&metrics.packets_received), load(&metrics.bytes_received), load(&metrics.bytes_sent), load(&metrics.rate_limit_drops));
+            let rate = |a: u64, b: u64| (a - b) as f64 / interval as f64;
+
+            let latency = metrics.broadcast_latency.snapshot();
+            let window = snapshot_delta(&latency, &last_latency);
+
+            info!(
+                "Stats - {} clients, {:.1} packets/s, {:.1} bytes/s in, {:.1} bytes/s out, {} drops, broadcast p50 {} / p99 {}.",
+                metrics.connected_clients.load(Ordering::Relaxed), rate(now.0, last.0), rate(now.1, last.1), rate(now.2, last.2), now.3 - last.3,
+                format_latency(Histogram::percentile(&window, 0.5)), format_latency(Histogram::percentile(&window, 0.99))
+            );
+
+            last = now;
+            last_latency = latency;
+        }
+    });
+}
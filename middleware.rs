@@ -0,0 +1,59 @@
+//! The chain every relayed packet passes through after the rate limit, each step can pass it on,
+//! change it, consume it or reject it. The `middleware` setting lists the steps in order: the built-in
+//! `filter` (the rules of `filter_file`) and middleware registered by embedders under a name with
+//! `ServerBuilder::add_middleware`. Registered middleware the setting doesn't name runs after the others.
+
+use std::sync::Arc;
+
+use crate::events::ClientInfo;
+use crate::logging::error;
+
+/// What happens to a packet after a step of the chain.
+pub enum Flow {
+    /// Passed on unchanged to the next step.
+    Pass,
+    /// Passed on with this payload instead.
+    Replace(Vec<u8>),
+    /// Handled by the step, not relayed.
+    Consume,
+    /// Not relayed and the sender is disconnected, with the reason sent in its kick notice.
+    Reject(String)
+}
+
+/// A step of the chain, called on the thread serving the client (see `events`).
+pub trait Middleware: Send + Sync {
+    /// Decides what happens to `payload`, a packet from `client` without its size prefix.
+    fn process(&self, client: &ClientInfo, payload: &[u8]) -> Flow;
+}
+
+#[derive(Clone)]
+pub enum Stage {
+    Filter,
+    Custom(String, Arc<dyn Middleware>)
+}
+
+impl Stage {
+    pub fn name(&self) -> &str {
+        match self {
+            Stage::Filter => "filter",
+            Stage::Custom(name, _) => name
+        }
+    }
+}
+
+/// The chain of the `middleware` setting, a comma separated list of names, with the `registered` middleware it doesn't name appended.
+pub fn chain(names: &str, registered: &[(String, Arc<dyn Middleware>)]) -> Vec<Stage> {
+    let mut stages = Vec::new();
+    for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        match registered.iter().find(|(registered, _)| registered == name) {
+            _ if stages.iter().any(|s: &Stage| s.name() == name) => error!("Middleware {} is listed twice, running it once!", name),
+            Some((name, middleware)) => stages.push(Stage::Custom(name.clone(), Arc::clone(middleware))),
+            None if name == "filter" => stages.push(Stage::Filter),
+            None => error!("Unknown middleware {}, skipping it!", name)
+        }
+    }
+    for (name, middleware) in registered {
+        if !stages.iter().any(|s| s.name() == name) { stages.push(Stage::Custom(name.clone(), Arc::clone(middleware))); }
+    }
+    stages
+}
@@ -0,0 +1,179 @@
+use std::fmt::Write as _;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use rand::Rng;
+
+use crate::http::json_string;
+use crate::logging::warning;
+use crate::random;
+
+const MAX_QUEUED_SPANS: usize = 4096;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+pub enum Value {
+    Int(i64),
+    Text(String)
+}
+
+pub struct Span {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_id: Option<[u8; 8]>,
+    name: &'static str,
+    kind: u8,
+    start: SystemTime,
+    attributes: Vec<(&'static str, Value)>
+}
+
+impl Span {
+    pub fn set(&mut self, key: &'static str, value: Value) {
+        self.attributes.push((key, value));
+    }
+}
+
+struct Exporter {
+    host: String,
+    path: String,
+    service: String,
+    sample_percent: u32,
+    queue: Mutex<Vec<(Span, SystemTime)>>
+}
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+/// Starts exporting spans to the OTLP/HTTP collector at `endpoint` (e.g. `http://127.0.0.1:4318`).
+///
+/// Only plain HTTP endpoints with JSON encoding are supported. Per-packet spans are only recorded
+/// for `sample_percent` percent of all packets, connection level spans are always recorded.
+pub fn init(endpoint: &str, service: &str, sample_percent: i32) -> Result<(), String> {
+    let rest = endpoint.strip_prefix("http://").ok_or("only http:// endpoints are supported")?;
+    let (host, path) = match rest.split_once('/') {
+        Some((h, p)) if !p.is_empty() => (h, format!("/{}", p)),
+        Some((h, _)) => (h, "/v1/traces".to_string()),
+        None => (rest, "/v1/traces".to_string())
+    };
+    let host = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+
+    let exporter = Exporter {
+        host, path,
+        service: service.to_string(),
+        sample_percent: sample_percent.clamp(0, 100) as u32,
+        queue: Mutex::new(Vec::new())
+    };
+    EXPORTER.set(exporter).map_err(|_| "exporter already initialized")?;
+
+    thread::spawn(|| loop {
+        thread::sleep(FLUSH_INTERVAL);
+        flush();
+    });
+
+    Ok(())
+}
+
+/// Whether the current packet should get per-packet spans.
+pub fn sample() -> bool {
+    EXPORTER.get().is_some_and(|e| e.sample_percent >= 100 || random::with(|rng| rng.random_range(0..100)) < e.sample_percent)
+}
+
+/// Starts a span, returns `None` when tracing is disabled.
+pub fn start(name: &'static str, parent: Option<&Span>) -> Option<Span> {
+    EXPORTER.get()?;
+
+    let (trace_id, span_id) = random::with(|rng| (rng.random(), rng.random()));
+    Some(Span {
+        trace_id: parent.map(|p| p.trace_id).unwrap_or(trace_id),
+        span_id,
+        parent_id: parent.map(|p| p.span_id),
+        name,
+        kind: if parent.is_none() { 2 } else { 1 }, // SERVER for connection roots, INTERNAL otherwise
+        start: SystemTime::now(),
+        attributes: Vec::new()
+    })
+}
+
+/// Ends a span and queues it for export.
+pub fn end(span: Option<Span>) {
+    let (Some(span), Some(exporter)) = (span, EXPORTER.get()) else { return; };
+
+    if let Ok(mut queue) = exporter.queue.lock() && queue.len() < MAX_QUEUED_SPANS {
+        queue.push((span, SystemTime::now()));
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0)
+}
+
+fn encode(exporter: &Exporter, spans: &[(Span, SystemTime)]) -> String {
+    let mut out = String::new();
+    let _ = write!(
+        out, r#"{{"resourceSpans":[{{"resource":{{"attributes":[{{"key":"service.name","value":{{"stringValue":{}}}}}]}},"scopeSpans":[{{"scope":{{"name":"echoserver"}},"spans":["#,
+        json_string(&exporter.service)
+    );
+
+    for (i, (span, end)) in spans.iter().enumerate() {
+        if i > 0 { out.push(','); }
+        let _ = write!(
+            out, r#"{{"traceId":"{}","spanId":"{}","parentSpanId":"{}","name":{},"kind":{},"startTimeUnixNano":"{}","endTimeUnixNano":"{}","attributes":["#,
+            hex(&span.trace_id), hex(&span.span_id), span.parent_id.map(|p| hex(&p)).unwrap_or_default(),
+            json_string(span.name), span.kind, nanos(span.start), nanos(*end)
+        );
+        for (j, (key, value)) in span.attributes.iter().enumerate() {
+            if j > 0 { out.push(','); }
+            let value = match value {
+                Value::Int(i) => format!(r#"{{"intValue":"{}"}}"#, i),
+                Value::Text(s) => format!(r#"{{"stringValue":{}}}"#, json_string(s))
+            };
+            let _ = write!(out, r#"{{"key":"{}","value":{}}}"#, key, value);
+        }
+        out.push_str("]}");
+    }
+
+    out.push_str("]}]}]}");
+    out
+}
+
+fn post(exporter: &Exporter, body: &str) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(&exporter.host)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        exporter.path, exporter.host, body.len()
+    );
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body.as_bytes())?;
+
+    let mut status = [0u8; 12];
+    stream.read_exact(&mut status)?;
+    if &status[9..10] != b"2" {
+        return Err(std::io::Error::other(format!("collector returned {}", String::from_utf8_lossy(&status[9..12]))));
+    }
+    Ok(())
+}
+
+fn flush() {
+    let Some(exporter) = EXPORTER.get() else { return; };
+
+    let spans = match exporter.queue.lock() {
+        Ok(mut q) => std::mem::take(&mut *q),
+        Err(_) => return
+    };
+    if spans.is_empty() { return; }
+
+    if let Err(e) = post(exporter, &encode(exporter, &spans)) {
+        warning!("Could not export {} spans to {} ({})!", spans.len(), exporter.host, e);
+    }
+}
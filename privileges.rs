@@ -0,0 +1,44 @@
+//! Dropping root privileges once every port is bound, so client data is never handled as root.
+
+use std::ffi::CString;
+
+/// Switches to `user` (and its groups) and/or `group`, either may be empty to keep the current one.
+/// Returns the resulting user and group IDs.
+pub fn drop_to(user: &str, group: &str) -> Result<(u32, u32), String> {
+    let user_name = CString::new(user).map_err(|_| "invalid user name")?;
+    let group_name = CString::new(group).map_err(|_| "invalid group name")?;
+
+    // SAFETY: getpwnam and getgrnam return pointers to static storage or null, the fields are read
+    // right away, before anything else could call them again.
+    let (uid, user_gid) = match user.is_empty() {
+        true => (None, None),
+        false => unsafe {
+            let passwd = libc::getpwnam(user_name.as_ptr());
+            if passwd.is_null() { return Err(format!("unknown user {}", user)); }
+            (Some((*passwd).pw_uid), Some((*passwd).pw_gid))
+        }
+    };
+    let gid = match group.is_empty() {
+        true => user_gid,
+        false => unsafe {
+            let entry = libc::getgrnam(group_name.as_ptr());
+            if entry.is_null() { return Err(format!("unknown group {}", group)); }
+            Some((*entry).gr_gid)
+        }
+    };
+
+    // SAFETY: plain system calls with valid arguments. The group has to change first, it can't once
+    // the user isn't root anymore.
+    unsafe {
+        if let Some(gid) = gid {
+            let groups = if uid.is_some() { libc::initgroups(user_name.as_ptr(), gid as _) } else { libc::setgroups(1, &gid) };
+            if groups != 0 { return Err(format!("could not set supplementary groups ({})", std::io::Error::last_os_error())); }
+            if libc::setgid(gid) != 0 { return Err(format!("could not switch to group {} ({})", gid, std::io::Error::last_os_error())); }
+        }
+        if let Some(uid) = uid {
+            if libc::setuid(uid) != 0 { return Err(format!("could not switch to user {} ({})", uid, std::io::Error::last_os_error())); }
+            if uid != 0 && libc::setuid(0) == 0 { return Err("root privileges could be regained".to_string()); }
+        }
+        Ok((libc::getuid(), libc::getgid()))
+    }
+}
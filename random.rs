@@ -0,0 +1,24 @@
+//! Randomness behind client ids, session ids and trace sampling. With `seed` set it all comes from one
+//! generator seeded with it, so a run with the same connections in the same order gets the same ids and
+//! samples the same packets, e.g. to replay a failed integration test. Challenge nonces never do.
+
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use rand::RngCore;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+static SEEDED: OnceLock<Mutex<StdRng>> = OnceLock::new();
+
+/// Makes every later draw come from a generator seeded with `seed`. Only the first call has an effect, like the logging setup.
+pub fn seed(seed: u64) {
+    let _ = SEEDED.set(Mutex::new(StdRng::seed_from_u64(seed)));
+}
+
+/// Calls `f` with the seeded generator if there is one, the thread's own otherwise.
+pub fn with<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+    match SEEDED.get() {
+        Some(rng) => f(&mut *rng.lock().unwrap_or_else(|e| e.into_inner())),
+        None => f(&mut rand::rng())
+    }
+}
@@ -0,0 +1,212 @@
+//! Per-client rate limiting of relayed packets (`rate_limiter`). What happens to a packet over the
+//! limit is up to the `rate_policy`, the limiter only tells how long it would have to wait.
+
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::events::ClientInfo;
+
+/// The limits of a client for the packet being checked, they change with rate overrides and `total_rate`.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    /// Bytes per second, 0 = unlimited. Also 0 for admins.
+    pub max_rate: i32,
+    /// Packets per second, 0 = unlimited.
+    pub max_packets: i32,
+    /// How much of the limits may be used up at once (`rate_burst`).
+    pub burst: Duration
+}
+
+/// Creates the limiter of every client that joins.
+pub trait RateLimiter: Send + Sync {
+    fn client(&self, client: &ClientInfo) -> Box<dyn ClientRateLimiter>;
+}
+
+/// The rate limit state of one client, only used by the thread serving it.
+pub trait ClientRateLimiter: Send {
+    /// How long a packet of `size` bytes arriving at `now` would have to wait to fit `limits`, zero if it fits.
+    fn check(&mut self, now: Instant, size: usize, limits: &Limits) -> Duration;
+
+    /// Counts a packet that passed `check`, or that is relayed anyway (`warn` policy, or after waiting).
+    fn record(&mut self, size: usize);
+}
+
+/// A bucket for bytes and one for packets, the default.
+pub struct TokenBuckets;
+
+impl RateLimiter for TokenBuckets {
+    fn client(&self, _client: &ClientInfo) -> Box<dyn ClientRateLimiter> {
+        Box::new(ClientBuckets { bytes: TokenBucket::new(), packets: TokenBucket::new() })
+    }
+}
+
+struct ClientBuckets {
+    bytes: TokenBucket,
+    packets: TokenBucket
+}
+
+impl ClientRateLimiter for ClientBuckets {
+    fn check(&mut self, now: Instant, _size: usize, limits: &Limits) -> Duration {
+        self.bytes.refill(now, limits.max_rate, limits.burst);
+        self.packets.refill(now, limits.max_packets, limits.burst);
+        self.bytes.shortfall(limits.max_rate).max(self.packets.shortfall(limits.max_packets))
+    }
+
+    fn record(&mut self, size: usize) {
+        self.bytes.take(size as f64);
+        self.packets.take(1.0);
+    }
+}
+
+/// Never limits, e.g. when a proxy in front of the server does.
+pub struct Unlimited;
+
+impl RateLimiter for Unlimited {
+    fn client(&self, _client: &ClientInfo) -> Box<dyn ClientRateLimiter> {
+        Box::new(Unlimited)
+    }
+}
+
+impl ClientRateLimiter for Unlimited {
+    fn check(&mut self, _now: Instant, _size: usize, _limits: &Limits) -> Duration {
+        Duration::ZERO
+    }
+
+    fn record(&mut self, _size: usize) { }
+}
+
+/// The built-in limiter named `name` (`token-bucket` or `none`).
+pub fn parse(name: &str) -> Option<Arc<dyn RateLimiter>> {
+    match name {
+        "token-bucket" => Some(Arc::new(TokenBuckets)),
+        "none" => Some(Arc::new(Unlimited)),
+        _ => None
+    }
+}
+
+/// A client's rate limit, filling up at the limit per second to `rate_burst` of it (at least one token).
+/// Packets pass while a token is left and take as many as their size (bytes) or one (packets), so the
+/// bucket can go below zero and a large packet delays the next ones.
+struct TokenBucket {
+    /// `None` while the bucket is full because it has not been limited yet, or not limited at the last refill.
+    tokens: Option<f64>,
+    last: Option<Instant>
+}
+
+impl TokenBucket {
+    /// A bucket that is full (at `rate_burst`) once it is limited.
+    fn new() -> TokenBucket {
+        TokenBucket { tokens: None, last: None }
+    }
+
+    /// Adds the tokens accrued at `rate` per second since the last refill. A rate of 0 never limits, the
+    /// bucket is full again when a limit comes back.
+    fn refill(&mut self, now: Instant, rate: i32, burst: Duration) {
+        let elapsed = self.last.map(|last| now.saturating_duration_since(last)).unwrap_or_default();
+        self.last = Some(now);
+        if rate <= 0 {
+            self.tokens = None;
+            return;
+        }
+        let capacity = (rate as f64 * burst.as_secs_f64()).max(1.0);
+        self.tokens = Some(match self.tokens {
+            Some(tokens) => (tokens + elapsed.as_secs_f64() * rate as f64).min(capacity),
+            None => capacity
+        });
+    }
+
+    /// Takes the tokens of a packet, nothing while unlimited.
+    fn take(&mut self, tokens: f64) {
+        if let Some(left) = &mut self.tokens { *left -= tokens; }
+    }
+
+    /// How long until a packet may pass at `rate`, zero while a token is left or the rate is 0.
+    fn shortfall(&self, rate: i32) -> Duration {
+        match self.tokens {
+            Some(tokens) if rate > 0 && tokens < 1.0 => Duration::from_secs_f64((1.0 - tokens) / rate as f64),
+            _ => Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn limits(max_rate: i32, max_packets: i32, burst: Duration) -> Limits {
+        Limits { max_rate, max_packets, burst }
+    }
+
+    #[test]
+    fn starts_at_the_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new();
+        bucket.refill(now, 10, 2 * SECOND);
+        assert_eq!(bucket.tokens, Some(20.0));
+
+        // at least one token, so a burst shorter than a token still lets packets through
+        let mut bucket = TokenBucket::new();
+        bucket.refill(now, 10, Duration::from_millis(10));
+        assert_eq!(bucket.tokens, Some(1.0));
+    }
+
+    #[test]
+    fn the_burst_passes_then_waits() {
+        let now = Instant::now();
+        let mut buckets = ClientBuckets { bytes: TokenBucket::new(), packets: TokenBucket::new() };
+        let limits = limits(0, 5, SECOND);
+        for _ in 0..5 {
+            assert_eq!(buckets.check(now, 100, &limits), Duration::ZERO);
+            buckets.record(100);
+        }
+        assert_eq!(buckets.check(now, 100, &limits), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn refills_at_the_rate_up_to_the_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new();
+        bucket.refill(now, 100, SECOND);
+        bucket.take(150.0);
+        assert_eq!(bucket.tokens, Some(-50.0));
+        assert_eq!(bucket.shortfall(100), Duration::from_millis(510));
+
+        bucket.refill(now + Duration::from_millis(500), 100, SECOND);
+        assert_eq!(bucket.tokens, Some(0.0));
+        assert_eq!(bucket.shortfall(100), Duration::from_millis(10));
+
+        bucket.refill(now + 10 * SECOND, 100, SECOND);
+        assert_eq!(bucket.tokens, Some(100.0));
+        assert_eq!(bucket.shortfall(100), Duration::ZERO);
+    }
+
+    #[test]
+    fn a_large_packet_delays_the_next() {
+        let now = Instant::now();
+        let mut buckets = ClientBuckets { bytes: TokenBucket::new(), packets: TokenBucket::new() };
+        let limits = limits(1000, 0, SECOND);
+        assert_eq!(buckets.check(now, 3000, &limits), Duration::ZERO);
+        buckets.record(3000);
+        assert_eq!(buckets.check(now, 10, &limits), Duration::from_millis(2001));
+        assert_eq!(buckets.check(now + 2 * SECOND, 10, &limits), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn a_rate_of_zero_is_unlimited() {
+        let now = Instant::now();
+        let mut buckets = ClientBuckets { bytes: TokenBucket::new(), packets: TokenBucket::new() };
+        let unlimited = limits(0, 0, SECOND);
+        for _ in 0..10_000 {
+            assert_eq!(buckets.check(now, 1 << 20, &unlimited), Duration::ZERO);
+            buckets.record(1 << 20);
+        }
+        // what was relayed while unlimited does not count once a limit comes back
+        let limited = limits(1000, 10, SECOND);
+        assert_eq!(buckets.check(now, 100, &limited), Duration::ZERO);
+        assert_eq!(buckets.bytes.tokens, Some(1000.0));
+        assert_eq!(buckets.packets.tokens, Some(10.0));
+    }
+}
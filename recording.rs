@@ -0,0 +1,102 @@
+//! Session recordings (`record`): every packet clients send once they joined, with the time it arrived
+//! and the sender's id, so a playtest's traffic can be fed back through a server with `echoserver replay`
+//! to reproduce a bug report.
+//!
+//! A recording is `MAGIC` followed by one entry per packet: `[microseconds since the recording started:
+//! u64 LE][sender id: i32 LE][frame]`, the frame as received (`[size: i32 LE][payload]`), before the rate
+//! limit and the middleware chain. Entries are buffered, the file is complete once the server stopped.
+
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Start of every recording, the version changes with the entry layout.
+pub const MAGIC: &[u8; 8] = b"ECHOREC1";
+
+static RECORDING: Mutex<Option<(BufWriter<File>, Instant)>> = Mutex::new(None);
+
+/// Starts recording to `path`, replacing what was recorded there before.
+pub fn open(path: &str) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    if let Ok(mut recording) = RECORDING.lock() { *recording = Some((file, Instant::now())); }
+    Ok(())
+}
+
+/// Appends a frame received from client `sender`, if recording.
+pub fn frame(sender: i32, size: [u8; 4], content: &[u8]) {
+    let Ok(mut recording) = RECORDING.lock() else { return; };
+    let Some((file, started)) = recording.as_mut() else { return; };
+
+    let at = started.elapsed().as_micros() as u64;
+    let written = file.write_all(&at.to_le_bytes())
+        .and_then(|_| file.write_all(&sender.to_le_bytes()))
+        .and_then(|_| file.write_all(&size))
+        .and_then(|_| file.write_all(content));
+    if written.is_err() { *recording = None; } // e.g. the disk is full, a truncated entry would end the replay there anyway
+}
+
+/// Writes out what is buffered, called at shutdown.
+pub fn flush() {
+    if let Ok(mut recording) = RECORDING.lock()
+        && let Some((file, _)) = recording.as_mut() {
+        let _ = file.flush();
+    }
+}
+
+/// A recorded packet.
+pub struct Entry {
+    /// When it arrived, counted from the start of the recording.
+    pub at: Duration,
+    /// Id the sender had in the recorded session.
+    pub sender: i32,
+    /// Without its size prefix.
+    pub payload: Vec<u8>
+}
+
+/// Reads the entries of a recording in the order they were recorded.
+pub struct Reader {
+    file: BufReader<File>
+}
+
+impl Reader {
+    pub fn open(path: &str) -> io::Result<Reader> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC { return Err(io::Error::new(ErrorKind::InvalidData, "not an echoserver recording")); }
+        Ok(Reader { file })
+    }
+
+    fn read_entry(&mut self) -> io::Result<Option<Entry>> {
+        let mut header = [0u8; 16];
+        match self.file.read_exact(&mut header) {
+            Ok(()) => { },
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e)
+        }
+        let at = u64::from_le_bytes(header[..8].try_into().unwrap_or_default());
+        let sender = i32::from_le_bytes(header[8..12].try_into().unwrap_or_default());
+        let size = i32::from_le_bytes(header[12..].try_into().unwrap_or_default());
+        if !(4..=crate::BUFFER_SIZE as i32).contains(&size) { return Err(io::Error::new(ErrorKind::InvalidData, format!("invalid frame size {}", size))); }
+
+        let mut payload = vec![0u8; size as usize - 4];
+        self.file.read_exact(&mut payload)?;
+        Ok(Some(Entry { at: Duration::from_micros(at), sender, payload }))
+    }
+}
+
+impl Iterator for Reader {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<io::Result<Entry>> {
+        self.read_entry().transpose()
+    }
+}
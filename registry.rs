@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use rand::Rng;
+
+use crate::auth::Identity;
+use crate::metrics::DisconnectReason;
+use crate::metrics::Metrics;
+use crate::metrics::Traffic;
+use crate::random;
+use crate::resume::History;
+use crate::resume::Retention;
+use crate::transport::Connection;
+
+pub type SharedConnections = Arc<RwLock<HashMap<i32, Client>>>;
+
+/// Sender ID of server-originated messages, never handed out to a client (see `ids`).
+pub const SERVER_ID: i32 = 0;
+
+/// Everything known about a connection apart from its socket.
+#[derive(Clone)]
+pub struct ClientMeta {
+    /// Random UUID identifying this connection and the ones that resumed it (see `resume`), unlike IDs it is never reused.
+    pub session: String,
+    pub addr: SocketAddr,
+    pub connected_at: SystemTime,
+    /// See `transport::Connection::transport`.
+    pub transport: &'static str,
+    /// Optional protocol features in effect for this client (e.g. `mirror`, `control_packets`).
+    pub features: Vec<&'static str>,
+    /// Who the client authenticated as with a token, `None` without token authentication.
+    pub identity: Option<Identity>
+}
+
+pub struct Client {
+    /// Only for shutting it down and inspecting it, frames are sent through `outbox`.
+    pub stream: Box<dyn Connection>,
+    pub outbox: Arc<Outbox>,
+    pub meta: ClientMeta,
+    pub traffic: Arc<Traffic>,
+    /// Set before the socket is shut down by an operator, so the client thread reports the right reason.
+    pub kicked: Arc<AtomicBool>,
+    /// Milliseconds relayed packets wait before they are queued for the client, see `State::set_latency`.
+    pub latency_ms: AtomicU32
+}
+
+impl Client {
+    /// Queues a frame for the client, disconnecting it if its send queue overflows under the `disconnect` policy.
+    pub fn send(&self, frame: Arc<[u8]>, relayed: bool) -> bool {
+        let queued = self.outbox.push(frame, relayed);
+        if !queued { self.disconnect_if_failed(); }
+        queued
+    }
+
+    /// Shuts the socket down if the outbox failed (e.g. its queue overflowed), its thread then removes it.
+    pub fn disconnect_if_failed(&self) {
+        if self.outbox.failure().is_some() { let _ = self.stream.shutdown(std::net::Shutdown::Both); }
+    }
+
+    /// Disconnects the client after writing `farewell` (e.g. a control frame with the reason),
+    /// its thread then removes it from the registry.
+    pub fn kick(&self, farewell: Option<&[u8]>) {
+        self.kicked.store(true, Ordering::SeqCst);
+        if let Some(frame) = farewell { self.outbox.push(Arc::from(frame), false); }
+        self.outbox.close();
+        let _ = self.stream.shutdown(std::net::Shutdown::Read);
+    }
+}
+
+/// A frame waiting in an `Outbox`.
+pub struct Outgoing {
+    /// The whole frame (size and content), shared by every client it is queued for.
+    pub frame: Arc<[u8]>,
+    /// Whether this is a relayed packet, only those count towards the sent metrics.
+    pub relayed: bool
+}
+
+/// What happens to a frame that doesn't fit into a client's send queue anymore.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Drop queued frames, oldest first, until it fits.
+    DropOldest,
+    /// Drop the new frame.
+    DropNewest,
+    /// Drop everything and disconnect the client.
+    Disconnect,
+    /// Queue it anyway and stop reading from the senders until the queue drained to half the limit,
+    /// nothing is dropped (except over `memory_limit`), see `State::backpressure`.
+    Backpressure
+}
+
+impl QueuePolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            QueuePolicy::DropOldest => "drop-oldest",
+            QueuePolicy::DropNewest => "drop-newest",
+            QueuePolicy::Disconnect => "disconnect",
+            QueuePolicy::Backpressure => "backpressure"
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<QueuePolicy> {
+        [QueuePolicy::DropOldest, QueuePolicy::DropNewest, QueuePolicy::Disconnect, QueuePolicy::Backpressure].into_iter().find(|p| p.name() == s)
+    }
+}
+
+struct Queue {
+    frames: VecDeque<Outgoing>,
+    /// Size of all `frames` together.
+    bytes: usize,
+    closed: bool,
+    /// Whether this queue counts towards `Metrics::saturated_queues`, see `Outbox::saturate`.
+    saturated: bool,
+    /// Why the client has to be disconnected, if it can't keep up.
+    failure: Option<DisconnectReason>,
+    /// The relayed frames taken out to be written, to replay them to a client that resumes, see `resume`.
+    history: Option<History>,
+    /// Relayed frames aren't taken out before this (unless the outbox is closed), see `Outbox::keep_history`.
+    held_until: Option<Instant>
+}
+
+/// Frames waiting to be written to a client. Senders only queue them, so a slow client doesn't hold
+/// up anyone else, and the client's writer (its own thread, or the event loop in the `poll` I/O mode)
+/// writes them in order.
+pub struct Outbox {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    /// Most bytes queued at once, 0 = unlimited.
+    limit: usize,
+    policy: QueuePolicy,
+    /// Most bytes queued for all clients together (`Metrics::buffered_bytes`), 0 = unlimited.
+    memory_limit: usize,
+    /// Where dropped frames are counted.
+    metrics: Arc<Metrics>,
+    /// Called when frames are queued or the outbox is closed, so the event loop doesn't wait for its poll timeout.
+    wake: Option<Box<dyn Fn() + Send + Sync>>
+}
+
+impl Outbox {
+    pub fn new(limit: usize, policy: QueuePolicy, memory_limit: usize, metrics: Arc<Metrics>, wake: Option<Box<dyn Fn() + Send + Sync>>) -> Outbox {
+        let queue = Queue { frames: VecDeque::new(), bytes: 0, closed: false, saturated: false, failure: None, history: None, held_until: None };
+        Outbox { queue: Mutex::new(queue), ready: Condvar::new(), limit, policy, memory_limit, metrics, wake }
+    }
+
+    /// Takes the oldest frame out of `queue`, unless it is a relayed frame that is held.
+    fn pop(&self, queue: &mut Queue) -> Option<Outgoing> {
+        if let Some(until) = queue.held_until {
+            let held = Instant::now() < until && !queue.closed;
+            if held && queue.frames.front().is_some_and(|o| o.relayed) { return None; }
+            if !held { queue.held_until = None; }
+        }
+
+        let outgoing = queue.frames.pop_front()?;
+        queue.bytes -= outgoing.frame.len();
+        self.metrics.buffered_bytes.fetch_sub(outgoing.frame.len() as u64, Ordering::Relaxed);
+        self.saturate(queue);
+        if outgoing.relayed && let Some(history) = &mut queue.history { Metrics::add(&self.metrics.history_evictions, history.record(Arc::clone(&outgoing.frame))); }
+        Some(outgoing)
+    }
+
+    /// Takes every relayed frame out of `queue`, the others stay in order.
+    fn take_relayed(&self, queue: &mut Queue) -> Vec<Arc<[u8]>> {
+        let (relayed, others): (VecDeque<Outgoing>, VecDeque<Outgoing>) = std::mem::take(&mut queue.frames).into_iter().partition(|o| o.relayed);
+        queue.frames = others;
+        let bytes: usize = relayed.iter().map(|o| o.frame.len()).sum();
+        queue.bytes -= bytes;
+        self.metrics.buffered_bytes.fetch_sub(bytes as u64, Ordering::Relaxed);
+        self.saturate(queue);
+        relayed.into_iter().map(|o| o.frame).collect()
+    }
+
+    /// Under the `backpressure` policy, counts the queue as saturated once it is over the limit until
+    /// it drained to half of it.
+    fn saturate(&self, queue: &mut Queue) {
+        if self.policy != QueuePolicy::Backpressure || self.limit == 0 { return; }
+        let saturated = queue.bytes > if queue.saturated { self.limit / 2 } else { self.limit };
+        if saturated == queue.saturated { return; }
+        queue.saturated = saturated;
+        match saturated {
+            true => self.metrics.saturated_queues.fetch_add(1, Ordering::Relaxed),
+            false => self.metrics.saturated_queues.fetch_sub(1, Ordering::Relaxed)
+        };
+    }
+
+    /// Queues a frame, making room for it according to the policy if the queue is full.
+    /// Returns false if the frame was dropped or the outbox is closed.
+    pub fn push(&self, frame: Arc<[u8]>, relayed: bool) -> bool {
+        let Ok(mut queue) = self.queue.lock() else { return false; };
+        if queue.closed { return false; }
+
+        if self.limit != 0 && queue.bytes + frame.len() > self.limit {
+            match self.policy {
+                QueuePolicy::DropOldest => {
+                    while queue.bytes + frame.len() > self.limit && self.pop(&mut queue).is_some() {
+                        Metrics::add(&self.metrics.queue_drops, 1);
+                    }
+                },
+                QueuePolicy::DropNewest => {
+                    Metrics::add(&self.metrics.queue_drops, 1);
+                    return false;
+                },
+                QueuePolicy::Disconnect => {
+                    Metrics::add(&self.metrics.queue_drops, queue.frames.len() as u64 + 1);
+                    drop(queue);
+                    self.fail(DisconnectReason::QueueFull);
+                    return false;
+                },
+                QueuePolicy::Backpressure => { }
+            }
+        }
+
+        if self.memory_limit != 0 && self.metrics.buffered_bytes.load(Ordering::Relaxed) as usize + frame.len() > self.memory_limit {
+            Metrics::add(&self.metrics.memory_drops, 1);
+            return false;
+        }
+
+        let was_empty = queue.frames.is_empty();
+        queue.bytes += frame.len();
+        Metrics::add(&self.metrics.buffered_bytes, frame.len() as u64);
+        queue.frames.push_back(Outgoing { frame, relayed });
+        self.saturate(&mut queue);
+        drop(queue);
+
+        self.ready.notify_one();
+        if was_empty && let Some(wake) = &self.wake { wake(); }
+        true
+    }
+
+    /// Waits for the next frame until `deadline` (if any), `None` once that passed or the outbox
+    /// is closed and everything queued was taken.
+    pub fn next(&self, deadline: Option<Instant>) -> Option<Outgoing> {
+        let mut queue = self.queue.lock().ok()?;
+        loop {
+            if let Some(outgoing) = self.pop(&mut queue) { return Some(outgoing); }
+            if queue.closed { return None; }
+            if deadline.is_some_and(|deadline| deadline <= Instant::now()) { return None; }
+            // held frames can be taken once the hold ends
+            let held = queue.held_until.filter(|_| !queue.frames.is_empty());
+            queue = match deadline.into_iter().chain(held).min() {
+                Some(until) => self.ready.wait_timeout(queue, until.saturating_duration_since(Instant::now())).ok()?.0,
+                None => self.ready.wait(queue).ok()?
+            };
+        }
+    }
+
+    /// The next frame if one is queued, without waiting.
+    pub fn try_next(&self) -> Option<Outgoing> {
+        let mut queue = self.queue.lock().ok()?;
+        self.pop(&mut queue)
+    }
+
+    /// Stops accepting frames, the ones already queued are still written.
+    pub fn close(&self) {
+        if let Ok(mut queue) = self.queue.lock() { queue.closed = true; }
+        self.ready.notify_all();
+        if let Some(wake) = &self.wake { wake(); }
+    }
+
+    /// Closes the outbox and drops what is queued, the client has to be disconnected for `reason`.
+    pub fn fail(&self, reason: DisconnectReason) {
+        if let Ok(mut queue) = self.queue.lock() {
+            self.metrics.buffered_bytes.fetch_sub(queue.bytes as u64, Ordering::Relaxed);
+            queue.frames.clear();
+            queue.bytes = 0;
+            self.saturate(&mut queue);
+            queue.failure.get_or_insert(reason);
+        }
+        self.close();
+    }
+
+    /// Why the outbox failed, see `Outbox::fail`.
+    pub fn failure(&self) -> Option<DisconnectReason> {
+        self.queue.lock().ok()?.failure
+    }
+
+    /// Keeps the relayed frames taken out to be written as far as `retention` allows, and holds the relayed
+    /// frames for `hold` or until `release`, so a client that resumes gets the frames it missed first.
+    pub fn keep_history(&self, retention: Retention, hold: Duration) {
+        let Ok(mut queue) = self.queue.lock() else { return; };
+        queue.history = Some(History::new(retention));
+        queue.held_until = Some(Instant::now() + hold);
+    }
+
+    /// Stops holding relayed frames, see `keep_history`.
+    pub fn release(&self) {
+        if let Ok(mut queue) = self.queue.lock() { queue.held_until = None; }
+        self.ready.notify_one();
+        if let Some(wake) = &self.wake { wake(); }
+    }
+
+    /// Takes the history out with the relayed frames that weren't written yet, once the client dropped.
+    pub fn detach(&self) -> Option<History> {
+        let mut queue = self.queue.lock().ok()?;
+        let unsent = self.take_relayed(&mut queue);
+        let mut history = queue.history.take()?;
+        for frame in unsent { Metrics::add(&self.metrics.history_evictions, history.record(frame)); }
+        Some(history)
+    }
+
+    /// Continues the `history` of a resumed session: drops the queued relayed frames (`history` has them as
+    /// well), queues `notice` (given the number of the first frame replayed) and the frames after the one
+    /// numbered `received`, and stops holding. Returns the number of the first frame replayed and how many are.
+    ///
+    /// The replay is limited like `push`: the frames that don't fit into the queue are dropped by the policy
+    /// (the oldest ones with `drop-oldest`, so the replay starts later) or the client is disconnected, and
+    /// none are queued beyond `memory_limit`. The notice is always queued, the client can't count without it.
+    pub fn resume(&self, notice: impl Fn(u64) -> Vec<u8>, mut history: History, received: u64) -> (u64, usize) {
+        let Ok(mut queue) = self.queue.lock() else { return (received + 1, 0); };
+        self.take_relayed(&mut queue);
+        Metrics::add(&self.metrics.history_evictions, history.expire());
+        let (mut first, mut missed) = history.split_off(received);
+
+        let mut bytes: usize = missed.iter().map(|f| f.len()).sum();
+        let room = self.limit.saturating_sub(queue.bytes + notice(first).len());
+        if self.limit != 0 && bytes > room {
+            match self.policy {
+                QueuePolicy::DropOldest => {
+                    let mut skipped = 0;
+                    while bytes > room && skipped < missed.len() {
+                        bytes -= missed[skipped].len();
+                        skipped += 1;
+                    }
+                    missed.drain(..skipped);
+                    history.leave_out(skipped as u64);
+                    first += skipped as u64;
+                    Metrics::add(&self.metrics.queue_drops, skipped as u64);
+                },
+                QueuePolicy::DropNewest => {
+                    while bytes > room && let Some(frame) = missed.pop() {
+                        bytes -= frame.len();
+                        Metrics::add(&self.metrics.queue_drops, 1);
+                    }
+                },
+                QueuePolicy::Disconnect => {
+                    Metrics::add(&self.metrics.queue_drops, (queue.frames.len() + missed.len()) as u64);
+                    drop(queue);
+                    self.fail(DisconnectReason::QueueFull);
+                    return (first, 0);
+                },
+                QueuePolicy::Backpressure => { }
+            }
+        }
+        let notice: Arc<[u8]> = Arc::from(notice(first));
+        let mut count = 0;
+        let replayed = std::iter::once(Outgoing { frame: notice, relayed: false }).chain(missed.into_iter().map(|frame| Outgoing { frame, relayed: true }));
+        for outgoing in replayed {
+            if outgoing.relayed && self.memory_limit != 0 && self.metrics.buffered_bytes.load(Ordering::Relaxed) as usize + outgoing.frame.len() > self.memory_limit {
+                Metrics::add(&self.metrics.memory_drops, 1);
+                continue;
+            }
+            count += outgoing.relayed as usize;
+            queue.bytes += outgoing.frame.len();
+            Metrics::add(&self.metrics.buffered_bytes, outgoing.frame.len() as u64);
+            queue.frames.push_back(outgoing);
+        }
+        self.saturate(&mut queue);
+        queue.history = Some(history);
+        queue.held_until = None;
+        drop(queue);
+
+        self.ready.notify_one();
+        if let Some(wake) = &self.wake { wake(); }
+        (first, count)
+    }
+
+    /// Size of the frames waiting to be written.
+    pub fn queued_bytes(&self) -> usize {
+        self.queue.lock().map(|q| q.bytes).unwrap_or(0)
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        let queue = self.queue.get_mut().unwrap_or_else(|e| e.into_inner());
+        self.metrics.buffered_bytes.fetch_sub(queue.bytes as u64, Ordering::Relaxed);
+        if queue.saturated { self.metrics.saturated_queues.fetch_sub(1, Ordering::Relaxed); }
+    }
+}
+
+/// Point-in-time copy of a registry entry, safe to use without holding the connections lock.
+pub struct ClientInfo {
+    pub id: i32,
+    pub meta: ClientMeta,
+    pub traffic: Arc<Traffic>
+}
+
+impl ClientInfo {
+    pub fn last_activity(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.traffic.last_activity_ms.load(Ordering::Relaxed))
+    }
+}
+
+/// Copies the metadata of all connected clients, sorted by ID.
+pub fn snapshot(connections: &SharedConnections) -> Option<Vec<ClientInfo>> {
+    let connections = connections.read().ok()?;
+
+    let mut clients: Vec<ClientInfo> = connections.iter()
+        .map(|(id, c)| ClientInfo { id: *id, meta: c.meta.clone(), traffic: Arc::clone(&c.traffic) })
+        .collect();
+    clients.sort_by_key(|c| c.id);
+    Some(clients)
+}
+
+/// Generates a random (version 4) UUID.
+pub fn new_session_id() -> String {
+    let mut b: [u8; 16] = random::with(|rng| rng.random());
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+
+    format!(
+        "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+    )
+}
+
+/// Prefix for log lines about a connection, e.g. `12345 [1b4e28ba-2fa1-41d2-883f-0016d3cca427]`.
+pub fn log_tag(id: i32, session: &str) -> String {
+    format!("{} [{}]", id, session)
+}
+
+/// Current time as milliseconds since the unix epoch, the format of `Traffic::last_activity_ms`.
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
@@ -0,0 +1,120 @@
+//! `echoserver replay`: feeds a recording (`record`) back through a running server, every recorded
+//! sender becoming a client of its own that connects when its first packet is due. Packets are sent at
+//! their recorded times, divided by `--speed`; what the clients receive is read and discarded.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use echoserver::EchoClient;
+use echoserver::recording::Reader;
+
+const USAGE: &str = "usage: echoserver replay FILE [host:port] [--speed N] [--secret SECRET]";
+
+struct Options {
+    file: String,
+    target: String,
+    /// Times faster than recorded, 0 = as fast as possible.
+    speed: f64,
+    /// Sent as the first frame when the server requires authentication.
+    secret: String
+}
+
+fn parse(args: &[String]) -> Result<Options, String> {
+    let mut options = Options { file: String::new(), target: "127.0.0.1:45565".to_string(), speed: 1.0, secret: String::new() };
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg.as_str(), None)
+        };
+        if !name.starts_with("--") {
+            if options.file.is_empty() { options.file = name.to_string(); }
+            else { options.target = if name.contains(':') { name.to_string() } else { format!("127.0.0.1:{}", name) }; }
+            continue;
+        }
+
+        let value = inline.or_else(|| args.next().cloned()).ok_or(format!("{} needs a value", name))?;
+        match name {
+            "--speed" => options.speed = value.parse::<f64>().ok().filter(|s| *s >= 0.0).ok_or(format!("invalid {} {}", name, value))?,
+            "--secret" => options.secret = value,
+            _ => return Err(format!("unknown option {}", name))
+        }
+    }
+
+    if options.file.is_empty() { return Err("no recording given".to_string()); }
+    Ok(options)
+}
+
+/// A client for a recorded sender, its received packets are read by a thread of its own.
+fn join(options: &Options) -> Result<EchoClient, String> {
+    let mut client = EchoClient::connect(&options.target).map_err(|e| format!("Could not connect to {} ({})", options.target, e))?;
+    if !options.secret.is_empty() {
+        client.authenticate(options.secret.as_bytes()).map_err(|e| format!("Could not authenticate with {} ({})", options.target, e))?;
+    }
+    let reader = client.try_clone().map_err(|e| format!("Could not clone the connection ({})", e))?;
+    reader.on_packet(|_| { });
+    Ok(client)
+}
+
+/// Runs the replay with the arguments after `replay`, returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let options = match parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return 2;
+        }
+    };
+    let reader = match Reader::open(&options.file) {
+        Ok(reader) => reader,
+        Err(e) => {
+            eprintln!("Could not open recording {} ({}).", options.file, e);
+            return 1;
+        }
+    };
+
+    println!("Replaying {} to {} at {}...", options.file, options.target, if options.speed == 0.0 { "full speed".to_string() } else { format!("{}x speed", options.speed) });
+    let started = Instant::now();
+    // `None` for senders whose client got disconnected
+    let mut clients: HashMap<i32, Option<EchoClient>> = HashMap::new();
+    let mut sent = 0u64;
+
+    for entry in reader {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("Could not read recording {} ({}), stopping after {} packets.", options.file, e, sent);
+                return 1;
+            }
+        };
+        if options.speed > 0.0 { thread::sleep((started + entry.at.div_f64(options.speed)).saturating_duration_since(Instant::now())); }
+
+        let client = match clients.entry(entry.sender) {
+            Entry::Occupied(client) => client.into_mut(),
+            Entry::Vacant(slot) => match join(&options) {
+                Ok(client) => slot.insert(Some(client)),
+                Err(e) => {
+                    eprintln!("{}, stopping after {} packets.", e, sent);
+                    return 1;
+                }
+            }
+        };
+        let Some(client) = client else { continue; };
+        if client.send(&entry.payload).is_ok() {
+            sent += 1;
+            continue;
+        }
+        eprintln!("The client of sender {} was disconnected, skipping its packets.", entry.sender);
+        clients.insert(entry.sender, None);
+    }
+
+    // give the server a moment to relay the last packets before the clients leave
+    thread::sleep(Duration::from_millis(500));
+    for client in clients.values().flatten() { let _ = client.close(); }
+    println!("Replayed      = {} packets from {} sender(s) in {:.1}s", sent, clients.len(), started.elapsed().as_secs_f64());
+    0
+}
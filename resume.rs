@@ -0,0 +1,370 @@
+//! Session resume (`resume_grace`, `resume_buffer`), so a client whose connection dropped (e.g. a phone
+//! switching networks) comes back as itself instead of as a new player. Every client gets a `Resume`
+//! control packet with a token when it joins. When it drops, its ID, session and the last packets written
+//! to it are parked for `resume_grace` seconds, and packets relayed meanwhile are kept for it as well.
+//! A client that sends the token back as the first packet of a new connection takes its old ID over and
+//! gets the packets after the last one it received, before any new ones.
+//!
+//! Packets are numbered per session in the order they are taken out of the client's outbox to be written,
+//! starting with 1, so the client and the server count the same packets however many were dropped from a
+//! full send queue. Control packets aren't numbered. How many are kept is up to the `Retention` of
+//! `resume_buffer` (bytes), `resume_packets` and `resume_max_age`, older ones can't be replayed and are
+//! counted in `history_evictions`.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::control;
+use crate::logging::info;
+use crate::registry;
+
+/// Size of a resume token.
+pub const TOKEN: usize = 32;
+/// How long relayed packets wait for a new connection's first packet, so a resuming client gets the ones it
+/// missed before any new ones.
+pub const HOLD: Duration = Duration::from_millis(500);
+
+/// How much of a session's packets a `History` keeps, the oldest are evicted beyond any of the limits (0 = unlimited).
+#[derive(Clone, Copy)]
+pub struct Retention {
+    /// `resume_buffer`
+    pub bytes: usize,
+    /// `resume_packets`
+    pub packets: usize,
+    /// `resume_max_age`
+    pub age: Duration
+}
+
+impl Retention {
+    /// Whether the oldest of `packets` packets together `bytes` large, kept since `since`, has to go.
+    fn exceeded(&self, packets: usize, bytes: usize, since: Instant, now: Instant) -> bool {
+        self.packets != 0 && packets > self.packets || self.bytes != 0 && bytes > self.bytes || !self.age.is_zero() && now.duration_since(since) >= self.age
+    }
+}
+
+/// The last packets written to a client (or kept for it while it is parked), numbered from 1.
+pub struct History {
+    /// Number the next packet gets.
+    next: u64,
+    /// The packets with when they were recorded, oldest first.
+    frames: VecDeque<(Instant, Arc<[u8]>)>,
+    /// Size of all `frames` together.
+    bytes: usize,
+    retention: Retention
+}
+
+impl History {
+    pub fn new(retention: Retention) -> History {
+        History { next: 1, frames: VecDeque::new(), bytes: 0, retention }
+    }
+
+    /// Keeps a packet, returns how many older ones were evicted for it.
+    pub fn record(&mut self, frame: Arc<[u8]>) -> u64 {
+        self.next += 1;
+        self.bytes += frame.len();
+        self.frames.push_back((Instant::now(), frame));
+        self.expire()
+    }
+
+    /// Evicts the packets beyond the retention limits, returns how many.
+    pub fn expire(&mut self) -> u64 {
+        let (now, mut evicted) = (Instant::now(), 0);
+        while let Some((since, oldest)) = self.frames.front() && self.retention.exceeded(self.frames.len(), self.bytes, *since, now) {
+            self.bytes -= oldest.len();
+            self.frames.pop_front();
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// Takes the frames after number `received` out, they are numbered again once they are recorded anew.
+    /// Returns the number of the first one, which is later than `received + 1` if older ones were evicted.
+    pub fn split_off(&mut self, received: u64) -> (u64, Vec<Arc<[u8]>>) {
+        let oldest = self.next - self.frames.len() as u64;
+        let keep = ((received + 1).saturating_sub(oldest) as usize).min(self.frames.len());
+        let missed: Vec<Arc<[u8]>> = self.frames.drain(keep..).map(|(_, frame)| frame).collect();
+        self.bytes -= missed.iter().map(|f| f.len()).sum::<usize>();
+        self.next = oldest + keep as u64;
+        (self.next, missed)
+    }
+
+    /// Leaves `count` numbers out, for frames taken out with `split_off` that won't be recorded anew.
+    pub fn leave_out(&mut self, count: u64) {
+        self.next += count;
+    }
+}
+
+/// A session that may be resumed until `until`.
+pub struct Parked {
+    pub id: i32,
+    pub session: String,
+    /// Name of the identity it authenticated with, a resuming client has to have the same.
+    pub user: Option<String>,
+    pub history: History,
+    until: Instant
+}
+
+/// The sessions that dropped less than `resume_grace` ago, by token.
+pub struct Resume {
+    pub grace: Duration,
+    /// What every client's `History` keeps.
+    pub retention: Retention,
+    parked: Mutex<HashMap<[u8; TOKEN], Parked>>
+}
+
+impl Resume {
+    pub fn new(grace: Duration, retention: Retention) -> Resume {
+        Resume { grace, retention, parked: Mutex::new(HashMap::new()) }
+    }
+
+    /// Keeps the session of a client that dropped for the grace period.
+    pub fn park(&self, token: [u8; TOKEN], id: i32, session: String, user: Option<String>, history: History) {
+        let Ok(mut parked) = self.parked.lock() else { return; };
+        parked.insert(token, Parked { id, session, user, history, until: Instant::now() + self.grace });
+    }
+
+    /// Takes the session parked with `token` if it may still be resumed by `user`.
+    pub fn take(&self, token: &[u8; TOKEN], user: Option<&str>) -> Option<Parked> {
+        let mut parked = self.parked.lock().ok()?;
+        expire(&mut parked);
+        if parked.get(token)?.user.as_deref() != user { return None; }
+        parked.remove(token)
+    }
+
+    /// Whether `id` belongs to a parked session, so it isn't given to anyone else.
+    pub fn holds(&self, id: i32) -> bool {
+        self.parked.lock().is_ok_and(|parked| parked.values().any(|p| p.id == id && p.until > Instant::now()))
+    }
+
+    /// Keeps a frame that was routed from `from` for every parked session, called by the router for each one
+    /// while it holds the connections lock, so no frame falls between a client's connections. Returns how many
+    /// older frames were evicted for it.
+    pub fn record(&self, from: i32, frame: &Arc<[u8]>) -> u64 {
+        let Ok(mut parked) = self.parked.lock() else { return 0; };
+        if parked.is_empty() { return 0; }
+        expire(&mut parked);
+        parked.values_mut().filter(|p| p.id != from).map(|session| session.history.record(Arc::clone(frame))).sum()
+    }
+}
+
+/// Drops the sessions whose grace period is over.
+fn expire(parked: &mut HashMap<[u8; TOKEN], Parked>) {
+    let now = Instant::now();
+    parked.retain(|_, p| {
+        if p.until > now { return true; }
+        info!("{} - Was not resumed in time, its session ended.", registry::log_tag(p.id, &p.session));
+        false
+    });
+}
+
+/// The token and the number of the last packet received of a resume request, the first packet of a
+/// connection whose payload is a `Resume` control packet with `[token][last packet: u64 LE]` as its body.
+pub fn request(payload: &[u8]) -> Option<([u8; TOKEN], u64)> {
+    let body = payload.strip_prefix(&control::MAGIC)?.strip_prefix(&[control::Kind::Resume as u8])?;
+    if body.len() != TOKEN + 8 { return None; }
+    let token: [u8; TOKEN] = body[..TOKEN].try_into().ok()?;
+    Some((token, u64::from_le_bytes(body[TOKEN..].try_into().ok()?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::DisconnectReason;
+    use crate::metrics::Metrics;
+    use crate::registry::Outbox;
+    use crate::registry::QueuePolicy;
+
+    const UNLIMITED: Retention = Retention { bytes: 0, packets: 0, age: Duration::ZERO };
+    const NOTICE: usize = 8;
+
+    fn frame(byte: u8) -> Arc<[u8]> {
+        Arc::from(vec![byte; 10])
+    }
+
+    fn history(frames: &[u8]) -> History {
+        let mut history = History::new(UNLIMITED);
+        for byte in frames { history.record(frame(*byte)); }
+        history
+    }
+
+    fn bytes(frames: &[Arc<[u8]>]) -> Vec<u8> {
+        frames.iter().map(|f| f[0]).collect()
+    }
+
+    fn outbox(limit: usize, policy: QueuePolicy, memory_limit: usize) -> Outbox {
+        let outbox = Outbox::new(limit, policy, memory_limit, Arc::new(Metrics::default()), None);
+        outbox.keep_history(UNLIMITED, HOLD);
+        outbox
+    }
+
+    /// What the outbox has queued now, the notice as `(first, None)` and every frame as `(number, Some(byte))`.
+    fn drain(outbox: &Outbox) -> Vec<u64> {
+        std::iter::from_fn(|| outbox.try_next()).map(|o| match o.relayed {
+            true => o.frame[0] as u64,
+            false => 1000 + u64::from_le_bytes(o.frame[..NOTICE].try_into().unwrap())
+        }).collect()
+    }
+
+    fn notice(first: u64) -> Vec<u8> {
+        first.to_le_bytes().to_vec()
+    }
+
+    #[test]
+    fn frames_are_numbered_from_one() {
+        assert_eq!(history(&[]).split_off(0).0, 1);
+
+        let (first, missed) = history(&[1, 2, 3]).split_off(1);
+        assert_eq!((first, bytes(&missed)), (2, vec![2, 3]));
+        let (first, missed) = history(&[1, 2, 3]).split_off(0);
+        assert_eq!((first, bytes(&missed)), (1, vec![1, 2, 3]));
+        let (first, missed) = history(&[1, 2, 3]).split_off(3);
+        assert_eq!((first, missed.len()), (4, 0));
+        // a client that claims more than it got gets nothing replayed
+        let (first, missed) = history(&[1, 2, 3]).split_off(7);
+        assert_eq!((first, missed.len()), (4, 0));
+    }
+
+    #[test]
+    fn replayed_frames_are_numbered_again() {
+        let mut history = history(&[1, 2, 3]);
+        let (first, missed) = history.split_off(1);
+        assert_eq!(first, 2);
+        for frame in missed { history.record(frame); }
+        history.record(frame(4));
+        let (first, missed) = history.split_off(3);
+        assert_eq!((first, bytes(&missed)), (4, vec![4]));
+        for frame in missed { history.record(frame); }
+
+        // two frames taken out were dropped instead of written
+        history.leave_out(2);
+        history.record(frame(7));
+        let (first, missed) = history.split_off(6);
+        assert_eq!((first, bytes(&missed)), (7, vec![7]));
+    }
+
+    #[test]
+    fn retention_evicts_the_oldest_frames() {
+        let mut by_count = History::new(Retention { packets: 2, ..UNLIMITED });
+        let evicted: u64 = [1, 2, 3, 4].into_iter().map(|b| by_count.record(frame(b))).sum();
+        assert_eq!(evicted, 2);
+        // the replay starts after the evicted ones
+        let (first, missed) = by_count.split_off(0);
+        assert_eq!((first, bytes(&missed)), (3, vec![3, 4]));
+
+        let mut by_size = History::new(Retention { bytes: 25, ..UNLIMITED });
+        let evicted: u64 = [1, 2, 3].into_iter().map(|b| by_size.record(frame(b))).sum();
+        assert_eq!(evicted, 1);
+        assert_eq!(bytes(&by_size.split_off(0).1), vec![2, 3]);
+
+        let mut by_age = History::new(Retention { age: Duration::from_millis(20), ..UNLIMITED });
+        by_age.record(frame(1));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(by_age.record(frame(2)), 1);
+        assert_eq!(by_age.split_off(0), (2, vec![frame(2)]));
+    }
+
+    #[test]
+    fn a_parked_session_is_taken_once_by_its_user() {
+        let resume = Resume::new(Duration::from_secs(60), UNLIMITED);
+        let token = [7; TOKEN];
+        resume.park(token, 42, "session".to_string(), Some("alice".to_string()), history(&[1]));
+        assert!(resume.holds(42));
+        assert!(!resume.holds(43));
+
+        assert!(resume.take(&[8; TOKEN], Some("alice")).is_none());
+        assert!(resume.take(&token, None).is_none());
+        assert!(resume.take(&token, Some("bob")).is_none());
+
+        let parked = resume.take(&token, Some("alice")).unwrap();
+        assert_eq!((parked.id, parked.session.as_str()), (42, "session"));
+        assert!(resume.take(&token, Some("alice")).is_none());
+        assert!(!resume.holds(42));
+    }
+
+    #[test]
+    fn parked_sessions_expire_after_the_grace_period() {
+        let resume = Resume::new(Duration::from_millis(20), UNLIMITED);
+        resume.park([1; TOKEN], 42, "session".to_string(), None, history(&[]));
+        assert!(resume.holds(42));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!resume.holds(42));
+        assert!(resume.take(&[1; TOKEN], None).is_none());
+    }
+
+    #[test]
+    fn parked_sessions_keep_the_frames_of_the_others() {
+        let resume = Resume::new(Duration::from_secs(60), UNLIMITED);
+        resume.park([1; TOKEN], 1, "one".to_string(), None, history(&[]));
+        resume.park([2; TOKEN], 2, "two".to_string(), None, history(&[]));
+        resume.record(1, &frame(5));
+        resume.record(2, &frame(6));
+
+        assert_eq!(bytes(&resume.take(&[1; TOKEN], None).unwrap().history.split_off(0).1), vec![6]);
+        assert_eq!(bytes(&resume.take(&[2; TOKEN], None).unwrap().history.split_off(0).1), vec![5]);
+    }
+
+    #[test]
+    fn requests_are_parsed() {
+        let body = [&[7u8; TOKEN][..], &41u64.to_le_bytes()].concat();
+        assert_eq!(request(&control::frame(control::Kind::Resume, &body)[4..]), Some(([7; TOKEN], 41)));
+        assert_eq!(request(&control::frame(control::Kind::Resume, &body[1..])[4..]), None);
+        assert_eq!(request(&control::frame(control::Kind::Resumed, &body)[4..]), None);
+        assert_eq!(request(&body), None);
+    }
+
+    #[test]
+    fn missed_frames_are_replayed_in_order_before_new_ones() {
+        let old = outbox(0, QueuePolicy::DropOldest, 0);
+        for byte in [1, 2, 3] { old.push(frame(byte), true); }
+        old.release();
+        assert_eq!(old.try_next().unwrap().frame[0], 1);
+        // 2 and 3 were never written
+        let history = old.detach().unwrap();
+
+        let new = outbox(0, QueuePolicy::DropOldest, 0);
+        new.push(frame(4), true);
+        assert!(new.try_next().is_none(), "relayed frames are held until the first frame arrives");
+        assert_eq!(new.resume(notice, history, 1), (2, 2));
+        new.push(frame(5), true);
+        // 4 arrived while the session was parked and is in the history as well
+        assert_eq!(drain(&new), vec![1002, 2, 3, 5]);
+
+        // and they were numbered as the client counts them
+        let history = new.detach().unwrap();
+        let again = outbox(0, QueuePolicy::DropOldest, 0);
+        assert_eq!(again.resume(notice, history, 3), (4, 1));
+        assert_eq!(drain(&again), vec![1004, 5]);
+    }
+
+    #[test]
+    fn the_replay_respects_the_queue_limit() {
+        let limit = NOTICE + 20;
+
+        let drop_oldest = outbox(limit, QueuePolicy::DropOldest, 0);
+        assert_eq!(drop_oldest.resume(notice, history(&[1, 2, 3]), 0), (2, 2));
+        assert_eq!(drain(&drop_oldest), vec![1002, 2, 3]);
+
+        let drop_newest = outbox(limit, QueuePolicy::DropNewest, 0);
+        assert_eq!(drop_newest.resume(notice, history(&[1, 2, 3]), 0), (1, 2));
+        assert_eq!(drain(&drop_newest), vec![1001, 1, 2]);
+
+        let disconnect = outbox(limit, QueuePolicy::Disconnect, 0);
+        disconnect.resume(notice, history(&[1, 2, 3]), 0);
+        assert!(matches!(disconnect.failure(), Some(DisconnectReason::QueueFull)));
+
+        let backpressure = outbox(limit, QueuePolicy::Backpressure, 0);
+        assert_eq!(backpressure.resume(notice, history(&[1, 2, 3]), 0), (1, 3));
+        assert_eq!(drain(&backpressure), vec![1001, 1, 2, 3]);
+    }
+
+    #[test]
+    fn the_replay_respects_the_memory_limit() {
+        let outbox = outbox(0, QueuePolicy::DropOldest, NOTICE + 20);
+        assert_eq!(outbox.resume(notice, history(&[1, 2, 3]), 0), (1, 2));
+        assert_eq!(drain(&outbox), vec![1001, 1, 2]);
+    }
+}
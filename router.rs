@@ -0,0 +1,101 @@
+//! Routing of relayed packets. A client's reader (its thread, or the event loop) only checks what it
+//! received (rate limit, filters, memory) and publishes the frame; the router thread decides who gets it
+//! and queues it in their outboxes, each client's writer sends it from there. Reading, routing and
+//! writing only meet at these channels, so new ways of picking recipients only touch `route`.
+//!
+//! There is a single router so frames are queued in the order they were published. It never waits on
+//! a client, a full outbox drops or fails instead (see `QueuePolicy`); readers wait while its own queue
+//! (`ROUTER_QUEUE`) is full.
+
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use crate::chaos::Chaos;
+use crate::fanout::Fanout;
+use crate::logging::error;
+use crate::metrics::Metrics;
+use crate::otlp;
+use crate::otlp::Value;
+use crate::registry::Outbox;
+use crate::registry::SharedConnections;
+use crate::resume::Resume;
+
+/// Frames published but not yet routed, readers wait once this many are.
+const ROUTER_QUEUE: usize = 4096;
+
+/// A frame received from a client, on its way to the router.
+struct Inbound {
+    from: i32,
+    /// The whole frame (size and content), shared by every recipient.
+    frame: Arc<[u8]>,
+    /// The `broadcast` span of a sampled packet, ended once it is queued for everyone.
+    span: Option<otlp::Span>
+}
+
+#[derive(Clone)]
+pub struct Router {
+    inbox: mpsc::SyncSender<Inbound>
+}
+
+impl Router {
+    /// Starts the router thread, it ends with the process. Frames go to every client except their sender,
+    /// and to the sender too with `mirror`. With `fanout` large broadcasts are spread over its threads,
+    /// unless `chaos` delays or drops frames for each recipient. With `resume` they are kept for the parked sessions as well.
+    pub fn spawn(
+        connections: SharedConnections, metrics: Arc<Metrics>, mirror: bool, fanout: Option<Fanout>, mut chaos: Chaos, resume: Option<Arc<Resume>>
+    ) -> std::io::Result<Router> {
+        let (inbox, receiver) = mpsc::sync_channel::<Inbound>(ROUTER_QUEUE);
+        thread::Builder::new().name("router".to_string()).spawn(move || {
+            for inbound in receiver { route(&connections, &metrics, mirror, fanout.as_ref(), &mut chaos, resume.as_deref(), inbound); }
+        })?;
+        Ok(Router { inbox })
+    }
+
+    /// Hands a frame received from client `from` to the router, waiting while its queue is full.
+    /// Returns false if the router is gone.
+    pub fn publish(&self, from: i32, frame: Arc<[u8]>, span: Option<otlp::Span>) -> bool {
+        self.inbox.send(Inbound { from, frame, span }).is_ok()
+    }
+}
+
+/// Queues a published frame for its recipients.
+fn route(connections: &SharedConnections, metrics: &Metrics, mirror: bool, fanout: Option<&Fanout>, chaos: &mut Chaos, resume: Option<&Resume>, inbound: Inbound) {
+    let Inbound { from, frame, mut span } = inbound;
+
+    let _connections = match connections.read() {
+        Ok(c) => c,
+        Err(_) => {
+            error!("Could not lock connections, dropping packet!");
+            otlp::end(span);
+            return;
+        }
+    };
+
+    Metrics::add(&metrics.packets_relayed, 1);
+
+    let mut recipients = 0;
+    let started = Instant::now();
+    let others = || _connections.iter().filter(|(id, _)| **id != from || mirror);
+    match fanout {
+        _ if chaos.applies(&_connections) => for (id, client) in others() {
+            if chaos.send(*id, client, &frame) { recipients += 1; }
+        },
+        Some(fanout) if _connections.len() >= fanout.min_recipients => {
+            let targets: Vec<(i32, Arc<Outbox>)> = others().map(|(id, client)| (*id, Arc::clone(&client.outbox))).collect();
+            let total = targets.len();
+            let missed = fanout.send(&frame, targets);
+            for client in missed.iter().filter_map(|id| _connections.get(id)) { client.disconnect_if_failed(); }
+            recipients = (total - missed.len()) as i64;
+        },
+        _ => for (_, client) in others() {
+            if client.send(Arc::clone(&frame), true) { recipients += 1; }
+        }
+    }
+    if let Some(resume) = resume { Metrics::add(&metrics.history_evictions, resume.record(from, &frame)); }
+    metrics.broadcast_latency.observe(started.elapsed());
+
+    if let Some(s) = span.as_mut() { s.set("broadcast.recipients", Value::Int(recipients)); }
+    otlp::end(span);
+}
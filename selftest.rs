@@ -0,0 +1,126 @@
+//! `echoserver --self-test`: starts a server on a free loopback port, connects a few clients and checks
+//! that packets are broadcast, mirrored and rate limited. Exits with 1 if a check fails, as a smoke test
+//! for packages and deployments. `config.yaml` and the command line are not read.
+
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use echoserver::EchoClient;
+use echoserver::Packet;
+use echoserver::Server;
+use echoserver::ServerHandle;
+use echoserver::control::Kind;
+
+const CLIENTS: usize = 3;
+/// How long a client waits for a packet that should arrive.
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// Packets per second allowed during the rate limit check, and how many more are sent at once.
+const MAX_PACKETS: i32 = 10;
+const FLOOD: usize = 40;
+
+pub fn run() -> i32 {
+    let server = Server::builder()
+        .port(0).storage("memory").stdin_console(false).control_packets(true).log_level("warning").max_rate(0)
+        .build();
+    let server = match server {
+        Ok(server) => Arc::new(server),
+        Err(e) => {
+            eprintln!("Could not start the server ({}).", e);
+            return 1;
+        }
+    };
+    let relay = { let server = Arc::clone(&server); thread::spawn(move || server.run()) };
+
+    // the clients stay connected until the server stopped, so it doesn't see them go away with packets unread
+    let mut clients = Vec::new();
+    let result = match server.local_addr() {
+        Ok(addr) => check(&server.handle(), &format!("127.0.0.1:{}", addr.port()), &mut clients),
+        Err(e) => Err(format!("no listener address ({})", e))
+    };
+    server.stop();
+    let _ = relay.join();
+    drop(clients);
+
+    match result {
+        Ok(()) => {
+            println!("Self-test passed.");
+            0
+        },
+        Err(e) => {
+            println!("Self-test failed: {}.", e);
+            1
+        }
+    }
+}
+
+fn check(handle: &ServerHandle, target: &str, clients: &mut Vec<EchoClient>) -> Result<(), String> {
+    for _ in 0..CLIENTS {
+        let client = EchoClient::connect(target).map_err(|e| format!("could not connect to {} ({})", target, e))?;
+        let _ = client.set_read_timeout(Some(TIMEOUT));
+        clients.push(client);
+    }
+    let started = Instant::now();
+    while handle.list_clients().len() < CLIENTS {
+        if started.elapsed() > TIMEOUT { return Err(format!("only {} of {} clients joined", handle.list_clients().len(), CLIENTS)); }
+        thread::sleep(Duration::from_millis(10));
+    }
+    println!("Connect       = ok, {} clients joined", CLIENTS);
+
+    clients[0].send(b"self-test").map_err(|e| format!("could not send ({})", e))?;
+    for (i, client) in clients.iter_mut().enumerate().skip(1) {
+        expect(client, b"self-test").map_err(|e| format!("broadcast to client {}: {}", i + 1, e))?;
+    }
+    println!("Broadcast     = ok, received by the {} other clients", CLIENTS - 1);
+
+    expect(&mut clients[0], b"self-test").map_err(|e| format!("mirror: {}", e))?;
+    println!("Mirror        = ok");
+
+    handle.set_limit("max_packets", MAX_PACKETS)?;
+    for n in 0..FLOOD {
+        clients[0].send(format!("flood {}", n).as_bytes()).map_err(|e| format!("could not send ({})", e))?;
+    }
+    let relayed = count_data(&mut clients[1]);
+    let throttled = received_throttled(&mut clients[0]);
+    handle.set_limit("max_packets", 0)?;
+    if relayed == 0 || relayed >= FLOOD { return Err(format!("rate limit: {} of {} packets over the limit of {}/s were relayed", relayed, FLOOD, MAX_PACKETS)); }
+    if !throttled { return Err("rate limit: the sender got no Throttled control packet".to_string()); }
+    println!("Rate limit    = ok, {} of {} packets relayed at {}/s", relayed, FLOOD, MAX_PACKETS);
+
+    Ok(())
+}
+
+/// Waits for the next packet that isn't a control packet, which has to be `payload`.
+fn expect(client: &mut EchoClient, payload: &[u8]) -> Result<(), String> {
+    loop {
+        match client.recv() {
+            Ok(Some(Packet::Data(received))) if received == payload => return Ok(()),
+            Ok(Some(Packet::Data(received))) => return Err(format!("received {:?} instead", String::from_utf8_lossy(&received))),
+            Ok(Some(Packet::Control(..))) => continue,
+            Ok(None) => return Err("disconnected".to_string()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => return Err("nothing received".to_string()),
+            Err(e) => return Err(e.to_string())
+        }
+    }
+}
+
+/// The packets received until none arrived for a while.
+fn count_data(client: &mut EchoClient) -> usize {
+    let _ = client.set_read_timeout(Some(Duration::from_millis(500)));
+    let mut n = 0;
+    while let Ok(Some(packet)) = client.recv() {
+        if matches!(packet, Packet::Data(_)) { n += 1; }
+    }
+    n
+}
+
+/// Whether a `Throttled` control packet arrived before none arrived for a while.
+fn received_throttled(client: &mut EchoClient) -> bool {
+    let _ = client.set_read_timeout(Some(Duration::from_millis(500)));
+    while let Ok(Some(packet)) = client.recv() {
+        if matches!(packet, Packet::Control(Kind::Throttled, _)) { return true; }
+    }
+    false
+}
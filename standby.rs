@@ -0,0 +1,346 @@
+//! Hot standby (`standby_port`, `standby_of`, `standby_secret`), so a crashed server doesn't end the
+//! session for good. The primary sends every standby connected to its `standby_port` a snapshot of its
+//! runtime state once per `INTERVAL`: the settings changed at runtime, whether it is paused, bans,
+//! temporary bans, rate limit overrides and the clients connected to it. A server started with
+//! `standby_of` follows the primary instead of binding its port, and once no snapshot arrived for
+//! `TAKEOVER` it binds the port (or the VIP it was moved to), takes over the last snapshot and starts
+//! accepting the clients that reconnect.
+//!
+//! The standby connects and the primary answers with a nonce. The standby returns a nonce of its own and
+//! its proof, then the primary its proof, both HMAC-SHA256 under `standby_secret` of who proves it and
+//! both nonces (see `proof`). The primary only proves itself once the standby did and only sends snapshots
+//! then, the standby only takes over snapshots of a primary that proved itself. Frames are
+//! `[size: i32 LE][payload]` like client frames, a snapshot is one line per entry, see `snapshot`.
+
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::auth;
+use crate::bans::Network;
+use crate::logging::debug;
+use crate::logging::error;
+use crate::logging::info;
+use crate::logging::warning;
+use crate::state::Offender;
+use crate::state::RateTarget;
+use crate::state::SharedState;
+
+/// How often the primary sends a snapshot.
+const INTERVAL: Duration = Duration::from_secs(1);
+/// How long the standby waits for a snapshot before it takes over.
+pub const TAKEOVER: Duration = Duration::from_secs(3);
+/// Largest snapshot accepted, a few hundred thousand clients.
+const MAX_SNAPSHOT: usize = 64 * 1024 * 1024;
+/// What the standby and the primary prove, so neither proof can be used for the other.
+const STANDBY: &[u8] = b"echoserver standby";
+const PRIMARY: &[u8] = b"echoserver primary";
+
+/// Serves snapshots of `state` to standbys connecting to `port` until the server stops.
+pub fn serve(port: i32, secret: &str, state: SharedState) -> io::Result<()> {
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port))?;
+    let secret = secret.to_string();
+    thread::Builder::new().name("standby".to_string()).spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (secret, state) = (secret.clone(), state.clone());
+            let addr = stream.peer_addr().map(|a| a.to_string()).unwrap_or_else(|_| "unknown".to_string());
+            let _ = thread::Builder::new().name("standby-sync".to_string()).spawn(move || {
+                match sync(stream, &secret, &state) {
+                    Ok(()) => info!("Standby {} disconnected.", addr),
+                    Err(e) => warning!("Standby {} disconnected ({}).", addr, e)
+                }
+            });
+        }
+    })?;
+    Ok(())
+}
+
+/// Authenticates a standby and sends it snapshots while the server runs.
+fn sync(mut stream: TcpStream, secret: &str, state: &SharedState) -> io::Result<()> {
+    stream.set_read_timeout(Some(TAKEOVER))?;
+    stream.set_write_timeout(Some(TAKEOVER))?;
+    admit(&mut stream, secret)?;
+    info!("Standby {} connected.", stream.peer_addr().map(|a| a.to_string()).unwrap_or_default());
+
+    while state.running.load(Ordering::SeqCst) {
+        write_frame(&mut stream, snapshot(state).as_bytes())?;
+        thread::sleep(INTERVAL);
+    }
+    Ok(())
+}
+
+/// The runtime state a standby takes over, one entry per line:
+/// `setting <key> <value>`, `paused`, `ban <network>`, `temp_ban <address> <seconds left> <offenses>`,
+/// `rate <client id or address> <bytes/s>` and `client <id> <session> <address>`.
+fn snapshot(state: &SharedState) -> String {
+    let mut lines = Vec::new();
+    for (key, value) in state.settings() { lines.push(format!("setting {} {}", key, value)); }
+    if state.paused.load(Ordering::SeqCst) { lines.push("paused".to_string()); }
+    if let Ok(bans) = state.bans.lock() { lines.extend(bans.networks().iter().map(|n| format!("ban {}", n))); }
+
+    let now = Instant::now();
+    if let Ok(offenders) = state.offenders.lock() {
+        lines.extend(offenders.iter().filter(|(_, o)| o.banned_until > now).map(|(ip, o)| format!("temp_ban {} {} {}", ip, o.banned_until.duration_since(now).as_secs().max(1), o.offenses)));
+    }
+    if let Ok(overrides) = state.rate_overrides.lock() {
+        lines.extend(overrides.iter().map(|(target, limit)| match target {
+            RateTarget::Client(id) => format!("rate {} {}", id, limit),
+            RateTarget::Address(ip) => format!("rate {} {}", ip, limit)
+        }));
+    }
+    if let Ok(connections) = state.connections.read() {
+        lines.extend(connections.iter().map(|(id, c)| format!("client {} {} {}", id, c.meta.session, c.meta.addr)));
+    }
+    lines.join("\n")
+}
+
+/// Follows the primary at `primary` until it is gone, returns its last snapshot. Waits for its first snapshot however long that takes.
+pub fn follow(primary: &str, secret: &str) -> String {
+    let mut last = String::new();
+    // when the last snapshot arrived, `None` until the first did
+    let mut heard: Option<Instant> = None;
+    let mut warned = false;
+
+    loop {
+        let mut received = false;
+        let result = subscribe(primary, secret, &mut |snapshot| {
+            if heard.is_none() { info!("Following the primary {} as its standby.", primary); }
+            (heard, last, received) = (Some(Instant::now()), snapshot, true);
+        });
+        match result {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && !received && !warned => {
+                error!("Primary {} closed the connection before sending a snapshot, check standby_secret!", primary);
+                warned = true;
+            },
+            Err(e) if e.kind() == ErrorKind::PermissionDenied && !warned => {
+                error!("{} does not know standby_secret, not following it!", primary);
+                warned = true;
+            },
+            Err(e) => debug!("Lost the primary {} ({}).", primary, e),
+            Ok(()) => { }
+        }
+
+        if heard.is_some_and(|heard| heard.elapsed() >= TAKEOVER) {
+            warning!("No snapshot from the primary {} for {}s, taking over!", primary, TAKEOVER.as_secs());
+            return last;
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+/// Connects to the primary and hands every snapshot to `received` until the connection breaks or stays silent for `TAKEOVER`.
+fn subscribe(primary: &str, secret: &str, received: &mut dyn FnMut(String)) -> io::Result<()> {
+    let address = primary.to_socket_addrs()?.next().ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("{} does not resolve", primary)))?;
+    let mut stream = TcpStream::connect_timeout(&address, INTERVAL)?;
+    stream.set_read_timeout(Some(TAKEOVER))?;
+    stream.set_write_timeout(Some(TAKEOVER))?;
+
+    join(&mut stream, secret)?;
+    loop {
+        let snapshot = read_frame(&mut stream)?;
+        received(String::from_utf8_lossy(&snapshot).into_owned());
+    }
+}
+
+/// Takes over the primary's last snapshot, before the first client is accepted.
+pub fn take_over(snapshot: &str, state: &SharedState) {
+    let (mut bans, mut temp_bans, mut overrides, mut clients) = (0, 0, 0, 0);
+    let now = Instant::now();
+    let current = state.settings();
+
+    for line in snapshot.lines() {
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["setting", key, value] => {
+                let Ok(value) = value.parse::<i32>() else { continue; };
+                if current.iter().any(|(k, v)| k == key && *v != value) { let _ = state.set(key, value, "standby takeover"); }
+            },
+            ["paused"] => { state.set_paused(true, "standby takeover"); },
+            ["ban", network] => {
+                let Ok(network) = network.parse::<Network>() else { continue; };
+                if let Ok(mut list) = state.bans.lock() {
+                    match list.add(network) {
+                        Ok(true) => bans += 1,
+                        Ok(false) => { },
+                        Err(e) => error!("Ban of {} is not persisted, could not save ban file ({})!", network, e)
+                    }
+                }
+            },
+            ["temp_ban", ip, seconds, offenses] => {
+                let (Ok(ip), Ok(seconds), Ok(offenses)) = (ip.parse::<IpAddr>(), seconds.parse::<u64>(), offenses.parse::<u32>()) else { continue; };
+                if let Ok(mut offenders) = state.offenders.lock() {
+                    offenders.insert(ip, Offender { offenses, last_offense: now, banned_until: now + Duration::from_secs(seconds) });
+                    temp_bans += 1;
+                }
+            },
+            ["rate", target, limit] => {
+                let (Some(target), Ok(limit)) = (RateTarget::parse(target), limit.parse::<i32>()) else { continue; };
+                if state.set_rate_override(target, Some(limit), "standby takeover") { overrides += 1; }
+            },
+            ["client", ..] => clients += 1,
+            _ => debug!("Ignored the snapshot line {}.", line)
+        }
+    }
+    info!("Took over from the primary: {} ban(s), {} temporary ban(s) and {} rate override(s), {} client(s) were connected to it.", bans, temp_bans, overrides, clients);
+}
+
+/// The primary's side of the handshake: checks the standby's proof, then proves itself.
+fn admit(stream: &mut TcpStream, secret: &str) -> io::Result<()> {
+    let nonce = auth::challenge();
+    write_frame(stream, &nonce)?;
+    let answer = read_frame(stream)?;
+    let (standby_nonce, standby_proof) = answer.split_at(answer.len().min(nonce.len()));
+    if standby_nonce.len() != nonce.len() || standby_nonce == nonce || !auth::constant_time_eq(standby_proof, &proof(secret, STANDBY, &nonce, standby_nonce)) {
+        return Err(io::Error::new(ErrorKind::PermissionDenied, "wrong standby_secret"));
+    }
+    write_frame(stream, &proof(secret, PRIMARY, standby_nonce, &nonce))
+}
+
+/// The standby's side of the handshake: proves itself, then checks the primary's proof.
+fn join(stream: &mut TcpStream, secret: &str) -> io::Result<()> {
+    let primary_nonce = read_frame(stream)?;
+    let nonce = auth::challenge();
+    if primary_nonce.len() != nonce.len() { return Err(io::Error::new(ErrorKind::InvalidData, "not an echoserver primary")); }
+    write_frame(stream, &[&nonce[..], &proof(secret, STANDBY, &primary_nonce, &nonce)].concat())?;
+    if !auth::constant_time_eq(&read_frame(stream)?, &proof(secret, PRIMARY, &nonce, &primary_nonce)) {
+        return Err(io::Error::new(ErrorKind::PermissionDenied, "the primary does not know standby_secret"));
+    }
+    Ok(())
+}
+
+/// What the `role` end sends to prove it knows the secret, bound to the nonce of the other end and its own.
+fn proof(secret: &str, role: &[u8], verifier_nonce: &[u8], prover_nonce: &[u8]) -> [u8; 32] {
+    auth::hmac_sha256(secret.as_bytes(), &[role, verifier_nonce, prover_nonce].concat())
+}
+
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> io::Result<()> {
+    stream.write_all(&[&((payload.len() + 4) as i32).to_le_bytes()[..], payload].concat())
+}
+
+fn read_frame(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut size = [0u8; 4];
+    stream.read_exact(&mut size)?;
+    let size = i32::from_le_bytes(size);
+    if size < 4 || size as usize > MAX_SNAPSHOT { return Err(io::Error::new(ErrorKind::InvalidData, format!("invalid frame size {}", size))); }
+    let mut payload = vec![0u8; size as usize - 4];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::Server;
+
+    /// Both ends of a connection, the standby's first, with the handshake of `primary` running on a thread.
+    fn handshake(primary_secret: &'static str) -> (TcpStream, thread::JoinHandle<io::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let standby = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        standby.set_read_timeout(Some(TAKEOVER)).unwrap();
+        let (mut primary, _) = listener.accept().unwrap();
+        (standby, thread::spawn(move || admit(&mut primary, primary_secret)))
+    }
+
+    fn server() -> Server {
+        Server::builder().port(0).ban_file("").storage("memory").stdin_console(false).build().unwrap()
+    }
+
+    #[test]
+    fn both_ends_prove_the_secret() {
+        let (mut standby, primary) = handshake("secret");
+        join(&mut standby, "secret").unwrap();
+        primary.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn a_standby_with_a_wrong_secret_gets_nothing() {
+        let (mut standby, primary) = handshake("secret");
+        assert_eq!(join(&mut standby, "wrong").unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(primary.join().unwrap().unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn a_primary_with_a_wrong_secret_is_not_followed() {
+        let (mut standby, primary) = handshake("wrong");
+        let standby = thread::spawn(move || join(&mut standby, "secret"));
+        assert_eq!(primary.join().unwrap().unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert!(standby.join().unwrap().is_err());
+
+        // nor is one that answers something else, e.g. the standby's own proof
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut standby = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut impostor, _) = listener.accept().unwrap();
+        let nonce = auth::challenge();
+        write_frame(&mut impostor, &nonce).unwrap();
+        let standby = thread::spawn(move || join(&mut standby, "secret"));
+        let answer = read_frame(&mut impostor).unwrap();
+        write_frame(&mut impostor, &answer[32..]).unwrap();
+        assert_eq!(standby.join().unwrap().unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn proofs_depend_on_the_role() {
+        let (a, b) = ([1u8; 32], [2u8; 32]);
+        assert_ne!(proof("secret", STANDBY, &a, &b), proof("secret", PRIMARY, &a, &b));
+        assert_ne!(proof("secret", STANDBY, &a, &b), proof("secret", STANDBY, &b, &a));
+        assert_ne!(proof("secret", STANDBY, &a, &b), proof("other", STANDBY, &a, &b));
+    }
+
+    #[test]
+    fn snapshots_are_taken_over() {
+        let primary = server();
+        let state = &primary.state;
+        state.set("max_rate", 16000, "test").unwrap();
+        state.set("max_players", 3, "test").unwrap();
+        state.set_paused(true, "test");
+        state.ban("203.0.113.0/24".parse().unwrap(), "test").unwrap();
+        state.ban("2001:db8::1".parse().unwrap(), "test").unwrap();
+        let now = Instant::now();
+        state.offenders.lock().unwrap().insert(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7)), Offender { offenses: 3, last_offense: now, banned_until: now + Duration::from_secs(60) });
+        state.offenders.lock().unwrap().insert(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 8)), Offender { offenses: 1, last_offense: now, banned_until: now });
+        state.set_rate_override(RateTarget::parse("198.51.100.9").unwrap(), Some(500), "test");
+        state.set_rate_override(RateTarget::parse("42").unwrap(), Some(700), "test");
+
+        let standby = server();
+        take_over(&snapshot(state), &standby.state);
+        let taken = &standby.state;
+        assert_eq!(taken.settings(), state.settings());
+        assert!(taken.paused.load(Ordering::SeqCst));
+        let bans: Vec<String> = taken.bans.lock().unwrap().networks().iter().map(|n| n.to_string()).collect();
+        assert_eq!(bans, ["203.0.113.0/24", "2001:db8::1"]);
+
+        // only the temporary bans still running, with about the time they had left
+        let offenders = taken.offenders.lock().unwrap();
+        assert_eq!(offenders.len(), 1);
+        let offender = &offenders[&IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7))];
+        assert_eq!(offender.offenses, 3);
+        assert!(offender.banned_until > Instant::now() + Duration::from_secs(58));
+
+        let overrides = taken.rate_overrides.lock().unwrap();
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(overrides.get(&RateTarget::parse("198.51.100.9").unwrap()), Some(&500));
+        assert_eq!(overrides.get(&RateTarget::parse("42").unwrap()), Some(&700));
+    }
+
+    #[test]
+    fn unknown_and_malformed_lines_are_skipped() {
+        let standby = server();
+        let before = standby.state.settings();
+        take_over("setting max_rate x\nsetting nope 5\nban not-a-network\ntemp_ban 1.2.3.4 x 1\nrate ? 5\nsomething else\n\nclient 1 s 1.2.3.4:5", &standby.state);
+        assert_eq!(standby.state.settings(), before);
+        assert!(standby.state.bans.lock().unwrap().networks().is_empty());
+        assert!(standby.state.offenders.lock().unwrap().is_empty());
+        assert!(standby.state.rate_overrides.lock().unwrap().is_empty());
+        assert!(!standby.state.paused.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::auth::Role;
+use crate::control;
+use crate::events::Hooks;
+use crate::federation::Federation;
+use crate::ids::IdAllocator;
+use crate::journal::Journal;
+use crate::kafka::Kafka;
+use crate::ratelimit::RateLimiter;
+use crate::clock::Clock;
+use crate::middleware::Stage;
+use crate::filter::FilterList;
+use crate::bans::AccessList;
+use crate::bans::BanList;
+use crate::bans::Network;
+use crate::logging::error;
+use crate::logging::info;
+use crate::logging::warning;
+use crate::metrics::Metrics;
+use crate::nats::Nats;
+use crate::registry;
+use crate::registry::Outbox;
+use crate::registry::QueuePolicy;
+use crate::registry::SharedConnections;
+use crate::resume;
+use crate::resume::Resume;
+use crate::router::Router;
+
+/// Runtime state shared by the accept loop, the client threads and the control interfaces.
+pub struct State {
+    pub connections: SharedConnections,
+    pub running: Arc<AtomicBool>,
+    pub metrics: Arc<Metrics>,
+    /// The limits below start out with their config values and can be changed at runtime, see `State::set`.
+    pub max_players: AtomicI32,
+    /// How many of the `max_players` slots only clients with an admin token may take.
+    pub reserved_slots: i32,
+    pub max_per_ip: AtomicI32,
+    pub max_rate: AtomicI32,
+    pub max_packets: AtomicI32,
+    pub slow_client_ms: AtomicI32,
+    pub total_rate: AtomicI32,
+    /// Networks that may not connect, persisted to `ban_file`.
+    pub bans: Mutex<BanList>,
+    /// Networks from the `allow` and `deny` settings.
+    pub access: Mutex<AccessList>,
+    /// Reads the allow and deny lists again, see `State::reload`.
+    pub load_access: Box<dyn Fn() -> Result<AccessList, String> + Send + Sync>,
+    /// Payload filter rules from `filter_file`, replaced as a whole on reload.
+    pub filters: Mutex<Arc<FilterList>>,
+    /// Whether clients get control packets, e.g. the reason when they are kicked.
+    pub control_packets: bool,
+    /// While set, new connections are refused but connected clients keep playing.
+    pub paused: AtomicBool,
+    /// Per-client byte rate limits that replace the global one, see `State::set_rate_override`.
+    pub rate_overrides: Mutex<HashMap<RateTarget, i32>>,
+    /// Open connections per address including ones still authenticating, see `State::claim_ip_slot`.
+    pub ip_connections: Mutex<HashMap<IpAddr, i32>>,
+    /// Offense history of addresses that got temporarily banned, see `State::temp_ban`.
+    pub offenders: Mutex<HashMap<IpAddr, Offender>>,
+    /// Seconds of the first temporary ban of an address, 0 = automatic bans are off.
+    pub auto_ban: i32,
+    /// What happens to packets over the rate limit.
+    pub rate_policy: RatePolicy,
+    /// Most bytes queued for a client, 0 = unlimited, and what happens to frames over it.
+    pub queue_limit: usize,
+    pub queue_policy: QueuePolicy,
+    /// Most bytes queued for all clients together, 0 = unlimited, see `State::memory_pressure`.
+    pub memory_limit: usize,
+    /// Queues relayed packets for their recipients, see `router`.
+    pub router: Router,
+    /// Links to the other servers packets are relayed to as well, see `federation`.
+    pub federation: Option<Arc<Federation>>,
+    /// The NATS subject packets are shared with other servers on, see `nats`.
+    pub nats: Option<Arc<Nats>>,
+    /// Where relayed packets are published for analytics, see `kafka`.
+    pub kafka: Option<Kafka>,
+    /// Where relayed packets are appended for audits, see `journal`.
+    pub journal: Option<Journal>,
+    /// Sessions of clients that dropped, kept for `resume_grace`, see `resume`.
+    pub resume: Option<Arc<Resume>>,
+    /// Set once a drain started, see `drain::start`.
+    pub draining: AtomicBool,
+    /// Default drain deadline in seconds.
+    pub drain_timeout: i32,
+    /// When the server started, for the uptime.
+    pub started: Instant,
+    /// Key client tokens are signed with, empty when token authentication is off.
+    pub token_secret: String,
+    /// Picks the ids of new clients, see `ids`.
+    pub ids: Arc<dyn IdAllocator>,
+    /// Creates the rate limiters of clients, see `ratelimit`.
+    pub rate_limiter: Arc<dyn RateLimiter>,
+    /// The time rate limits go by, see `clock`.
+    pub clock: Arc<dyn Clock>,
+    /// Steps every relayed packet passes through, see `middleware`.
+    pub pipeline: Vec<Stage>,
+    /// The embedder's callbacks, see `events`.
+    pub hooks: Hooks,
+    /// Written to by `State::stop`, the accept loop waits on the other end next to the listener.
+    #[cfg(unix)]
+    pub accept_wake: UnixStream
+}
+
+pub type SharedState = Arc<State>;
+
+/// Share of `memory_limit` from which `State::memory_pressure` applies.
+const MEMORY_PRESSURE_PERCENT: usize = 90;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RatePolicy {
+    /// Drop packets over the limit.
+    Drop,
+    /// Hold packets until they fit the limit, which slows the client down.
+    Delay,
+    /// Relay them anyway, only notify the client and the audit log.
+    Warn,
+    /// Drop them and disconnect clients that exceed the limit too often.
+    Disconnect
+}
+
+impl RatePolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            RatePolicy::Drop => "drop",
+            RatePolicy::Delay => "delay",
+            RatePolicy::Warn => "warn",
+            RatePolicy::Disconnect => "disconnect"
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<RatePolicy> {
+        [RatePolicy::Drop, RatePolicy::Delay, RatePolicy::Warn, RatePolicy::Disconnect].into_iter().find(|p| p.name() == s)
+    }
+}
+
+/// Temporary bans escalate for offenses within this time of the previous one.
+const OFFENSE_MEMORY: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_TEMP_BAN: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct Offender {
+    pub offenses: u32,
+    pub last_offense: Instant,
+    pub banned_until: Instant
+}
+
+/// One connection counted against `max_per_ip`, see `State::claim_ip_slot`.
+pub struct IpSlot {
+    state: SharedState,
+    ip: IpAddr
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let Ok(mut counts) = self.state.ip_connections.lock() else { return; };
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count <= 0 { counts.remove(&self.ip); }
+        }
+    }
+}
+
+/// What a rate limit override applies to, a single connection takes precedence over its address.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateTarget {
+    Client(i32),
+    Address(IpAddr)
+}
+
+impl RateTarget {
+    /// Parses a client ID or an IP address.
+    pub fn parse(s: &str) -> Option<RateTarget> {
+        s.parse::<i32>().ok().map(RateTarget::Client).or_else(|| s.parse::<IpAddr>().ok().map(RateTarget::Address))
+    }
+}
+
+impl fmt::Display for RateTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RateTarget::Client(id) => write!(f, "client {}", id),
+            RateTarget::Address(ip) => write!(f, "{}", ip)
+        }
+    }
+}
+
+/// Names of the settings `State::set` accepts, all `0` = off/unlimited.
+pub const SETTINGS: [&str; 6] = ["max_players", "max_per_ip", "max_rate", "max_packets", "total_rate", "slow_client_ms"];
+
+impl State {
+    /// Stops the server, waking the accept loop so it notices right away.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        #[cfg(unix)]
+        let _ = (&self.accept_wake).write(&[1]);
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.bans.lock().map(|b| b.contains(ip)).unwrap_or(false)
+            || self.offenders.lock().map(|o| o.get(ip).is_some_and(|o| o.banned_until > Instant::now())).unwrap_or(false)
+    }
+
+    /// Bans `ip` for `auto_ban` seconds, doubled for every earlier offense within a day (at most a day),
+    /// and kicks its clients. The ban is kept in memory only. Returns the duration and offense count.
+    pub fn temp_ban(&self, ip: IpAddr, reason: &str) -> (Duration, u32) {
+        let now = Instant::now();
+        let (duration, offenses) = match self.offenders.lock() {
+            Ok(mut offenders) => {
+                offenders.retain(|_, o| o.banned_until > now || now.duration_since(o.last_offense) < OFFENSE_MEMORY);
+
+                let offender = offenders.entry(ip).or_insert(Offender { offenses: 0, last_offense: now, banned_until: now });
+                offender.offenses += 1;
+                offender.last_offense = now;
+
+                let base = Duration::from_secs(self.auto_ban.max(0) as u64);
+                let duration = base.saturating_mul(1 << (offender.offenses - 1).min(16)).min(MAX_TEMP_BAN);
+                offender.banned_until = now + duration;
+                (duration, offender.offenses)
+            },
+            Err(_) => return (Duration::ZERO, 0)
+        };
+        Metrics::add(&self.metrics.temp_bans, 1);
+
+        let kicked = match self.connections.read() {
+            Ok(connections) => {
+                let farewell = self.kick_frame(&format!("temporarily banned for {}s ({})", duration.as_secs(), reason));
+                connections.values().filter(|c| c.meta.addr.ip() == ip).map(|c| c.kick(farewell.as_deref())).count()
+            },
+            Err(_) => 0
+        };
+
+        warning!("Temporarily banned {} for {}s ({}, offense {}), {} client(s) kicked.", ip, duration.as_secs(), reason, offenses, kicked);
+        (duration, offenses)
+    }
+
+    /// A send queue for a new client, `wake` is called whenever frames get queued.
+    pub fn outbox(&self, wake: Option<Box<dyn Fn() + Send + Sync>>) -> Arc<Outbox> {
+        let outbox = Outbox::new(self.queue_limit, self.queue_policy, self.memory_limit, Arc::clone(&self.metrics), wake);
+        if let Some(resume) = &self.resume { outbox.keep_history(resume.retention, resume::HOLD); }
+        Arc::new(outbox)
+    }
+
+    /// Whether the queued frames take up most of `memory_limit`, new connections are refused and packets
+    /// shed then, so the writers can catch up before frames have to be dropped.
+    pub fn memory_pressure(&self) -> bool {
+        self.memory_limit != 0 && self.metrics.buffered_bytes.load(Ordering::Relaxed) as usize >= self.memory_limit / 100 * MEMORY_PRESSURE_PERCENT
+    }
+
+    /// Whether clients may not be read from under the `backpressure` queue policy, because the send
+    /// queue of a recipient is over its limit.
+    pub fn backpressure(&self) -> bool {
+        self.queue_policy == QueuePolicy::Backpressure && self.metrics.saturated_queues.load(Ordering::Relaxed) > 0
+    }
+
+    /// Whether a client with `role` may join next to `clients` connected ones, the last `reserved_slots` are kept for admins.
+    pub fn has_room(&self, clients: usize, role: Role) -> bool {
+        let max_players = self.max_players.load(Ordering::Relaxed);
+        let reserved = if role == Role::Admin { 0 } else { self.reserved_slots };
+        max_players == 0 || (clients as i32) < max_players - reserved
+    }
+
+    /// Counts a new connection from `ip` unless that would exceed `max_per_ip`.
+    /// The connection counts until the returned slot is dropped.
+    pub fn claim_ip_slot(self: &Arc<Self>, ip: IpAddr) -> Option<IpSlot> {
+        let max_per_ip = self.max_per_ip.load(Ordering::Relaxed);
+        let mut counts = self.ip_connections.lock().ok()?;
+
+        let count = counts.entry(ip).or_insert(0);
+        if max_per_ip != 0 && *count >= max_per_ip { return None; }
+        *count += 1;
+        Some(IpSlot { state: Arc::clone(self), ip })
+    }
+
+    /// `Kicked` control packet with `reason`, `None` while control packets are disabled.
+    pub fn kick_frame(&self, reason: &str) -> Option<Vec<u8>> {
+        self.control_packets.then(|| control::frame(control::Kind::Kicked, reason.as_bytes()))
+    }
+
+    /// Disconnects client `id`, telling it `reason` first if control packets are enabled.
+    /// `source` names the interface the request came from (for the log). Returns false if there is no such client.
+    pub fn kick(&self, id: i32, reason: &str, source: &str) -> bool {
+        let Ok(connections) = self.connections.read() else { return false; };
+        let Some(client) = connections.get(&id) else { return false; };
+
+        client.kick(self.kick_frame(reason).as_deref());
+        let reason = if reason.is_empty() { String::new() } else { format!(" ({})", reason) };
+        info!("{} - Kicked over the {}{}.", registry::log_tag(id, &client.meta.session), source, reason);
+        true
+    }
+
+    /// Bans `network` and kicks every client connected from it, returns how many were kicked.
+    /// The ban is in effect even if saving the ban file fails, which is returned as an error.
+    pub fn ban(&self, network: Network, source: &str) -> Result<usize, String> {
+        let saved = match self.bans.lock() {
+            Ok(mut bans) => bans.add(network).map(|_| ()).map_err(|e| format!("could not save ban file ({})", e)),
+            Err(_) => return Err("could not lock ban list".to_string())
+        };
+
+        let kicked = match self.connections.read() {
+            Ok(connections) => {
+                let farewell = self.kick_frame("banned");
+                connections.values().filter(|c| network.contains(&c.meta.addr.ip())).map(|c| c.kick(farewell.as_deref())).count()
+            },
+            Err(_) => 0
+        };
+
+        info!("Banned {} over the {}, {} client(s) kicked.", network, source, kicked);
+        if let Err(e) = &saved { error!("Ban of {} is not persisted, {}!", network, e); }
+        saved.map(|_| kicked)
+    }
+
+    /// Whether the allow and deny lists let `ip` connect.
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        self.access.lock().map(|a| a.permits(ip)).unwrap_or(false)
+    }
+
+    /// Re-reads the ban file and the allow and deny lists, then kicks clients that may not connect anymore.
+    /// Returns the number of bans and kicked clients. Nothing changes if either can't be read.
+    pub fn reload(&self, source: &str) -> Result<(usize, usize), String> {
+        let access = (self.load_access)()?;
+        let filters = self.filters().reload().map_err(|e| format!("could not load filter file ({})", e))?;
+        let networks = match self.bans.lock() {
+            Ok(mut bans) => {
+                bans.reload()?;
+                bans.networks().to_vec()
+            },
+            Err(_) => return Err("could not lock ban list".to_string())
+        };
+        match self.access.lock() {
+            Ok(mut current) => *current = access.clone(),
+            Err(_) => return Err("could not lock allow and deny lists".to_string())
+        }
+        let rules = filters.rules().len();
+        match self.filters.lock() {
+            Ok(mut current) => *current = Arc::new(filters),
+            Err(_) => return Err("could not lock filter rules".to_string())
+        }
+
+        let kicked = match self.connections.read() {
+            Ok(connections) => {
+                let (banned, not_allowed) = (self.kick_frame("banned"), self.kick_frame("not allowed"));
+                connections.values().filter_map(|c| {
+                    let ip = c.meta.addr.ip();
+                    if networks.iter().any(|n| n.contains(&ip)) { Some((c, &banned)) }
+                    else if !access.permits(&ip) { Some((c, &not_allowed)) } else { None }
+                }).map(|(c, farewell)| c.kick(farewell.as_deref())).count()
+            },
+            Err(_) => 0
+        };
+
+        info!(
+            "Reloaded {} ban(s), {} allowed and {} denied network(s) and {} filter rule(s) over the {}, {} client(s) kicked.",
+            networks.len(), access.allow().len(), access.deny().len(), rules, source, kicked
+        );
+        Ok((networks.len(), kicked))
+    }
+
+    /// The current filter rules.
+    pub fn filters(&self) -> Arc<FilterList> {
+        Arc::clone(&self.filters.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Lifts a ban, returns false if `network` wasn't banned (exactly like this, bans aren't split).
+    pub fn unban(&self, network: &Network, source: &str) -> Result<bool, String> {
+        let Ok(mut bans) = self.bans.lock() else { return Err("could not lock ban list".to_string()); };
+
+        let mut removed = bans.remove(network).map_err(|e| format!("could not save ban file ({})", e))?;
+        if let Ok(mut offenders) = self.offenders.lock() {
+            let now = Instant::now();
+            for (_, offender) in offenders.iter_mut().filter(|(ip, o)| network.contains(ip) && o.banned_until > now) {
+                offender.banned_until = now;
+                removed = true;
+            }
+        }
+        if removed { info!("Unbanned {} over the {}.", network, source); }
+        Ok(removed)
+    }
+
+    /// Sends `message` from `registry::SERVER_ID` to every client, returns how many it was queued for.
+    pub fn announce(&self, message: &str, source: &str) -> Result<usize, String> {
+        if !self.control_packets { return Err("announcements need control_packets to be enabled".to_string()); }
+
+        let mut body = registry::SERVER_ID.to_le_bytes().to_vec();
+        body.extend_from_slice(message.as_bytes());
+
+        let sent = self.broadcast(&control::frame(control::Kind::Announcement, &body));
+        info!("Announcement sent to {} client(s) over the {}: {}", sent, source, message);
+        Ok(sent)
+    }
+
+    /// Queues a server-originated frame for every client, returns how many it was queued for.
+    pub fn broadcast(&self, frame: &[u8]) -> usize {
+        let Ok(connections) = self.connections.read() else { return 0; };
+        let frame: Arc<[u8]> = Arc::from(frame);
+        connections.values().filter(|client| client.send(Arc::clone(&frame), false)).count()
+    }
+
+    /// Stops or resumes accepting new connections, returns false if that was already the case
+    /// or the server is draining (which can't be undone).
+    pub fn set_paused(&self, paused: bool, source: &str) -> bool {
+        if self.draining.load(Ordering::SeqCst) { return false; }
+        if self.paused.swap(paused, Ordering::SeqCst) == paused { return false; }
+        if paused { info!("Paused accepting connections over the {}.", source); } else { info!("Resumed accepting connections over the {}.", source); }
+        true
+    }
+
+    /// Byte rate limit client `id` currently gets: its override if it has one, otherwise `max_rate`,
+    /// tightened to an equal share of `total_rate` among the connected clients while that is set. 0 = unlimited.
+    pub fn client_rate_limit(&self, id: i32, ip: &IpAddr) -> i32 {
+        if let Ok(overrides) = self.rate_overrides.lock()
+            && let Some(limit) = overrides.get(&RateTarget::Client(id)).or_else(|| overrides.get(&RateTarget::Address(*ip))) {
+            return *limit;
+        }
+
+        let max_rate = self.max_rate.load(Ordering::Relaxed);
+        let total_rate = self.total_rate.load(Ordering::Relaxed);
+        if total_rate == 0 { return max_rate; }
+
+        let clients = self.metrics.connected_clients.load(Ordering::Relaxed).max(1);
+        let share = (total_rate as i64 / clients).max(1) as i32;
+        if max_rate == 0 { share } else { share.min(max_rate) }
+    }
+
+    /// Delays the packets relayed to client `id` by `ms` milliseconds, 0 = not at all, e.g. to play with a
+    /// high ping on a local playtest. False if there is no such client.
+    pub fn set_latency(&self, id: i32, ms: u32, source: &str) -> bool {
+        let Ok(connections) = self.connections.read() else { return false; };
+        let Some(client) = connections.get(&id) else { return false; };
+
+        client.latency_ms.store(ms, Ordering::Relaxed);
+        match ms {
+            0 => info!("{} - Added latency removed over the {}.", registry::log_tag(id, &client.meta.session), source),
+            ms => info!("{} - Latency of {}ms added over the {}.", registry::log_tag(id, &client.meta.session), ms, source)
+        }
+        true
+    }
+
+    /// Sets (`Some`) or removes (`None`) a rate limit override, returns false when removing one that didn't exist.
+    pub fn set_rate_override(&self, target: RateTarget, limit: Option<i32>, source: &str) -> bool {
+        let Ok(mut overrides) = self.rate_overrides.lock() else { return false; };
+
+        match limit {
+            Some(limit) => {
+                overrides.insert(target, limit);
+                info!("Rate limit of {} set to {} over the {}.", target, limit, source);
+                true
+            },
+            None if overrides.remove(&target).is_some() => {
+                info!("Rate limit override of {} removed over the {}.", target, source);
+                true
+            },
+            None => false
+        }
+    }
+
+    fn setting(&self, key: &str) -> Option<&AtomicI32> {
+        match key {
+            "max_players" => Some(&self.max_players),
+            "max_per_ip" => Some(&self.max_per_ip),
+            "max_rate" => Some(&self.max_rate),
+            "max_packets" => Some(&self.max_packets),
+            "slow_client_ms" => Some(&self.slow_client_ms),
+            "total_rate" => Some(&self.total_rate),
+            _ => None
+        }
+    }
+
+    /// Current value of every setting that can be changed at runtime.
+    pub fn settings(&self) -> Vec<(&'static str, i32)> {
+        SETTINGS.iter().filter_map(|key| Some((*key, self.setting(key)?.load(Ordering::Relaxed)))).collect()
+    }
+
+    /// Validates `key` and `value` without changing anything.
+    pub fn check_setting(&self, key: &str, value: i32) -> Result<(), String> {
+        if self.setting(key).is_none() { return Err(format!("unknown or read-only setting {} (try {})", key, SETTINGS.join(", "))); }
+        if value < 0 { return Err(format!("{} must not be negative", key)); }
+        Ok(())
+    }
+
+    /// Changes a limit for all current and future clients. Lowering `max_players` or `max_per_ip` only affects new connections.
+    pub fn set(&self, key: &str, value: i32, source: &str) -> Result<(), String> {
+        self.check_setting(key, value)?;
+        if let Some(setting) = self.setting(key) { setting.store(value, Ordering::Relaxed); }
+
+        info!("Setting {} changed to {} over the {}.", key, value, source);
+        Ok(())
+    }
+}
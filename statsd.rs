@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::metrics::Histogram;
+use crate::metrics::Metrics;
+use crate::metrics::snapshot_delta;
+
+/// Periodically pushes all metrics to a StatsD server over UDP.
+///
+/// Counters are sent as deltas since the previous flush. With `tags` enabled the disconnect
+/// reason is attached as a DogStatsD tag (`|#reason:closed`) instead of being part of the name.
+pub fn spawn(metrics: Arc<Metrics>, address: &str, prefix: &str, interval: i32, tags: bool) -> std::io::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(address)?;
+
+    let prefix = prefix.to_string();
+    let interval = Duration::from_secs(interval.max(1) as u64);
+
+    thread::spawn(move || {
+        let mut last = HashMap::<String, u64>::new();
+        let mut last_latency = metrics.broadcast_latency.snapshot();
+
+        loop {
+            thread::sleep(interval);
+
+            let mut out = String::new();
+            for (name, _, value) in metrics.gauges() {
+                let _ = writeln!(out, "{}.{}:{}|g", prefix, name, value);
+            }
+
+            let mut counter = |key: String, metric: &str, value: u64, tag: &str| {
+                let delta = value - last.insert(key, value).unwrap_or(0);
+                if delta != 0 { let _ = writeln!(out, "{}.{}:{}|c{}", prefix, metric, delta, tag); }
+            };
+
+            for (name, _, value) in metrics.counters() {
+                counter(name.to_string(), name, value, "");
+            }
+            for (reason, value) in metrics.disconnects() {
+                let key = format!("disconnects.{}", reason);
+                if tags {
+                    counter(key, "disconnects", value, &format!("|#reason:{}", reason));
+                } else {
+                    counter(key.clone(), &key, value, "");
+                }
+            }
+
+            let latency = metrics.broadcast_latency.snapshot();
+            let window = snapshot_delta(&latency, &last_latency);
+            for (name, p) in [("p50", 0.5), ("p99", 0.99)] {
+                if let Some(us) = Histogram::percentile(&window, p) {
+                    let _ = writeln!(out, "{}.broadcast_latency_{}_us:{}|g", prefix, name, us);
+                }
+            }
+            last_latency = latency;
+
+            let _ = socket.send(out.trim_end().as_bytes());
+        }
+    });
+
+    Ok(())
+}
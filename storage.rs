@@ -0,0 +1,138 @@
+//! Where state that outlives the process is kept (`storage`, `ServerBuilder::with_storage`): the bans
+//! of `ban_file`, under that setting as their key. Built in are `files`, where a key is the path of a file
+//! (relative to the working directory, as in the settings), and `memory`, which forgets everything when the
+//! server stops. Embedders can keep it elsewhere, e.g. in their database. The resume history lives in
+//! memory only and the journal is written to its own directory, neither goes through here.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// A store of byte values by key, called from any thread.
+pub trait Storage: Send + Sync {
+    /// The value under `key`, `None` if there is none.
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Replaces the value under `key`, a reader sees either the old or the new value.
+    fn store(&self, key: &str, value: &[u8]) -> io::Result<()>;
+}
+
+/// A file per key, the default.
+pub struct Files;
+
+impl Storage for Files {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(key) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn store(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        // written next to it first, so a crash never leaves half a file
+        let temp = format!("{}.tmp", key);
+        fs::write(&temp, value)?;
+        fs::rename(&temp, key)
+    }
+}
+
+/// Values in memory only, e.g. for tests.
+#[derive(Default)]
+pub struct Memory {
+    values: Mutex<HashMap<String, Vec<u8>>>
+}
+
+impl Storage for Memory {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.values.lock().unwrap_or_else(|e| e.into_inner()).get(key).cloned())
+    }
+
+    fn store(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.values.lock().unwrap_or_else(|e| e.into_inner()).insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+}
+
+/// The built-in storage named `name` (`files` or `memory`).
+pub fn parse(name: &str) -> Option<Arc<dyn Storage>> {
+    match name {
+        "files" => Some(Arc::new(Files)),
+        "memory" => Some(Arc::new(Memory::default())),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(name: &str) -> std::path::PathBuf {
+        let directory = std::env::temp_dir().join(format!("echoserver-storage-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn files_store_and_load() {
+        let directory = directory("files");
+        let key = directory.join("bans.txt").to_string_lossy().into_owned();
+        assert_eq!(Files.load(&key).unwrap(), None);
+
+        Files.store(&key, b"1.2.3.4\n").unwrap();
+        assert_eq!(Files.load(&key).unwrap().as_deref(), Some(&b"1.2.3.4\n"[..]));
+        Files.store(&key, b"").unwrap();
+        assert_eq!(Files.load(&key).unwrap().as_deref(), Some(&b""[..]));
+
+        // the value is written next to the file and renamed over it, nothing else is left behind
+        let names: Vec<String> = fs::read_dir(&directory).unwrap().flatten().map(|e| e.file_name().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, ["bans.txt"]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn files_replace_a_leftover_temporary_file() {
+        let directory = directory("leftover");
+        let key = directory.join("bans.txt").to_string_lossy().into_owned();
+        fs::write(format!("{}.tmp", key), b"half a file").unwrap();
+        Files.store(&key, b"10.0.0.0/8\n").unwrap();
+        assert_eq!(fs::read(&key).unwrap(), b"10.0.0.0/8\n");
+        assert!(!std::path::Path::new(&format!("{}.tmp", key)).exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn files_report_what_they_can_not_do() {
+        let directory = directory("errors");
+        // a directory can't be read as a value, nor can anything be stored where there is no directory
+        assert!(Files.load(&directory.to_string_lossy()).is_err());
+        let missing = directory.join("missing").join("bans.txt").to_string_lossy().into_owned();
+        assert_eq!(Files.store(&missing, b"x").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(Files.load(&missing).unwrap(), None);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn memory_stores_and_loads() {
+        let memory = Memory::default();
+        assert_eq!(memory.load("bans").unwrap(), None);
+        memory.store("bans", b"1.2.3.4\n").unwrap();
+        memory.store("other", b"x").unwrap();
+        memory.store("bans", b"5.6.7.8\n").unwrap();
+        assert_eq!(memory.load("bans").unwrap().as_deref(), Some(&b"5.6.7.8\n"[..]));
+        assert_eq!(memory.load("other").unwrap().as_deref(), Some(&b"x"[..]));
+        // nothing touches the disk
+        assert!(!std::path::Path::new("bans").exists());
+    }
+
+    #[test]
+    fn built_in_storage_by_name() {
+        assert!(parse("files").is_some());
+        assert!(parse("memory").is_some());
+        assert!(parse("sqlite").is_none());
+    }
+}
@@ -0,0 +1,56 @@
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use echoserver::EchoClient;
+use echoserver::Server;
+use echoserver::ServerBuilder;
+use echoserver::ServerConfig;
+
+fn builder() -> ServerBuilder {
+    Server::builder().port(0).ban_file("").storage("memory").stdin_console(false).handshake_timeout(0)
+}
+
+/// Whether a client from localhost stays connected, a refused one is closed right away.
+fn accepted(server: &Server) -> bool {
+    let mut client = EchoClient::connect(server.local_addr().unwrap()).unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    matches!(client.recv(), Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+}
+
+fn started(server: Server) -> Arc<Server> {
+    let server = Arc::new(server);
+    let running = Arc::clone(&server);
+    thread::spawn(move || running.run());
+    server
+}
+
+#[test]
+fn reload_without_ban_file() {
+    let server = builder().build().unwrap();
+    let handle = server.handle();
+    handle.ban("203.0.113.7").unwrap();
+
+    // the ban is kept in memory only and survives the reload
+    assert_eq!(handle.reload(), Ok((1, 0)));
+}
+
+#[test]
+fn reload_keeps_the_access_lists_built() {
+    let server = started(builder().deny("127.0.0.1").build().unwrap());
+    assert!(!accepted(&server));
+    server.handle().reload().unwrap();
+    assert!(!accepted(&server));
+    server.stop();
+}
+
+#[test]
+fn reload_reads_the_access_lists_from_the_config_source() {
+    let source = || ServerConfig { deny: "127.0.0.1".to_string(), ..ServerConfig::default() };
+    let server = started(builder().reload_config(source).build().unwrap());
+    assert!(accepted(&server));
+    server.handle().reload().unwrap();
+    assert!(!accepted(&server));
+    server.stop();
+}
@@ -0,0 +1,212 @@
+//! TLS with pre-shared keys (TLS-PSK) using the system OpenSSL, built with the `tls-psk` feature.
+//!
+//! Every encrypted connection gets a pump thread that owns the TLS session and relays the plaintext
+//! over a loopback socket pair, so the rest of the server keeps working with plain `TcpStream`s.
+
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::ffi::c_char;
+use std::ffi::c_int;
+use std::ffi::c_uint;
+use std::ffi::c_void;
+use std::io::Read;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::net::Shutdown;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::os::fd::AsRawFd;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+const SSL_ERROR_WANT_READ: c_int = 2;
+const SSL_ERROR_WANT_WRITE: c_int = 3;
+
+/// How long a client gets to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[link(name = "ssl")]
+unsafe extern "C" {
+    fn TLS_server_method() -> *const c_void;
+    fn SSL_CTX_new(method: *const c_void) -> *mut c_void;
+    fn SSL_CTX_set_cipher_list(ctx: *mut c_void, list: *const c_char) -> c_int;
+    fn SSL_CTX_use_psk_identity_hint(ctx: *mut c_void, hint: *const c_char) -> c_int;
+    fn SSL_CTX_set_psk_server_callback(ctx: *mut c_void, cb: extern "C" fn(*mut c_void, *const c_char, *mut u8, c_uint) -> c_uint);
+    fn SSL_new(ctx: *mut c_void) -> *mut c_void;
+    fn SSL_set_fd(ssl: *mut c_void, fd: c_int) -> c_int;
+    fn SSL_accept(ssl: *mut c_void) -> c_int;
+    fn SSL_read(ssl: *mut c_void, buf: *mut c_void, num: c_int) -> c_int;
+    fn SSL_write(ssl: *mut c_void, buf: *const c_void, num: c_int) -> c_int;
+    fn SSL_get_error(ssl: *const c_void, ret: c_int) -> c_int;
+    fn SSL_shutdown(ssl: *mut c_void) -> c_int;
+    fn SSL_free(ssl: *mut c_void);
+}
+
+struct Context {
+    ctx: *mut c_void,
+    key: Vec<u8>,
+    identity: String
+}
+
+// SAFETY: an SSL_CTX may be shared between threads once it is set up, it is never changed afterwards.
+unsafe impl Send for Context {}
+unsafe impl Sync for Context {}
+
+static CONTEXT: OnceLock<Context> = OnceLock::new();
+
+/// Sets up TLS-PSK with a hex encoded `key`, clients must use `identity` as their PSK identity.
+pub fn init(key: &str, identity: &str) -> Result<(), String> {
+    let key = parse_hex(key).ok_or("the key must be hex encoded")?;
+    if key.is_empty() || key.len() > 64 { return Err("the key must be 1 to 64 bytes long".to_string()); }
+    let hint = CString::new(identity).map_err(|_| "invalid identity")?;
+
+    // SAFETY: plain OpenSSL setup calls, every pointer passed is valid for the duration of the call.
+    let ctx = unsafe {
+        let ctx = SSL_CTX_new(TLS_server_method());
+        if ctx.is_null() { return Err("could not create the TLS context".to_string()); }
+        if SSL_CTX_set_cipher_list(ctx, c"PSK".as_ptr()) != 1 { return Err("no PSK cipher suites available".to_string()); }
+        SSL_CTX_use_psk_identity_hint(ctx, hint.as_ptr());
+        SSL_CTX_set_psk_server_callback(ctx, psk_callback);
+        ctx
+    };
+
+    CONTEXT.set(Context { ctx, key, identity: identity.to_string() }).map_err(|_| "TLS is already set up".to_string())
+}
+
+extern "C" fn psk_callback(_: *mut c_void, identity: *const c_char, psk: *mut u8, max_psk_len: c_uint) -> c_uint {
+    let Some(context) = CONTEXT.get() else { return 0; };
+    if identity.is_null() || context.key.len() > max_psk_len as usize { return 0; }
+
+    // SAFETY: OpenSSL passes a NUL terminated identity and a buffer of `max_psk_len` bytes.
+    unsafe {
+        if CStr::from_ptr(identity).to_bytes() != context.identity.as_bytes() { return 0; }
+        std::ptr::copy_nonoverlapping(context.key.as_ptr(), psk, context.key.len());
+    }
+    context.key.len() as c_uint
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) { return None; }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Owns an `SSL` session, freeing it when dropped.
+struct Session(*mut c_void);
+
+// SAFETY: a session is only ever used by one thread at a time, first the handshake, then its pump thread.
+unsafe impl Send for Session {}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // SAFETY: the pointer came from SSL_new and is only used by the thread owning this session.
+        unsafe {
+            SSL_shutdown(self.0);
+            SSL_free(self.0);
+        }
+    }
+}
+
+/// Runs the TLS handshake on `stream` and returns a plain stream carrying the decrypted traffic.
+pub fn accept(stream: TcpStream) -> Result<TcpStream, String> {
+    let context = CONTEXT.get().ok_or("TLS is not set up")?;
+
+    // SAFETY: the context outlives every session, the socket stays open as long as the session exists.
+    let session = unsafe {
+        let ssl = SSL_new(context.ctx);
+        if ssl.is_null() { return Err("could not create a TLS session".to_string()); }
+        Session(ssl)
+    };
+    // SAFETY: see above.
+    unsafe { SSL_set_fd(session.0, stream.as_raw_fd()); }
+
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+    // SAFETY: the session is valid and its socket is in blocking mode.
+    if unsafe { SSL_accept(session.0) } != 1 { return Err("TLS handshake failed".to_string()); }
+
+    let (inner, outer) = loopback_pair().map_err(|e| format!("could not create loopback pair ({})", e))?;
+    stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+    thread::spawn(move || {
+        pump(&session, &stream, &outer);
+        drop(session);
+        let _ = outer.shutdown(Shutdown::Both);
+        let _ = stream.shutdown(Shutdown::Both);
+    });
+    Ok(inner)
+}
+
+/// Two connected sockets on 127.0.0.1.
+fn loopback_pair() -> std::io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let outer = TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        let (inner, addr) = listener.accept()?;
+        if addr == outer.local_addr()? { return Ok((inner, outer)); }
+    }
+}
+
+/// Relays between the TLS session on the non-blocking `socket` and the plain `plain` stream until either side closes.
+fn pump(session: &Session, socket: &TcpStream, mut plain: &TcpStream) {
+    let mut buffer = [0u8; 4096];
+
+    loop {
+        // TLS -> plain, until OpenSSL needs more data from the socket
+        loop {
+            // SAFETY: the buffer is valid for its full length.
+            let n = unsafe { SSL_read(session.0, buffer.as_mut_ptr() as *mut c_void, buffer.len() as c_int) };
+            if n > 0 {
+                if plain.write_all(&buffer[..n as usize]).is_err() { return; }
+                continue;
+            }
+            // SAFETY: the session is valid.
+            match unsafe { SSL_get_error(session.0, n) } {
+                SSL_ERROR_WANT_READ | SSL_ERROR_WANT_WRITE => break,
+                _ => return
+            }
+        }
+
+        let Some(plain_ready) = wait(socket, plain) else { return; };
+
+        // plain -> TLS
+        if plain_ready {
+            let n = match plain.read(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n
+            };
+            if !write_all(session, socket, &buffer[..n]) { return; }
+        }
+    }
+}
+
+/// Waits up to a second for either stream to become readable, returns whether `plain` is (`None` if polling failed).
+fn wait(socket: &TcpStream, plain: &TcpStream) -> Option<bool> {
+    let mut fds = [
+        libc::pollfd { fd: socket.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        libc::pollfd { fd: plain.as_raw_fd(), events: libc::POLLIN, revents: 0 }
+    ];
+    // SAFETY: `fds` is a valid array of two pollfd structs.
+    let result = unsafe { libc::poll(fds.as_mut_ptr(), 2, 1000) };
+    if result < 0 && std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted { return None; }
+    Some(fds[1].revents != 0)
+}
+
+/// Encrypts and writes all of `data`, waiting for the non-blocking socket to drain when needed.
+fn write_all(session: &Session, socket: &TcpStream, data: &[u8]) -> bool {
+    loop {
+        // SAFETY: `data` is valid for its full length, retries pass the same arguments as OpenSSL requires.
+        let n = unsafe { SSL_write(session.0, data.as_ptr() as *const c_void, data.len() as c_int) };
+        if n > 0 { return true; }
+
+        // SAFETY: the session is valid.
+        let events = match unsafe { SSL_get_error(session.0, n) } {
+            SSL_ERROR_WANT_WRITE => libc::POLLOUT,
+            SSL_ERROR_WANT_READ => libc::POLLIN,
+            _ => return false
+        };
+        let mut fd = libc::pollfd { fd: socket.as_raw_fd(), events, revents: 0 };
+        // SAFETY: `fd` is a single valid pollfd struct.
+        unsafe { libc::poll(&mut fd, 1, 1000); }
+    }
+}
@@ -0,0 +1,53 @@
+//! Hexdump tracing of raw frames for protocol debugging (`--trace-packets`).
+
+use std::fmt::Write as _;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::Ordering;
+
+use crate::logging::info;
+use crate::registry;
+
+/// -1 = off, 0 = every client, anything else = only that client ID.
+static FILTER: AtomicI32 = AtomicI32::new(-1);
+
+const BYTES_PER_LINE: usize = 16;
+
+pub fn enable(client: i32) {
+    FILTER.store(client.max(0), Ordering::Relaxed);
+}
+
+/// Whether frames to or from `id` are traced, check this before building anything to dump.
+pub fn enabled(id: i32) -> bool {
+    let filter = FILTER.load(Ordering::Relaxed);
+    filter == 0 || filter == id
+}
+
+/// Logs a frame (given as its consecutive parts, e.g. size prefix and payload) to or from client `id`.
+pub fn frame(direction: &str, id: i32, session: &str, parts: &[&[u8]]) {
+    if !enabled(id) { return; }
+
+    let bytes: Vec<u8> = parts.concat();
+    info!("{} - {} frame ({} bytes):\n{}", registry::log_tag(id, session), direction, bytes.len(), hexdump(&bytes));
+}
+
+/// Formats `bytes` like `hexdump -C`: offset, 16 hex bytes and an ASCII gutter per line.
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+
+    for (line, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        let _ = write!(out, "{:08x}  ", line * BYTES_PER_LINE);
+        for i in 0..BYTES_PER_LINE {
+            match chunk.get(i) {
+                Some(b) => { let _ = write!(out, "{:02x} ", b); },
+                None => out.push_str("   ")
+            }
+            if i == BYTES_PER_LINE / 2 - 1 { out.push(' '); }
+        }
+
+        let ascii: String = chunk.iter().map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }).collect();
+        let _ = write!(out, " |{}|", ascii);
+        if (line + 1) * BYTES_PER_LINE < bytes.len() { out.push('\n'); }
+    }
+
+    out
+}
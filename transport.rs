@@ -0,0 +1,186 @@
+//! What carries client connections. The handshake, the registry and the writers work with any
+//! `Connection`; the main TCP listener is served by the accept loop (and the event loop in `poll` and
+//! `uring` mode), every other `Transport` by a thread of its own with a thread per client.
+//!
+//! Built in are TCP (TLS-PSK runs over it, see `tls`) and unix sockets (`unix_socket`), embedders can
+//! add their own with `ServerBuilder::add_transport`, e.g. WebSocket.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// One client's connection. Reads and writes take `&self` like `TcpStream`'s, so a connection and its
+/// clones (see `try_clone`) can be read and written from different threads.
+pub trait Connection: Send + Sync {
+    /// Label in client listings, e.g. `tcp`.
+    fn transport(&self) -> &'static str;
+
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize>;
+
+    fn write(&self, bytes: &[u8]) -> io::Result<usize>;
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Another handle to the same connection, for the writer thread and the registry.
+    fn try_clone(&self) -> io::Result<Box<dyn Connection>>;
+
+    /// Ends the connection for every handle, a blocked `read` returns.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+    /// Bytes written that the peer has not taken yet, if the transport can tell.
+    fn unsent_bytes(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Where connections come from besides the main TCP listener.
+pub trait Transport: Send + Sync {
+    /// Waits for the next connection. The address is the one it counts as for bans, `max_per_ip` and the logs.
+    fn accept(&self) -> io::Result<(Box<dyn Connection>, SocketAddr)>;
+}
+
+impl Write for &(dyn Connection + '_) {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        Connection::write(*self, bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Connection::flush(*self)
+    }
+}
+
+impl Connection for TcpStream {
+    fn transport(&self) -> &'static str {
+        "tcp"
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buffer)
+    }
+
+    fn write(&self, bytes: &[u8]) -> io::Result<usize> {
+        Write::write(&mut &*self, bytes)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    /// Bytes in the socket's send queue (Linux only).
+    fn unsent_bytes(&self) -> Option<usize> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            let mut n: libc::c_int = 0;
+            // SAFETY: TIOCOUTQ writes a single c_int into `n` for a valid socket descriptor.
+            let result = unsafe { libc::ioctl(self.as_raw_fd(), libc::TIOCOUTQ, &mut n) };
+            if result == 0 { Some(n as usize) } else { None }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        None
+    }
+}
+
+#[cfg(unix)]
+pub use unix::UnixTransport;
+
+#[cfg(unix)]
+mod unix {
+    use std::io;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::Ipv4Addr;
+    use std::net::Shutdown;
+    use std::net::SocketAddr;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixListener;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use super::Connection;
+    use super::Transport;
+
+    /// Clients on the same machine connecting to `unix_socket`. They all count as `127.0.0.1:0`.
+    pub struct UnixTransport {
+        listener: UnixListener
+    }
+
+    impl UnixTransport {
+        pub fn bind(path: &str) -> io::Result<UnixTransport> {
+            // a socket left behind by a previous run would make bind fail
+            if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) { std::fs::remove_file(path)?; }
+            Ok(UnixTransport { listener: UnixListener::bind(path)? })
+        }
+    }
+
+    impl Transport for UnixTransport {
+        fn accept(&self) -> io::Result<(Box<dyn Connection>, SocketAddr)> {
+            let (stream, _) = self.listener.accept()?;
+            Ok((Box::new(stream), SocketAddr::from((Ipv4Addr::LOCALHOST, 0))))
+        }
+    }
+
+    impl Connection for UnixStream {
+        fn transport(&self) -> &'static str {
+            "unix"
+        }
+
+        fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+            Read::read(&mut &*self, buffer)
+        }
+
+        fn write(&self, bytes: &[u8]) -> io::Result<usize> {
+            Write::write(&mut &*self, bytes)
+        }
+
+        fn try_clone(&self) -> io::Result<Box<dyn Connection>> {
+            Ok(Box::new(UnixStream::try_clone(self)?))
+        }
+
+        fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            UnixStream::shutdown(self, how)
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            UnixStream::set_read_timeout(self, timeout)
+        }
+
+        fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            UnixStream::set_write_timeout(self, timeout)
+        }
+
+        fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+            UnixStream::set_nonblocking(self, nonblocking)
+        }
+    }
+}
@@ -0,0 +1,250 @@
+//! A minimal io_uring submission and completion ring on top of the raw system calls, for the `uring`
+//! I/O mode (Linux 5.6 and later). Only the operations the event loop needs are supported.
+
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::fd::RawFd;
+use std::ptr;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+
+const IORING_OP_TIMEOUT: u8 = 11;
+const IORING_OP_SEND: u8 = 26;
+const IORING_OP_RECV: u8 = 27;
+
+/// `io_sqring_offsets` from linux/io_uring.h.
+#[repr(C)]
+#[derive(Default)]
+struct SqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64
+}
+
+/// `io_cqring_offsets` from linux/io_uring.h.
+#[repr(C)]
+#[derive(Default)]
+struct CqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64
+}
+
+/// `io_uring_params` from linux/io_uring.h.
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqOffsets,
+    cq_off: CqOffsets
+}
+
+/// An operation to submit, `io_uring_sqe` from linux/io_uring.h.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64
+}
+
+impl Sqe {
+    /// Receives up to `len` bytes from the socket `fd` into `buf`.
+    pub fn recv(fd: RawFd, buf: *mut u8, len: usize, user_data: u64) -> Sqe {
+        Sqe { opcode: IORING_OP_RECV, fd, addr: buf as u64, len: len as u32, user_data, ..Sqe::default() }
+    }
+
+    /// Sends up to `len` bytes from `buf` to the socket `fd`.
+    pub fn send(fd: RawFd, buf: *const u8, len: usize, user_data: u64) -> Sqe {
+        Sqe { opcode: IORING_OP_SEND, fd, addr: buf as u64, len: len as u32, op_flags: libc::MSG_NOSIGNAL as u32, user_data, ..Sqe::default() }
+    }
+
+    /// Completes (with `-ETIME`) once `timeout` passed.
+    pub fn timeout(timeout: *const Timespec, user_data: u64) -> Sqe {
+        Sqe { opcode: IORING_OP_TIMEOUT, fd: -1, addr: timeout as u64, len: 1, user_data, ..Sqe::default() }
+    }
+}
+
+/// `__kernel_timespec` from linux/time_types.h.
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64
+}
+
+/// `io_uring_cqe` from linux/io_uring.h.
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32
+}
+
+/// A finished operation: its `user_data` and its result, a negative errno if it failed.
+pub struct Completion {
+    pub user_data: u64,
+    pub result: i32
+}
+
+/// A region shared with the kernel, unmapped on drop.
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize
+}
+
+impl Mmap {
+    fn new(fd: &OwnedFd, len: usize, offset: libc::off_t) -> io::Result<Mmap> {
+        // SAFETY: maps a region of the ring's file descriptor, the kernel checks `len` and `offset`.
+        let ptr = unsafe { libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_POPULATE, fd.as_raw_fd(), offset) };
+        if ptr == libc::MAP_FAILED { return Err(io::Error::last_os_error()); }
+        Ok(Mmap { ptr, len })
+    }
+
+    /// Pointer to the `T` at byte `offset`, as given by the kernel in `Params`.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        // SAFETY: the kernel's offsets lie within the mapping.
+        unsafe { self.ptr.cast::<u8>().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` describe a mapping created in `Mmap::new`.
+        unsafe { libc::munmap(self.ptr, self.len); }
+    }
+}
+
+pub struct Ring {
+    // the mappings are declared before `fd` so they are unmapped before it is closed
+    sq: Mmap,
+    cq: Mmap,
+    sqes: Mmap,
+    fd: OwnedFd,
+    sq_entries: u32,
+    sq_mask: u32,
+    cq_mask: u32,
+    sq_off: SqOffsets,
+    cq_off: CqOffsets,
+    /// Operations added since the last `submit`.
+    unsubmitted: u32
+}
+
+// SAFETY: the ring is only ever used by the thread that owns it, the raw pointers into the shared
+// mappings are not tied to the thread that created them.
+unsafe impl Send for Ring {}
+
+impl Ring {
+    pub fn new(entries: u32) -> io::Result<Ring> {
+        let mut params = Params::default();
+        // SAFETY: `params` is a valid io_uring_params struct the kernel fills in.
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params as *mut Params) };
+        if fd < 0 { return Err(io::Error::last_os_error()); }
+        // SAFETY: io_uring_setup returned a new file descriptor that nothing else owns.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * size_of::<u32>();
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+        let sq = Mmap::new(&fd, sq_len, IORING_OFF_SQ_RING)?;
+        let cq = Mmap::new(&fd, cq_len, IORING_OFF_CQ_RING)?;
+        let sqes = Mmap::new(&fd, params.sq_entries as usize * size_of::<Sqe>(), IORING_OFF_SQES)?;
+
+        // SAFETY: the masks are plain values at the kernel's offsets, they never change.
+        let (sq_mask, cq_mask) = unsafe { (*sq.at::<u32>(params.sq_off.ring_mask), *cq.at::<u32>(params.cq_off.ring_mask)) };
+        Ok(Ring { sq, cq, sqes, fd, sq_entries: params.sq_entries, sq_mask, cq_mask, sq_off: params.sq_off, cq_off: params.cq_off, unsubmitted: 0 })
+    }
+
+    fn atomic(&self, map: &Mmap, offset: u32) -> &AtomicU32 {
+        // SAFETY: head and tail are aligned u32s shared with the kernel, which only accesses them atomically.
+        unsafe { AtomicU32::from_ptr(map.at(offset)) }
+    }
+
+    /// Adds an operation, submitting the ones before it first if the queue is full.
+    ///
+    /// # Safety
+    /// The buffers `sqe` points to must stay valid until its completion was taken with `Ring::next`.
+    pub unsafe fn push(&mut self, sqe: Sqe) -> io::Result<()> {
+        let tail = self.atomic(&self.sq, self.sq_off.tail).load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.atomic(&self.sq, self.sq_off.head).load(Ordering::Acquire)) >= self.sq_entries {
+            self.submit(0)?;
+            if tail.wrapping_sub(self.atomic(&self.sq, self.sq_off.head).load(Ordering::Acquire)) >= self.sq_entries {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+        }
+
+        let index = tail & self.sq_mask;
+        // SAFETY: `index` is within the sqe array and the submission ring, and the kernel doesn't
+        // read that slot until the tail is moved past it.
+        unsafe {
+            self.sqes.at::<Sqe>(0).add(index as usize).write(sqe);
+            self.sq.at::<u32>(self.sq_off.array).add(index as usize).write(index);
+        }
+        self.atomic(&self.sq, self.sq_off.tail).store(tail.wrapping_add(1), Ordering::Release);
+        self.unsubmitted += 1;
+        Ok(())
+    }
+
+    /// Submits the added operations and waits until at least `wait` completions are available.
+    pub fn submit(&mut self, wait: u32) -> io::Result<()> {
+        let flags = if wait > 0 { IORING_ENTER_GETEVENTS } else { 0 };
+        // SAFETY: plain system call on the ring's file descriptor without a signal mask.
+        let submitted = unsafe {
+            libc::syscall(libc::SYS_io_uring_enter, self.fd.as_raw_fd(), self.unsubmitted, wait, flags, ptr::null::<libc::sigset_t>(), 0usize)
+        };
+        if submitted < 0 {
+            let error = io::Error::last_os_error();
+            // interrupted, or completions have to be taken before more can be submitted
+            if error.kind() == io::ErrorKind::Interrupted || error.raw_os_error() == Some(libc::EBUSY) { return Ok(()); }
+            return Err(error);
+        }
+        self.unsubmitted -= (submitted as u32).min(self.unsubmitted);
+        Ok(())
+    }
+
+    /// Takes the next completion if there is one.
+    pub fn next(&mut self) -> Option<Completion> {
+        let head = self.atomic(&self.cq, self.cq_off.head).load(Ordering::Relaxed);
+        if head == self.atomic(&self.cq, self.cq_off.tail).load(Ordering::Acquire) { return None; }
+
+        // SAFETY: the entry at `head` was written by the kernel before it moved the tail past it.
+        let cqe = unsafe { self.cq.at::<Cqe>(self.cq_off.cqes).add((head & self.cq_mask) as usize).read() };
+        self.atomic(&self.cq, self.cq_off.head).store(head.wrapping_add(1), Ordering::Release);
+        Some(Completion { user_data: cqe.user_data, result: cqe.res })
+    }
+}